@@ -0,0 +1,64 @@
+use sqlx::PgPool;
+use std::time::Duration;
+use terrashine::apitoken::{ApiTokens, Scope, TokenIdentity};
+
+#[sqlx::test]
+async fn test_api_token_lifecycle(pool: PgPool) {
+    let tokens = ApiTokens::new(pool);
+    let token = tokens
+        .create("ci", &[Scope::Read, Scope::ProvidersAdmin], None)
+        .await
+        .expect("Error occurred");
+
+    let identity = tokens
+        .authenticate(&token)
+        .await
+        .expect("Error occurred")
+        .expect("Token not found");
+    assert_eq!(
+        identity,
+        TokenIdentity {
+            name: "ci".into(),
+            scopes: vec![Scope::Read, Scope::ProvidersAdmin],
+        }
+    );
+    assert!(!identity.has_scope(Scope::CredentialsWrite));
+    assert_eq!(
+        tokens
+            .authenticate("not a token")
+            .await
+            .expect("Error occurred"),
+        None
+    );
+
+    let listed = tokens.list().await.expect("Error occurred");
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].scopes, vec!["read", "providers:admin"]);
+    assert!(listed[0].last_used_at.is_some(), "Use was not recorded");
+    assert_eq!(listed[0].expires_at, None);
+
+    assert!(
+        tokens.create("ci", &[Scope::Read], None).await.is_err(),
+        "Token names must be unique"
+    );
+    assert!(tokens.revoke("ci").await.expect("Error occurred"));
+    assert!(!tokens.revoke("ci").await.expect("Error occurred"));
+    assert_eq!(
+        tokens.authenticate(&token).await.expect("Error occurred"),
+        None
+    );
+}
+
+#[sqlx::test]
+async fn test_expired_api_token(pool: PgPool) {
+    let tokens = ApiTokens::new(pool);
+    let token = tokens
+        .create("expiring", &[Scope::Read], Some(Duration::from_millis(1)))
+        .await
+        .expect("Error occurred");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        tokens.authenticate(&token).await.expect("Error occurred"),
+        None
+    );
+}
@@ -1,6 +1,7 @@
 use sqlx::PgPool;
-use terrashine::credhelper::database::DatabaseCredentials;
-use terrashine::credhelper::{Credential, CredentialHelper};
+use terrashine::credhelper::database::{rotate_tokens, DatabaseCredentials};
+use terrashine::credhelper::encryption::TokenCipher;
+use terrashine::credhelper::{Credential, CredentialHelper, Token};
 
 #[sqlx::test]
 async fn test_insert_credential_helper(pool: PgPool) {
@@ -95,3 +96,191 @@ async fn test_forget_credential(pool: PgPool) {
         "Delete unrelated row detected"
     );
 }
+
+async fn stored_token(pool: &PgPool, hostname: &str) -> String {
+    let (token,): (String,) = sqlx::query_as(
+        r#"select "auth_token" from "terraform_registry_host" where "hostname" = $1;"#,
+    )
+    .bind(hostname)
+    .fetch_one(pool)
+    .await
+    .expect("Error occurred");
+    token
+}
+
+#[sqlx::test]
+async fn test_encrypted_credentials(pool: PgPool) {
+    let old_key = TokenCipher::new(vec![("k1".into(), [1; 32])]).unwrap();
+    let mut creds = DatabaseCredentials::new(pool.clone()).with_cipher(Some(old_key));
+    creds
+        .store("terraform1.isawan.net".into(), "password1".into())
+        .await
+        .expect("Error occurred");
+    let mut plaintext = DatabaseCredentials::new(pool.clone());
+    plaintext
+        .store("terraform2.isawan.net".into(), "password2".into())
+        .await
+        .expect("Error occurred");
+
+    let stored = stored_token(&pool, "terraform1.isawan.net").await;
+    assert!(
+        stored.starts_with("enc:v1:k1:"),
+        "Token stored in plaintext"
+    );
+    assert!(
+        plaintext.get("terraform1.isawan.net").await.is_err(),
+        "Encrypted token read without keys"
+    );
+
+    let new_key = TokenCipher::new(vec![("k2".into(), [2; 32]), ("k1".into(), [1; 32])]).unwrap();
+    assert_eq!(
+        rotate_tokens(&pool, &new_key)
+            .await
+            .expect("Error occurred"),
+        2
+    );
+    assert_eq!(
+        rotate_tokens(&pool, &new_key)
+            .await
+            .expect("Error occurred"),
+        0
+    );
+    assert!(stored_token(&pool, "terraform1.isawan.net")
+        .await
+        .starts_with("enc:v1:k2:"));
+    assert!(stored_token(&pool, "terraform2.isawan.net")
+        .await
+        .starts_with("enc:v1:k2:"));
+
+    let only_new_key = TokenCipher::new(vec![("k2".into(), [2; 32])]).unwrap();
+    let creds = DatabaseCredentials::new(pool).with_cipher(Some(only_new_key));
+    assert_eq!(
+        creds
+            .get("terraform1.isawan.net")
+            .await
+            .expect("Error occurred"),
+        Credential::Entry(Some("password1".into()))
+    );
+    assert_eq!(
+        creds
+            .get("terraform2.isawan.net")
+            .await
+            .expect("Error occurred"),
+        Credential::Entry(Some("password2".into()))
+    );
+}
+
+#[sqlx::test]
+async fn test_list_credentials_usage(pool: PgPool) {
+    let mut creds = DatabaseCredentials::new(pool);
+    creds
+        .store("terraform1.isawan.net".into(), "password1".into())
+        .await
+        .expect("Error occurred");
+    creds
+        .store("terraform2.isawan.net".into(), "password2".into())
+        .await
+        .expect("Error occurred");
+
+    let request = reqwest::Client::new().get("http://terraform1.isawan.net");
+    let _ = creds
+        .transform(request, "terraform1.isawan.net")
+        .await
+        .expect("Error occurred");
+    creds
+        .record_auth_failure("terraform2.isawan.net", 403)
+        .await
+        .expect("Error occurred");
+    creds
+        .record_auth_failure("unknown.isawan.net", 401)
+        .await
+        .expect("Error occurred");
+
+    let listed = creds.list().await.expect("Error occurred");
+    assert_eq!(
+        listed
+            .iter()
+            .map(|c| c.hostname.as_str())
+            .collect::<Vec<_>>(),
+        vec!["terraform1.isawan.net", "terraform2.isawan.net"]
+    );
+    assert!(listed
+        .iter()
+        .all(|c| c.created_at.is_some() && c.updated_at.is_some()));
+    assert!(listed[0].last_used_at.is_some(), "Use not recorded");
+    assert_eq!(listed[0].last_auth_failure, None);
+    assert_eq!(listed[1].last_used_at, None);
+    assert_eq!(
+        listed[1].last_auth_failure.as_ref().map(|f| f.status),
+        Some(403),
+        "Auth failure not recorded"
+    );
+}
+
+#[sqlx::test]
+async fn test_typed_credentials(pool: PgPool) {
+    // Rows written before tokens were typed are bearer tokens
+    sqlx::query(
+        r#"insert into "terraform_registry_host" ("hostname", "auth_token") values ($1, $2);"#,
+    )
+    .bind("terraform1.isawan.net")
+    .bind("password1")
+    .execute(&pool)
+    .await
+    .expect("Error occurred");
+    let key = TokenCipher::new(vec![("k1".into(), [1; 32])]).unwrap();
+    let mut creds = DatabaseCredentials::new(pool.clone()).with_cipher(Some(key));
+    assert_eq!(
+        creds
+            .get("terraform1.isawan.net")
+            .await
+            .expect("Error occurred"),
+        Credential::Entry(Some(Token::Bearer("password1".into())))
+    );
+
+    let basic = Token::Basic {
+        username: "user".into(),
+        password: "password2".into(),
+    };
+    let header = Token::Header {
+        name: "X-JFrog-Art-Api".into(),
+        value: "password3".into(),
+    };
+    creds
+        .store("terraform2.isawan.net".into(), basic.clone())
+        .await
+        .expect("Error occurred");
+    creds
+        .store("terraform3.isawan.net".into(), header.clone())
+        .await
+        .expect("Error occurred");
+    assert!(stored_token(&pool, "terraform2.isawan.net")
+        .await
+        .starts_with("enc:v1:k1:"));
+    assert_eq!(
+        creds
+            .get("terraform2.isawan.net")
+            .await
+            .expect("Error occurred"),
+        Credential::Entry(Some(basic))
+    );
+    assert_eq!(
+        creds
+            .get("terraform3.isawan.net")
+            .await
+            .expect("Error occurred"),
+        Credential::Entry(Some(header))
+    );
+
+    creds
+        .store("terraform3.isawan.net".into(), "password4".into())
+        .await
+        .expect("Error occurred");
+    assert_eq!(
+        creds
+            .get("terraform3.isawan.net")
+            .await
+            .expect("Error occurred"),
+        Credential::Entry(Some(Token::Bearer("password4".into())))
+    );
+}
@@ -1,3 +1,4 @@
+mod apitoken;
 mod credhelper;
 mod util;
 
@@ -33,11 +34,43 @@ fn test_server_startup(_: PoolOptions<Postgres>, db_options: PgConnectOptions) {
         s3_bucket_prefix: prefix,
         s3_endpoint: Some(Url::parse("http://localhost:9000").unwrap()),
         http_redirect_url: Url::parse("https://localhost:9443/").unwrap(),
+        registry_default_hostname: "registry.terraform.io".to_string(),
+        trusted_proxies: vec![],
         http_listen: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
         refresh_interval: Duration::from_secs(10),
         upstream_registry_port: 443,
+        upstream_config: None,
+        upstream_mirror_url: None,
+        allowed_protocols: vec![],
+        allowed_platforms: vec![],
+        download_allowed_hosts: vec![],
+        download_private_hosts: vec![],
+        download_max_redirects: 5,
+        download_credential_hosts: vec![],
+        terraform_credentials: false,
+        terraform_cli_config: None,
+        credentials_helper: None,
+        credentials_helper_args: vec![],
+        credentials_helper_timeout: Duration::from_secs(10),
+        credentials_helper_cache_ttl: Duration::from_secs(60),
+        credentials_helper_store: false,
+        token_encryption: Default::default(),
+        tls: Default::default(),
         http_proxy: None,
         no_proxy: None,
+        webhook_url: vec![],
+        webhook_secret: None,
+        webhook_max_attempts: 12,
+        private_registry_hostname: None,
+        signing_key: None,
+        signing_key_passphrase: None,
+        api_auth: false,
+        mirror_auth: false,
+        mirror_url_secret: None,
+        api_publish_token: None,
+        releases_upstream_url: Url::parse("https://releases.hashicorp.com/").unwrap(),
+        releases_signing_key: None,
+        offline: false,
     };
     let cancellation_token = tokio_util::sync::CancellationToken::new();
     let (tx, rx) = tokio::sync::oneshot::channel();
@@ -74,11 +107,43 @@ fn test_end_to_end_terraform_flow(_: PoolOptions<Postgres>, db_options: PgConnec
         s3_bucket_prefix: prefix,
         s3_endpoint: Some(Url::parse("http://localhost:9000").unwrap()),
         http_redirect_url: Url::parse("https://localhost:9443/mirror/v1/").unwrap(),
+        registry_default_hostname: "registry.terraform.io".to_string(),
+        trusted_proxies: vec![],
         http_listen: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9543),
         refresh_interval: Duration::from_secs(10),
         upstream_registry_port: 443,
+        upstream_config: None,
+        upstream_mirror_url: None,
+        allowed_protocols: vec![],
+        allowed_platforms: vec![],
+        download_allowed_hosts: vec![],
+        download_private_hosts: vec![],
+        download_max_redirects: 5,
+        download_credential_hosts: vec![],
+        terraform_credentials: false,
+        terraform_cli_config: None,
+        credentials_helper: None,
+        credentials_helper_args: vec![],
+        credentials_helper_timeout: Duration::from_secs(10),
+        credentials_helper_cache_ttl: Duration::from_secs(60),
+        credentials_helper_store: false,
+        token_encryption: Default::default(),
+        tls: Default::default(),
         http_proxy: None,
         no_proxy: None,
+        webhook_url: vec![],
+        webhook_secret: None,
+        webhook_max_attempts: 12,
+        private_registry_hostname: None,
+        signing_key: None,
+        signing_key_passphrase: None,
+        api_auth: false,
+        mirror_auth: false,
+        mirror_url_secret: None,
+        api_publish_token: None,
+        releases_upstream_url: Url::parse("https://releases.hashicorp.com/").unwrap(),
+        releases_signing_key: None,
+        offline: false,
     };
     let cancellation_token = tokio_util::sync::CancellationToken::new();
     let (tx, rx) = tokio::sync::oneshot::channel();
@@ -144,11 +209,43 @@ fn test_health_check_functionality(_: PoolOptions<Postgres>, db_options: PgConne
         s3_bucket_prefix: prefix,
         s3_endpoint: Some(Url::parse("http://localhost:9000").unwrap()),
         http_redirect_url: Url::parse("https://localhost:9445/mirror/v1/").unwrap(),
+        registry_default_hostname: "registry.terraform.io".to_string(),
+        trusted_proxies: vec![],
         http_listen: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9545),
         refresh_interval: Duration::from_secs(10),
         upstream_registry_port: 443,
+        upstream_config: None,
+        upstream_mirror_url: None,
+        allowed_protocols: vec![],
+        allowed_platforms: vec![],
+        download_allowed_hosts: vec![],
+        download_private_hosts: vec![],
+        download_max_redirects: 5,
+        download_credential_hosts: vec![],
+        terraform_credentials: false,
+        terraform_cli_config: None,
+        credentials_helper: None,
+        credentials_helper_args: vec![],
+        credentials_helper_timeout: Duration::from_secs(10),
+        credentials_helper_cache_ttl: Duration::from_secs(60),
+        credentials_helper_store: false,
+        token_encryption: Default::default(),
+        tls: Default::default(),
         http_proxy: None,
         no_proxy: None,
+        webhook_url: vec![],
+        webhook_secret: None,
+        webhook_max_attempts: 12,
+        private_registry_hostname: None,
+        signing_key: None,
+        signing_key_passphrase: None,
+        api_auth: false,
+        mirror_auth: false,
+        mirror_url_secret: None,
+        api_publish_token: None,
+        releases_upstream_url: Url::parse("https://releases.hashicorp.com/").unwrap(),
+        releases_signing_key: None,
+        offline: false,
     };
     let cancellation_token = tokio_util::sync::CancellationToken::new();
     let (tx, rx) = tokio::sync::oneshot::channel();
@@ -162,6 +259,7 @@ fn test_health_check_functionality(_: PoolOptions<Postgres>, db_options: PgConne
 
     terrashine::healthy::run_healthy(IsHealthyArgs {
         http_listen: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9545),
+        tls_cert: None,
     })
     .await
     .expect("Health check failed");
@@ -169,3 +267,398 @@ fn test_health_check_functionality(_: PoolOptions<Postgres>, db_options: PgConne
     // run is_healthy
     handle.abort();
 }
+
+/// Set up an offline terrashine and check only cached content is served
+#[traced_test]
+#[sqlx::test]
+fn test_offline_mode(pool_options: PoolOptions<Postgres>, db_options: PgConnectOptions) {
+    let db = pool_options.connect_with(db_options.clone()).await.unwrap();
+    let (provider_id,): (i64,) = sqlx::query_as(
+        r#"
+        insert into "terraform_provider" ("hostname", "namespace", "type", "last_refreshed")
+        values ('registry.terraform.io', 'hashicorp', 'random', now())
+        returning "id";
+        "#,
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let (version_id,): (i64,) = sqlx::query_as(
+        r#"
+        insert into "terraform_provider_version" ("provider_id", "version", "os", "arch")
+        values ($1, '3.6.0', 'linux', 'amd64')
+        returning "id";
+        "#,
+    )
+    .bind(provider_id)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let prefix = format!("{}/", Uuid::new_v4());
+    let config = ServerArgs {
+        database_url: db_options,
+        database_pool: 3,
+        s3_bucket_name: "terrashine".to_string(),
+        s3_bucket_prefix: prefix,
+        s3_endpoint: Some(Url::parse("http://localhost:9000").unwrap()),
+        http_redirect_url: Url::parse("https://localhost:9446/mirror/v1/").unwrap(),
+        registry_default_hostname: "registry.terraform.io".to_string(),
+        trusted_proxies: vec![],
+        http_listen: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+        refresh_interval: Duration::from_secs(10),
+        upstream_registry_port: 443,
+        upstream_config: None,
+        upstream_mirror_url: None,
+        allowed_protocols: vec![],
+        allowed_platforms: vec![],
+        download_allowed_hosts: vec![],
+        download_private_hosts: vec![],
+        download_max_redirects: 5,
+        download_credential_hosts: vec![],
+        terraform_credentials: false,
+        terraform_cli_config: None,
+        credentials_helper: None,
+        credentials_helper_args: vec![],
+        credentials_helper_timeout: Duration::from_secs(10),
+        credentials_helper_cache_ttl: Duration::from_secs(60),
+        credentials_helper_store: false,
+        token_encryption: Default::default(),
+        tls: Default::default(),
+        http_proxy: None,
+        no_proxy: None,
+        webhook_url: vec![],
+        webhook_secret: None,
+        webhook_max_attempts: 12,
+        private_registry_hostname: None,
+        signing_key: None,
+        signing_key_passphrase: None,
+        api_auth: false,
+        mirror_auth: false,
+        mirror_url_secret: None,
+        api_publish_token: None,
+        releases_upstream_url: Url::parse("https://releases.hashicorp.com/").unwrap(),
+        releases_signing_key: None,
+        offline: true,
+    };
+    let cancellation_token = tokio_util::sync::CancellationToken::new();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let handle = tokio::spawn(terrashine::run_server(
+        config,
+        None,
+        cancellation_token.child_token(),
+        tx,
+    ));
+    let socket = rx.await.unwrap().msg;
+
+    let status = |path: String| async move {
+        reqwest::get(format!("http://{socket}{path}"))
+            .await
+            .unwrap()
+            .status()
+    };
+    assert_eq!(
+        status("/mirror/v1/registry.terraform.io/hashicorp/random/index.json".into()).await,
+        StatusCode::OK
+    );
+    for path in [
+        "/mirror/v1/registry.terraform.io/hashicorp/null/index.json".to_string(),
+        format!("/mirror/v1/artifacts/{version_id}"),
+        "/modules/v1/registry.terraform.io/hashicorp/consul/aws/versions".to_string(),
+        "/releases/terraform/index.json".to_string(),
+        "/releases/terraform/1.9.0/terraform_1.9.0_linux_amd64.zip".to_string(),
+        // Registry login discovers its service upstream
+        "/api/v1/logins/registry.terraform.io".to_string(),
+    ] {
+        assert_eq!(status(path.clone()).await, StatusCode::NOT_FOUND, "{path}");
+    }
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://{socket}/api/v1/logins/registry.terraform.io"
+        ))
+        .header("content-type", "application/json")
+        .body(r#"{ "data": { "redirect_uri": "http://localhost:10000/login" } }"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(logs_contain(
+        "Offline mode, refusing to fetch uncached content from upstream"
+    ));
+
+    cancellation_token.cancel();
+    handle.abort();
+}
+
+/// Check credentials set with the API are stored with the credentials helper when enabled
+#[sqlx::test]
+fn test_credentials_helper_store(
+    pool_options: PoolOptions<Postgres>,
+    db_options: PgConnectOptions,
+) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let db = pool_options.connect_with(db_options.clone()).await.unwrap();
+    let folder = tempfile::tempdir().expect("Could not create folder");
+    let helper = folder.path().join("terraform-credentials-test");
+    std::fs::write(
+        &helper,
+        r#"#!/bin/sh
+cd "$(dirname "$0")"
+echo "$1 $2" >> calls
+case "$1" in
+  get) if [ -f "$2" ]; then cat "$2"; else echo '{}'; fi ;;
+  store) cat > "$2" ;;
+  forget) rm -f "$2" ;;
+esac
+"#,
+    )
+    .unwrap();
+    std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let prefix = format!("{}/", Uuid::new_v4());
+    let config = ServerArgs {
+        database_url: db_options,
+        database_pool: 3,
+        s3_bucket_name: "terrashine".to_string(),
+        s3_bucket_prefix: prefix,
+        s3_endpoint: Some(Url::parse("http://localhost:9000").unwrap()),
+        http_redirect_url: Url::parse("https://localhost:9447/mirror/v1/").unwrap(),
+        registry_default_hostname: "registry.terraform.io".to_string(),
+        trusted_proxies: vec![],
+        http_listen: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+        refresh_interval: Duration::from_secs(10),
+        upstream_registry_port: 443,
+        upstream_config: None,
+        upstream_mirror_url: None,
+        allowed_protocols: vec![],
+        allowed_platforms: vec![],
+        download_allowed_hosts: vec![],
+        download_private_hosts: vec![],
+        download_max_redirects: 5,
+        download_credential_hosts: vec![],
+        terraform_credentials: false,
+        terraform_cli_config: None,
+        credentials_helper: Some(helper),
+        credentials_helper_args: vec![],
+        credentials_helper_timeout: Duration::from_secs(10),
+        credentials_helper_cache_ttl: Duration::from_secs(60),
+        credentials_helper_store: true,
+        token_encryption: Default::default(),
+        tls: Default::default(),
+        http_proxy: None,
+        no_proxy: None,
+        webhook_url: vec![],
+        webhook_secret: None,
+        webhook_max_attempts: 12,
+        private_registry_hostname: None,
+        signing_key: None,
+        signing_key_passphrase: None,
+        api_auth: false,
+        mirror_auth: false,
+        mirror_url_secret: None,
+        api_publish_token: None,
+        releases_upstream_url: Url::parse("https://releases.hashicorp.com/").unwrap(),
+        releases_signing_key: None,
+        offline: true,
+    };
+    let cancellation_token = tokio_util::sync::CancellationToken::new();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let handle = tokio::spawn(terrashine::run_server(
+        config,
+        None,
+        cancellation_token.child_token(),
+        tx,
+    ));
+    let socket = rx.await.unwrap().msg;
+    let client = reqwest::Client::new();
+    let url = format!("http://{socket}/api/v1/credentials/example.com");
+
+    let response = client
+        .post(&url)
+        .header("content-type", "application/json")
+        .body(r#"{ "data": { "token": "password1" } }"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        std::fs::read_to_string(folder.path().join("example.com")).unwrap(),
+        r#"{"token":"password1"}"#
+    );
+    let (stored,): (i64,) = sqlx::query_as(r#"select count(*) from "terraform_registry_host";"#)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0, "Credential stored in the database");
+
+    let response = client.delete(&url).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!folder.path().join("example.com").exists());
+    assert_eq!(
+        std::fs::read_to_string(folder.path().join("calls")).unwrap(),
+        "store example.com\nforget example.com\n"
+    );
+
+    cancellation_token.cancel();
+    handle.abort();
+}
+
+/// Check the mirror index only lists versions supporting an allowed protocol and platform
+#[sqlx::test]
+fn test_version_filtering(pool_options: PoolOptions<Postgres>, db_options: PgConnectOptions) {
+    let db = pool_options.connect_with(db_options.clone()).await.unwrap();
+    let (provider_id,): (i64,) = sqlx::query_as(
+        r#"
+        insert into "terraform_provider" ("hostname", "namespace", "type", "last_refreshed")
+        values ('registry.terraform.io', 'hashicorp', 'random', now())
+        returning "id";
+        "#,
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    let (windows_provider_id,): (i64,) = sqlx::query_as(
+        r#"
+        insert into "terraform_provider" ("hostname", "namespace", "type", "last_refreshed")
+        values ('registry.terraform.io', 'hashicorp', 'null', now())
+        returning "id";
+        "#,
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        r#"
+        insert into "terraform_provider_version" ("provider_id", "version", "os", "arch", "protocols")
+        values
+            ($1, '3.6.0', 'linux', 'amd64', '{5.0}'),
+            ($1, '3.5.0', 'windows', 'amd64', '{5.0}'),
+            ($1, '1.0.0', 'linux', 'amd64', '{4.0}'),
+            ($1, '0.1.0', 'linux', 'amd64', null),
+            ($2, '3.2.0', 'windows', 'amd64', '{5.0}');
+        "#,
+    )
+    .bind(provider_id)
+    .bind(windows_provider_id)
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        r#"
+        insert into "terraform_provider_platforms" ("hostname", "namespace", "type", "platforms")
+        values ('registry.terraform.io', 'hashicorp', 'null', '{windows_amd64}');
+        "#,
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let prefix = format!("{}/", Uuid::new_v4());
+    let config = ServerArgs {
+        database_url: db_options,
+        database_pool: 3,
+        s3_bucket_name: "terrashine".to_string(),
+        s3_bucket_prefix: prefix,
+        s3_endpoint: Some(Url::parse("http://localhost:9000").unwrap()),
+        http_redirect_url: Url::parse("https://localhost:9447/mirror/v1/").unwrap(),
+        registry_default_hostname: "registry.terraform.io".to_string(),
+        trusted_proxies: vec![],
+        http_listen: SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+        refresh_interval: Duration::from_secs(10),
+        upstream_registry_port: 443,
+        upstream_config: None,
+        upstream_mirror_url: None,
+        allowed_protocols: vec![5, 6],
+        allowed_platforms: vec!["linux_amd64".to_string()],
+        download_allowed_hosts: vec![],
+        download_private_hosts: vec![],
+        download_max_redirects: 5,
+        download_credential_hosts: vec![],
+        terraform_credentials: false,
+        terraform_cli_config: None,
+        credentials_helper: None,
+        credentials_helper_args: vec![],
+        credentials_helper_timeout: Duration::from_secs(10),
+        credentials_helper_cache_ttl: Duration::from_secs(60),
+        credentials_helper_store: false,
+        token_encryption: Default::default(),
+        tls: Default::default(),
+        http_proxy: None,
+        no_proxy: None,
+        webhook_url: vec![],
+        webhook_secret: None,
+        webhook_max_attempts: 12,
+        private_registry_hostname: None,
+        signing_key: None,
+        signing_key_passphrase: None,
+        api_auth: false,
+        mirror_auth: false,
+        mirror_url_secret: None,
+        api_publish_token: None,
+        releases_upstream_url: Url::parse("https://releases.hashicorp.com/").unwrap(),
+        releases_signing_key: None,
+        offline: true,
+    };
+    let cancellation_token = tokio_util::sync::CancellationToken::new();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let handle = tokio::spawn(terrashine::run_server(
+        config,
+        None,
+        cancellation_token.child_token(),
+        tx,
+    ));
+    let socket = rx.await.unwrap().msg;
+
+    let versions = |provider: &'static str| async move {
+        let index = reqwest::get(format!(
+            "http://{socket}/mirror/v1/registry.terraform.io/hashicorp/{provider}/index.json"
+        ))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        let index: serde_json::Value = serde_json::from_str(&index).unwrap();
+        let mut versions = index["versions"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        versions.sort();
+        versions
+    };
+    assert_eq!(versions("random").await, vec!["0.1.0", "3.6.0"]);
+    assert_eq!(versions("null").await, vec!["3.2.0"]);
+
+    // Protocols of versions without a downloaded package come from the upstream index,
+    // and versions are filtered by them as in the mirror index
+    let registry: serde_json::Value = serde_json::from_str(
+        &reqwest::Client::new()
+            .get(format!(
+                "http://{socket}/providers/v1/hashicorp/random/versions"
+            ))
+            .header("host", "localhost")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap(),
+    )
+    .unwrap();
+    let protocols = |version: &str| {
+        registry["versions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|v| v["version"] == version)
+            .map(|v| v["protocols"].clone())
+    };
+    assert_eq!(protocols("3.6.0"), Some(serde_json::json!(["5.0"])));
+    assert_eq!(protocols("1.0.0"), None);
+
+    cancellation_token.cancel();
+    handle.abort();
+}
@@ -0,0 +1,14 @@
+create table if not exists "webhook_delivery" (
+    "id" bigint generated by default as identity primary key,
+    "endpoint" text not null,
+    "event_type" text not null,
+    "payload" jsonb not null,
+    "attempts" integer not null default 0,
+    "last_error" text,
+    "created_at" timestamp with time zone not null default now(),
+    "next_attempt_at" timestamp with time zone not null default now(),
+    "abandoned_at" timestamp with time zone
+);
+
+create index if not exists "webhook_delivery_pending" on "webhook_delivery" ("next_attempt_at")
+    where "abandoned_at" is null;
@@ -0,0 +1,33 @@
+create table if not exists "terraform_module" (
+    "id" bigint generated by default as identity primary key,
+    "hostname" text not null check (char_length("hostname") <= 253),
+    "namespace" text not null check (char_length("namespace") <= 255),
+    "name" text not null check (char_length("name") <= 255),
+    "system" text not null check (char_length("system") <= 255),
+    "last_refreshed" timestamp with time zone not null,
+    constraint "unique_module" unique ("hostname", "namespace", "name", "system")
+);
+
+create table if not exists "terraform_module_version" (
+    "id" bigint generated by default as identity primary key,
+
+    "module_id" bigint references "terraform_module" ("id") not null,
+    "version" text not null check (char_length("version") <= 255),
+
+    -- Location of the module package as resolved from the upstream X-Terraform-Get header
+    "upstream_source" text,
+    -- Set when the upstream source is an archive that can be cached
+    "archive_format" text check (char_length("archive_format") <= 16),
+    "subdirectory" text,
+
+    "artifact_id" bigint,
+    "artifact_timestamp" timestamp with time zone,
+
+    constraint "module_version_tuple" unique ("module_id", "version"),
+    constraint "unique_module_artifact_id" unique ("artifact_id"),
+    constraint "consistent_module_upload_details" check (
+        ("artifact_id" is null and "artifact_timestamp" is null)
+        or
+        ("artifact_id" is not null and "artifact_timestamp" is not null
+            and "archive_format" is not null))
+);
@@ -0,0 +1,11 @@
+-- Package metadata from the provider registry "Find a Provider Package" response.
+-- This is recorded so that terrashine can serve the provider registry protocol.
+create table if not exists "terraform_provider_package" (
+    "version_id" bigint primary key references "terraform_provider_version" ("id"),
+    "protocols" text[] not null,
+    "filename" text not null check (char_length("filename") <= 255),
+    "shasum" text not null check (char_length("shasum") <= 255),
+    "shasums" text not null,
+    "shasums_signature" bytea not null,
+    "signing_keys" jsonb not null
+);
@@ -0,0 +1,29 @@
+-- Cached index documents from a releases.hashicorp.com style releases server.
+-- The product index is stored with an empty version.
+create table if not exists "release_index" (
+    "product" text not null check (char_length("product") <= 255),
+    "version" text not null check (char_length("version") <= 255),
+    "body" text not null,
+    "last_refreshed" timestamp with time zone not null,
+    primary key ("product", "version")
+);
+
+-- Verified checksums for a release, used to verify cached archives.
+create table if not exists "release_version" (
+    "id" bigint generated by default as identity primary key,
+    "product" text not null check (char_length("product") <= 255),
+    "version" text not null check (char_length("version") <= 255),
+    "shasums" text not null,
+    "shasums_signature" bytea not null,
+    constraint "release_version_tuple" unique ("product", "version")
+);
+
+create table if not exists "release_artifact" (
+    "id" bigint generated by default as identity primary key,
+    "release_version_id" bigint references "release_version" ("id") not null,
+    "filename" text not null check (char_length("filename") <= 255),
+    "artifact_id" bigint not null,
+    "artifact_timestamp" timestamp with time zone not null,
+    constraint "release_artifact_tuple" unique ("release_version_id", "filename"),
+    constraint "unique_release_artifact_id" unique ("artifact_id")
+);
@@ -0,0 +1,10 @@
+-- Connection overrides for upstream hosts.
+-- Rows take precedence over entries for the same hostname in the upstream config file.
+create table if not exists "upstream_host" (
+    "hostname" text primary key check (char_length("hostname") <= 255),
+    "base_url" text check (char_length("base_url") <= 2048),
+    "port" integer check ("port" between 1 and 65535),
+    "proxy" text check (char_length("proxy") <= 2048),
+    "ca_bundle" text,
+    "timeout_seconds" integer check ("timeout_seconds" > 0)
+);
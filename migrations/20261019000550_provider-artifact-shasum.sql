@@ -0,0 +1,2 @@
+-- sha256 checksum of the cached provider package, served to child mirrors as a zh: hash
+alter table "terraform_provider_version" add column "artifact_shasum" text check (char_length("artifact_shasum") <= 255);
@@ -0,0 +1,5 @@
+-- Ordered upstream sources for a hostname, either "origin" or a network mirror URL
+alter table "upstream_host" add column "sources" text[];
+
+-- Upstream source a provider package was fetched from
+alter table "terraform_provider_version" add column "artifact_source" text;
@@ -0,0 +1,2 @@
+-- Plugin protocols supported by a provider version, null when unknown
+alter table "terraform_provider_version" add column "protocols" text[];
@@ -0,0 +1,9 @@
+-- Per provider overrides of the allowed platforms, as {os}_{arch} names.
+-- A row replaces the configured platform allowlist for the provider.
+create table if not exists "terraform_provider_platforms" (
+    "hostname" text not null check (char_length("hostname") <= 253),
+    "namespace" text not null check (char_length("namespace") <= 255),
+    "type" text not null check (char_length("type") <= 255),
+    "platforms" text[] not null,
+    primary key ("hostname", "namespace", "type")
+);
@@ -0,0 +1,6 @@
+-- PEM client certificate and key presented to the upstream for mutual TLS
+alter table "upstream_host" add column "client_cert" text;
+alter table "upstream_host" add column "client_key" text;
+
+-- sha256/{base64} digests of public keys the upstream certificate must match
+alter table "upstream_host" add column "spki_pins" text[];
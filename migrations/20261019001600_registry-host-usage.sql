@@ -0,0 +1,8 @@
+-- Inventory metadata of stored registry auth tokens, the tokens themselves are never listed
+alter table "terraform_registry_host" add column "created_at" timestamp with time zone not null default now();
+alter table "terraform_registry_host" add column "updated_at" timestamp with time zone not null default now();
+alter table "terraform_registry_host" add column "last_used_at" timestamp with time zone;
+
+-- Most recent 401 or 403 response from the upstream while using the token
+alter table "terraform_registry_host" add column "last_auth_failure_at" timestamp with time zone;
+alter table "terraform_registry_host" add column "last_auth_failure_status" integer check ("last_auth_failure_status" between 100 and 599);
@@ -0,0 +1,6 @@
+-- How the auth token is sent upstream, existing tokens are bearer tokens
+alter table "terraform_registry_host" add column "auth_scheme" text not null default 'bearer'
+    check ("auth_scheme" in ('bearer', 'basic', 'header'));
+
+-- Username for basic auth or header name for header auth, the secret stays in "auth_token"
+alter table "terraform_registry_host" add column "auth_name" text check (char_length("auth_name") <= 255);
@@ -0,0 +1,18 @@
+-- Pending login.v1 authorization code flows, keyed by their OAuth state
+create table if not exists "registry_login_request" (
+    "state" text primary key check (char_length("state") <= 255),
+    "hostname" text not null check (char_length("hostname") <= 253),
+    "code_verifier" text not null,
+    "redirect_uri" text not null check (char_length("redirect_uri") <= 2048),
+    "created_at" timestamp with time zone not null default now()
+);
+
+-- Tokens obtained with login.v1, the access token is stored as the credential for the hostname.
+-- Access tokens with a refresh token are refreshed before they expire.
+create table if not exists "registry_login" (
+    "hostname" text primary key check (char_length("hostname") <= 253),
+    "client_id" text not null,
+    "token_url" text not null check (char_length("token_url") <= 2048),
+    "refresh_token" text,
+    "expires_at" timestamp with time zone
+);
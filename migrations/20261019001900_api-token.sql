@@ -0,0 +1,10 @@
+-- Tokens for the admin API, only the SHA-256 digest of each token is stored
+create table if not exists "api_token" (
+    "id" bigserial primary key,
+    "name" text not null unique check (char_length("name") <= 255),
+    "token_hash" bytea not null unique,
+    "scopes" text[] not null,
+    "created_at" timestamp with time zone not null default now(),
+    "last_used_at" timestamp with time zone,
+    "expires_at" timestamp with time zone
+);
@@ -0,0 +1,9 @@
+-- Hostnames of stored credentials are looked up in lowercase, they were previously stored as given.
+-- Where several rows only differ in case, the most recently updated one is kept.
+delete from "terraform_registry_host" as "t1"
+using "terraform_registry_host" as "t2"
+where lower("t1"."hostname") = lower("t2"."hostname")
+    and ("t1"."updated_at", "t1"."id") < ("t2"."updated_at", "t2"."id");
+
+update "terraform_registry_host" set "hostname" = lower("hostname")
+where "hostname" <> lower("hostname");
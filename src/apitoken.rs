@@ -0,0 +1,323 @@
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use std::{fmt::Display, str::FromStr, time::Duration};
+use tracing::{error, info};
+
+use crate::{
+    config::{ApiTokenArgs, ApiTokenCommand},
+    registry::random_secret,
+};
+
+/// Minimum time between updates of a token's last use, so every request does not write.
+const LAST_USED_INTERVAL_SECONDS: f64 = 60.0;
+
+/// Permission granted to an API token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// Reading stored credentials and their metadata
+    Read,
+    /// Storing and deleting credentials, including logging in to registries
+    CredentialsWrite,
+    /// Publishing providers
+    ProvidersAdmin,
+    /// Installing providers through the mirror, for Terraform clients
+    Mirror,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [
+        Scope::Read,
+        Scope::CredentialsWrite,
+        Scope::ProvidersAdmin,
+        Scope::Mirror,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::CredentialsWrite => "credentials:write",
+            Scope::ProvidersAdmin => "providers:admin",
+            Scope::Mirror => "mirror:read",
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown scope {s}, expected one of {}",
+                    Scope::ALL.map(|scope| scope.as_str()).join(", ")
+                )
+            })
+    }
+}
+
+/// The token a request was authenticated with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenIdentity {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl TokenIdentity {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// Metadata of a stored token, the token itself cannot be recovered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiTokenSummary {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub expires_at: Option<String>,
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// API tokens stored in the database.
+///
+/// Tokens are random 256 bit secrets, so a plain SHA-256 digest is enough to keep
+/// the stored values from being usable.
+#[derive(Debug, Clone)]
+pub struct ApiTokens {
+    db: PgPool,
+}
+
+impl ApiTokens {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// Creates a token, returning the only copy of it.
+    pub async fn create(
+        &self,
+        name: &str,
+        scopes: &[Scope],
+        expires_in: Option<Duration>,
+    ) -> Result<String, sqlx::Error> {
+        let token = random_secret();
+        let scopes = scopes
+            .iter()
+            .map(|scope| scope.as_str().to_string())
+            .collect::<Vec<_>>();
+        sqlx::query!(
+            r#"
+            insert into "api_token" ("name", "token_hash", "scopes", "expires_at")
+            values ($1, $2, $3, now() + make_interval(secs => $4));
+            "#,
+            name,
+            hash_token(&token),
+            &scopes,
+            expires_in.map(|duration| duration.as_secs_f64()),
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(token)
+    }
+
+    /// Looks up an unexpired token, recording that it was used.
+    ///
+    /// Scopes that are not known to this version of terrashine are ignored.
+    pub async fn authenticate(&self, token: &str) -> Result<Option<TokenIdentity>, sqlx::Error> {
+        let token_hash = hash_token(token);
+        let Some(row) = sqlx::query!(
+            r#"
+            select
+                "name",
+                "scopes",
+                ("last_used_at" is null
+                    or "last_used_at" < now() - make_interval(secs => $2)) as "stale!"
+            from "api_token"
+            where "token_hash" = $1
+                and ("expires_at" is null or "expires_at" > now());
+            "#,
+            token_hash,
+            LAST_USED_INTERVAL_SECONDS,
+        )
+        .fetch_optional(&self.db)
+        .await?
+        else {
+            return Ok(None);
+        };
+        if row.stale {
+            sqlx::query!(
+                r#"
+                update "api_token" set "last_used_at" = now() where "token_hash" = $1;
+                "#,
+                token_hash,
+            )
+            .execute(&self.db)
+            .await?;
+        }
+        Ok(Some(TokenIdentity {
+            name: row.name,
+            scopes: row
+                .scopes
+                .iter()
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+        }))
+    }
+
+    /// Looks up an unexpired token by name, for requests authorized on its behalf
+    /// without the token itself, such as signed download URLs.
+    pub async fn find(&self, name: &str) -> Result<Option<TokenIdentity>, sqlx::Error> {
+        let row = sqlx::query!(
+            r#"
+            select "name", "scopes" from "api_token"
+            where "name" = $1
+                and ("expires_at" is null or "expires_at" > now());
+            "#,
+            name,
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(row.map(|row| TokenIdentity {
+            name: row.name,
+            scopes: row
+                .scopes
+                .iter()
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+        }))
+    }
+
+    pub async fn list(&self) -> Result<Vec<ApiTokenSummary>, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"
+            select
+                "name",
+                "scopes",
+                to_char("created_at" at time zone 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "created_at!",
+                to_char("last_used_at" at time zone 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "last_used_at",
+                to_char("expires_at" at time zone 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "expires_at"
+            from "api_token"
+            order by "name";
+            "#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| ApiTokenSummary {
+                name: row.name,
+                scopes: row.scopes,
+                created_at: row.created_at,
+                last_used_at: row.last_used_at,
+                expires_at: row.expires_at,
+            })
+            .collect())
+    }
+
+    /// Deletes a token, returning whether it existed.
+    pub async fn revoke(&self, name: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            delete from "api_token" where "name" = $1;
+            "#,
+            name,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Manages API tokens in the database.
+pub async fn run_api_token(config: ApiTokenArgs) -> Result<(), ()> {
+    let db = match PgPoolOptions::new()
+        .acquire_timeout(Duration::from_secs(10))
+        .connect_with(config.database_url.clone())
+        .await
+    {
+        Ok(pool) => pool,
+        Err(error) => {
+            error!(reason = %error, "Could not initialize pool, exiting.");
+            return Err(());
+        }
+    };
+    let tokens = ApiTokens::new(db);
+
+    match config.command {
+        ApiTokenCommand::Create(args) => {
+            match tokens
+                .create(&args.name, &args.scopes, args.expires_in)
+                .await
+            {
+                Ok(token) => {
+                    info!(name = %args.name, scopes = ?args.scopes, "Created API token");
+                    println!("{token}");
+                    Ok(())
+                }
+                Err(error) => {
+                    error!(reason = %error, "Could not create API token, exiting.");
+                    Err(())
+                }
+            }
+        }
+        ApiTokenCommand::List => match tokens.list().await {
+            Ok(summaries) => {
+                for token in summaries {
+                    println!(
+                        "{}\t{}\tcreated={}\tlast_used={}\texpires={}",
+                        token.name,
+                        token.scopes.join(","),
+                        token.created_at,
+                        token.last_used_at.as_deref().unwrap_or("never"),
+                        token.expires_at.as_deref().unwrap_or("never"),
+                    );
+                }
+                Ok(())
+            }
+            Err(error) => {
+                error!(reason = %error, "Could not list API tokens, exiting.");
+                Err(())
+            }
+        },
+        ApiTokenCommand::Revoke(args) => match tokens.revoke(&args.name).await {
+            Ok(true) => {
+                info!(name = %args.name, "Revoked API token");
+                Ok(())
+            }
+            Ok(false) => {
+                error!(name = %args.name, "No API token with this name, exiting.");
+                Err(())
+            }
+            Err(error) => {
+                error!(reason = %error, "Could not revoke API token, exiting.");
+                Err(())
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scope() {
+        for scope in Scope::ALL {
+            assert_eq!(scope.as_str().parse::<Scope>().unwrap(), scope);
+        }
+        assert!("admin".parse::<Scope>().is_err());
+        assert!("READ".parse::<Scope>().is_err());
+    }
+}
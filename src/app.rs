@@ -1,4 +1,4 @@
-use axum::{extract::FromRef, routing::get, Router};
+use axum::{extract::FromRef, middleware::from_fn_with_state, routing::get, Router};
 use axum_prometheus::{
     metrics_exporter_prometheus::PrometheusHandle, PrometheusMetricLayerBuilder,
 };
@@ -10,50 +10,81 @@ use tower_http::{
 };
 use tracing::Level;
 
-use crate::http::api::APIState;
+use crate::http::api::{
+    v1::{login::RegistryLogin, provider::ProviderPublisher},
+    APIState,
+};
 use crate::{
+    apitoken::ApiTokens,
     config::ServerArgs,
-    credhelper::{database::DatabaseCredentials, CredentialHelper},
+    credhelper::{CredentialHelper, UpstreamCredentials},
     http::artifacts::artifacts_handler,
+    http::auth::{require_api_token, require_mirror_token, ApiAuth, MirrorAuth, MirrorUrlSigner},
+    http::discovery::discovery_handler,
     http::healthcheck::healthcheck_handler,
     http::index::index_handler,
+    http::modules::{
+        module_artifacts_handler, module_download_handler, module_versions_handler,
+        registry_module_download_handler, registry_module_versions_handler,
+    },
+    http::provider_registry::{
+        registry_download_handler, registry_versions_handler, shasums_handler,
+        shasums_signature_handler,
+    },
+    http::releases::{release_file_handler, release_index_handler, release_version_index_handler},
     http::version::version_handler,
     refresh::RefreshRequest,
     registry::RegistryClient,
+    signing::SignatureVerifier,
+    webhook::Webhooks,
 };
 
 #[derive(Clone)]
 pub(crate) struct AppState<C> {
     pub(crate) s3_client: aws_sdk_s3::Client,
-    pub(crate) http_client: reqwest::Client,
     pub(crate) db_client: Pool<Postgres>,
-    pub(crate) registry_client: RegistryClient<DatabaseCredentials>,
+    pub(crate) registry_client: RegistryClient<UpstreamCredentials>,
     pub(crate) config: ServerArgs,
     pub(crate) refresher_tx: mpsc::Sender<RefreshRequest>,
     pub(crate) credentials: C,
+    pub(crate) webhooks: Webhooks,
+    pub(crate) publisher: Option<ProviderPublisher>,
+    pub(crate) login: Option<RegistryLogin>,
+    pub(crate) release_verifier: Option<SignatureVerifier>,
+    /// Signs artifact URLs when mirror clients must authenticate
+    pub(crate) mirror_urls: Option<MirrorUrlSigner>,
 }
 
 impl<C> AppState<C> {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         config: ServerArgs,
         s3: aws_sdk_s3::Client,
         db: Pool<Postgres>,
-        http: reqwest::Client,
+        registry: RegistryClient<UpstreamCredentials>,
         refresher_tx: mpsc::Sender<RefreshRequest>,
         credentials: C,
+        webhooks: Webhooks,
+        publisher: Option<ProviderPublisher>,
+        login: Option<RegistryLogin>,
+        release_verifier: Option<SignatureVerifier>,
     ) -> Self {
+        let mirror_urls = match (config.mirror_auth, &config.mirror_url_secret) {
+            (true, Some(secret)) => Some(MirrorUrlSigner::new(secret.as_bytes())),
+            _ => None,
+        };
         Self {
             s3_client: s3,
-            http_client: http.clone(),
-            db_client: db.clone(),
-            registry_client: RegistryClient::new(
-                config.upstream_registry_port,
-                http,
-                DatabaseCredentials::new(db),
-            ),
+            db_client: db,
+            registry_client: registry,
             config,
             refresher_tx,
             credentials,
+            webhooks,
+            publisher,
+            login,
+            release_verifier,
+            mirror_urls,
         }
     }
 }
@@ -62,6 +93,8 @@ impl<C: Clone> FromRef<AppState<C>> for APIState<C> {
     fn from_ref(state: &AppState<C>) -> Self {
         Self {
             credentials: state.credentials.clone(),
+            publisher: state.publisher.clone(),
+            login: state.login.clone(),
         }
     }
 }
@@ -75,6 +108,15 @@ pub(crate) fn provider_mirror_app<C: Clone + Send + Sync + CredentialHelper + 's
             "/api/v1/credentials/{hostname}",
             &["/api/v1/credentials/:hostname"],
         )
+        .with_group_patterns_as("/api/v1/logins/{hostname}", &["/api/v1/logins/{hostname}"])
+        .with_group_patterns_as(
+            "/api/v1/logins/{hostname}/token",
+            &["/api/v1/logins/{hostname}/token"],
+        )
+        .with_group_patterns_as(
+            "/api/v1/providers/{namespace}/{provider_type}/{version}/{os}/{arch}",
+            &["/api/v1/providers/{namespace}/{provider_type}/{version}/{os}/{arch}"],
+        )
         .with_group_patterns_as(
             "/mirror/v1/{hostname}/{namespace}/{provider_type}/index.json",
             &["/mirror/v1/{hostname}/{namespace}/{provider_type}/index.json"],
@@ -87,10 +129,65 @@ pub(crate) fn provider_mirror_app<C: Clone + Send + Sync + CredentialHelper + 's
             "/mirror/v1/artifacts/{version_id}",
             &["/mirror/v1/artifacts/{version_id}"],
         )
+        .with_group_patterns_as(
+            "/mirror/v1/artifacts/{version_id}/SHA256SUMS",
+            &["/mirror/v1/artifacts/{version_id}/SHA256SUMS"],
+        )
+        .with_group_patterns_as(
+            "/mirror/v1/artifacts/{version_id}/SHA256SUMS.sig",
+            &["/mirror/v1/artifacts/{version_id}/SHA256SUMS.sig"],
+        )
+        .with_group_patterns_as(
+            "/providers/v1/{namespace}/{provider_type}/versions",
+            &["/providers/v1/{namespace}/{provider_type}/versions"],
+        )
+        .with_group_patterns_as(
+            "/providers/v1/{namespace}/{provider_type}/{version}/download/{os}/{arch}",
+            &["/providers/v1/{namespace}/{provider_type}/{version}/download/{os}/{arch}"],
+        )
+        .with_group_patterns_as(
+            "/modules/v1/{hostname}/{namespace}/{name}/{system}/versions",
+            &["/modules/v1/{hostname}/{namespace}/{name}/{system}/versions"],
+        )
+        .with_group_patterns_as(
+            "/modules/v1/{hostname}/{namespace}/{name}/{system}/{version}/download",
+            &["/modules/v1/{hostname}/{namespace}/{name}/{system}/{version}/download"],
+        )
+        .with_group_patterns_as(
+            "/modules/v1/{namespace}/{name}/{system}/versions",
+            &["/modules/v1/{namespace}/{name}/{system}/versions"],
+        )
+        .with_group_patterns_as(
+            "/modules/v1/{namespace}/{name}/{system}/{version}/download",
+            &["/modules/v1/{namespace}/{name}/{system}/{version}/download"],
+        )
+        .with_group_patterns_as(
+            "/modules/v1/artifacts/{version_id}",
+            &["/modules/v1/artifacts/{version_id}"],
+        )
+        .with_group_patterns_as(
+            "/releases/{product}/index.json",
+            &["/releases/{product}/index.json"],
+        )
+        .with_group_patterns_as(
+            "/releases/{product}/{version}/index.json",
+            &["/releases/{product}/{version}/index.json"],
+        )
+        .with_group_patterns_as(
+            "/releases/{product}/{version}/{filename}",
+            &["/releases/{product}/{version}/{filename}"],
+        )
         .build();
 
-    let api = crate::http::api::routes(APIState::from_ref(&state));
-    let mirror = Router::new()
+    let mut api = crate::http::api::routes(APIState::from_ref(&state));
+    if state.config.api_auth {
+        let auth = ApiAuth::new(
+            ApiTokens::new(state.db_client.clone()),
+            &state.config.trusted_proxies,
+        );
+        api = api.route_layer(from_fn_with_state(auth, require_api_token));
+    }
+    let mut providers = Router::new()
         .route(
             "/mirror/v1/{hostname}/{namespace}/{provider_type}/index.json",
             get(index_handler),
@@ -100,13 +197,75 @@ pub(crate) fn provider_mirror_app<C: Clone + Send + Sync + CredentialHelper + 's
             get(version_handler),
         )
         .route("/mirror/v1/artifacts/{version_id}", get(artifacts_handler))
+        .route(
+            "/mirror/v1/artifacts/{version_id}/SHA256SUMS",
+            get(shasums_handler),
+        )
+        .route(
+            "/mirror/v1/artifacts/{version_id}/SHA256SUMS.sig",
+            get(shasums_signature_handler),
+        )
+        .route(
+            "/providers/v1/{namespace}/{provider_type}/versions",
+            get(registry_versions_handler),
+        )
+        .route(
+            "/providers/v1/{namespace}/{provider_type}/{version}/download/{os}/{arch}",
+            get(registry_download_handler),
+        );
+    if let Some(urls) = &state.mirror_urls {
+        let auth = MirrorAuth {
+            api: ApiAuth::new(
+                ApiTokens::new(state.db_client.clone()),
+                &state.config.trusted_proxies,
+            ),
+            urls: urls.clone(),
+        };
+        providers = providers.route_layer(from_fn_with_state(auth, require_mirror_token));
+    }
+    let mut mirror = Router::new()
+        .route("/.well-known/terraform.json", get(discovery_handler))
+        .route(
+            "/modules/v1/{hostname}/{namespace}/{name}/{system}/versions",
+            get(module_versions_handler),
+        )
+        .route(
+            "/modules/v1/{hostname}/{namespace}/{name}/{system}/{version}/download",
+            get(module_download_handler),
+        )
+        .route(
+            "/modules/v1/{namespace}/{name}/{system}/versions",
+            get(registry_module_versions_handler),
+        )
+        .route(
+            "/modules/v1/{namespace}/{name}/{system}/{version}/download",
+            get(registry_module_download_handler),
+        )
+        .route(
+            "/modules/v1/artifacts/{version_id}",
+            get(module_artifacts_handler),
+        )
         .route("/healthcheck", get(healthcheck_handler))
         .route(
             "/metrics",
             get(|| async move { metric_handle.map_or("".to_string(), |x| x.render()) }),
         );
+    // Releases are only mirrored when their checksum signatures can be verified
+    if state.release_verifier.is_some() {
+        mirror = mirror
+            .route("/releases/{product}/index.json", get(release_index_handler))
+            .route(
+                "/releases/{product}/{version}/index.json",
+                get(release_version_index_handler),
+            )
+            .route(
+                "/releases/{product}/{version}/{filename}",
+                get(release_file_handler),
+            );
+    }
     Router::new()
         .merge(api)
+        .merge(providers)
         .merge(mirror)
         .layer(
             TraceLayer::new_for_http()
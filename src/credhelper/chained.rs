@@ -0,0 +1,208 @@
+use std::marker::Send;
+
+use super::{
+    types::{Credential, CredentialMetadata, Token},
+    CredentialHelper,
+};
+
+/// Credential helper trying two helpers in order, chain them to try more.
+///
+/// Credentials are looked up in `first`, then in `rest` if `first` has none for
+/// the hostname. Credentials are stored in and forgotten from `first`, or from
+/// `rest` once [`ChainedCredentials::store_in_rest`] is set. Listings combine both,
+/// with `first` taking precedence for hostnames in each.
+#[derive(Clone, Debug)]
+pub struct ChainedCredentials<A, B> {
+    first: A,
+    rest: B,
+    store_in_rest: bool,
+}
+
+impl<A, B> ChainedCredentials<A, B> {
+    pub fn new(first: A, rest: B) -> Self {
+        Self {
+            first,
+            rest,
+            store_in_rest: false,
+        }
+    }
+
+    /// Sends `store` and `forget` to `rest` instead of `first`.
+    pub fn store_in_rest(mut self, store_in_rest: bool) -> Self {
+        self.store_in_rest = store_in_rest;
+        self
+    }
+}
+
+impl<A, B> CredentialHelper for ChainedCredentials<A, B>
+where
+    A: CredentialHelper + Send,
+    B: CredentialHelper + Send,
+{
+    async fn get(&self, hostname: impl AsRef<str> + Send) -> Result<Credential, anyhow::Error> {
+        let hostname = hostname.as_ref();
+        match self.first.get(hostname).await? {
+            Credential::NotFound => self.rest.get(hostname).await,
+            credential => Ok(credential),
+        }
+    }
+
+    async fn store(&mut self, hostname: String, cred: Token) -> Result<(), anyhow::Error> {
+        if self.store_in_rest {
+            self.rest.store(hostname, cred).await
+        } else {
+            self.first.store(hostname, cred).await
+        }
+    }
+
+    async fn forget(&mut self, hostname: impl AsRef<str> + Send) -> Result<(), anyhow::Error> {
+        if self.store_in_rest {
+            self.rest.forget(hostname).await
+        } else {
+            self.first.forget(hostname).await
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<CredentialMetadata>, anyhow::Error> {
+        let mut credentials = self.first.list().await?;
+        for credential in self.rest.list().await? {
+            if !credentials
+                .iter()
+                .any(|c| c.hostname == credential.hostname)
+            {
+                credentials.push(credential);
+            }
+        }
+        Ok(credentials)
+    }
+
+    async fn record_use(&self, hostname: impl AsRef<str> + Send) -> Result<(), anyhow::Error> {
+        let hostname = hostname.as_ref();
+        self.first.record_use(hostname).await?;
+        self.rest.record_use(hostname).await
+    }
+
+    async fn record_auth_failure(
+        &self,
+        hostname: impl AsRef<str> + Send,
+        status: u16,
+    ) -> Result<(), anyhow::Error> {
+        let hostname = hostname.as_ref();
+        self.first.record_auth_failure(hostname, status).await?;
+        self.rest.record_auth_failure(hostname, status).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credhelper::{faulty::FaultyCredentials, memory::MemoryCredentials};
+
+    #[tokio::test]
+    async fn test_chained_lookup_order() {
+        let mut first = MemoryCredentials::default();
+        let mut rest = MemoryCredentials::default();
+        first
+            .store("a.example.com".into(), "first".into())
+            .await
+            .unwrap();
+        rest.store("a.example.com".into(), "rest".into())
+            .await
+            .unwrap();
+        rest.store("b.example.com".into(), "rest".into())
+            .await
+            .unwrap();
+        let mut chained = ChainedCredentials::new(first.clone(), rest.clone());
+
+        assert_eq!(
+            chained.get("a.example.com").await.unwrap(),
+            Credential::Entry(Some("first".into()))
+        );
+        assert_eq!(
+            chained.get("b.example.com").await.unwrap(),
+            Credential::Entry(Some("rest".into()))
+        );
+        assert_eq!(
+            chained.get("c.example.com").await.unwrap(),
+            Credential::NotFound
+        );
+
+        chained
+            .store("c.example.com".into(), "new".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            first.get("c.example.com").await.unwrap(),
+            Credential::Entry(Some("new".into()))
+        );
+        assert_eq!(
+            rest.get("c.example.com").await.unwrap(),
+            Credential::NotFound
+        );
+
+        chained.forget("a.example.com").await.unwrap();
+        assert_eq!(
+            chained.get("a.example.com").await.unwrap(),
+            Credential::Entry(Some("rest".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chained_store_in_rest() {
+        let first = MemoryCredentials::default();
+        let rest = MemoryCredentials::default();
+        let mut chained = ChainedCredentials::new(first.clone(), rest.clone()).store_in_rest(true);
+
+        chained
+            .store("a.example.com".into(), "new".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            first.get("a.example.com").await.unwrap(),
+            Credential::NotFound
+        );
+        assert_eq!(
+            rest.get("a.example.com").await.unwrap(),
+            Credential::Entry(Some("new".into()))
+        );
+
+        chained.forget("a.example.com").await.unwrap();
+        assert_eq!(
+            rest.get("a.example.com").await.unwrap(),
+            Credential::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chained_list() {
+        let mut first = MemoryCredentials::default();
+        let mut rest = MemoryCredentials::default();
+        first
+            .store("a.example.com".into(), "first".into())
+            .await
+            .unwrap();
+        rest.store("a.example.com".into(), "rest".into())
+            .await
+            .unwrap();
+        rest.store("b.example.com".into(), "rest".into())
+            .await
+            .unwrap();
+        let chained = ChainedCredentials::new(first, rest);
+
+        let mut hostnames = chained
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.hostname)
+            .collect::<Vec<_>>();
+        hostnames.sort();
+        assert_eq!(hostnames, vec!["a.example.com", "b.example.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_chained_errors_are_not_skipped() {
+        let chained = ChainedCredentials::new(FaultyCredentials::new(), MemoryCredentials::new());
+        assert!(chained.get("a.example.com").await.is_err());
+    }
+}
@@ -2,61 +2,303 @@ use std::marker::Send;
 
 use sqlx::PgPool;
 
-use super::{types::Credential, CredentialHelper};
+use super::{
+    encryption::{is_encrypted, TokenCipher},
+    types::{AuthFailure, Credential, CredentialMetadata, Token},
+    CredentialHelper,
+};
+
+/// Usage is recorded at most this often per hostname, to avoid a write for every upstream request.
+const RECORD_USE_INTERVAL_SECONDS: f64 = 60.0;
 
 // Credential helper implementation by storing in the database
 #[derive(Clone)]
 pub struct DatabaseCredentials {
     pool: PgPool,
+    /// Tokens are stored in plaintext when None
+    cipher: Option<TokenCipher>,
 }
 
 impl DatabaseCredentials {
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        Self { pool, cipher: None }
+    }
+
+    /// Encrypts tokens when storing them, and decrypts them when read.
+    pub fn with_cipher(mut self, cipher: Option<TokenCipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    fn decrypt(&self, hostname: &str, stored: String) -> Result<String, anyhow::Error> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(hostname, &stored),
+            None if is_encrypted(&stored) => Err(anyhow::anyhow!(
+                "Token for {hostname} is encrypted but no token encryption keys are configured"
+            )),
+            None => Ok(stored),
+        }
     }
 }
 
+/// Rebuilds a token from its stored scheme, name and decrypted secret.
+fn token_from_parts(
+    scheme: &str,
+    name: Option<String>,
+    secret: String,
+) -> Result<Token, anyhow::Error> {
+    match (scheme, name) {
+        ("bearer", _) => Ok(Token::Bearer(secret)),
+        ("basic", Some(username)) => Ok(Token::Basic {
+            username,
+            password: secret,
+        }),
+        ("header", Some(name)) => Ok(Token::Header {
+            name,
+            value: secret,
+        }),
+        (scheme, _) => Err(anyhow::anyhow!(
+            "Stored {scheme} auth_token has no auth_name"
+        )),
+    }
+}
+
+/// Re-encrypts stored tokens that are in plaintext or encrypted with an old key.
+///
+/// Returns the number of tokens re-encrypted.
+pub async fn rotate_tokens(pool: &PgPool, cipher: &TokenCipher) -> Result<u64, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    let rows = sqlx::query!(
+        r#"
+        select "hostname", "auth_token" as "auth_token!" from "terraform_registry_host"
+        where "auth_token" is not null
+        for update;
+        "#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut rotated = 0;
+    for row in rows {
+        // Tokens are bound to the lowercase hostname they are looked up with
+        let Some(token) = cipher.rotate(&row.hostname.to_ascii_lowercase(), &row.auth_token)?
+        else {
+            continue;
+        };
+        sqlx::query!(
+            r#"
+            update "terraform_registry_host" set "auth_token" = $2
+            where "hostname" = $1;
+            "#,
+            row.hostname,
+            token,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tracing::info!(hostname = %row.hostname, "Re-encrypted auth_token");
+        rotated += 1;
+    }
+    tx.commit().await?;
+    Ok(rotated)
+}
+
 impl CredentialHelper for DatabaseCredentials {
     async fn get(&self, hostname: impl AsRef<str> + Send) -> Result<Credential, anyhow::Error> {
+        let hostname = hostname.as_ref().to_ascii_lowercase();
         let query = sqlx::query!(
             r#"
-            select auth_token from terraform_registry_host
+            select auth_token, auth_scheme, auth_name from terraform_registry_host
             where hostname = $1;
         "#,
-            hostname.as_ref()
+            hostname
         );
         let result = query.fetch_optional(&self.pool).await?;
         if let Some(row) = result {
-            Ok(Credential::Entry(row.auth_token))
+            let token = row
+                .auth_token
+                .map(|token| self.decrypt(&hostname, token))
+                .transpose()?
+                .map(|secret| token_from_parts(&row.auth_scheme, row.auth_name, secret))
+                .transpose()?;
+            Ok(Credential::Entry(token))
         } else {
             Ok(Credential::NotFound)
         }
     }
 
-    async fn store(&mut self, hostname: String, cred: String) -> Result<(), anyhow::Error> {
+    async fn store(&mut self, hostname: String, cred: Token) -> Result<(), anyhow::Error> {
+        // Hostnames are looked up in lowercase, and are part of the encrypted token
+        let hostname = hostname.to_ascii_lowercase();
+        let scheme = cred.scheme();
+        let (name, secret) = match cred {
+            Token::Bearer(token) => (None, token),
+            Token::Basic { username, password } => (Some(username), password),
+            Token::Header { name, value } => (Some(name), value),
+        };
+        let secret = match &self.cipher {
+            Some(cipher) => cipher.encrypt(&hostname, &secret)?,
+            None => secret,
+        };
         let query = sqlx::query!(
             r#"
-            insert into "terraform_registry_host" ("hostname", "auth_token")
-            values ($1, $2)
+            insert into "terraform_registry_host" ("hostname", "auth_token", "auth_scheme", "auth_name")
+            values ($1, $2, $3, $4)
             on conflict ("hostname")
-                do update set "auth_token" = "excluded"."auth_token";
+                do update set
+                    "auth_token" = "excluded"."auth_token",
+                    "auth_scheme" = "excluded"."auth_scheme",
+                    "auth_name" = "excluded"."auth_name",
+                    "updated_at" = now();
         "#,
             hostname,
-            cred,
+            secret,
+            scheme,
+            name,
         );
         let _ = query.execute(&self.pool).await?;
-        tracing::info!(?hostname, "store new auth_token");
+        tracing::info!(?hostname, scheme, "store new auth_token");
         Ok(())
     }
 
     async fn forget(&mut self, hostname: impl AsRef<str> + Send) -> Result<(), anyhow::Error> {
+        let hostname = hostname.as_ref().to_ascii_lowercase();
         let query = sqlx::query!(
             r#"
             delete from "terraform_registry_host" where "hostname" = $1;
         "#,
-            hostname.as_ref()
+            hostname
+        );
+        let _ = query.execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<CredentialMetadata>, anyhow::Error> {
+        let rows = sqlx::query!(
+            r#"
+            select
+                "hostname",
+                to_char("created_at" at time zone 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "created_at!",
+                to_char("updated_at" at time zone 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "updated_at!",
+                to_char("last_used_at" at time zone 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "last_used_at",
+                to_char("last_auth_failure_at" at time zone 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') as "last_auth_failure_at",
+                "last_auth_failure_status"
+            from "terraform_registry_host"
+            order by "hostname";
+        "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| CredentialMetadata {
+                hostname: row.hostname,
+                created_at: Some(row.created_at),
+                updated_at: Some(row.updated_at),
+                last_used_at: row.last_used_at,
+                last_auth_failure: row
+                    .last_auth_failure_at
+                    .zip(row.last_auth_failure_status)
+                    .map(|(at, status)| AuthFailure {
+                        at,
+                        status: status as u16,
+                    }),
+            })
+            .collect())
+    }
+
+    async fn record_use(&self, hostname: impl AsRef<str> + Send) -> Result<(), anyhow::Error> {
+        let hostname = hostname.as_ref().to_ascii_lowercase();
+        let query = sqlx::query!(
+            r#"
+            update "terraform_registry_host" set "last_used_at" = now()
+            where "hostname" = $1
+                and ("last_used_at" is null
+                    or "last_used_at" < now() - make_interval(secs => $2));
+        "#,
+            hostname,
+            RECORD_USE_INTERVAL_SECONDS,
         );
         let _ = query.execute(&self.pool).await?;
         Ok(())
     }
+
+    async fn record_auth_failure(
+        &self,
+        hostname: impl AsRef<str> + Send,
+        status: u16,
+    ) -> Result<(), anyhow::Error> {
+        let hostname = hostname.as_ref().to_ascii_lowercase();
+        let query = sqlx::query!(
+            r#"
+            update "terraform_registry_host"
+            set "last_auth_failure_at" = now(), "last_auth_failure_status" = $2
+            where "hostname" = $1;
+        "#,
+            hostname,
+            i32::from(status),
+        );
+        if query.execute(&self.pool).await?.rows_affected() > 0 {
+            tracing::warn!(%hostname, status, "Upstream rejected auth_token");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn test_hostname_is_case_insensitive(db: PgPool) {
+        let cipher = TokenCipher::new(vec![("k1".to_string(), [1u8; 32])]).unwrap();
+        let mut credentials = DatabaseCredentials::new(db).with_cipher(Some(cipher));
+        let token = Token::Bearer("password1".into());
+        credentials
+            .store("Registry.Example.com".into(), token.clone())
+            .await
+            .unwrap();
+
+        for hostname in ["registry.example.com", "REGISTRY.example.com"] {
+            assert_eq!(
+                credentials.get(hostname).await.unwrap(),
+                Credential::Entry(Some(token.clone()))
+            );
+        }
+        let hostnames = credentials
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|metadata| metadata.hostname)
+            .collect::<Vec<_>>();
+        assert_eq!(hostnames, vec!["registry.example.com"]);
+
+        credentials.forget("Registry.Example.com").await.unwrap();
+        assert_eq!(
+            credentials.get("registry.example.com").await.unwrap(),
+            Credential::NotFound
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_rotate_binds_lowercase_hostname(db: PgPool) {
+        sqlx::query(
+            r#"insert into "terraform_registry_host" ("hostname", "auth_token") values ('Legacy.Example.com', 'password1')"#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let cipher = TokenCipher::new(vec![("k1".to_string(), [1u8; 32])]).unwrap();
+        assert_eq!(rotate_tokens(&db, &cipher).await.unwrap(), 1);
+
+        // Stand in for the stored hostname having been lowercased
+        sqlx::query(r#"update "terraform_registry_host" set "hostname" = lower("hostname")"#)
+            .execute(&db)
+            .await
+            .unwrap();
+        let credentials = DatabaseCredentials::new(db).with_cipher(Some(cipher));
+        assert_eq!(
+            credentials.get("Legacy.Example.com").await.unwrap(),
+            Credential::Entry(Some(Token::Bearer("password1".into())))
+        );
+    }
 }
@@ -0,0 +1,292 @@
+use aes_gcm::{
+    aead::{Aead, OsRng, Payload},
+    AeadCore, Aes256Gcm, Key, KeyInit, Nonce,
+};
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+/// Prefix of encrypted tokens, tokens without it are stored in plaintext.
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+/// Envelope encryption of registry auth tokens at rest.
+///
+/// Each token is encrypted with its own random data key, bound to the hostname,
+/// and the data key is encrypted with a key encryption key. Tokens are stored as
+/// `enc:v1:{key_id}:{encrypted data key}:{encrypted token}`, so rotating the key
+/// encryption key only re-encrypts data keys.
+#[derive(Clone)]
+pub struct TokenCipher {
+    /// Key ID new tokens are encrypted with
+    current: String,
+    keys: Arc<HashMap<String, Key<Aes256Gcm>>>,
+}
+
+impl std::fmt::Debug for TokenCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenCipher")
+            .field("current", &self.current)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// A stored token split into its parts.
+enum StoredToken<'a> {
+    Plaintext(&'a str),
+    Encrypted {
+        key_id: &'a str,
+        data_key: Vec<u8>,
+        token: Vec<u8>,
+    },
+}
+
+impl<'a> StoredToken<'a> {
+    fn parse(stored: &'a str) -> Result<Self, anyhow::Error> {
+        let Some(encrypted) = stored.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Ok(Self::Plaintext(stored));
+        };
+        let mut parts = encrypted.splitn(3, ':');
+        let (Some(key_id), Some(data_key), Some(token)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("Malformed encrypted token");
+        };
+        Ok(Self::Encrypted {
+            key_id,
+            data_key: STANDARD
+                .decode(data_key)
+                .context("Malformed encrypted token")?,
+            token: STANDARD
+                .decode(token)
+                .context("Malformed encrypted token")?,
+        })
+    }
+}
+
+/// Encrypts with a random nonce, which is prepended to the ciphertext.
+fn seal(key: &Key<Aes256Gcm>, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Could not encrypt token"))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn open(key: &Key<Aes256Gcm>, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    anyhow::ensure!(sealed.len() > NONCE_LEN, "Malformed encrypted token");
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    Aes256Gcm::new(key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Could not decrypt token"))
+}
+
+impl TokenCipher {
+    /// Creates a cipher from `(key_id, key)` pairs, encrypting with the first key.
+    pub fn new(keys: Vec<(String, [u8; 32])>) -> Result<Self, anyhow::Error> {
+        let current = keys
+            .first()
+            .map(|(id, _)| id.clone())
+            .context("No token encryption keys configured")?;
+        let mut map = HashMap::new();
+        for (id, key) in keys {
+            anyhow::ensure!(
+                !id.is_empty()
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "Invalid token encryption key ID {id}"
+            );
+            anyhow::ensure!(
+                map.insert(id.clone(), key.into()).is_none(),
+                "Duplicate token encryption key ID {id}"
+            );
+        }
+        Ok(Self {
+            current,
+            keys: Arc::new(map),
+        })
+    }
+
+    /// Parses `{key_id}:{base64 key}` entries, separated by commas or new lines.
+    fn parse_keys(keys: &str) -> Result<Vec<(String, [u8; 32])>, anyhow::Error> {
+        keys.split([',', '\n'])
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+            .map(|entry| {
+                let (id, key) = entry
+                    .split_once(':')
+                    .context("Token encryption keys must be given as {key_id}:{base64 key}")?;
+                let key = STANDARD
+                    .decode(key)
+                    .ok()
+                    .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                    .with_context(|| format!("Token encryption key {id} is not 32 base64 bytes"))?;
+                Ok((id.to_string(), key))
+            })
+            .collect()
+    }
+
+    /// Loads keys from the configured value and file, keys given directly come first.
+    pub fn from_config(
+        keys: Option<&str>,
+        keys_file: Option<&Path>,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        let mut parsed = match keys {
+            Some(keys) => Self::parse_keys(keys)?,
+            None => vec![],
+        };
+        if let Some(path) = keys_file {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Reading token encryption keys {}", path.display()))?;
+            parsed.extend(Self::parse_keys(&contents)?);
+        }
+        if parsed.is_empty() {
+            return Ok(None);
+        }
+        Self::new(parsed).map(Some)
+    }
+
+    fn key(&self, id: &str) -> Result<&Key<Aes256Gcm>, anyhow::Error> {
+        self.keys
+            .get(id)
+            .with_context(|| format!("Token encryption key {id} is not configured"))
+    }
+
+    fn format(key_id: &str, data_key: &[u8], token: &[u8]) -> String {
+        format!(
+            "{ENCRYPTED_PREFIX}{key_id}:{}:{}",
+            STANDARD.encode(data_key),
+            STANDARD.encode(token)
+        )
+    }
+
+    /// Encrypts a token for a hostname with the current key.
+    pub fn encrypt(&self, hostname: &str, token: &str) -> Result<String, anyhow::Error> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let token = seal(&data_key, token.as_bytes(), hostname.as_bytes())?;
+        let data_key = seal(self.key(&self.current)?, &data_key, self.current.as_bytes())?;
+        Ok(Self::format(&self.current, &data_key, &token))
+    }
+
+    /// Decrypts a stored token, plaintext tokens are returned as they are.
+    pub fn decrypt(&self, hostname: &str, stored: &str) -> Result<String, anyhow::Error> {
+        match StoredToken::parse(stored)? {
+            StoredToken::Plaintext(token) => Ok(token.to_string()),
+            StoredToken::Encrypted {
+                key_id,
+                data_key,
+                token,
+            } => {
+                let data_key = open(self.key(key_id)?, &data_key, key_id.as_bytes())?;
+                let data_key = Key::<Aes256Gcm>::from_exact_iter(data_key)
+                    .context("Malformed encrypted token")?;
+                let token = open(&data_key, &token, hostname.as_bytes())?;
+                Ok(String::from_utf8(token)?)
+            }
+        }
+    }
+
+    /// Re-encrypts a stored token with the current key, or None if it already is.
+    ///
+    /// Plaintext tokens are encrypted, and for encrypted tokens only the data key is re-encrypted.
+    pub fn rotate(&self, hostname: &str, stored: &str) -> Result<Option<String>, anyhow::Error> {
+        match StoredToken::parse(stored)? {
+            StoredToken::Plaintext(token) => self.encrypt(hostname, token).map(Some),
+            StoredToken::Encrypted { key_id, .. } if key_id == self.current => Ok(None),
+            StoredToken::Encrypted {
+                key_id,
+                data_key,
+                token,
+            } => {
+                let data_key = open(self.key(key_id)?, &data_key, key_id.as_bytes())?;
+                let data_key = seal(self.key(&self.current)?, &data_key, self.current.as_bytes())?;
+                Ok(Some(Self::format(&self.current, &data_key, &token)))
+            }
+        }
+    }
+}
+
+/// Whether a stored token is encrypted.
+pub(crate) fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(ENCRYPTED_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(ids: &[&str]) -> TokenCipher {
+        TokenCipher::new(
+            ids.iter()
+                .map(|id| (id.to_string(), [id.len() as u8; 32]))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let cipher = cipher(&["k1"]);
+        let stored = cipher.encrypt("example.com", "password1").unwrap();
+        assert!(stored.starts_with("enc:v1:k1:"));
+        assert!(!stored.contains("password1"));
+        assert_ne!(stored, cipher.encrypt("example.com", "password1").unwrap());
+        assert_eq!(cipher.decrypt("example.com", &stored).unwrap(), "password1");
+        assert_eq!(
+            cipher.decrypt("example.com", "plaintext").unwrap(),
+            "plaintext"
+        );
+    }
+
+    #[test]
+    fn test_token_is_bound_to_hostname() {
+        let cipher = cipher(&["k1"]);
+        let stored = cipher.encrypt("example.com", "password1").unwrap();
+        assert!(cipher.decrypt("evil.example.com", &stored).is_err());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let old = cipher(&["k1"]);
+        let stored = old.encrypt("example.com", "password1").unwrap();
+
+        let new = cipher(&["key2", "k1"]);
+        assert_eq!(new.decrypt("example.com", &stored).unwrap(), "password1");
+        let rotated = new.rotate("example.com", &stored).unwrap().unwrap();
+        assert!(rotated.starts_with("enc:v1:key2:"));
+        assert_eq!(new.rotate("example.com", &rotated).unwrap(), None);
+        assert_eq!(new.decrypt("example.com", &rotated).unwrap(), "password1");
+        assert!(old.decrypt("example.com", &rotated).is_err());
+
+        let encrypted = new.rotate("example.com", "plaintext").unwrap().unwrap();
+        assert_eq!(new.decrypt("example.com", &encrypted).unwrap(), "plaintext");
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let key = STANDARD.encode([1u8; 32]);
+        let keys = TokenCipher::parse_keys(&format!("# current\nk2:{key}\n\nk1:{key}\n")).unwrap();
+        assert_eq!(
+            keys.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(),
+            vec!["k2", "k1"]
+        );
+        assert!(TokenCipher::parse_keys(&key).is_err());
+        assert!(TokenCipher::parse_keys(&format!("k1:{}", STANDARD.encode([1u8; 16]))).is_err());
+        assert!(TokenCipher::new(vec![("k:1".into(), [0; 32])]).is_err());
+        assert!(TokenCipher::new(vec![("k1".into(), [0; 32]), ("k1".into(), [1; 32])]).is_err());
+    }
+}
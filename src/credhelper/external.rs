@@ -0,0 +1,298 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    marker::Send,
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use super::{
+    types::{Credential, Token},
+    CredentialHelper,
+};
+
+/// Credentials object exchanged with helper programs.
+#[derive(Debug, Serialize, Deserialize)]
+struct HelperCredentials {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+/// Credential helper running an external Terraform credentials helper program.
+///
+/// Programs implement Terraform's `terraform-credentials-{name}` protocol, taking
+/// the arguments configured for them followed by `get`, `store` or `forget` and
+/// the hostname. Credentials are exchanged as JSON objects on stdin and stdout.
+/// Results of `get`, including missing credentials, are cached for `cache_ttl`.
+#[derive(Clone, Debug)]
+pub struct ExternalCredentials {
+    program: PathBuf,
+    args: Vec<String>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: Arc<Mutex<HashMap<String, (Instant, Credential)>>>,
+}
+
+impl ExternalCredentials {
+    pub fn new(
+        program: PathBuf,
+        args: Vec<String>,
+        timeout: Duration,
+        cache_ttl: Duration,
+    ) -> Self {
+        Self {
+            program,
+            args,
+            timeout,
+            cache_ttl,
+            cache: Default::default(),
+        }
+    }
+
+    /// Runs the helper program, returning its stdout.
+    async fn run(
+        &self,
+        verb: &str,
+        hostname: &str,
+        input: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .args([verb, hostname])
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Running credentials helper {}", self.program.display()))?;
+        let run = async {
+            if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+                stdin.write_all(&input).await?;
+            }
+            child.wait_with_output().await
+        };
+        let output = tokio::time::timeout(self.timeout, run)
+            .await
+            .with_context(|| {
+                format!(
+                    "Credentials helper {} timed out after {:?} for {verb} {hostname}",
+                    self.program.display(),
+                    self.timeout
+                )
+            })??;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Credentials helper {} failed for {verb} {hostname} with {}: {}",
+                self.program.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+
+    fn cached(&self, hostname: &str) -> Option<Credential> {
+        let cache = self
+            .cache
+            .lock()
+            .expect("Credentials helper cache poisoned");
+        cache
+            .get(hostname)
+            .filter(|(fetched, _)| fetched.elapsed() < self.cache_ttl)
+            .map(|(_, credential)| credential.clone())
+    }
+
+    fn invalidate(&self, hostname: &str) {
+        self.cache
+            .lock()
+            .expect("Credentials helper cache poisoned")
+            .remove(hostname);
+    }
+}
+
+impl CredentialHelper for ExternalCredentials {
+    async fn get(&self, hostname: impl AsRef<str> + Send) -> Result<Credential, anyhow::Error> {
+        let hostname = hostname.as_ref().to_ascii_lowercase();
+        if let Some(credential) = self.cached(&hostname) {
+            return Ok(credential);
+        }
+        let output = self.run("get", &hostname, None).await?;
+        let credentials: HelperCredentials = serde_json::from_slice(&output)
+            .context("Credentials helper returned invalid credentials")?;
+        let credential = match credentials.token {
+            Some(token) => Credential::Entry(Some(Token::Bearer(token))),
+            None => Credential::NotFound,
+        };
+        if !self.cache_ttl.is_zero() {
+            self.cache
+                .lock()
+                .expect("Credentials helper cache poisoned")
+                .insert(hostname, (Instant::now(), credential.clone()));
+        }
+        Ok(credential)
+    }
+
+    async fn store(&mut self, hostname: String, cred: Token) -> Result<(), anyhow::Error> {
+        let hostname = hostname.to_ascii_lowercase();
+        // The credentials helper protocol only has bearer tokens
+        let Token::Bearer(token) = cred else {
+            anyhow::bail!(
+                "Credentials helpers can only store bearer tokens, not {}",
+                cred.scheme()
+            );
+        };
+        let input = serde_json::to_vec(&HelperCredentials { token: Some(token) })?;
+        self.invalidate(&hostname);
+        self.run("store", &hostname, Some(input)).await?;
+        Ok(())
+    }
+
+    async fn forget(&mut self, hostname: impl AsRef<str> + Send) -> Result<(), anyhow::Error> {
+        let hostname = hostname.as_ref().to_ascii_lowercase();
+        self.invalidate(&hostname);
+        self.run("forget", &hostname, None).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    /// Writes a helper program that stores tokens as files in its directory.
+    fn write_helper(script: &str) -> (TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("terraform-credentials-test");
+        std::fs::write(
+            &path,
+            format!("#!/bin/sh\ncd \"$(dirname \"$0\")\"\n{script}"),
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (dir, path)
+    }
+
+    const FILE_HELPER: &str = r#"
+echo "$1 $2" >> calls
+case "$1" in
+  get) if [ -f "$2" ]; then cat "$2"; else echo '{}'; fi ;;
+  store) cat > "$2" ;;
+  forget) rm -f "$2" ;;
+  *) echo "unknown verb $1" >&2; exit 1 ;;
+esac
+"#;
+
+    fn calls(dir: &TempDir) -> usize {
+        std::fs::read_to_string(dir.path().join("calls"))
+            .unwrap_or_default()
+            .lines()
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_external_helper_protocol() {
+        let (dir, path) = write_helper(FILE_HELPER);
+        let mut helper = ExternalCredentials::new(
+            path,
+            vec![],
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+        );
+
+        assert_eq!(
+            helper.get("Example.com").await.unwrap(),
+            Credential::NotFound
+        );
+        helper
+            .store("example.com".into(), "password1".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            helper.get("example.com").await.unwrap(),
+            Credential::Entry(Some("password1".into()))
+        );
+        helper.forget("example.com").await.unwrap();
+        assert_eq!(
+            helper.get("example.com").await.unwrap(),
+            Credential::NotFound
+        );
+        assert!(helper
+            .store(
+                "example.com".into(),
+                Token::Basic {
+                    username: "user".into(),
+                    password: "password1".into()
+                }
+            )
+            .await
+            .is_err());
+        assert_eq!(calls(&dir), 5);
+    }
+
+    #[tokio::test]
+    async fn test_external_helper_caches_results() {
+        let (dir, path) = write_helper(FILE_HELPER);
+        std::fs::write(dir.path().join("example.com"), r#"{"token":"password1"}"#).unwrap();
+        let helper = ExternalCredentials::new(
+            path.clone(),
+            vec![],
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+        );
+        for _ in 0..3 {
+            assert_eq!(
+                helper.get("example.com").await.unwrap(),
+                Credential::Entry(Some("password1".into()))
+            );
+        }
+        assert_eq!(calls(&dir), 1);
+
+        let helper =
+            ExternalCredentials::new(path, vec![], Duration::from_secs(10), Duration::ZERO);
+        helper.get("example.com").await.unwrap();
+        helper.get("example.com").await.unwrap();
+        assert_eq!(calls(&dir), 3);
+    }
+
+    #[tokio::test]
+    async fn test_external_helper_errors() {
+        let (_dir, path) = write_helper("echo 'secret store unavailable' >&2\nexit 1\n");
+        let helper = ExternalCredentials::new(
+            path,
+            vec![],
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+        );
+        let error = helper.get("example.com").await.unwrap_err();
+        assert!(error.to_string().contains("secret store unavailable"));
+
+        let (_dir, path) = write_helper("sleep 5\n");
+        let helper = ExternalCredentials::new(
+            path,
+            vec![],
+            Duration::from_millis(100),
+            Duration::from_secs(60),
+        );
+        let error = helper.get("example.com").await.unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+
+        let (_dir, path) = write_helper("echo 'not json'\n");
+        let helper = ExternalCredentials::new(
+            path,
+            vec![],
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+        );
+        assert!(helper.get("example.com").await.is_err());
+    }
+}
@@ -1,7 +1,7 @@
 /// Implementation of the memory credential helper that always throws an error
 ///
 /// This is useful for testing the error handling of the credential helper
-use super::{Credential, CredentialHelper};
+use super::{Credential, CredentialHelper, CredentialMetadata, Token};
 
 #[derive(Clone, Debug)]
 pub(crate) struct FaultyCredentials;
@@ -18,11 +18,15 @@ impl CredentialHelper for FaultyCredentials {
         Err(anyhow::anyhow!("Error occurred"))
     }
 
-    async fn store(&mut self, _hostname: String, _cred: String) -> Result<(), anyhow::Error> {
+    async fn store(&mut self, _hostname: String, _cred: Token) -> Result<(), anyhow::Error> {
         Err(anyhow::anyhow!("Error occurred"))
     }
 
     async fn forget(&mut self, _hostname: impl AsRef<str> + Send) -> Result<(), anyhow::Error> {
         Err(anyhow::anyhow!("Error occurred"))
     }
+
+    async fn list(&self) -> Result<Vec<CredentialMetadata>, anyhow::Error> {
+        Err(anyhow::anyhow!("Error occurred"))
+    }
 }
@@ -4,12 +4,15 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use super::{types::Credential, CredentialHelper};
+use super::{
+    types::{Credential, CredentialMetadata, Token},
+    CredentialHelper,
+};
 
 // Credential helper implementation by storing in the database
 #[derive(Clone, Debug)]
 pub struct MemoryCredentials {
-    map: Arc<RwLock<HashMap<String, Option<String>>>>,
+    map: Arc<RwLock<HashMap<String, Option<Token>>>>,
 }
 
 impl MemoryCredentials {
@@ -36,7 +39,7 @@ impl CredentialHelper for MemoryCredentials {
             .map_or(Credential::NotFound, |v| Credential::Entry(v.clone())))
     }
 
-    async fn store(&mut self, hostname: String, cred: String) -> Result<(), anyhow::Error> {
+    async fn store(&mut self, hostname: String, cred: Token) -> Result<(), anyhow::Error> {
         self.map
             .try_write()
             .map_err(|_| {
@@ -55,4 +58,11 @@ impl CredentialHelper for MemoryCredentials {
             .remove(hostname.as_ref());
         Ok(())
     }
+
+    async fn list(&self) -> Result<Vec<CredentialMetadata>, anyhow::Error> {
+        let map = self.map.try_read().map_err(|_| {
+            anyhow::anyhow!("Could not acquire read lock on in memory credential store")
+        })?;
+        Ok(map.keys().cloned().map(CredentialMetadata::new).collect())
+    }
 }
@@ -1,7 +1,26 @@
+pub mod chained;
 pub mod database;
+pub mod encryption;
+pub mod external;
 pub mod faulty;
 pub mod memory;
+pub mod terraform;
 mod types;
 
+pub use types::AuthFailure;
 pub use types::Credential;
 pub use types::CredentialHelper;
+pub use types::CredentialMetadata;
+pub use types::Token;
+
+/// Credentials used for upstream requests.
+///
+/// Those stored through the API take precedence over Terraform CLI credentials,
+/// and the external credentials helper is only run when neither has a token.
+pub type UpstreamCredentials = chained::ChainedCredentials<
+    database::DatabaseCredentials,
+    chained::ChainedCredentials<
+        terraform::TerraformCredentials,
+        Option<external::ExternalCredentials>,
+    >,
+>;
@@ -0,0 +1,289 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    marker::Send,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use super::{
+    types::{Credential, Token},
+    CredentialHelper,
+};
+
+const ENV_PREFIX: &str = "TF_TOKEN_";
+
+/// Normalizes a hostname for comparison as Terraform does.
+///
+/// Hostnames are lower cased and punycode encoded, and the default HTTPS port is removed.
+fn normalize_hostname(hostname: &str) -> Option<String> {
+    let (host, port) = match hostname.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => (host, Some(port)),
+        _ => (hostname, None),
+    };
+    let host = url::Host::parse(host).ok()?.to_string();
+    Some(match port {
+        Some(port) if port != "443" => format!("{host}:{port}"),
+        _ => host,
+    })
+}
+
+/// Decodes the hostname of a `TF_TOKEN_*` environment variable.
+///
+/// Periods are encoded as `_` and hyphens as `__`, as they are not allowed in variable names.
+fn env_hostname(name: &str) -> Option<String> {
+    let encoded = name.strip_prefix(ENV_PREFIX)?;
+    normalize_hostname(&encoded.replace("__", "-").replace('_', "."))
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonCredentials {
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonConfig {
+    #[serde(default)]
+    credentials: HashMap<String, JsonCredentials>,
+}
+
+/// Parses the `credentials` blocks of a Terraform CLI config file, in HCL or JSON syntax.
+fn parse_cli_config(contents: &str, json: bool) -> Result<Vec<(String, String)>, anyhow::Error> {
+    if json {
+        let config: JsonConfig = serde_json::from_str(contents)?;
+        return Ok(config
+            .credentials
+            .into_iter()
+            .filter_map(|(hostname, credentials)| Some((hostname, credentials.token?)))
+            .collect());
+    }
+    let body = hcl::parse(contents)?;
+    let mut credentials = vec![];
+    for block in body
+        .blocks()
+        .filter(|block| block.identifier() == "credentials")
+    {
+        let [label] = block.labels() else {
+            anyhow::bail!("credentials blocks must have a single hostname label");
+        };
+        let token = block
+            .body()
+            .attributes()
+            .find(|attribute| attribute.key() == "token")
+            .map(|attribute| match attribute.expr() {
+                hcl::Expression::String(token) => Ok(token.clone()),
+                _ => Err(anyhow::anyhow!(
+                    "Token for {} must be a string",
+                    label.as_str()
+                )),
+            })
+            .transpose()?;
+        if let Some(token) = token {
+            credentials.push((label.as_str().to_string(), token));
+        }
+    }
+    Ok(credentials)
+}
+
+/// Read only credentials provisioned for the Terraform CLI.
+///
+/// Tokens are read once, from `credentials` blocks in the CLI config file and
+/// `credentials.tfrc.json`, and from `TF_TOKEN_*` environment variables, which
+/// take precedence as they do in Terraform.
+#[derive(Clone, Debug, Default)]
+pub struct TerraformCredentials {
+    tokens: Arc<HashMap<String, String>>,
+}
+
+impl TerraformCredentials {
+    /// Loads credentials from the given CLI config file, or `~/.terraformrc` if it exists.
+    pub fn load(cli_config: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+        let mut files = vec![];
+        if let Some(home) = &home {
+            files.push(home.join(".terraform.d").join("credentials.tfrc.json"));
+        }
+        match (cli_config, &home) {
+            (Some(path), _) => files.push(path.to_path_buf()),
+            (None, Some(home)) => files.push(home.join(".terraformrc")),
+            (None, None) => {}
+        }
+
+        let mut credentials = vec![];
+        for path in files {
+            if cli_config != Some(path.as_path()) && !path.exists() {
+                continue;
+            }
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Reading Terraform CLI config {}", path.display()))?;
+            let json = path
+                .extension()
+                .is_some_and(|extension| extension == "json");
+            credentials.extend(
+                parse_cli_config(&contents, json)
+                    .with_context(|| format!("Parsing Terraform CLI config {}", path.display()))?,
+            );
+        }
+        let credentials = Self::from_sources(credentials, std::env::vars());
+        tracing::info!(
+            hostnames = credentials.tokens.len(),
+            "Loaded Terraform CLI credentials"
+        );
+        Ok(credentials)
+    }
+
+    /// Builds credentials from config file entries in increasing precedence, then environment variables.
+    fn from_sources(
+        config: impl IntoIterator<Item = (String, String)>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Self {
+        let mut tokens = HashMap::new();
+        for (hostname, token) in config {
+            match normalize_hostname(&hostname) {
+                Some(hostname) => {
+                    tokens.insert(hostname, token);
+                }
+                None => tracing::warn!(%hostname, "Ignoring credentials for invalid hostname"),
+            }
+        }
+        for (name, token) in env {
+            if !name.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            match env_hostname(&name) {
+                Some(hostname) => {
+                    tokens.insert(hostname, token);
+                }
+                None => tracing::warn!(%name, "Ignoring credentials for invalid hostname"),
+            }
+        }
+        Self {
+            tokens: Arc::new(tokens),
+        }
+    }
+}
+
+impl CredentialHelper for TerraformCredentials {
+    async fn get(&self, hostname: impl AsRef<str> + Send) -> Result<Credential, anyhow::Error> {
+        Ok(normalize_hostname(hostname.as_ref())
+            .and_then(|hostname| self.tokens.get(&hostname))
+            .map_or(Credential::NotFound, |token| {
+                Credential::Entry(Some(Token::Bearer(token.clone())))
+            }))
+    }
+
+    async fn store(&mut self, _hostname: String, _cred: Token) -> Result<(), anyhow::Error> {
+        Err(anyhow::anyhow!("Terraform CLI credentials are read only"))
+    }
+
+    async fn forget(&mut self, _hostname: impl AsRef<str> + Send) -> Result<(), anyhow::Error> {
+        Err(anyhow::anyhow!("Terraform CLI credentials are read only"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(token: &str) -> Credential {
+        Credential::Entry(Some(token.into()))
+    }
+
+    #[test]
+    fn test_env_hostname() {
+        assert_eq!(
+            env_hostname("TF_TOKEN_app_terraform_io").as_deref(),
+            Some("app.terraform.io")
+        );
+        assert_eq!(
+            env_hostname("TF_TOKEN_my__registry_example_com").as_deref(),
+            Some("my-registry.example.com")
+        );
+        assert_eq!(
+            env_hostname("TF_TOKEN_xn____caf__dma_fr").as_deref(),
+            Some("xn--caf-dma.fr")
+        );
+        assert_eq!(env_hostname("TF_TOKEN_"), None);
+    }
+
+    #[test]
+    fn test_normalize_hostname() {
+        assert_eq!(
+            normalize_hostname("Registry.Example.COM").as_deref(),
+            Some("registry.example.com")
+        );
+        assert_eq!(
+            normalize_hostname("café.fr").as_deref(),
+            Some("xn--caf-dma.fr")
+        );
+        assert_eq!(
+            normalize_hostname("registry.example.com:443").as_deref(),
+            Some("registry.example.com")
+        );
+        assert_eq!(
+            normalize_hostname("registry.example.com:8443").as_deref(),
+            Some("registry.example.com:8443")
+        );
+    }
+
+    #[test]
+    fn test_parse_cli_config() {
+        let credentials = parse_cli_config(
+            r#"
+            plugin_cache_dir = "$HOME/.terraform.d/plugin-cache"
+
+            credentials "app.terraform.io" {
+              token = "hcl-token"
+            }
+
+            credentials "registry.example.com" {
+            }
+            "#,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            credentials,
+            vec![("app.terraform.io".to_string(), "hcl-token".to_string())]
+        );
+
+        let credentials = parse_cli_config(
+            r#"{"credentials": {"app.terraform.io": {"token": "json-token"}}}"#,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            credentials,
+            vec![("app.terraform.io".to_string(), "json-token".to_string())]
+        );
+
+        assert!(parse_cli_config(r#"credentials { token = "a" }"#, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_env_takes_precedence() {
+        let credentials = TerraformCredentials::from_sources(
+            [
+                ("App.Terraform.io".to_string(), "config".to_string()),
+                ("registry.example.com".to_string(), "config".to_string()),
+            ],
+            [
+                ("TF_TOKEN_app_terraform_io".to_string(), "env".to_string()),
+                ("PATH".to_string(), "/usr/bin".to_string()),
+            ],
+        );
+        assert_eq!(
+            credentials.get("app.terraform.io").await.unwrap(),
+            entry("env")
+        );
+        assert_eq!(
+            credentials.get("REGISTRY.example.com").await.unwrap(),
+            entry("config")
+        );
+        assert_eq!(
+            credentials.get("example.com").await.unwrap(),
+            Credential::NotFound
+        );
+    }
+}
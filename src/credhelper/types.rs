@@ -1,11 +1,115 @@
+use anyhow::Context;
 use futures::Future;
+use http::{HeaderName, HeaderValue};
 use reqwest::RequestBuilder;
 use std::marker::Send;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Credential {
     NotFound,
-    Entry(Option<String>),
+    Entry(Option<Token>),
+}
+
+/// A secret and how it is presented to the upstream registry.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Token {
+    /// Sent as a bearer token, as Terraform itself does
+    Bearer(String),
+    /// Sent with HTTP basic authentication
+    Basic { username: String, password: String },
+    /// Sent as the value of a custom header, such as `X-JFrog-Art-Api`
+    Header { name: String, value: String },
+}
+
+impl Token {
+    /// Name of the scheme, as given to and stored by the API.
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            Token::Bearer(_) => "bearer",
+            Token::Basic { .. } => "basic",
+            Token::Header { .. } => "header",
+        }
+    }
+
+    /// Checks the token can be sent in a request.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        match self {
+            Token::Bearer(token) => {
+                HeaderValue::try_from(format!("Bearer {token}")).context("Invalid bearer token")?;
+            }
+            Token::Basic { username, .. } => {
+                anyhow::ensure!(!username.contains(':'), "Basic auth username contains ':'");
+            }
+            Token::Header { name, value } => {
+                let name = HeaderName::try_from(name.as_str())
+                    .with_context(|| format!("Invalid header name {name}"))?;
+                anyhow::ensure!(
+                    name != http::header::HOST,
+                    "Credentials cannot be sent in the host header"
+                );
+                HeaderValue::try_from(value.as_str()).context("Invalid header value")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the token to a request.
+    pub fn apply(&self, request: RequestBuilder) -> Result<RequestBuilder, anyhow::Error> {
+        self.validate()?;
+        Ok(match self {
+            Token::Bearer(token) => request.bearer_auth(token),
+            Token::Basic { username, password } => request.basic_auth(username, Some(password)),
+            Token::Header { name, value } => {
+                let mut value = HeaderValue::try_from(value.as_str())?;
+                value.set_sensitive(true);
+                request.header(name.as_str(), value)
+            }
+        })
+    }
+}
+
+impl From<String> for Token {
+    fn from(token: String) -> Self {
+        Token::Bearer(token)
+    }
+}
+
+impl From<&str> for Token {
+    fn from(token: &str) -> Self {
+        Token::Bearer(token.to_string())
+    }
+}
+
+/// Metadata about a stored credential, which never includes the secret itself.
+///
+/// Timestamps are RFC 3339 in UTC, and are absent when the helper does not track them.
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize)]
+pub struct CredentialMetadata {
+    pub hostname: String,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub last_auth_failure: Option<AuthFailure>,
+}
+
+impl CredentialMetadata {
+    /// Metadata of a credential known only by its hostname.
+    pub fn new(hostname: String) -> Self {
+        Self {
+            hostname,
+            created_at: None,
+            updated_at: None,
+            last_used_at: None,
+            last_auth_failure: None,
+        }
+    }
+}
+
+/// An upstream rejecting a request made with a credential.
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize)]
+pub struct AuthFailure {
+    pub at: String,
+    pub status: u16,
 }
 
 pub trait CredentialHelper: Sync {
@@ -16,7 +120,7 @@ pub trait CredentialHelper: Sync {
     fn store(
         &mut self,
         hostname: String,
-        cred: String,
+        cred: Token,
     ) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
 
     /// Forget a credential.
@@ -26,21 +130,101 @@ pub trait CredentialHelper: Sync {
         hostname: impl AsRef<str> + Send,
     ) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
 
+    /// List the credentials stored in the helper.
+    /// NOTE: Helpers that cannot enumerate their credentials list none
+    fn list(&self) -> impl Future<Output = Result<Vec<CredentialMetadata>, anyhow::Error>> + Send {
+        async { Ok(vec![]) }
+    }
+
+    /// Record that the credential for a hostname was sent upstream.
+    fn record_use(
+        &self,
+        _hostname: impl AsRef<str> + Send,
+    ) -> impl Future<Output = Result<(), anyhow::Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Record the upstream rejecting a request for a hostname with a 401 or 403 status.
+    fn record_auth_failure(
+        &self,
+        _hostname: impl AsRef<str> + Send,
+        _status: u16,
+    ) -> impl Future<Output = Result<(), anyhow::Error>> + Send {
+        async { Ok(()) }
+    }
+
     fn transform(
         &self,
         request: RequestBuilder,
         hostname: impl AsRef<str> + Send,
     ) -> impl std::future::Future<Output = Result<RequestBuilder, anyhow::Error>> + Send {
-        async {
+        async move {
+            let hostname = hostname.as_ref();
             match self.get(hostname).await? {
                 Credential::NotFound => Ok(request),
                 Credential::Entry(None) => Ok(request),
-                Credential::Entry(Some(token)) => Ok(request.bearer_auth(token)),
+                Credential::Entry(Some(token)) => {
+                    let request = token.apply(request)?;
+                    // Usage tracking is best effort and never fails the request
+                    if let Err(error) = self.record_use(hostname).await {
+                        tracing::warn!(reason = ?error, %hostname, "Could not record credential use");
+                    }
+                    Ok(request)
+                }
             }
         }
     }
 }
 
+/// An optional credential helper, which has no credentials when not configured.
+impl<T: CredentialHelper + Send> CredentialHelper for Option<T> {
+    async fn get(&self, hostname: impl AsRef<str> + Send) -> Result<Credential, anyhow::Error> {
+        match self {
+            Some(helper) => helper.get(hostname).await,
+            None => Ok(Credential::NotFound),
+        }
+    }
+
+    async fn store(&mut self, hostname: String, cred: Token) -> Result<(), anyhow::Error> {
+        match self {
+            Some(helper) => helper.store(hostname, cred).await,
+            None => Err(anyhow::anyhow!("No credential helper configured")),
+        }
+    }
+
+    async fn forget(&mut self, hostname: impl AsRef<str> + Send) -> Result<(), anyhow::Error> {
+        match self {
+            Some(helper) => helper.forget(hostname).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<CredentialMetadata>, anyhow::Error> {
+        match self {
+            Some(helper) => helper.list().await,
+            None => Ok(vec![]),
+        }
+    }
+
+    async fn record_use(&self, hostname: impl AsRef<str> + Send) -> Result<(), anyhow::Error> {
+        match self {
+            Some(helper) => helper.record_use(hostname).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn record_auth_failure(
+        &self,
+        hostname: impl AsRef<str> + Send,
+        status: u16,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            Some(helper) => helper.record_auth_failure(hostname, status).await,
+            None => Ok(()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::memory::MemoryCredentials;
@@ -72,6 +256,89 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_request_transform_typed_credentials() {
+        let mut creds = MemoryCredentials::default();
+        creds
+            .store(
+                "basic.example.com".into(),
+                Token::Basic {
+                    username: "user".into(),
+                    password: "password1".into(),
+                },
+            )
+            .await
+            .unwrap();
+        creds
+            .store(
+                "header.example.com".into(),
+                Token::Header {
+                    name: "X-JFrog-Art-Api".into(),
+                    value: "password1".into(),
+                },
+            )
+            .await
+            .unwrap();
+        let client = reqwest::Client::new();
+
+        let request = creds
+            .transform(client.get("http://basic.example.com"), "basic.example.com")
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.headers().get("authorization").unwrap(),
+            "Basic dXNlcjpwYXNzd29yZDE="
+        );
+
+        let request = creds
+            .transform(
+                client.get("http://header.example.com"),
+                "header.example.com",
+            )
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.headers().get("x-jfrog-art-api").unwrap(),
+            "password1"
+        );
+        assert_eq!(request.headers().get("authorization"), None);
+    }
+
+    #[test]
+    fn test_token_validation() {
+        assert!(Token::Bearer("password1".into()).validate().is_ok());
+        assert!(Token::Bearer("pass\nword1".into()).validate().is_err());
+        assert!(Token::Basic {
+            username: "us:er".into(),
+            password: "password1".into()
+        }
+        .validate()
+        .is_err());
+        for name in ["X Bad", "Host", ""] {
+            assert!(Token::Header {
+                name: name.into(),
+                value: "password1".into()
+            }
+            .validate()
+            .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unconfigured_optional_helper() {
+        let mut creds: Option<MemoryCredentials> = None;
+        assert_eq!(creds.get("localhost").await.unwrap(), Credential::NotFound);
+        assert!(creds
+            .store("localhost".into(), "password1".into())
+            .await
+            .is_err());
+        assert!(creds.forget("localhost").await.is_ok());
+    }
+
     #[tokio::test]
     async fn test_request_transform_unknown_credential() {
         let mut creds = MemoryCredentials::default();
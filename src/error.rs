@@ -39,6 +39,24 @@ pub enum TerrashineError {
     },
     #[error("Broken refresher channel as the receiver has been dropped")]
     BrokenRefresherChannel,
+    #[error("Module download from {hostname} for {path} did not return an X-Terraform-Get header")]
+    ModuleSourceMissing { hostname: String, path: String },
+    #[error("Artifact checksum mismatch, expected sha256 {expected} but received {actual}")]
+    ArtifactChecksumMismatch { expected: String, actual: String },
+    #[error("No checksum is available for {name} from {source_name}, {reason}")]
+    ArtifactChecksumUnavailable {
+        name: String,
+        source_name: String,
+        reason: String,
+    },
+    #[error("Signature verification failed for {name}")]
+    SignatureVerificationFailure { name: String },
+    #[error("Private provider {provider:?} has not been published")]
+    ProviderNotFound { provider: TerraformProvider },
+    #[error("Download from {url} is not allowed, {reason}")]
+    DownloadNotAllowed { url: String, reason: String },
+    #[error("No upstream source has {name}")]
+    UpstreamSourcesExhausted { name: String },
     #[error(transparent)]
     Anyhow {
         #[from]
@@ -59,6 +77,13 @@ impl IntoResponse for TerrashineError {
             TerrashineError::TooManyRequestsInChannel { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             TerrashineError::BrokenRefresherChannel => StatusCode::INTERNAL_SERVER_ERROR,
             TerrashineError::ProviderGetBuildUrlFailure { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            TerrashineError::ModuleSourceMissing { .. } => StatusCode::BAD_GATEWAY,
+            TerrashineError::ArtifactChecksumMismatch { .. } => StatusCode::BAD_GATEWAY,
+            TerrashineError::ArtifactChecksumUnavailable { .. } => StatusCode::BAD_GATEWAY,
+            TerrashineError::ProviderNotFound { .. } => StatusCode::NOT_FOUND,
+            TerrashineError::SignatureVerificationFailure { .. } => StatusCode::BAD_GATEWAY,
+            TerrashineError::UpstreamSourcesExhausted { .. } => StatusCode::NOT_FOUND,
+            TerrashineError::DownloadNotAllowed { .. } => StatusCode::FORBIDDEN,
         }
         .into_response()
     }
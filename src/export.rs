@@ -0,0 +1,477 @@
+use anyhow::Context;
+use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::ExportArgs,
+    http::{
+        artifacts::artifact_s3_key,
+        response_types::{MirrorIndex, MirrorVersion, TargetPlatformIdentifier},
+        version::archive_name,
+    },
+    s3_client,
+};
+
+const DEFAULT_REGISTRY_HOSTNAME: &str = "registry.terraform.io";
+
+/// Fully qualified provider source address.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ProviderAddress {
+    pub(crate) hostname: String,
+    pub(crate) namespace: String,
+    pub(crate) provider_type: String,
+}
+
+impl ProviderAddress {
+    /// Parses a source address in the form [hostname/]namespace/type
+    pub(crate) fn parse(address: &str) -> Option<Self> {
+        let address = address.trim().to_ascii_lowercase();
+        match *address.split('/').collect::<Vec<_>>().as_slice() {
+            [namespace, provider_type] if !namespace.is_empty() && !provider_type.is_empty() => {
+                Some(Self {
+                    hostname: DEFAULT_REGISTRY_HOSTNAME.to_string(),
+                    namespace: namespace.to_string(),
+                    provider_type: provider_type.to_string(),
+                })
+            }
+            [hostname, namespace, provider_type]
+                if !hostname.is_empty() && !namespace.is_empty() && !provider_type.is_empty() =>
+            {
+                Some(Self {
+                    hostname: hostname.to_string(),
+                    namespace: namespace.to_string(),
+                    provider_type: provider_type.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Directory of the provider in the packed filesystem mirror layout.
+    pub(crate) fn mirror_path(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.hostname, self.namespace, self.provider_type
+        )
+    }
+}
+
+/// Reads the provider versions selected by a dependency lock file.
+pub(crate) fn parse_lock_file(contents: &str) -> Vec<(ProviderAddress, String)> {
+    let mut locked = vec![];
+    let mut current = None;
+    for line in contents.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("provider ") {
+            current = ProviderAddress::parse(rest.trim_end_matches('{').trim().trim_matches('"'));
+        } else if let Some(value) = line.strip_prefix("version") {
+            let version = value
+                .trim_start()
+                .strip_prefix('=')
+                .map(|v| v.trim().trim_matches('"'));
+            if let (Some(provider), Some(version)) = (current.take(), version) {
+                locked.push((provider, version.to_string()));
+            }
+        }
+    }
+    locked
+}
+
+/// Selects which cached provider packages are exported.
+#[derive(Debug, Default)]
+pub(crate) struct ExportFilter {
+    pub(crate) providers: Vec<ProviderAddress>,
+    pub(crate) versions: Vec<String>,
+    pub(crate) platforms: Vec<String>,
+    pub(crate) locked: Vec<(ProviderAddress, String)>,
+}
+
+impl ExportFilter {
+    fn matches(&self, package: &CachedPackage) -> bool {
+        if !self.platforms.is_empty()
+            && !self
+                .platforms
+                .contains(&archive_name(&package.os, &package.arch))
+        {
+            return false;
+        }
+        let filtered = !self.providers.is_empty() || !self.versions.is_empty();
+        let by_filter = (self.providers.is_empty() || self.providers.contains(&package.provider))
+            && (self.versions.is_empty() || self.versions.contains(&package.version));
+        if self.locked.is_empty() {
+            return by_filter;
+        }
+        let by_lock = self.locked.iter().any(|(provider, version)| {
+            *provider == package.provider && *version == package.version
+        });
+        by_lock || (filtered && by_filter)
+    }
+}
+
+#[derive(Debug)]
+struct CachedPackage {
+    provider: ProviderAddress,
+    version: String,
+    os: String,
+    arch: String,
+    artifact_id: i64,
+}
+
+impl CachedPackage {
+    fn filename(&self) -> String {
+        format!(
+            "terraform-provider-{}_{}_{}_{}.zip",
+            self.provider.provider_type, self.version, self.os, self.arch
+        )
+    }
+}
+
+/// Destination of an export, either a directory or a gzip compressed tarball.
+enum ExportWriter {
+    Directory(PathBuf),
+    Tarball(Box<tar::Builder<GzEncoder<File>>>),
+}
+
+impl ExportWriter {
+    fn directory(path: &Path) -> Self {
+        Self::Directory(path.to_path_buf())
+    }
+
+    fn tarball(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+        Ok(Self::Tarball(Box::new(tar::Builder::new(GzEncoder::new(
+            file,
+            Compression::default(),
+        )))))
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Directory(root) => {
+                let path = root.join(path);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("Creating {}", parent.display()))?;
+                }
+                std::fs::write(&path, data).with_context(|| format!("Writing {}", path.display()))
+            }
+            Self::Tarball(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default(),
+                );
+                builder
+                    .append_data(&mut header, path, data)
+                    .with_context(|| format!("Adding {path} to tarball"))
+            }
+        }
+    }
+
+    fn finish(self) -> Result<(), anyhow::Error> {
+        if let Self::Tarball(builder) = self {
+            builder.into_inner()?.finish()?.flush()?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn run_export(args: ExportArgs) -> Result<(), ()> {
+    match export(args).await {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            error!("{:#}", e);
+            Err(())
+        }
+    }
+}
+
+async fn export(args: ExportArgs) -> Result<(), anyhow::Error> {
+    let mut filter = ExportFilter {
+        providers: args
+            .providers
+            .iter()
+            .map(|address| {
+                ProviderAddress::parse(address)
+                    .with_context(|| format!("Invalid provider address {address}"))
+            })
+            .collect::<Result<_, _>>()?,
+        versions: args.versions.clone(),
+        platforms: args.platforms.clone(),
+        locked: vec![],
+    };
+    for lock_file in args.lock_files.iter() {
+        let contents = std::fs::read_to_string(lock_file)
+            .with_context(|| format!("Reading lock file {}", lock_file.display()))?;
+        filter.locked.extend(parse_lock_file(&contents));
+    }
+
+    let db = PgPoolOptions::new()
+        .max_connections(1)
+        .connect_with(args.store.database_url.clone())
+        .await
+        .context("Connecting to database")?;
+    let s3 = s3_client(args.store.s3_endpoint.as_ref()).await;
+
+    let packages = list_cached_packages(&db)
+        .await?
+        .into_iter()
+        .filter(|package| filter.matches(package))
+        .collect::<Vec<_>>();
+    for (provider, version) in filter.locked.iter() {
+        if !packages
+            .iter()
+            .any(|package| package.provider == *provider && package.version == *version)
+        {
+            warn!(?provider, %version, "Locked provider version has no cached packages");
+        }
+    }
+    anyhow::ensure!(!packages.is_empty(), "No cached provider packages matched");
+
+    let mut writer = match (&args.output_dir, &args.output_tarball) {
+        (Some(dir), _) => ExportWriter::directory(dir),
+        (None, Some(tarball)) => ExportWriter::tarball(tarball)?,
+        (None, None) => anyhow::bail!("An output directory or tarball is required"),
+    };
+
+    // Group packages into the index and version documents for each provider
+    let mut providers = BTreeMap::<&ProviderAddress, BTreeMap<&str, MirrorVersion>>::new();
+    for package in packages.iter() {
+        let filename = package.filename();
+        let key = artifact_s3_key(&args.store.s3_bucket_prefix, package.artifact_id);
+        let data = s3
+            .get_object()
+            .bucket(&args.store.s3_bucket_name)
+            .key(&key)
+            .send()
+            .await
+            .with_context(|| format!("Downloading {key}"))?
+            .body
+            .collect()
+            .await
+            .with_context(|| format!("Downloading {key}"))?
+            .into_bytes();
+        let hash = format!("zh:{}", hex::encode(Sha256::digest(&data)));
+        writer.write(
+            &format!("{}/{filename}", package.provider.mirror_path()),
+            &data,
+        )?;
+        info!(provider = ?package.provider, version = %package.version, %filename, "Exported provider package");
+
+        providers
+            .entry(&package.provider)
+            .or_default()
+            .entry(&package.version)
+            .or_insert_with(|| MirrorVersion {
+                archives: HashMap::new(),
+            })
+            .archives
+            .insert(
+                archive_name(&package.os, &package.arch),
+                TargetPlatformIdentifier {
+                    url: filename,
+                    hashes: vec![hash],
+                },
+            );
+    }
+
+    for (provider, versions) in providers.iter() {
+        for (version, mirror_version) in versions.iter() {
+            writer.write(
+                &format!("{}/{version}.json", provider.mirror_path()),
+                &serde_json::to_vec(mirror_version)?,
+            )?;
+        }
+        let index = MirrorIndex::from(versions.keys().map(|v| v.to_string()).collect::<Vec<_>>());
+        writer.write(
+            &format!("{}/index.json", provider.mirror_path()),
+            &serde_json::to_vec(&index)?,
+        )?;
+    }
+    writer.finish()?;
+    info!(count = packages.len(), "Export complete");
+    Ok(())
+}
+
+async fn list_cached_packages(db: &PgPool) -> Result<Vec<CachedPackage>, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"
+        select
+            "terraform_provider"."hostname",
+            "terraform_provider"."namespace",
+            "terraform_provider"."type",
+            "terraform_provider_version"."version",
+            "terraform_provider_version"."os",
+            "terraform_provider_version"."arch",
+            "terraform_provider_version"."artifact_id" as "artifact_id!"
+        from "terraform_provider_version"
+        inner join "terraform_provider" on
+            "terraform_provider_version"."provider_id" = "terraform_provider"."id"
+        where "terraform_provider_version"."artifact_id" is not null
+        order by
+            "terraform_provider"."hostname",
+            "terraform_provider"."namespace",
+            "terraform_provider"."type",
+            "terraform_provider_version"."version",
+            "terraform_provider_version"."os",
+            "terraform_provider_version"."arch";
+        "#
+    )
+    .fetch_all(db)
+    .await
+    .context("Listing cached provider packages")?;
+    Ok(rows
+        .into_iter()
+        .map(|row| CachedPackage {
+            provider: ProviderAddress {
+                hostname: row.hostname,
+                namespace: row.namespace,
+                provider_type: row.r#type,
+            },
+            version: row.version,
+            os: row.os,
+            arch: row.arch,
+            artifact_id: row.artifact_id,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(address: &str, version: &str, os: &str, arch: &str) -> CachedPackage {
+        CachedPackage {
+            provider: ProviderAddress::parse(address).unwrap(),
+            version: version.into(),
+            os: os.into(),
+            arch: arch.into(),
+            artifact_id: 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_provider_address() {
+        assert_eq!(
+            ProviderAddress::parse("hashicorp/AWS"),
+            Some(ProviderAddress {
+                hostname: "registry.terraform.io".into(),
+                namespace: "hashicorp".into(),
+                provider_type: "aws".into(),
+            })
+        );
+        assert_eq!(
+            ProviderAddress::parse("example.com/acme/widget")
+                .unwrap()
+                .mirror_path(),
+            "example.com/acme/widget"
+        );
+        assert_eq!(ProviderAddress::parse("aws"), None);
+    }
+
+    #[test]
+    fn test_parse_lock_file() {
+        let contents =
+            include_str!("../resources/test/terraform/random-import-stack/.terraform.lock.hcl");
+        assert_eq!(
+            parse_lock_file(contents),
+            vec![
+                (
+                    ProviderAddress::parse("hashicorp/null").unwrap(),
+                    "3.2.1".to_string()
+                ),
+                (
+                    ProviderAddress::parse("hashicorp/random").unwrap(),
+                    "3.4.3".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter_everything() {
+        let filter = ExportFilter::default();
+        assert!(filter.matches(&package("hashicorp/aws", "5.0.0", "linux", "amd64")));
+    }
+
+    #[test]
+    fn test_filter_provider_version_and_platform() {
+        let filter = ExportFilter {
+            providers: vec![ProviderAddress::parse("hashicorp/aws").unwrap()],
+            versions: vec!["5.0.0".into()],
+            platforms: vec!["linux_amd64".into()],
+            locked: vec![],
+        };
+        assert!(filter.matches(&package("hashicorp/aws", "5.0.0", "linux", "amd64")));
+        assert!(!filter.matches(&package("hashicorp/aws", "5.0.0", "darwin", "arm64")));
+        assert!(!filter.matches(&package("hashicorp/aws", "4.0.0", "linux", "amd64")));
+        assert!(!filter.matches(&package("hashicorp/null", "5.0.0", "linux", "amd64")));
+    }
+
+    #[test]
+    fn test_filter_lock_file() {
+        let filter = ExportFilter {
+            locked: vec![(
+                ProviderAddress::parse("hashicorp/random").unwrap(),
+                "3.4.3".into(),
+            )],
+            ..Default::default()
+        };
+        assert!(filter.matches(&package("hashicorp/random", "3.4.3", "linux", "amd64")));
+        assert!(!filter.matches(&package("hashicorp/random", "3.4.2", "linux", "amd64")));
+        assert!(!filter.matches(&package("hashicorp/aws", "5.0.0", "linux", "amd64")));
+    }
+
+    #[test]
+    fn test_write_tarball() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mirror.tar.gz");
+        let mut writer = ExportWriter::tarball(&path).unwrap();
+        writer
+            .write("registry.terraform.io/hashicorp/random/index.json", b"{}")
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut archive =
+            tar::Archive::new(flate2::read::GzDecoder::new(File::open(&path).unwrap()));
+        let names = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["registry.terraform.io/hashicorp/random/index.json"]
+        );
+    }
+
+    #[test]
+    fn test_write_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = ExportWriter::directory(dir.path());
+        writer
+            .write("registry.terraform.io/hashicorp/random/3.4.3.json", b"{}")
+            .unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            std::fs::read(
+                dir.path()
+                    .join("registry.terraform.io/hashicorp/random/3.4.3.json")
+            )
+            .unwrap(),
+            b"{}"
+        );
+    }
+}
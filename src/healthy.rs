@@ -4,10 +4,23 @@ use crate::config::IsHealthyArgs;
 use std::convert::Into;
 
 pub async fn run_healthy(args: IsHealthyArgs) -> Result<(), ()> {
-    let result = reqwest::get(format!("http://{}/healthcheck", args.http_listen))
-        .await
-        .map_err(Into::<anyhow::Error>::into)
-        .and_then(|x| x.error_for_status().map_err(Into::into));
+    let (scheme, http) = match args.tls_cert {
+        // The server's certificate is issued for its public hostname, not the listen address
+        Some(_) => (
+            "https",
+            reqwest::Client::builder().danger_accept_invalid_certs(true),
+        ),
+        None => ("http", reqwest::Client::builder()),
+    };
+    let result = async {
+        http.build()?
+            .get(format!("{scheme}://{}/healthcheck", args.http_listen))
+            .send()
+            .await?
+            .error_for_status()
+    }
+    .await
+    .map_err(Into::<anyhow::Error>::into);
     match result {
         Ok(_) => Ok(()),
         Err(err) => {
@@ -1,23 +1,43 @@
-use axum::{routing::post, Router};
+use axum::{
+    extract::DefaultBodyLimit,
+    routing::{get, post, put},
+    Router,
+};
 
 use crate::credhelper::CredentialHelper;
 
-use self::v1::credential::{delete, exists, update};
+use self::v1::{
+    credential::{delete, exists, list, update},
+    login::{complete, service, start, RegistryLogin},
+    provider::{publish, ProviderPublisher},
+};
 
 pub(crate) mod v1;
 
+/// Largest provider package accepted by the publishing API.
+const MAX_PROVIDER_PACKAGE_BYTES: usize = 512 * 1024 * 1024;
+
 #[derive(Clone)]
 pub(crate) struct APIState<C> {
     pub(crate) credentials: C,
+    pub(crate) publisher: Option<ProviderPublisher>,
+    pub(crate) login: Option<RegistryLogin>,
 }
 
 pub(crate) fn routes<S, C: Clone + Send + Sync + 'static + CredentialHelper>(
     state: APIState<C>,
 ) -> Router<S> {
     Router::new()
+        .route("/api/v1/credentials", get(list))
         .route(
             "/api/v1/credentials/{hostname}",
             post(update).delete(delete).get(exists),
         )
+        .route("/api/v1/logins/{hostname}", get(service).post(start))
+        .route("/api/v1/logins/{hostname}/token", post(complete))
+        .route(
+            "/api/v1/providers/{namespace}/{provider_type}/{version}/{os}/{arch}",
+            put(publish).layer(DefaultBodyLimit::max(MAX_PROVIDER_PACKAGE_BYTES)),
+        )
         .with_state(state)
 }
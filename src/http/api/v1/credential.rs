@@ -1,31 +1,84 @@
 use axum::{
-    extract::{Path, State},
+    extract::{rejection::JsonRejection, Path, State},
     Json,
 };
 use http::StatusCode;
 use serde_json::Value;
 
-use crate::{credhelper::CredentialHelper, http::api::APIState};
+use crate::{
+    credhelper::{CredentialHelper, Token},
+    http::api::APIState,
+};
 
 #[derive(Debug, serde::Deserialize)]
 pub(crate) struct UpdateRequest {
     pub(crate) data: UpdateData,
 }
 
+/// A token of a given type, or a bearer token when no type is given.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub(crate) enum UpdateData {
+    Typed(TypedUpdateData),
+    Bearer(BearerUpdateData),
+}
+
+/// Untyped bearer token, as accepted before token types were added.
+///
+/// Unknown fields are denied so a request with an invalid `type` is rejected,
+/// rather than stored as a bearer token.
 #[derive(Debug, serde::Deserialize)]
-pub(crate) struct UpdateData {
+#[serde(deny_unknown_fields)]
+pub(crate) struct BearerUpdateData {
     pub(crate) token: String,
 }
 
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum TypedUpdateData {
+    Bearer { token: String },
+    Basic { username: String, password: String },
+    Header { name: String, value: String },
+}
+
+impl From<UpdateData> for Token {
+    fn from(data: UpdateData) -> Self {
+        match data {
+            UpdateData::Bearer(BearerUpdateData { token })
+            | UpdateData::Typed(TypedUpdateData::Bearer { token }) => Token::Bearer(token),
+            UpdateData::Typed(TypedUpdateData::Basic { username, password }) => {
+                Token::Basic { username, password }
+            }
+            UpdateData::Typed(TypedUpdateData::Header { name, value }) => {
+                Token::Header { name, value }
+            }
+        }
+    }
+}
+
 pub(crate) async fn update<C: CredentialHelper>(
     State(APIState {
         mut credentials, ..
     }): State<APIState<C>>,
     Path(hostname): Path<String>,
-    Json(UpdateRequest {
-        data: UpdateData { token: value },
-    }): Json<UpdateRequest>,
+    request: Result<Json<UpdateRequest>, JsonRejection>,
 ) -> (StatusCode, Json<Value>) {
+    let data = match request {
+        Ok(Json(UpdateRequest { data })) => data,
+        Err(rejection) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": { "msg": rejection.body_text() } })),
+            )
+        }
+    };
+    let value = Token::from(data);
+    if let Err(e) = value.validate() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": { "msg": e.to_string() } })),
+        );
+    }
     match credentials.store(hostname, value).await {
         Ok(_) => (StatusCode::OK, Json(serde_json::json!({ "data": {} }))),
         Err(e) => {
@@ -42,10 +95,24 @@ pub(crate) async fn update<C: CredentialHelper>(
 
 pub(crate) async fn delete<C: CredentialHelper>(
     State(APIState {
-        mut credentials, ..
+        mut credentials,
+        login,
+        ..
     }): State<APIState<C>>,
     Path(hostname): Path<String>,
 ) -> (StatusCode, Json<Value>) {
+    // Forget the login first, so its access token is not refreshed back into place
+    if let Some(login) = login {
+        if let Err(e) = login.forget(&hostname).await {
+            tracing::error!(reason=?e, "Error occurred deleting registry login");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(
+                    serde_json::json!({ "error": { "msg": "Error occurred deleting credential" } }),
+                ),
+            );
+        }
+    }
     match credentials.forget(&hostname).await {
         Ok(_) => (StatusCode::OK, Json(serde_json::json!({ "data": {} }))),
         Err(e) => {
@@ -87,6 +154,30 @@ pub(crate) async fn exists<C: CredentialHelper>(
     }
 }
 
+/// Lists the stored credentials with their metadata, never the tokens themselves.
+pub(crate) async fn list<C: CredentialHelper>(
+    State(APIState { credentials, .. }): State<APIState<C>>,
+) -> (StatusCode, Json<Value>) {
+    match credentials.list().await {
+        Ok(mut credentials) => {
+            credentials.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+            (
+                StatusCode::OK,
+                Json(serde_json::json!({ "data": credentials })),
+            )
+        }
+        Err(e) => {
+            tracing::error!(reason=?e, "Error occurred listing credentials");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(
+                    serde_json::json!({ "error": { "msg": "Error occurred listing credentials" } }),
+                ),
+            )
+        }
+    }
+}
+
 // Testing the update and delete of the credentials
 #[cfg(test)]
 mod tests {
@@ -105,6 +196,8 @@ mod tests {
         let credentials = MemoryCredentials::default();
         let state = APIState {
             credentials: credentials.clone(),
+            publisher: None,
+            login: None,
         };
         let request = axum::http::Request::builder()
             .method("POST")
@@ -128,6 +221,83 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_update_typed_credential_for_hostname() {
+        let credentials = MemoryCredentials::default();
+        let state = APIState {
+            credentials: credentials.clone(),
+            publisher: None,
+            login: None,
+        };
+        for (hostname, body, expected) in [
+            (
+                "bearer.example.com",
+                r#"{ "data": { "type": "bearer", "token": "password1" } }"#,
+                Token::Bearer("password1".into()),
+            ),
+            (
+                "basic.example.com",
+                r#"{ "data": { "type": "basic", "username": "user", "password": "password1" } }"#,
+                Token::Basic {
+                    username: "user".into(),
+                    password: "password1".into(),
+                },
+            ),
+            (
+                "header.example.com",
+                r#"{ "data": { "type": "header", "name": "X-JFrog-Art-Api", "value": "password1" } }"#,
+                Token::Header {
+                    name: "X-JFrog-Art-Api".into(),
+                    value: "password1".into(),
+                },
+            ),
+        ] {
+            let request = axum::http::Request::builder()
+                .method("POST")
+                .uri(format!("/api/v1/credentials/{hostname}"))
+                .header("content-type", "application/json")
+                .body(body.to_string())
+                .unwrap();
+            let response = routes(state.clone()).oneshot(request).await.unwrap();
+            assert!(response.status().is_success());
+            assert_eq!(
+                credentials.get(hostname).await.unwrap(),
+                Credential::Entry(Some(expected))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_invalid_credential_for_hostname() {
+        let credentials = MemoryCredentials::default();
+        let state = APIState {
+            credentials: credentials.clone(),
+            publisher: None,
+            login: None,
+        };
+        for body in [
+            r#"{ "data": { "type": "header", "name": "X Bad Header", "value": "password1" } }"#,
+            r#"{ "data": { "type": "basic", "username": "us:er", "password": "password1" } }"#,
+            r#"{ "data": { "type": "digest", "token": "password1" } }"#,
+            r#"{ "data": { "type": "basic", "token": "password1" } }"#,
+            r#"{ "data": { "type": "Bearer", "token": "password1" } }"#,
+            r#"{ "data": {} }"#,
+        ] {
+            let request = axum::http::Request::builder()
+                .method("POST")
+                .uri("/api/v1/credentials/example.com")
+                .header("content-type", "application/json")
+                .body(body.to_string())
+                .unwrap();
+            let response = routes(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{body}");
+        }
+        assert_eq!(
+            credentials.get("example.com").await.unwrap(),
+            Credential::NotFound
+        );
+    }
+
     #[tokio::test]
     async fn test_delete_credential_for_hostname() {
         let mut credentials = MemoryCredentials::default();
@@ -138,6 +308,8 @@ mod tests {
 
         let state = APIState {
             credentials: credentials.clone(),
+            publisher: None,
+            login: None,
         };
         let request = axum::http::Request::builder()
             .method("DELETE")
@@ -163,6 +335,8 @@ mod tests {
 
         let state = APIState {
             credentials: credentials.clone(),
+            publisher: None,
+            login: None,
         };
         let request = axum::http::Request::builder()
             .method("GET")
@@ -178,6 +352,8 @@ mod tests {
         let credentials = MemoryCredentials::default();
         let state = APIState {
             credentials: credentials.clone(),
+            publisher: None,
+            login: None,
         };
         let request = axum::http::Request::builder()
             .method("GET")
@@ -188,12 +364,61 @@ mod tests {
         assert!(response.status().is_client_error());
     }
 
+    #[tokio::test]
+    async fn test_list_credentials() {
+        let mut credentials = MemoryCredentials::default();
+        for hostname in ["b.example.com", "a.example.com"] {
+            credentials
+                .store(hostname.into(), "password1".into())
+                .await
+                .unwrap();
+        }
+        let state = APIState {
+            credentials,
+            publisher: None,
+            login: None,
+        };
+        let request = axum::http::Request::builder()
+            .method("GET")
+            .uri("/api/v1/credentials")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = routes(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(!String::from_utf8_lossy(&body).contains("password1"));
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "data": [
+                {
+                    "hostname": "a.example.com",
+                    "created_at": null,
+                    "updated_at": null,
+                    "last_used_at": null,
+                    "last_auth_failure": null,
+                },
+                {
+                    "hostname": "b.example.com",
+                    "created_at": null,
+                    "updated_at": null,
+                    "last_used_at": null,
+                    "last_auth_failure": null,
+                },
+            ]})
+        );
+    }
+
     /// Test error handling of updates when error occurs
     #[tokio::test]
     async fn test_error_occur_on_insert() {
         let credentials = FaultyCredentials::new();
         let state = APIState {
-            credentials: credentials,
+            credentials,
+            publisher: None,
+            login: None,
         };
         let request = axum::http::Request::builder()
             .method("POST")
@@ -216,7 +441,9 @@ mod tests {
     async fn test_error_occur_on_delete() {
         let credentials = FaultyCredentials::new();
         let state = APIState {
-            credentials: credentials,
+            credentials,
+            publisher: None,
+            login: None,
         };
         let request = axum::http::Request::builder()
             .method("DELETE")
@@ -231,7 +458,9 @@ mod tests {
     async fn test_error_occur_on_get() {
         let credentials = FaultyCredentials::new();
         let state = APIState {
-            credentials: credentials,
+            credentials,
+            publisher: None,
+            login: None,
         };
         let request = axum::http::Request::builder()
             .method("GET")
@@ -241,4 +470,21 @@ mod tests {
         let response = routes(state).oneshot(request).await.unwrap();
         assert!(response.status() == StatusCode::INTERNAL_SERVER_ERROR);
     }
+
+    #[tokio::test]
+    async fn test_error_occur_on_list() {
+        let credentials = FaultyCredentials::new();
+        let state = APIState {
+            credentials,
+            publisher: None,
+            login: None,
+        };
+        let request = axum::http::Request::builder()
+            .method("GET")
+            .uri("/api/v1/credentials")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = routes(state).oneshot(request).await.unwrap();
+        assert!(response.status() == StatusCode::INTERNAL_SERVER_ERROR);
+    }
 }
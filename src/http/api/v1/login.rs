@@ -0,0 +1,581 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use http::StatusCode;
+use serde_json::Value;
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use crate::{
+    credhelper::{
+        encryption::{is_encrypted, TokenCipher},
+        CredentialHelper, Token, UpstreamCredentials,
+    },
+    error::TerrashineError,
+    http::{api::APIState, modules::upstream_status},
+    registry::{random_secret, LoginToken, RegistryClient},
+};
+
+/// Time an admin has to complete a login after starting it.
+const LOGIN_REQUEST_TTL_SECONDS: f64 = 600.0;
+/// Access tokens are refreshed this long before they expire.
+const REFRESH_MARGIN_SECONDS: f64 = 300.0;
+/// How often access tokens are checked for refreshing.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything required to log in to registries with the `login.v1` protocol.
+#[derive(Clone)]
+pub(crate) struct RegistryLogin {
+    pub(crate) db: PgPool,
+    pub(crate) registry: RegistryClient<UpstreamCredentials>,
+    /// Refresh tokens are stored in plaintext when None
+    pub(crate) cipher: Option<TokenCipher>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum LoginError {
+    #[error(
+        "Redirect URI must be http://localhost:{{port}}/login with a port allowed by the registry"
+    )]
+    InvalidRedirect,
+    #[error("Unknown or expired login state")]
+    UnknownState,
+    #[error(transparent)]
+    Upstream(#[from] TerrashineError),
+}
+
+impl From<sqlx::Error> for LoginError {
+    fn from(error: sqlx::Error) -> Self {
+        LoginError::Upstream(error.into())
+    }
+}
+
+impl From<anyhow::Error> for LoginError {
+    fn from(error: anyhow::Error) -> Self {
+        LoginError::Upstream(error.into())
+    }
+}
+
+impl LoginError {
+    fn status(&self) -> StatusCode {
+        match self {
+            LoginError::InvalidRedirect | LoginError::UnknownState => StatusCode::BAD_REQUEST,
+            LoginError::Upstream(TerrashineError::TerraformServiceNotSupported { .. }) => {
+                StatusCode::NOT_FOUND
+            }
+            LoginError::Upstream(TerrashineError::Anyhow { .. }) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            LoginError::Upstream(error) => upstream_status(error),
+        }
+    }
+}
+
+/// Refresh tokens are bound to a different name than registry tokens when encrypted,
+/// so one cannot be swapped for the other.
+fn refresh_token_aad(hostname: &str) -> String {
+    format!("{hostname}#refresh_token")
+}
+
+impl RegistryLogin {
+    /// Starts a login, returning the URL the user authorizes terrashine at and the OAuth state.
+    pub(crate) async fn start(
+        &self,
+        hostname: &str,
+        redirect_uri: &Url,
+    ) -> Result<(Url, String), LoginError> {
+        let hostname = &hostname.to_ascii_lowercase();
+        let service = self.registry.login_service(hostname).await?;
+        if !service.allows_redirect(redirect_uri) {
+            return Err(LoginError::InvalidRedirect);
+        }
+        let state = random_secret();
+        let code_verifier = random_secret();
+        let mut tx = self.db.begin().await?;
+        sqlx::query!(
+            r#"
+            delete from "registry_login_request"
+            where "created_at" < now() - make_interval(secs => $1);
+            "#,
+            LOGIN_REQUEST_TTL_SECONDS,
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            insert into "registry_login_request" ("state", "hostname", "code_verifier", "redirect_uri")
+            values ($1, $2, $3, $4);
+            "#,
+            state,
+            hostname,
+            code_verifier,
+            redirect_uri.as_str(),
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        tracing::info!(%hostname, "Started registry login");
+        Ok((
+            service.authorization_url(redirect_uri, &state, &code_verifier),
+            state,
+        ))
+    }
+
+    /// Exchanges the authorization code of a started login for tokens, storing the access
+    /// token as the credential for the hostname.
+    pub(crate) async fn complete<C: CredentialHelper>(
+        &self,
+        credentials: &mut C,
+        hostname: &str,
+        state: &str,
+        code: &str,
+    ) -> Result<LoginToken, LoginError> {
+        let hostname = &hostname.to_ascii_lowercase();
+        let request = sqlx::query!(
+            r#"
+            delete from "registry_login_request"
+            where "state" = $1
+                and "hostname" = $2
+                and "created_at" >= now() - make_interval(secs => $3)
+            returning "code_verifier", "redirect_uri";
+            "#,
+            state,
+            hostname,
+            LOGIN_REQUEST_TTL_SECONDS,
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(LoginError::UnknownState)?;
+        let service = self.registry.login_service(hostname).await?;
+        let token = self
+            .registry
+            .login_token(
+                &service.token_url,
+                &[
+                    ("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", &request.redirect_uri),
+                    ("client_id", &service.client_id),
+                    ("code_verifier", &request.code_verifier),
+                ],
+            )
+            .await?;
+        credentials
+            .store(
+                hostname.to_string(),
+                Token::Bearer(token.access_token.clone()),
+            )
+            .await?;
+        let refresh_token = self.encrypt_refresh_token(hostname, token.refresh_token.as_deref())?;
+        sqlx::query!(
+            r#"
+            insert into "registry_login" ("hostname", "client_id", "token_url", "refresh_token", "expires_at")
+            values ($1, $2, $3, $4, now() + make_interval(secs => $5))
+            on conflict ("hostname") do update set
+                "client_id" = "excluded"."client_id",
+                "token_url" = "excluded"."token_url",
+                "refresh_token" = "excluded"."refresh_token",
+                "expires_at" = "excluded"."expires_at";
+            "#,
+            hostname,
+            service.client_id,
+            service.token_url.as_str(),
+            refresh_token,
+            token.expires_in.map(|seconds| seconds as f64),
+        )
+        .execute(&self.db)
+        .await?;
+        tracing::info!(%hostname, refreshable = token.refresh_token.is_some(), "Completed registry login");
+        Ok(token)
+    }
+
+    /// Forgets the refresh token of a hostname, so its credential is not stored again.
+    ///
+    /// Logins stored before hostnames were lowercased are matched regardless of case.
+    pub(crate) async fn forget(&self, hostname: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            delete from "registry_login" where lower("hostname") = $1;
+            "#,
+            hostname.to_ascii_lowercase(),
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    fn encrypt_refresh_token(
+        &self,
+        hostname: &str,
+        refresh_token: Option<&str>,
+    ) -> Result<Option<String>, anyhow::Error> {
+        match (&self.cipher, refresh_token) {
+            (Some(cipher), Some(token)) => cipher
+                .encrypt(&refresh_token_aad(hostname), token)
+                .map(Some),
+            (_, token) => Ok(token.map(str::to_string)),
+        }
+    }
+
+    fn decrypt_refresh_token(
+        &self,
+        hostname: &str,
+        stored: String,
+    ) -> Result<String, anyhow::Error> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&refresh_token_aad(hostname), &stored),
+            None if is_encrypted(&stored) => Err(anyhow::anyhow!(
+                "Refresh token for {hostname} is encrypted but no token encryption keys are configured"
+            )),
+            None => Ok(stored),
+        }
+    }
+
+    /// Refreshes access tokens that are about to expire.
+    ///
+    /// Each login is refreshed on its own, so a login that cannot be refreshed does
+    /// not hold back the others.
+    ///
+    /// Returns the number of access tokens refreshed.
+    pub(crate) async fn refresh<C: CredentialHelper>(
+        &self,
+        credentials: &mut C,
+    ) -> Result<u64, anyhow::Error> {
+        let hostnames = sqlx::query_scalar!(
+            r#"
+            select "hostname" from "registry_login"
+            where "refresh_token" is not null
+                and "expires_at" < now() + make_interval(secs => $1);
+            "#,
+            REFRESH_MARGIN_SECONDS,
+        )
+        .fetch_all(&self.db)
+        .await?;
+        let mut refreshed = 0;
+        for hostname in hostnames {
+            match self.refresh_login(credentials, &hostname).await {
+                Ok(true) => refreshed += 1,
+                Ok(false) => {}
+                Err(error) => {
+                    tracing::error!(%hostname, reason = ?error, "Could not refresh registry access token");
+                }
+            }
+        }
+        Ok(refreshed)
+    }
+
+    /// Refreshes the access token of a single login, returning whether it was refreshed.
+    ///
+    /// The login is locked while it is refreshed, as registries may revoke a refresh
+    /// token once it is used so only one instance may use it. The rotated refresh token
+    /// is committed before the access token is stored, so it is never lost.
+    async fn refresh_login<C: CredentialHelper>(
+        &self,
+        credentials: &mut C,
+        hostname: &str,
+    ) -> Result<bool, anyhow::Error> {
+        let mut tx = self.db.begin().await?;
+        let Some(row) = sqlx::query!(
+            r#"
+            select "client_id", "token_url", "refresh_token" as "refresh_token!"
+            from "registry_login"
+            where "hostname" = $1
+                and "refresh_token" is not null
+                and "expires_at" < now() + make_interval(secs => $2)
+            for update skip locked;
+            "#,
+            hostname,
+            REFRESH_MARGIN_SECONDS,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            // Refreshed or being refreshed by another instance
+            return Ok(false);
+        };
+        let refresh_token = self.decrypt_refresh_token(hostname, row.refresh_token)?;
+        let token_url = Url::parse(&row.token_url)?;
+        let token = self
+            .registry
+            .login_token(
+                &token_url,
+                &[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &refresh_token),
+                    ("client_id", &row.client_id),
+                ],
+            )
+            .await;
+        let token = match token {
+            Ok(token) => token,
+            Err(TerrashineError::ProviderResponseFailure { source })
+                if source.status().is_some_and(|s| s.is_client_error()) =>
+            {
+                tracing::error!(%hostname, reason = %source, "Refresh token was rejected, log in to the registry again");
+                sqlx::query!(
+                    r#"
+                    update "registry_login" set "refresh_token" = null where "hostname" = $1;
+                    "#,
+                    hostname,
+                )
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                return Ok(false);
+            }
+            Err(error) => {
+                tracing::warn!(%hostname, reason = ?error, "Could not refresh access token");
+                return Ok(false);
+            }
+        };
+
+        let rotated = self.encrypt_refresh_token(hostname, token.refresh_token.as_deref())?;
+        sqlx::query!(
+            r#"
+            update "registry_login"
+            set "refresh_token" = coalesce($2, "refresh_token"),
+                "expires_at" = now() + make_interval(secs => $3)
+            where "hostname" = $1;
+            "#,
+            hostname,
+            rotated,
+            token.expires_in.map(|seconds| seconds as f64),
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        if let Err(error) = credentials
+            .store(hostname.to_string(), Token::Bearer(token.access_token))
+            .await
+        {
+            // Refresh again on the next run to obtain an access token that can be stored
+            sqlx::query!(
+                r#"
+                update "registry_login" set "expires_at" = now() where "hostname" = $1;
+                "#,
+                hostname,
+            )
+            .execute(&self.db)
+            .await?;
+            return Err(error);
+        }
+        tracing::info!(%hostname, "Refreshed registry access token");
+        Ok(true)
+    }
+}
+
+/// Periodically refreshes access tokens obtained by logging in to registries.
+pub(crate) async fn login_refresher(
+    login: RegistryLogin,
+    mut credentials: UpstreamCredentials,
+    cancel: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(error) = login.refresh(&mut credentials).await {
+                    tracing::error!(reason = ?error, "Error occurred refreshing registry access tokens");
+                }
+            }
+            _ = cancel.cancelled() => break,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct StartRequest {
+    pub(crate) data: StartData,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct StartData {
+    pub(crate) redirect_uri: Url,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct CompleteRequest {
+    pub(crate) data: CompleteData,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct CompleteData {
+    pub(crate) state: String,
+    pub(crate) code: String,
+}
+
+fn error_response(status: StatusCode, msg: &str) -> (StatusCode, Json<Value>) {
+    (status, Json(serde_json::json!({ "error": { "msg": msg } })))
+}
+
+fn login_error_response(error: LoginError, msg: &str) -> (StatusCode, Json<Value>) {
+    let status = error.status();
+    if status.is_server_error() {
+        tracing::error!(reason = ?error, "{msg}");
+        return error_response(status, msg);
+    }
+    error_response(status, &error.to_string())
+}
+
+/// Describes the login service of a registry, including the redirect ports it allows.
+pub(crate) async fn service<C>(
+    State(APIState { login, .. }): State<APIState<C>>,
+    Path(hostname): Path<String>,
+) -> (StatusCode, Json<Value>) {
+    let Some(login) = login else {
+        return error_response(StatusCode::NOT_FOUND, "Registry login is not enabled");
+    };
+    match login.registry.login_service(&hostname).await {
+        Ok(service) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "data": {
+                "client": service.client_id,
+                "ports": [service.ports.0, service.ports.1],
+                "scopes": service.scopes,
+            } })),
+        ),
+        Err(e) => login_error_response(e.into(), "Error occurred discovering login service"),
+    }
+}
+
+pub(crate) async fn start<C>(
+    State(APIState { login, .. }): State<APIState<C>>,
+    Path(hostname): Path<String>,
+    Json(StartRequest {
+        data: StartData { redirect_uri },
+    }): Json<StartRequest>,
+) -> (StatusCode, Json<Value>) {
+    let Some(login) = login else {
+        return error_response(StatusCode::NOT_FOUND, "Registry login is not enabled");
+    };
+    match login.start(&hostname, &redirect_uri).await {
+        Ok((authorization_url, state)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "data": {
+                "authorization_url": authorization_url,
+                "state": state,
+            } })),
+        ),
+        Err(e) => login_error_response(e, "Error occurred starting login"),
+    }
+}
+
+pub(crate) async fn complete<C: CredentialHelper>(
+    State(APIState {
+        mut credentials,
+        login,
+        ..
+    }): State<APIState<C>>,
+    Path(hostname): Path<String>,
+    Json(CompleteRequest {
+        data: CompleteData { state, code },
+    }): Json<CompleteRequest>,
+) -> (StatusCode, Json<Value>) {
+    let Some(login) = login else {
+        return error_response(StatusCode::NOT_FOUND, "Registry login is not enabled");
+    };
+    match login
+        .complete(&mut credentials, &hostname, &state, &code)
+        .await
+    {
+        Ok(token) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "data": {
+                "expires_in": token.expires_in,
+                "refreshable": token.refresh_token.is_some(),
+            } })),
+        ),
+        Err(e) => login_error_response(e, "Error occurred completing login"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        credhelper::{
+            chained::ChainedCredentials, database::DatabaseCredentials, memory::MemoryCredentials,
+            terraform::TerraformCredentials,
+        },
+        http::api::routes,
+        registry::{DownloadPolicy, HttpSettings, UpstreamRoutes},
+    };
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_login_not_enabled() {
+        let state = APIState {
+            credentials: MemoryCredentials::default(),
+            publisher: None,
+            login: None,
+        };
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri("/api/v1/logins/example.com")
+            .header("content-type", "application/json")
+            .body(r#"{ "data": { "redirect_uri": "http://localhost:10000/login" } }"#.to_string())
+            .unwrap();
+        let response = routes(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_login_error_status() {
+        assert_eq!(LoginError::UnknownState.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            LoginError::InvalidRedirect.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            LoginError::Upstream(TerrashineError::TerraformServiceNotSupported {
+                service_type: "login",
+                hostname: "example.com".into(),
+            })
+            .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_forget_ignores_case(db: PgPool) {
+        let routes = UpstreamRoutes::new(
+            443,
+            HttpSettings::default(),
+            HashMap::new(),
+            None,
+            DownloadPolicy::default(),
+        );
+        let credentials = ChainedCredentials::new(
+            DatabaseCredentials::new(db.clone()),
+            ChainedCredentials::new(TerraformCredentials::default(), None),
+        );
+        let login = RegistryLogin {
+            db: db.clone(),
+            registry: RegistryClient::new(routes, vec![], credentials),
+            cipher: None,
+        };
+        for hostname in ["registry.example.com", "Legacy.Example.com"] {
+            sqlx::query(
+                r#"insert into "registry_login" ("hostname", "client_id", "token_url") values ($1, 'terraform-cli', 'https://example.com/token')"#,
+            )
+            .bind(hostname)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+
+        login.forget("Registry.Example.com").await.unwrap();
+        login.forget("legacy.example.com").await.unwrap();
+        let remaining: i64 = sqlx::query_scalar(r#"select count(*) from "registry_login""#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+}
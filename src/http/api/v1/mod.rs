@@ -1 +1,3 @@
 pub(crate) mod credential;
+pub(crate) mod login;
+pub(crate) mod provider;
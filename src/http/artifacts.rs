@@ -1,7 +1,15 @@
 use crate::{
     app::AppState,
     credhelper::CredentialHelper,
-    registry::{ProviderResponse, RegistryClient},
+    error::TerrashineError,
+    http::{
+        modules::upstream_status,
+        offline::{refuse_cache_miss, CacheMiss},
+        platforms::AllowedPlatforms,
+    },
+    refresh::TerraformProvider,
+    registry::RegistryClient,
+    webhook::Event,
 };
 use anyhow::Context;
 use aws_sdk_s3::{
@@ -14,23 +22,23 @@ use axum::{
     extract::{Path, State},
     response::{IntoResponse, Response},
 };
-use futures::{future::TryFutureExt, StreamExt};
+use futures::StreamExt;
 use http::{HeaderValue, StatusCode, Uri};
-use reqwest::Client;
+use sha2::{Digest, Sha256};
 use sqlx::{query_as, PgPool};
 use std::{pin::Pin, time::Duration};
-use tokio::try_join;
+use tokio::join;
 use tokio_stream::Stream;
 
 const PREALLOCATED_BUFFER_BYTES: usize = 12_582_912;
 const S3_MINIMUM_UPLOAD_CHUNK_BYTES: usize = 10_485_760;
 
-struct ArtifactResponse {
+pub(crate) struct ArtifactResponse {
     uri: HeaderValue,
 }
 
 impl ArtifactResponse {
-    fn new(uri: Uri) -> Self {
+    pub(crate) fn new(uri: Uri) -> Self {
         ArtifactResponse {
             uri: HeaderValue::try_from(uri.to_string()).expect("URL not a valid header"),
         }
@@ -55,11 +63,11 @@ impl IntoResponse for ArtifactResponse {
 
 pub(crate) async fn artifacts_handler<C>(
     State(AppState {
-        http_client: http,
         registry_client: registry,
         db_client: db,
         s3_client: s3,
         config: args,
+        webhooks,
         ..
     }): State<AppState<C>>,
     Path(version_id): Path<i64>,
@@ -77,6 +85,22 @@ pub(crate) async fn artifacts_handler<C>(
         }
     };
     tracing::debug!(?artifact_detail, "Artifact details found in database");
+    let provider = TerraformProvider {
+        hostname: artifact_detail.hostname.clone(),
+        namespace: artifact_detail.namespace.clone(),
+        provider_type: artifact_detail.provider_type.clone(),
+    };
+    match AllowedPlatforms::for_request(&db, &args, &provider).await {
+        Ok(platforms) if platforms.contains(&artifact_detail.os, &artifact_detail.arch) => {}
+        Ok(_) => {
+            tracing::debug!(?artifact_detail, "Platform requested is not allowed");
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Err(e) => {
+            tracing::error!(reason=?e, "Error querying database for allowed platforms");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
     let artifact = match artifact_detail.artifact_id {
         Some(id) => {
             tracing::debug!("Artifact already downloaded");
@@ -89,20 +113,44 @@ pub(crate) async fn artifacts_handler<C>(
                 os: artifact_detail.os,
                 arch: artifact_detail.arch,
                 artifact_id: id,
+                source: None,
             }
         }
+        None if args.offline => {
+            tracing::debug!(?artifact_detail, "Artifact not cached");
+            refuse_cache_miss(CacheMiss::ProviderArtifact);
+            return Err(StatusCode::NOT_FOUND);
+        }
         None => {
             // Make upstream request and stash if artifact not stored.
             tracing::debug!("Fetching artifact from upstream");
-            let upstream_response = get_upstream(http, registry, &artifact_detail).map_err(|e| {
-                tracing::error!(reason = ?e, "Error occured fetching artifact upstream");
-                StatusCode::BAD_GATEWAY
-            });
-            let response_id = allocate_artifact_id(&db).map_err(|e| {
+            let (response_id, upstream_response) = join!(
+                allocate_artifact_id(&db),
+                get_upstream(registry, &artifact_detail)
+            );
+            let (source, shasum, body) = match upstream_response {
+                Ok(x) => x,
+                Err(e) => {
+                    tracing::error!(reason = ?e, "Error occured fetching artifact upstream");
+                    webhooks
+                        .emit(Event::UpstreamFailure {
+                            hostname: artifact_detail.hostname,
+                            namespace: artifact_detail.namespace,
+                            provider_type: artifact_detail.provider_type,
+                            version: Some(artifact_detail.version),
+                            reason: format!("{e:#}"),
+                        })
+                        .await;
+                    return Err(match e.downcast_ref::<TerrashineError>() {
+                        Some(error) => upstream_status(error),
+                        None => StatusCode::BAD_GATEWAY,
+                    });
+                }
+            };
+            let id = response_id.map_err(|e| {
                 tracing::error!(reason = ?e, "Error occured allocating artifact id from database");
                 StatusCode::INTERNAL_SERVER_ERROR
-            });
-            let (id, body) = try_join!(response_id, upstream_response)?;
+            })?;
             let artifact = Artifact {
                 version_id: artifact_detail.version_id,
                 hostname: artifact_detail.hostname,
@@ -112,24 +160,40 @@ pub(crate) async fn artifacts_handler<C>(
                 os: artifact_detail.os,
                 arch: artifact_detail.arch,
                 artifact_id: id,
+                source: Some(source),
             };
-            stash_artifact(
+            let stash_result = stash_artifact(
                 &db,
                 &s3,
                 &args.s3_bucket_name,
                 &args.s3_bucket_prefix,
                 &artifact,
+                &shasum,
                 body,
             )
-            .await
-            .map_err(|e| {
-                tracing::error!(reason = ?e, "Error occurred stashing artifact");
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?;
+            .await;
+            match stash_result {
+                Ok(()) => {
+                    webhooks.emit(artifact.cached_event()).await;
+                }
+                Err(e) => {
+                    tracing::error!(reason = ?e, "Error occurred stashing artifact");
+                    return match e.downcast::<TerrashineError>() {
+                        Ok(TerrashineError::ArtifactChecksumMismatch { expected, actual }) => {
+                            webhooks
+                                .emit(artifact.integrity_failure_event(expected, actual))
+                                .await;
+                            Err(StatusCode::BAD_GATEWAY)
+                        }
+                        _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
+                    };
+                }
+            }
             artifact
         }
     };
-    let req = presign_request(&s3, &args.s3_bucket_name, &args.s3_bucket_prefix, &artifact)
+    let key = artifact.to_s3_key(&args.s3_bucket_prefix);
+    let req = presign_artifact(&s3, &args.s3_bucket_name, &key)
         .await
         .map_err(|e| {
             tracing::error!(reason = ?e, "Error presigning url");
@@ -162,17 +226,51 @@ struct Artifact {
     os: String,
     arch: String,
     artifact_id: i64,
+    /// Upstream source the artifact was fetched from, if fetched by this request
+    source: Option<String>,
 }
 
 impl Artifact {
+    fn cached_event(&self) -> Event {
+        Event::ArtifactCached {
+            hostname: self.hostname.clone(),
+            namespace: self.namespace.clone(),
+            provider_type: self.provider_type.clone(),
+            version: self.version.clone(),
+            os: self.os.clone(),
+            arch: self.arch.clone(),
+        }
+    }
+
+    fn integrity_failure_event(&self, expected_shasum: String, actual_shasum: String) -> Event {
+        Event::IntegrityFailure {
+            hostname: self.hostname.clone(),
+            namespace: self.namespace.clone(),
+            provider_type: self.provider_type.clone(),
+            version: self.version.clone(),
+            os: self.os.clone(),
+            arch: self.arch.clone(),
+            expected_shasum,
+            actual_shasum,
+        }
+    }
+
     fn to_s3_key(&self, prefix: &str) -> String {
-        let mut key = String::from(prefix);
-        key.push_str("artifacts/");
-        key.push_str(&self.artifact_id.to_string());
-        key
+        artifact_s3_key(prefix, self.artifact_id)
     }
 }
 
+/// Object key of an artifact in the artifact store.
+///
+/// Artifact ids are allocated from a single sequence so keys are unique across
+/// every kind of cached artifact.
+pub(crate) fn artifact_s3_key(prefix: &str, artifact_id: i64) -> String {
+    let mut key = String::from(prefix);
+    key.push_str("artifacts/");
+    key.push_str(&artifact_id.to_string());
+    key
+}
+
 async fn get_artifact_from_database(
     db: &PgPool,
     version_id: i64,
@@ -202,27 +300,32 @@ async fn get_artifact_from_database(
 }
 
 async fn get_upstream<T: CredentialHelper>(
-    http: Client,
     registry: RegistryClient<T>,
     artifact: &ArtifactDetails,
-) -> Result<Pin<Box<impl Stream<Item = reqwest::Result<Bytes>>>>, anyhow::Error> {
-    let provider_path = format!(
-        "{}/{}/{}/download/{}/{}",
-        artifact.namespace, artifact.provider_type, artifact.version, artifact.os, artifact.arch
-    );
-    let provider: ProviderResponse = registry
-        .provider_get(&artifact.hostname, &provider_path)
+) -> Result<
+    (
+        String,
+        String,
+        Pin<Box<impl Stream<Item = reqwest::Result<Bytes>>>>,
+    ),
+    anyhow::Error,
+> {
+    let download = registry
+        .provider_download(
+            &artifact.hostname,
+            &artifact.namespace,
+            &artifact.provider_type,
+            &artifact.version,
+            &artifact.os,
+            &artifact.arch,
+        )
         .await?;
-    let stream = http
-        .get(provider.download_url)
-        .send()
-        .await?
-        .error_for_status()?
-        .bytes_stream();
-    Ok(Box::pin(stream))
+    tracing::debug!(source = %download.source, "Downloading artifact from upstream source");
+    let stream = download.response.bytes_stream();
+    Ok((download.source, download.shasum, Box::pin(stream)))
 }
 
-async fn allocate_artifact_id(db: &PgPool) -> Result<i64, anyhow::Error> {
+pub(crate) async fn allocate_artifact_id(db: &PgPool) -> Result<i64, anyhow::Error> {
     sqlx::query!(
         r#"
             select nextval('artifact_ids') as "id!";
@@ -240,10 +343,27 @@ async fn stash_artifact(
     bucket_name: &str,
     bucket_prefix: &str,
     artifact: &Artifact,
-    mut stream: Pin<Box<impl Stream<Item = reqwest::Result<Bytes>>>>,
+    expected_shasum: &str,
+    stream: Pin<Box<impl Stream<Item = reqwest::Result<Bytes>>>>,
 ) -> Result<(), anyhow::Error> {
     let key = artifact.to_s3_key(bucket_prefix);
-    let req = s3.create_multipart_upload().bucket(bucket_name).key(&key);
+    upload_artifact(s3, bucket_name, &key, Some(expected_shasum), stream).await?;
+    store_artifact_in_database(db, artifact, expected_shasum).await?;
+    Ok(())
+}
+
+/// Streams an artifact into the artifact store using a multipart upload.
+///
+/// If an expected sha256 checksum is provided, the upload is aborted when the
+/// streamed content does not match it.
+pub(crate) async fn upload_artifact(
+    s3: &aws_sdk_s3::Client,
+    bucket_name: &str,
+    key: &str,
+    expected_shasum: Option<&str>,
+    mut stream: Pin<Box<impl Stream<Item = reqwest::Result<Bytes>>>>,
+) -> Result<(), anyhow::Error> {
+    let req = s3.create_multipart_upload().bucket(bucket_name).key(key);
     let multipart_upload = req.send().await?;
     let upload_id = multipart_upload
         .upload_id()
@@ -251,10 +371,12 @@ async fn stash_artifact(
     let mut upload_buffer = Vec::with_capacity(PREALLOCATED_BUFFER_BYTES);
     let mut upload_parts = Vec::new();
     let mut part_number = 1;
+    let mut hasher = Sha256::new();
 
     loop {
         match stream.next().await {
             Some(Ok(chunk)) => {
+                hasher.update(&chunk);
                 upload_buffer.extend_from_slice(&chunk.slice(..));
                 if upload_buffer.len() < S3_MINIMUM_UPLOAD_CHUNK_BYTES {
                     continue;
@@ -263,7 +385,7 @@ async fn stash_artifact(
                 tracing::debug!(?part_number, ?key, ?upload_id, size = ?upload_buffer.len(), "Uploading S3 part");
                 let upload_part = s3
                     .upload_part()
-                    .key(&key)
+                    .key(key)
                     .bucket(bucket_name)
                     .upload_id(upload_id)
                     .body(ByteStream::from(Bytes::from(upload_buffer)))
@@ -288,7 +410,7 @@ async fn stash_artifact(
                 // Cleanup aborted upload before returning error
                 let abort_response = s3
                     .abort_multipart_upload()
-                    .key(&key)
+                    .key(key)
                     .bucket(bucket_name)
                     .upload_id(upload_id)
                     .send()
@@ -309,7 +431,7 @@ async fn stash_artifact(
         tracing::debug!(?part_number, ?key, ?upload_id, size = ?upload_buffer.len(), "Uploading s3 part");
         let upload_part = s3
             .upload_part()
-            .key(&key)
+            .key(key)
             .bucket(bucket_name)
             .upload_id(upload_id)
             .body(upload_buffer.into())
@@ -324,6 +446,25 @@ async fn stash_artifact(
                 .build(),
         );
     }
+    // Verify the package matches the checksum published by the registry before
+    // making it visible, a mismatch means the download is corrupt or tampered with.
+    let actual_shasum = hex::encode(hasher.finalize());
+    if let Some(expected_shasum) = expected_shasum {
+        if !actual_shasum.eq_ignore_ascii_case(expected_shasum) {
+            s3.abort_multipart_upload()
+                .key(key)
+                .bucket(bucket_name)
+                .upload_id(upload_id)
+                .send()
+                .await?;
+            return Err(TerrashineError::ArtifactChecksumMismatch {
+                expected: expected_shasum.to_string(),
+                actual: actual_shasum,
+            }
+            .into());
+        }
+    }
+
     // Finalize upload
     tracing::debug!(
         ?upload_parts,
@@ -336,27 +477,33 @@ async fn stash_artifact(
         .build();
     s3.complete_multipart_upload()
         .bucket(bucket_name)
-        .key(&key)
+        .key(key)
         .multipart_upload(completed_upload_request)
         .upload_id(upload_id)
         .send()
         .await?;
 
-    store_artifact_in_database(db, artifact).await?;
-
     Ok(())
 }
 
-async fn store_artifact_in_database(db: &PgPool, artifact: &Artifact) -> Result<(), anyhow::Error> {
+async fn store_artifact_in_database(
+    db: &PgPool,
+    artifact: &Artifact,
+    shasum: &str,
+) -> Result<(), anyhow::Error> {
     sqlx::query!(
         r#"
             update "terraform_provider_version"
             set "artifact_id" = $1,
-                "artifact_timestamp" = now()
+                "artifact_timestamp" = now(),
+                "artifact_source" = $3,
+                "artifact_shasum" = $4
                 where "id" = $2;
         "#,
         artifact.artifact_id,
         artifact.version_id,
+        artifact.source,
+        shasum,
     )
     .execute(db)
     .await
@@ -364,17 +511,16 @@ async fn store_artifact_in_database(db: &PgPool, artifact: &Artifact) -> Result<
     Ok(())
 }
 
-async fn presign_request(
+pub(crate) async fn presign_artifact(
     s3: &aws_sdk_s3::Client,
     bucket_name: &str,
-    bucket_prefix: &str,
-    artifact: &Artifact,
+    key: &str,
 ) -> Result<Uri, anyhow::Error> {
     let expires_in = Duration::from_secs(120);
     let presigned_request = s3
         .get_object()
         .bucket(bucket_name)
-        .key(artifact.to_s3_key(bucket_prefix))
+        .key(key)
         .presigned(PresigningConfig::expires_in(expires_in)?)
         .await?;
     Ok(presigned_request.uri().parse::<Uri>()?)
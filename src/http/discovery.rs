@@ -0,0 +1,134 @@
+use axum::Json;
+use http::{header::HOST, HeaderMap, Uri};
+use std::net::IpAddr;
+
+use crate::config::ServerArgs;
+
+use super::response_types::ServiceDiscovery;
+
+pub(crate) async fn discovery_handler() -> Json<ServiceDiscovery> {
+    Json(ServiceDiscovery {
+        modules_v1: "/modules/v1/",
+        providers_v1: "/providers/v1/",
+    })
+}
+
+/// Determines the upstream registry from the hostname the client used to reach terrashine.
+///
+/// X-Forwarded-Host is only honoured for requests from a trusted proxy, as any other client
+/// could use it to choose the upstream registry regardless of the hostname it connected to.
+/// Requests for terrashine's own hostname are served from the default registry.
+pub(crate) fn upstream_hostname(
+    headers: &HeaderMap,
+    uri: &Uri,
+    peer: IpAddr,
+    args: &ServerArgs,
+) -> String {
+    let forwarded = args
+        .trusted_proxies
+        .contains(&peer)
+        .then(|| headers.get("x-forwarded-host"))
+        .flatten();
+    let host = forwarded
+        .or_else(|| headers.get(HOST))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .and_then(|value| value.trim().parse::<http::uri::Authority>().ok())
+        .or_else(|| uri.authority().cloned())
+        .map(|authority| authority.host().to_ascii_lowercase());
+    match host {
+        Some(host) if Some(host.as_str()) != args.http_redirect_url.host_str() => host,
+        _ => args.registry_default_hostname.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use http::HeaderValue;
+    use std::net::Ipv4Addr;
+
+    use crate::config::Args;
+
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10));
+    const PROXY: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn server_args() -> ServerArgs {
+        let Args::Server(args) = Args::try_parse_from([
+            "./terrashine",
+            "server",
+            "--http-redirect-url",
+            "https://mirror.example.com/",
+            "--s3-bucket-name",
+            "terrashine",
+            "--trusted-proxies",
+            "192.0.2.1",
+        ])
+        .expect("Could not parse") else {
+            panic!("Expected server subcommand");
+        };
+        args
+    }
+
+    #[tokio::test]
+    async fn test_discovery_advertises_services() {
+        let Json(discovery) = discovery_handler().await;
+        assert_eq!(
+            serde_json::to_value(discovery).unwrap(),
+            serde_json::json!({
+                "modules.v1": "/modules/v1/",
+                "providers.v1": "/providers/v1/",
+            })
+        );
+    }
+
+    #[test]
+    fn test_upstream_hostname_from_alias() {
+        let args = server_args();
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, HeaderValue::from_static("Registry.Example.com:443"));
+        let uri = Uri::from_static("/providers/v1/hashicorp/random/versions");
+        assert_eq!(
+            upstream_hostname(&headers, &uri, CLIENT, &args),
+            "registry.example.com"
+        );
+    }
+
+    #[test]
+    fn test_upstream_hostname_forwarded_by_trusted_proxy() {
+        let args = server_args();
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, HeaderValue::from_static("registry.example.com"));
+        headers.insert(
+            "x-forwarded-host",
+            HeaderValue::from_static("other.example.com"),
+        );
+        let uri = Uri::from_static("/providers/v1/hashicorp/random/versions");
+        assert_eq!(
+            upstream_hostname(&headers, &uri, PROXY, &args),
+            "other.example.com"
+        );
+        assert_eq!(
+            upstream_hostname(&headers, &uri, CLIENT, &args),
+            "registry.example.com"
+        );
+    }
+
+    #[test]
+    fn test_upstream_hostname_defaults() {
+        let args = server_args();
+        let mut headers = HeaderMap::new();
+        let uri = Uri::from_static("/providers/v1/hashicorp/random/versions");
+        assert_eq!(
+            upstream_hostname(&headers, &uri, CLIENT, &args),
+            "registry.terraform.io"
+        );
+        headers.insert(HOST, HeaderValue::from_static("mirror.example.com"));
+        assert_eq!(
+            upstream_hostname(&headers, &uri, CLIENT, &args),
+            "registry.terraform.io"
+        );
+    }
+}
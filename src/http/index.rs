@@ -1,9 +1,11 @@
 use crate::{
     app::AppState,
+    config::ServerArgs,
     credhelper::CredentialHelper,
     error::TerrashineError,
     refresh::{RefreshRequest, RefreshResponse, TerraformProvider},
     registry::{ProviderPlatform, ProviderVersionItem, ProviderVersions, RegistryClient},
+    webhook::{describe_error, Event, Webhooks},
 };
 use axum::response::IntoResponse;
 use axum::{
@@ -16,37 +18,53 @@ use http::{
 };
 use hyper::HeaderMap;
 use sqlx::PgPool;
-use std::collections::HashMap;
-use std::{fmt::Debug, time::Duration};
-use tokio::sync::{mpsc::error::SendTimeoutError, oneshot};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use tokio::sync::{
+    mpsc::{self, error::SendTimeoutError},
+    oneshot,
+};
 use tracing::Span;
 
-use super::response_types::MirrorIndex;
+use super::{
+    offline::{refuse_cache_miss, CacheMiss},
+    platforms::AllowedPlatforms,
+    response_types::{MirrorIndex, MirrorIndexVersion},
+};
 
 pub(crate) async fn index_handler<C>(
     State(AppState {
         db_client: db,
         refresher_tx: tx,
+        config: args,
         ..
     }): State<AppState<C>>,
     Path((hostname, namespace, provider_type)): Path<(String, String, String)>,
 ) -> Result<MirrorIndex, TerrashineError> {
-    match list_provider_versions(&db, &hostname, &namespace, &provider_type).await {
-        Ok(Some(mirror_index)) => {
-            let provider = TerraformProvider {
-                hostname,
-                namespace,
-                provider_type,
-            };
-            let result = tx.try_send(RefreshRequest {
-                provider,
-                response_channel: None,
-                span: Span::current(),
-            });
-            // We don't care if it errors in this path, log and continue on.
-            if let Err(e) = result {
-                tracing::trace!(reason=?e, "Failed to send provider refresh request");
+    let provider = TerraformProvider {
+        hostname,
+        namespace,
+        provider_type,
+    };
+    let platforms = AllowedPlatforms::for_request(&db, &args, &provider).await?;
+
+    // Privately published providers only exist in the database, as does everything when offline
+    let private = args.is_private_hostname(&provider.hostname);
+    if private || args.offline {
+        return match list_provider_versions(&db, &args, &platforms, &provider).await? {
+            Some(mirror_index) => Ok(mirror_index),
+            None => {
+                if !private {
+                    refuse_cache_miss(CacheMiss::ProviderIndex);
+                }
+                Err(TerrashineError::ProviderNotFound { provider })
             }
+        };
+    }
+
+    match list_provider_versions(&db, &args, &platforms, &provider).await {
+        Ok(Some(mirror_index)) => {
+            request_background_refresh(&tx, provider);
             return Ok(mirror_index);
         }
         Ok(None) => {
@@ -61,17 +79,42 @@ pub(crate) async fn index_handler<C>(
     }
 
     // If we didn't see anything in the database, now we'll request it from upstream
-    // We do this by sending a message to the refresher channel and then wait for a
-    // message back via the oneshot channel to confirm provider has been refreshed.
-    // This path only occurs when a brand new provider index is first encountered.
-    // The refresher handles on going updates of known terraform provider versions.
-    let (resp_tx, resp_rx) = oneshot::channel();
+    let mut versions = request_refresh(&tx, provider).await?;
+    versions
+        .versions
+        .retain(|item| args.is_protocol_allowed(&item.protocols));
+    Ok(MirrorIndex::from(versions))
+}
 
-    let provider = TerraformProvider {
-        hostname,
-        namespace,
-        provider_type,
-    };
+/// Requests a refresh of a known provider without waiting for the result.
+///
+/// The refresher ignores the request if the refresh interval has not yet passed.
+pub(crate) fn request_background_refresh(
+    tx: &mpsc::Sender<RefreshRequest>,
+    provider: TerraformProvider,
+) {
+    let result = tx.try_send(RefreshRequest {
+        provider,
+        response_channel: None,
+        span: Span::current(),
+    });
+    // We don't care if it errors in this path, log and continue on.
+    if let Err(e) = result {
+        tracing::trace!(reason=?e, "Failed to send provider refresh request");
+    }
+}
+
+/// Requests a refresh of a provider from the refresher task and waits for the result.
+///
+/// We do this by sending a message to the refresher channel and then wait for a
+/// message back via the oneshot channel to confirm provider has been refreshed.
+/// This path only occurs when a brand new provider index is first encountered.
+/// The refresher handles on going updates of known terraform provider versions.
+pub(crate) async fn request_refresh(
+    tx: &mpsc::Sender<RefreshRequest>,
+    provider: TerraformProvider,
+) -> Result<ProviderVersions, TerrashineError> {
+    let (resp_tx, resp_rx) = oneshot::channel();
 
     tracing::debug!("Sending request to refresher task");
     tx.send_timeout(
@@ -98,7 +141,7 @@ pub(crate) async fn index_handler<C>(
     )?;
 
     match resp_rx.await {
-        Ok(RefreshResponse::RefreshPerformed(Ok(versions))) => Ok(MirrorIndex::from(versions)),
+        Ok(RefreshResponse::RefreshPerformed(Ok(versions))) => Ok(versions),
         Ok(RefreshResponse::RefreshPerformed(Err(err))) => {
             tracing::error!(reason=%err, "Error occurred while adding new provider from upstream");
             Err(err)
@@ -121,50 +164,94 @@ pub(crate) async fn index_handler<C>(
 pub(crate) async fn refresh_versions<T: CredentialHelper>(
     db: &PgPool,
     registry: &RegistryClient<T>,
-    hostname: &str,
-    namespace: &str,
-    provider_type: &str,
+    webhooks: &Webhooks,
+    allowed_platforms: &[String],
+    provider: &TerraformProvider,
 ) -> Result<ProviderVersions, TerrashineError> {
-    let provider_versions = registry
-        .provider_get(hostname, &format!("{namespace}/{provider_type}/versions"))
-        .await?;
+    let TerraformProvider {
+        hostname,
+        namespace,
+        provider_type,
+    } = provider;
+    let mut provider_versions = match registry
+        .provider_versions(hostname, namespace, provider_type)
+        .await
+    {
+        Ok(versions) => versions,
+        Err(error) => {
+            webhooks
+                .emit(Event::UpstreamFailure {
+                    hostname: hostname.to_string(),
+                    namespace: namespace.to_string(),
+                    provider_type: provider_type.to_string(),
+                    version: None,
+                    reason: describe_error(&error),
+                })
+                .await;
+            return Err(error);
+        }
+    };
 
-    store_provider_versions(db, hostname, namespace, provider_type, &provider_versions).await?;
+    AllowedPlatforms::for_provider(db, allowed_platforms, provider)
+        .await?
+        .retain(&mut provider_versions);
+    let new_versions =
+        store_provider_versions(db, hostname, namespace, provider_type, &provider_versions).await?;
+    if !new_versions.is_empty() {
+        webhooks
+            .emit(Event::ProviderVersionsDiscovered {
+                hostname: hostname.to_string(),
+                namespace: namespace.to_string(),
+                provider_type: provider_type.to_string(),
+                versions: new_versions,
+            })
+            .await;
+    }
 
     Ok(provider_versions)
 }
 
+/// Lists the known versions of a provider that support an allowed plugin protocol.
+///
+/// Returns None if the provider is not known, rather than having no allowed versions.
 async fn list_provider_versions(
     db: &PgPool,
-    hostname: &str,
-    namespace: &str,
-    provider_type: &str,
+    args: &ServerArgs,
+    platforms: &AllowedPlatforms,
+    provider: &TerraformProvider,
 ) -> Result<Option<MirrorIndex>, TerrashineError> {
     let query = sqlx::query!(
         r#"
-        select "version" as "version?" from "terraform_provider_version"
+        select "version" as "version?", "os", "arch", "protocols"
+        from "terraform_provider_version"
         left join "terraform_provider" on
             "terraform_provider_version"."provider_id" = "terraform_provider"."id"
             where "terraform_provider"."hostname" = $1
                 and "terraform_provider"."namespace" = $2
                 and "terraform_provider"."type" = $3;
         "#,
-        hostname,
-        namespace,
-        provider_type,
+        provider.hostname,
+        provider.namespace,
+        provider.provider_type,
     );
 
     let rows = query.fetch_all(db).await?;
 
     match *rows.as_slice() {
         [] => Ok(None),
-        [..] => Ok(Some(
-            rows.into_iter()
-                .map(|row| row.version)
-                .collect::<Option<Vec<String>>>()
-                .unwrap_or_default()
-                .into(),
-        )),
+        [..] => Ok(Some(MirrorIndex {
+            versions: rows
+                .into_iter()
+                .filter(|row| {
+                    args.is_protocol_allowed(row.protocols.as_deref().unwrap_or_default())
+                })
+                .filter(|row| platforms.contains(&row.os, &row.arch))
+                .filter_map(|row| {
+                    let protocols = row.protocols.unwrap_or_default();
+                    Some((row.version?, MirrorIndexVersion { protocols }))
+                })
+                .collect(),
+        })),
     }
 }
 
@@ -174,16 +261,21 @@ async fn store_provider_versions(
     namespace: &str,
     provider_type: &str,
     response: &ProviderVersions,
-) -> Result<u64, TerrashineError> {
+) -> Result<Vec<String>, TerrashineError> {
     let mut transaction = db.begin().await?;
     let mut versions = vec![];
     let mut oses = vec![];
     let mut arches = vec![];
+    let mut protocols = vec![];
     for version_item @ ProviderVersionItem { version, .. } in response.versions.iter() {
+        // Protocols are stored comma separated as unnest flattens nested arrays
+        let version_protocols =
+            (!version_item.protocols.is_empty()).then(|| version_item.protocols.join(","));
         for ProviderPlatform { os, arch } in version_item.platforms.iter() {
             versions.push(version.to_string());
             oses.push(os.to_string());
             arches.push(arch.to_string());
+            protocols.push(version_protocols.clone());
         }
     }
 
@@ -215,21 +307,27 @@ async fn store_provider_versions(
     // version tuples as an array, turning them into rows and joining it
     // on the hostname, namespace and type with the known providers to get
     // the provider id.
+    // Known versions only have their protocols filled in if they were
+    // previously unknown, and "xmax" is zero only for newly inserted rows.
     let query = sqlx::query!(
         r#"
         insert into "terraform_provider_version"
-            ("version", "os", "arch", "provider_id", "artifact_id")
-            select "t1"."hostname", "t1"."namespace", "t1"."type", "t2"."id", null from
-                (select * from unnest($1::text[], $2::text[], $3::text[]))
-                    as "t1" ("hostname", "namespace", "type")
+            ("version", "os", "arch", "provider_id", "artifact_id", "protocols")
+            select "t1"."hostname", "t1"."namespace", "t1"."type", "t2"."id", null,
+                string_to_array("t1"."protocols", ',') from
+                (select * from unnest($1::text[], $2::text[], $3::text[], $7::text[]))
+                    as "t1" ("hostname", "namespace", "type", "protocols")
                 cross join
                 (select "id" from "terraform_provider"
                     where "hostname" = $4
                         and "namespace" = $5
                         and "type" = $6 limit 1) as t2
-            on conflict do nothing
+            on conflict ("provider_id", "version", "os", "arch") do update
+                set "protocols" = "excluded"."protocols"
+                where "terraform_provider_version"."protocols" is null
+                    and "excluded"."protocols" is not null
             returning
-            "version", "os", "arch";
+            "version", "os", "arch", ("xmax" = 0) as "inserted!";
         "#,
         &versions[..],
         &oses[..],
@@ -237,6 +335,7 @@ async fn store_provider_versions(
         &hostname,
         &namespace[..],
         &provider_type[..],
+        &protocols[..] as &[Option<String>],
     );
 
     let records = query.fetch_all(&mut *transaction).await?;
@@ -244,19 +343,24 @@ async fn store_provider_versions(
     tracing::debug!(?records, "Saving new provider versions to database");
     transaction.commit().await?;
 
-    let count = records.len();
+    let count = records.iter().filter(|record| record.inserted).count();
     tracing::info!(%count, "Saved provider versions to the database");
 
-    Ok(count
-        .try_into()
-        .expect("Could not cast row count to 64 bit number"))
+    // Return each newly seen version once, rather than once per platform
+    let new_versions = records
+        .into_iter()
+        .filter(|record| record.inserted)
+        .map(|record| record.version)
+        .collect::<BTreeSet<_>>();
+    Ok(new_versions.into_iter().collect())
 }
 
 impl From<ProviderVersions> for MirrorIndex {
     fn from(provider_versions: ProviderVersions) -> MirrorIndex {
         let mut versions = HashMap::new();
-        for version in provider_versions.versions.iter() {
-            versions.insert(version.version.to_owned(), HashMap::new());
+        for version in provider_versions.versions.into_iter() {
+            let protocols = version.protocols;
+            versions.insert(version.version, MirrorIndexVersion { protocols });
         }
         MirrorIndex { versions }
     }
@@ -266,7 +370,7 @@ impl From<Vec<String>> for MirrorIndex {
     fn from(versions: Vec<String>) -> MirrorIndex {
         let mut version_maps = HashMap::new();
         for version in versions.iter() {
-            version_maps.insert(version.to_owned(), HashMap::new());
+            version_maps.insert(version.to_owned(), MirrorIndexVersion::default());
         }
         MirrorIndex {
             versions: version_maps,
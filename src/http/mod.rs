@@ -1,6 +1,13 @@
 pub(crate) mod api;
 pub(crate) mod artifacts;
+pub(crate) mod auth;
+pub(crate) mod discovery;
 pub(crate) mod healthcheck;
 pub(crate) mod index;
+pub(crate) mod modules;
+pub(crate) mod offline;
+pub(crate) mod platforms;
+pub(crate) mod provider_registry;
+pub(crate) mod releases;
 pub(crate) mod response_types;
 pub(crate) mod version;
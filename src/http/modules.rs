@@ -0,0 +1,847 @@
+use crate::{
+    app::AppState,
+    credhelper::CredentialHelper,
+    error::TerrashineError,
+    http::{
+        artifacts::{
+            allocate_artifact_id, artifact_s3_key, presign_artifact, upload_artifact,
+            ArtifactResponse,
+        },
+        discovery::upstream_hostname,
+        offline::{refuse_cache_miss, CacheMiss},
+    },
+    registry::{ModuleVersionsResponse, RegistryClient, TERRAFORM_GET_HEADER},
+};
+use anyhow::Context;
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    response::{IntoResponse, Response},
+};
+use http::{
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    HeaderMap, HeaderValue, StatusCode, Uri,
+};
+use sqlx::PgPool;
+use std::{net::SocketAddr, time::Duration};
+use url::Url;
+
+use super::response_types::{ModuleIndex, ModuleIndexVersion, ModuleIndexVersions};
+
+/// Archive formats supported by terraform for module packages, longest suffix first.
+const ARCHIVE_FORMATS: &[&str] = &[
+    "tar.bz2", "tar.gz", "tar.xz", "tar.zst", "tbz2", "tgz", "txz", "tzst", "zip", "tar",
+];
+
+#[derive(Debug, Clone)]
+pub(crate) struct TerraformModule {
+    hostname: String,
+    namespace: String,
+    name: String,
+    system: String,
+}
+
+impl TerraformModule {
+    fn path(&self) -> String {
+        format!("{}/{}/{}", self.namespace, self.name, self.system)
+    }
+}
+
+pub(crate) async fn module_versions_handler<C>(
+    State(state): State<AppState<C>>,
+    Path((hostname, namespace, name, system)): Path<(String, String, String, String)>,
+) -> Result<ModuleIndex, StatusCode> {
+    let module = TerraformModule {
+        hostname,
+        namespace,
+        name,
+        system,
+    };
+    module_versions(state, module).await
+}
+
+/// Module registry protocol "List Available Versions", with the upstream registry taken from
+/// the hostname the client used to reach terrashine.
+pub(crate) async fn registry_module_versions_handler<C>(
+    State(state): State<AppState<C>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path((namespace, name, system)): Path<(String, String, String)>,
+) -> Result<ModuleIndex, StatusCode> {
+    let module = TerraformModule {
+        hostname: upstream_hostname(&headers, &uri, peer.ip(), &state.config),
+        namespace,
+        name,
+        system,
+    };
+    module_versions(state, module).await
+}
+
+async fn module_versions<C>(
+    AppState {
+        db_client: db,
+        registry_client: registry,
+        config: args,
+        ..
+    }: AppState<C>,
+    module: TerraformModule,
+) -> Result<ModuleIndex, StatusCode> {
+    if args.offline {
+        return match list_module_versions(&db, &module, args.refresh_interval).await {
+            Ok(Some((_, versions))) => Ok(versions.into()),
+            Ok(None) => {
+                refuse_cache_miss(CacheMiss::ModuleIndex);
+                Err(StatusCode::NOT_FOUND)
+            }
+            Err(error) => {
+                tracing::error!(reason = %error, "Error occurred fetching module from database");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+    }
+    match list_module_versions(&db, &module, args.refresh_interval).await {
+        Ok(Some((true, versions))) => return Ok(versions.into()),
+        Ok(Some((false, versions))) => {
+            tracing::debug!("Module is stale, refreshing from upstream");
+            return match refresh_module_versions(&db, &registry, &module).await {
+                Ok(versions) => Ok(versions.into()),
+                Err(error) => {
+                    tracing::warn!(reason = %error, "Could not refresh module, serving stale versions");
+                    Ok(versions.into())
+                }
+            };
+        }
+        Ok(None) => {
+            tracing::debug!("Unknown module requested, fetching upstream");
+        }
+        Err(error) => {
+            tracing::warn!(
+                reason = %error,
+                "Error occurred fetching module from database, fetching upstream"
+            );
+        }
+    }
+    match refresh_module_versions(&db, &registry, &module).await {
+        Ok(versions) => Ok(versions.into()),
+        Err(error) => {
+            tracing::error!(reason = %error, "Error occurred while adding new module from upstream");
+            Err(upstream_status(&error))
+        }
+    }
+}
+
+pub(crate) async fn module_download_handler<C>(
+    State(state): State<AppState<C>>,
+    Path((hostname, namespace, name, system, version)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+) -> Result<ModuleDownload, StatusCode> {
+    let module = TerraformModule {
+        hostname,
+        namespace,
+        name,
+        system,
+    };
+    module_download(state, module, version).await
+}
+
+/// Module registry protocol "Download Source Code for a Specific Module Version", with the
+/// upstream registry taken from the hostname the client used to reach terrashine.
+pub(crate) async fn registry_module_download_handler<C>(
+    State(state): State<AppState<C>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path((namespace, name, system, version)): Path<(String, String, String, String)>,
+) -> Result<ModuleDownload, StatusCode> {
+    let module = TerraformModule {
+        hostname: upstream_hostname(&headers, &uri, peer.ip(), &state.config),
+        namespace,
+        name,
+        system,
+    };
+    module_download(state, module, version).await
+}
+
+async fn module_download<C>(
+    AppState {
+        db_client: db,
+        registry_client: registry,
+        config: args,
+        ..
+    }: AppState<C>,
+    module: TerraformModule,
+    version: String,
+) -> Result<ModuleDownload, StatusCode> {
+    let mut details = get_module_version(&db, &module, &version)
+        .await
+        .map_err(|e| {
+            tracing::error!(reason = ?e, "Error querying database for module version");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if details.is_none() && args.offline {
+        tracing::debug!(%version, "Module version not cached");
+        refuse_cache_miss(CacheMiss::ModuleVersion);
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if details.is_none() {
+        // The download endpoint can be used directly as a module source without
+        // listing versions first, so the module may not be known yet.
+        tracing::debug!("Module version not found in database, refreshing from upstream");
+        refresh_module_versions(&db, &registry, &module)
+            .await
+            .map_err(|e| {
+                tracing::error!(reason = %e, "Error occurred while adding new module from upstream");
+                upstream_status(&e)
+            })?;
+        details = get_module_version(&db, &module, &version)
+            .await
+            .map_err(|e| {
+                tracing::error!(reason = ?e, "Error querying database for module version");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+    }
+    let Some(details) = details else {
+        tracing::debug!(%version, "Module version not found upstream");
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    if details.upstream_source.is_none() && args.offline {
+        tracing::debug!(%version, "Module source not cached");
+        refuse_cache_miss(CacheMiss::ModuleSource);
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if details.upstream_source.is_none() {
+        let path = format!("{}/{}/download", module.path(), version);
+        let (download_url, source) = registry
+            .module_download_source(&module.hostname, &path)
+            .await
+            .map_err(|e| {
+                tracing::error!(reason = %e, "Error occurred fetching module download location");
+                upstream_status(&e)
+            })?;
+        let resolved = ModuleSource::parse(&download_url, &source);
+        tracing::debug!(?resolved, "Resolved upstream module source");
+        store_module_source(&db, details.id, &resolved)
+            .await
+            .map_err(|e| {
+                tracing::error!(reason = ?e, "Error storing module source");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        return ModuleDownload::new(details.id, &resolved);
+    }
+
+    let resolved = match (details.upstream_source, details.archive_format) {
+        (Some(url), Some(archive_format)) => ModuleSource::Archive {
+            url: Url::parse(&url).map_err(|e| {
+                tracing::error!(reason = ?e, "Invalid module source stored in database");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?,
+            archive_format,
+            subdirectory: details.subdirectory,
+        },
+        (Some(source), None) => ModuleSource::Passthrough(source),
+        (None, _) => unreachable!("upstream source checked above"),
+    };
+    ModuleDownload::new(details.id, &resolved)
+}
+
+pub(crate) async fn module_artifacts_handler<C: CredentialHelper>(
+    State(AppState {
+        registry_client: registry,
+        db_client: db,
+        s3_client: s3,
+        config: args,
+        ..
+    }): State<AppState<C>>,
+    Path(version_id): Path<i64>,
+) -> Result<ArtifactResponse, StatusCode> {
+    let artifact = match get_module_artifact(&db, version_id).await {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            tracing::debug!(?version_id, "Cachable module version not found in database");
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Err(e) => {
+            tracing::error!(reason = ?e, ?version_id, "Error querying database for module artifact");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let artifact_id = match artifact.artifact_id {
+        Some(id) => id,
+        None if args.offline => {
+            tracing::debug!(source = %artifact.upstream_source, "Module package not cached");
+            refuse_cache_miss(CacheMiss::ModuleArtifact);
+            return Err(StatusCode::NOT_FOUND);
+        }
+        None => {
+            tracing::debug!(source = %artifact.upstream_source, "Fetching module package from upstream");
+            stash_module_artifact(
+                &registry,
+                &db,
+                &s3,
+                &args.s3_bucket_name,
+                &args.s3_bucket_prefix,
+                version_id,
+                &artifact,
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!(reason = ?e, "Error occurred caching module package");
+                upstream_status(&e)
+            })?
+        }
+    };
+    let key = artifact_s3_key(&args.s3_bucket_prefix, artifact_id);
+    let uri = presign_artifact(&s3, &args.s3_bucket_name, &key)
+        .await
+        .map_err(|e| {
+            tracing::error!(reason = ?e, "Error presigning url");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(ArtifactResponse::new(uri))
+}
+
+pub(crate) fn upstream_status(error: &TerrashineError) -> StatusCode {
+    match error {
+        TerrashineError::ProviderResponseFailure { source }
+            if source.status() == Some(reqwest::StatusCode::NOT_FOUND) =>
+        {
+            StatusCode::NOT_FOUND
+        }
+        TerrashineError::UpstreamSourcesExhausted { .. } => StatusCode::NOT_FOUND,
+        // Refused by the download policy, retrying will not help
+        TerrashineError::DownloadNotAllowed { .. } => StatusCode::FORBIDDEN,
+        TerrashineError::DatabaseError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Location of a module package, as advertised by the upstream `X-Terraform-Get` header.
+#[derive(Debug, PartialEq, Eq)]
+enum ModuleSource {
+    /// An archive over HTTP(S) which can be stored in the artifact cache.
+    Archive {
+        url: Url,
+        archive_format: String,
+        subdirectory: Option<String>,
+    },
+    /// Any other source, such as a git repository, which is handed to the client unchanged.
+    Passthrough(String),
+}
+
+impl ModuleSource {
+    /// Parses an `X-Terraform-Get` value following the go-getter source syntax.
+    ///
+    /// Relative sources are resolved against the URL of the download endpoint.
+    fn parse(download_url: &Url, source: &str) -> Self {
+        Self::parse_archive(download_url, source).unwrap_or_else(|| {
+            // Relative locations are only meaningful to the client against upstream
+            if source.starts_with('/') || source.starts_with("./") || source.starts_with("../") {
+                if let Ok(url) = download_url.join(source) {
+                    return ModuleSource::Passthrough(url.to_string());
+                }
+            }
+            ModuleSource::Passthrough(source.to_string())
+        })
+    }
+
+    fn parse_archive(download_url: &Url, source: &str) -> Option<Self> {
+        // Forced getters take the form `getter::url`, only plain HTTP can be cached
+        let source = match source.split_once("::") {
+            Some((getter, rest)) if !getter.contains('/') => match getter {
+                "http" | "https" => rest,
+                _ => return None,
+            },
+            _ => source,
+        };
+        // Without a scheme, the client only treats explicitly relative paths as URLs,
+        // anything else is handed to go-getter detectors such as github.com shorthands.
+        let is_relative =
+            source.starts_with('/') || source.starts_with("./") || source.starts_with("../");
+        if !source.contains("://") && !is_relative {
+            return None;
+        }
+
+        // Subdirectories within the package are separated with a double slash
+        let search_start = source.find("://").map_or(0, |i| i + 3);
+        let (source, subdirectory) = match source[search_start..].find("//") {
+            Some(i) => {
+                let (source, subdirectory) = source.split_at(search_start + i);
+                let subdirectory = &subdirectory[2..];
+                match subdirectory.split_once('?') {
+                    Some((subdirectory, query)) => {
+                        (format!("{source}?{query}"), Some(subdirectory.to_string()))
+                    }
+                    None => (source.to_string(), Some(subdirectory.to_string())),
+                }
+            }
+            None => (source.to_string(), None),
+        };
+        let subdirectory = subdirectory.filter(|s| !s.is_empty());
+
+        let mut url = download_url.join(&source).ok()?;
+        if url.scheme() != "https" && url.scheme() != "http" {
+            return None;
+        }
+
+        let mut archive_format = None;
+        let query = url
+            .query_pairs()
+            .filter(|(k, v)| {
+                if k == "archive" {
+                    archive_format = Some(v.to_string());
+                    false
+                } else {
+                    true
+                }
+            })
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect::<Vec<_>>();
+        if query.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(query);
+        }
+
+        let archive_format = archive_format.or_else(|| {
+            ARCHIVE_FORMATS
+                .iter()
+                .find(|format| url.path().ends_with(&format!(".{format}")))
+                .map(|format| format.to_string())
+        })?;
+        if !ARCHIVE_FORMATS.contains(&archive_format.as_str()) {
+            return None;
+        }
+
+        Some(ModuleSource::Archive {
+            url,
+            archive_format,
+            subdirectory,
+        })
+    }
+}
+
+/// Response of the module download endpoint.
+pub(crate) struct ModuleDownload {
+    source: HeaderValue,
+}
+
+impl ModuleDownload {
+    fn new(version_id: i64, source: &ModuleSource) -> Result<Self, StatusCode> {
+        let source = match source {
+            // Relative to the download endpoint, so this resolves against the
+            // hostname the client used to reach terrashine.
+            ModuleSource::Archive {
+                archive_format,
+                subdirectory,
+                ..
+            } => {
+                let mut s = format!("/modules/v1/artifacts/{version_id}");
+                if let Some(subdirectory) = subdirectory {
+                    s.push_str("//");
+                    s.push_str(subdirectory);
+                }
+                s.push_str("?archive=");
+                s.push_str(archive_format);
+                s
+            }
+            ModuleSource::Passthrough(source) => source.clone(),
+        };
+        let source = HeaderValue::try_from(source).map_err(|e| {
+            tracing::error!(reason = %e, "Module source is not a valid header value");
+            StatusCode::BAD_GATEWAY
+        })?;
+        Ok(Self { source })
+    }
+}
+
+impl IntoResponse for ModuleDownload {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::NO_CONTENT,
+            [(TERRAFORM_GET_HEADER, self.source)],
+        )
+            .into_response()
+    }
+}
+
+impl From<Vec<String>> for ModuleIndex {
+    fn from(versions: Vec<String>) -> ModuleIndex {
+        ModuleIndex {
+            modules: vec![ModuleIndexVersions {
+                versions: versions
+                    .into_iter()
+                    .map(|version| ModuleIndexVersion { version })
+                    .collect(),
+            }],
+        }
+    }
+}
+
+impl IntoResponse for ModuleIndex {
+    fn into_response(self) -> Response {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=60"),
+        );
+        let response = match serde_json::to_string(&self) {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::error!(reason = ?e, "Could not serialize ModuleIndex");
+                return (headers, StatusCode::INTERNAL_SERVER_ERROR).into_response();
+            }
+        };
+        (headers, response).into_response()
+    }
+}
+
+async fn refresh_module_versions<T: CredentialHelper>(
+    db: &PgPool,
+    registry: &RegistryClient<T>,
+    module: &TerraformModule,
+) -> Result<Vec<String>, TerrashineError> {
+    let response: ModuleVersionsResponse = registry
+        .module_get(&module.hostname, &format!("{}/versions", module.path()))
+        .await?;
+    let versions = response
+        .modules
+        .into_iter()
+        .flat_map(|m| m.versions)
+        .map(|v| v.version)
+        .collect::<Vec<_>>();
+    store_module_versions(db, module, &versions).await?;
+    Ok(versions)
+}
+
+/// Lists known versions of a module along with whether the listing is fresh.
+async fn list_module_versions(
+    db: &PgPool,
+    module: &TerraformModule,
+    refresh_interval: Duration,
+) -> Result<Option<(bool, Vec<String>)>, TerrashineError> {
+    let rows = sqlx::query!(
+        r#"
+        select
+            "terraform_module"."last_refreshed" > now() - $5 * interval '1 second' as "fresh!",
+            "terraform_module_version"."version" as "version?"
+        from "terraform_module"
+        left join "terraform_module_version" on
+            "terraform_module_version"."module_id" = "terraform_module"."id"
+        where "terraform_module"."hostname" = $1
+            and "terraform_module"."namespace" = $2
+            and "terraform_module"."name" = $3
+            and "terraform_module"."system" = $4;
+        "#,
+        module.hostname,
+        module.namespace,
+        module.name,
+        module.system,
+        refresh_interval.as_secs_f64(),
+    )
+    .fetch_all(db)
+    .await?;
+
+    let Some(first) = rows.first() else {
+        return Ok(None);
+    };
+    let fresh = first.fresh;
+    let versions = rows.into_iter().filter_map(|row| row.version).collect();
+    Ok(Some((fresh, versions)))
+}
+
+async fn store_module_versions(
+    db: &PgPool,
+    module: &TerraformModule,
+    versions: &[String],
+) -> Result<(), TerrashineError> {
+    let mut transaction = db.begin().await?;
+    let module_id = sqlx::query!(
+        r#"
+        insert into "terraform_module"
+            ("hostname", "namespace", "name", "system", "last_refreshed")
+        values ($1, $2, $3, $4, now())
+        on conflict ("hostname", "namespace", "name", "system")
+            do update set "last_refreshed" = "excluded"."last_refreshed"
+        returning "id";
+        "#,
+        module.hostname,
+        module.namespace,
+        module.name,
+        module.system,
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .id;
+
+    let records = sqlx::query!(
+        r#"
+        insert into "terraform_module_version" ("module_id", "version")
+            select $1, "version" from unnest($2::text[]) as "t" ("version")
+        on conflict do nothing
+        returning "version";
+        "#,
+        module_id,
+        versions,
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    let count = records.len();
+    tracing::info!(%count, "Saved module versions to the database");
+    Ok(())
+}
+
+struct ModuleVersionDetails {
+    id: i64,
+    upstream_source: Option<String>,
+    archive_format: Option<String>,
+    subdirectory: Option<String>,
+}
+
+async fn get_module_version(
+    db: &PgPool,
+    module: &TerraformModule,
+    version: &str,
+) -> Result<Option<ModuleVersionDetails>, anyhow::Error> {
+    let result = sqlx::query_as!(
+        ModuleVersionDetails,
+        r#"
+        select
+            "terraform_module_version"."id",
+            "upstream_source",
+            "archive_format",
+            "subdirectory"
+        from "terraform_module_version"
+        inner join "terraform_module"
+            on "terraform_module_version"."module_id" = "terraform_module"."id"
+        where "terraform_module"."hostname" = $1
+            and "terraform_module"."namespace" = $2
+            and "terraform_module"."name" = $3
+            and "terraform_module"."system" = $4
+            and "terraform_module_version"."version" = $5;
+        "#,
+        module.hostname,
+        module.namespace,
+        module.name,
+        module.system,
+        version,
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(result)
+}
+
+async fn store_module_source(
+    db: &PgPool,
+    version_id: i64,
+    source: &ModuleSource,
+) -> Result<(), anyhow::Error> {
+    let (upstream_source, archive_format, subdirectory) = match source {
+        ModuleSource::Archive {
+            url,
+            archive_format,
+            subdirectory,
+        } => (
+            url.to_string(),
+            Some(archive_format.as_str()),
+            subdirectory.as_deref(),
+        ),
+        ModuleSource::Passthrough(source) => (source.clone(), None, None),
+    };
+    sqlx::query!(
+        r#"
+        update "terraform_module_version"
+        set "upstream_source" = $2,
+            "archive_format" = $3,
+            "subdirectory" = $4
+        where "id" = $1 and "artifact_id" is null;
+        "#,
+        version_id,
+        upstream_source,
+        archive_format,
+        subdirectory,
+    )
+    .execute(db)
+    .await
+    .context("Writing module source to database")?;
+    Ok(())
+}
+
+struct ModuleArtifactDetails {
+    hostname: String,
+    upstream_source: String,
+    artifact_id: Option<i64>,
+}
+
+async fn get_module_artifact(
+    db: &PgPool,
+    version_id: i64,
+) -> Result<Option<ModuleArtifactDetails>, anyhow::Error> {
+    let result = sqlx::query_as!(
+        ModuleArtifactDetails,
+        r#"
+        select
+            "terraform_module"."hostname",
+            "upstream_source" as "upstream_source!",
+            "artifact_id"
+        from "terraform_module_version"
+        inner join "terraform_module"
+            on "terraform_module_version"."module_id" = "terraform_module"."id"
+        where "terraform_module_version"."id" = $1
+            and "upstream_source" is not null
+            and "archive_format" is not null;
+        "#,
+        version_id,
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(result)
+}
+
+async fn stash_module_artifact<T: CredentialHelper>(
+    registry: &RegistryClient<T>,
+    db: &PgPool,
+    s3: &aws_sdk_s3::Client,
+    bucket_name: &str,
+    bucket_prefix: &str,
+    version_id: i64,
+    artifact: &ModuleArtifactDetails,
+) -> Result<i64, TerrashineError> {
+    let artifact_id = allocate_artifact_id(db).await?;
+    let url = Url::parse(&artifact.upstream_source).context("Invalid module source")?;
+    let stream = registry
+        .download(Some(&artifact.hostname), url)
+        .await?
+        .bytes_stream();
+    let key = artifact_s3_key(bucket_prefix, artifact_id);
+    upload_artifact(s3, bucket_name, &key, None, Box::pin(stream)).await?;
+
+    // A concurrent request may have already cached the same package, in which
+    // case the first stored artifact wins.
+    let row = sqlx::query!(
+        r#"
+        update "terraform_module_version"
+        set "artifact_id" = coalesce("artifact_id", $1),
+            "artifact_timestamp" = coalesce("artifact_timestamp", now())
+        where "id" = $2
+        returning "artifact_id" as "artifact_id!";
+        "#,
+        artifact_id,
+        version_id,
+    )
+    .fetch_one(db)
+    .await
+    .with_context(|| format!("Writing module artifact id({artifact_id}) to database"))?;
+    Ok(row.artifact_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download_url() -> Url {
+        Url::parse("https://registry.example.com/v1/modules/hashicorp/consul/aws/1.0.0/download")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_archive_by_extension() {
+        assert_eq!(
+            ModuleSource::parse(
+                &download_url(),
+                "https://cdn.example.com/consul-1.0.0.tar.gz?token=abc"
+            ),
+            ModuleSource::Archive {
+                url: Url::parse("https://cdn.example.com/consul-1.0.0.tar.gz?token=abc").unwrap(),
+                archive_format: "tar.gz".into(),
+                subdirectory: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_archive_parameter_and_subdirectory() {
+        assert_eq!(
+            ModuleSource::parse(
+                &download_url(),
+                "https::https://cdn.example.com/consul//modules/server?archive=zip"
+            ),
+            ModuleSource::Archive {
+                url: Url::parse("https://cdn.example.com/consul").unwrap(),
+                archive_format: "zip".into(),
+                subdirectory: Some("modules/server".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_relative_archive() {
+        assert_eq!(
+            ModuleSource::parse(&download_url(), "../../archive.tgz"),
+            ModuleSource::Archive {
+                url: Url::parse(
+                    "https://registry.example.com/v1/modules/hashicorp/consul/archive.tgz"
+                )
+                .unwrap(),
+                archive_format: "tgz".into(),
+                subdirectory: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_passthrough() {
+        let source = "git::https://github.com/hashicorp/terraform-aws-consul?ref=v1.0.0";
+        assert_eq!(
+            ModuleSource::parse(&download_url(), source),
+            ModuleSource::Passthrough(source.into())
+        );
+        assert_eq!(
+            ModuleSource::parse(&download_url(), "github.com/hashicorp/example.zip"),
+            ModuleSource::Passthrough("github.com/hashicorp/example.zip".into())
+        );
+        assert_eq!(
+            ModuleSource::parse(&download_url(), "https://example.com/not-an-archive"),
+            ModuleSource::Passthrough("https://example.com/not-an-archive".into())
+        );
+    }
+
+    #[test]
+    fn test_download_response_source() {
+        let source = ModuleSource::Archive {
+            url: Url::parse("https://cdn.example.com/consul.zip").unwrap(),
+            archive_format: "zip".into(),
+            subdirectory: Some("modules/server".into()),
+        };
+        assert_eq!(
+            ModuleDownload::new(5, &source).unwrap().source,
+            "/modules/v1/artifacts/5//modules/server?archive=zip"
+        );
+    }
+
+    #[test]
+    fn test_download_response_invalid_source() {
+        let source = ModuleSource::Passthrough("git::https://example.com/repo\n".into());
+        assert_eq!(
+            ModuleDownload::new(5, &source).err(),
+            Some(StatusCode::BAD_GATEWAY)
+        );
+    }
+
+    #[test]
+    fn test_upstream_status_download_not_allowed() {
+        let error = TerrashineError::DownloadNotAllowed {
+            url: "https://10.0.0.1/module.zip".into(),
+            reason: "resolves to a private address".into(),
+        };
+        assert_eq!(upstream_status(&error), StatusCode::FORBIDDEN);
+    }
+}
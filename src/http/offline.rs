@@ -0,0 +1,45 @@
+use axum_prometheus::metrics::counter;
+
+/// Requests refused in offline mode because the content was not cached.
+const OFFLINE_CACHE_MISSES_METRIC: &str = "terrashine_offline_cache_misses_total";
+
+/// Kind of content refused in offline mode, used as the metric label.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CacheMiss {
+    ProviderIndex,
+    ProviderPackage,
+    ProviderArtifact,
+    ModuleIndex,
+    ModuleVersion,
+    ModuleSource,
+    ModuleArtifact,
+    ReleaseIndex,
+    ReleaseChecksums,
+    ReleaseArtifact,
+}
+
+impl CacheMiss {
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheMiss::ProviderIndex => "provider_index",
+            CacheMiss::ProviderPackage => "provider_package",
+            CacheMiss::ProviderArtifact => "provider_artifact",
+            CacheMiss::ModuleIndex => "module_index",
+            CacheMiss::ModuleVersion => "module_version",
+            CacheMiss::ModuleSource => "module_source",
+            CacheMiss::ModuleArtifact => "module_artifact",
+            CacheMiss::ReleaseIndex => "release_index",
+            CacheMiss::ReleaseChecksums => "release_checksums",
+            CacheMiss::ReleaseArtifact => "release_artifact",
+        }
+    }
+}
+
+/// Records a request that would have been fetched from upstream if terrashine was online.
+pub(crate) fn refuse_cache_miss(kind: CacheMiss) {
+    tracing::warn!(
+        kind = kind.as_str(),
+        "Offline mode, refusing to fetch uncached content from upstream"
+    );
+    counter!(OFFLINE_CACHE_MISSES_METRIC, "kind" => kind.as_str()).increment(1);
+}
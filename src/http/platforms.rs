@@ -0,0 +1,122 @@
+use sqlx::PgPool;
+
+use super::version::archive_name;
+use crate::{config::ServerArgs, refresh::TerraformProvider, registry::ProviderVersions};
+
+/// Platforms a provider is mirrored for, as `{os}_{arch}` names.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AllowedPlatforms {
+    /// Every platform is allowed when None
+    platforms: Option<Vec<String>>,
+}
+
+impl AllowedPlatforms {
+    fn new(platforms: &[String]) -> Self {
+        Self {
+            platforms: (!platforms.is_empty()).then(|| {
+                platforms
+                    .iter()
+                    .map(|platform| platform.to_ascii_lowercase())
+                    .collect()
+            }),
+        }
+    }
+
+    /// Platforms allowed for an upstream provider.
+    ///
+    /// A per provider override replaces the configured allowlist entirely.
+    pub(crate) async fn for_provider(
+        db: &PgPool,
+        default: &[String],
+        provider: &TerraformProvider,
+    ) -> Result<Self, sqlx::Error> {
+        let row = sqlx::query!(
+            r#"
+            select "platforms" from "terraform_provider_platforms"
+            where "hostname" = $1
+                and "namespace" = $2
+                and "type" = $3;
+            "#,
+            provider.hostname,
+            provider.namespace,
+            provider.provider_type,
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(match row {
+            Some(row) => Self::new(&row.platforms),
+            None => Self::new(default),
+        })
+    }
+
+    /// Platforms allowed when serving a provider, privately published providers are never restricted.
+    pub(crate) async fn for_request(
+        db: &PgPool,
+        args: &ServerArgs,
+        provider: &TerraformProvider,
+    ) -> Result<Self, sqlx::Error> {
+        if args.is_private_hostname(&provider.hostname) {
+            return Ok(Self::default());
+        }
+        Self::for_provider(db, &args.allowed_platforms, provider).await
+    }
+
+    pub(crate) fn contains(&self, os: &str, arch: &str) -> bool {
+        match &self.platforms {
+            Some(platforms) => platforms.contains(&archive_name(os, arch).to_ascii_lowercase()),
+            None => true,
+        }
+    }
+
+    /// Removes disallowed platforms, and versions left without any platforms.
+    pub(crate) fn retain(&self, versions: &mut ProviderVersions) {
+        for version in versions.versions.iter_mut() {
+            version
+                .platforms
+                .retain(|platform| self.contains(&platform.os, &platform.arch));
+        }
+        versions
+            .versions
+            .retain(|version| !version.platforms.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{ProviderPlatform, ProviderVersionItem};
+
+    fn version(version: &str, platforms: &[(&str, &str)]) -> ProviderVersionItem {
+        ProviderVersionItem {
+            version: version.to_string(),
+            protocols: vec![],
+            platforms: platforms
+                .iter()
+                .map(|(os, arch)| ProviderPlatform {
+                    os: os.to_string(),
+                    arch: arch.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_allowed_platforms() {
+        let allowed = AllowedPlatforms::new(&["linux_amd64".into(), "Darwin_ARM64".into()]);
+        assert!(allowed.contains("linux", "amd64"));
+        assert!(allowed.contains("darwin", "arm64"));
+        assert!(!allowed.contains("windows", "amd64"));
+        assert!(AllowedPlatforms::new(&[]).contains("windows", "amd64"));
+
+        let mut versions = ProviderVersions {
+            versions: vec![
+                version("1.0.0", &[("linux", "amd64"), ("windows", "amd64")]),
+                version("0.1.0", &[("freebsd", "386")]),
+            ],
+        };
+        allowed.retain(&mut versions);
+        assert_eq!(versions.versions.len(), 1);
+        assert_eq!(versions.versions[0].version, "1.0.0");
+        assert_eq!(versions.versions[0].platforms.len(), 1);
+    }
+}
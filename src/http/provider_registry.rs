@@ -0,0 +1,488 @@
+use crate::{
+    apitoken::TokenIdentity,
+    app::AppState,
+    config::ServerArgs,
+    credhelper::CredentialHelper,
+    error::TerrashineError,
+    refresh::TerraformProvider,
+    registry::{ProviderResponse, RegistryClient},
+};
+use anyhow::Context;
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, StatusCode, Uri};
+use sqlx::PgPool;
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use super::{
+    auth::download_query,
+    discovery::upstream_hostname,
+    index::{request_background_refresh, request_refresh},
+    offline::{refuse_cache_miss, CacheMiss},
+    platforms::AllowedPlatforms,
+    response_types::{RegistryDownload, RegistryPlatform, RegistryVersion, RegistryVersions},
+    version::build_url,
+};
+
+const SHASUMS_SUFFIX: &str = "/SHA256SUMS";
+const SHASUMS_SIGNATURE_SUFFIX: &str = "/SHA256SUMS.sig";
+
+pub(crate) async fn registry_versions_handler<C>(
+    State(AppState {
+        db_client: db,
+        refresher_tx: tx,
+        config: args,
+        ..
+    }): State<AppState<C>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path((namespace, provider_type)): Path<(String, String)>,
+) -> Result<Json<RegistryVersions>, TerrashineError> {
+    let provider = TerraformProvider {
+        hostname: upstream_hostname(&headers, &uri, peer.ip(), &args),
+        namespace,
+        provider_type,
+    };
+    let mut platforms = list_provider_platforms(&db, &provider).await?;
+    if args.is_private_hostname(&provider.hostname) {
+        if platforms.is_empty() {
+            return Err(TerrashineError::ProviderNotFound { provider });
+        }
+    } else if args.offline {
+        if platforms.is_empty() {
+            refuse_cache_miss(CacheMiss::ProviderIndex);
+            return Err(TerrashineError::ProviderNotFound { provider });
+        }
+    } else if platforms.is_empty() {
+        tracing::debug!(?provider, "Unknown provider requested, fetching upstream");
+        request_refresh(&tx, provider.clone()).await?;
+        platforms = list_provider_platforms(&db, &provider).await?;
+    } else {
+        request_background_refresh(&tx, provider.clone());
+    }
+    let allowed = AllowedPlatforms::for_request(&db, &args, &provider).await?;
+    platforms.retain(|row| allowed.contains(&row.os, &row.arch) && row.is_protocol_allowed(&args));
+    Ok(Json(RegistryVersions::from(platforms)))
+}
+
+pub(crate) async fn registry_download_handler<C: CredentialHelper>(
+    State(AppState {
+        db_client: db,
+        registry_client: registry,
+        config: args,
+        mirror_urls,
+        ..
+    }): State<AppState<C>>,
+    identity: Option<Extension<TokenIdentity>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    uri: Uri,
+    Path((namespace, provider_type, version, os, arch)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+) -> Result<Json<RegistryDownload>, StatusCode> {
+    let provider = TerraformProvider {
+        hostname: upstream_hostname(&headers, &uri, peer.ip(), &args),
+        namespace,
+        provider_type,
+    };
+    match AllowedPlatforms::for_request(&db, &args, &provider).await {
+        Ok(allowed) if allowed.contains(&os, &arch) => {}
+        Ok(_) => {
+            tracing::debug!(?provider, %version, %os, %arch, "Platform requested is not allowed");
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Err(e) => {
+            tracing::error!(reason = ?e, "Error querying database for allowed platforms");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    let version_id = match find_version_id(&db, &provider, &version, &os, &arch).await {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            tracing::debug!(?provider, %version, %os, %arch, "Provider package not found in database");
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Err(e) => {
+            tracing::error!(reason = ?e, "Error querying database for provider package");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let package = match get_provider_package(&db, version_id).await {
+        Ok(Some(package)) => package,
+        Ok(None) if args.is_private_hostname(&provider.hostname) => {
+            tracing::debug!(?provider, %version, %os, %arch, "Private provider package has not been published");
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(None) if args.offline => {
+            tracing::debug!(?provider, %version, %os, %arch, "Provider package metadata not cached");
+            refuse_cache_miss(CacheMiss::ProviderPackage);
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(None) => fetch_provider_package(
+            &db, &registry, &provider, version_id, &version, &os, &arch,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!(reason = ?e, "Error fetching provider package metadata from upstream");
+            StatusCode::BAD_GATEWAY
+        })?,
+        Err(e) => {
+            tracing::error!(reason = ?e, "Error querying database for provider package");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let signing_keys = serde_json::from_str(&package.signing_keys).map_err(|e| {
+        tracing::error!(reason = ?e, "Invalid signing keys stored in database");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let base_url = build_url(args.http_redirect_url.to_string(), version_id);
+    // Terraform does not send credentials for these URLs, so they are signed when required
+    let query = download_query(mirror_urls.as_ref(), identity.as_deref(), version_id);
+    Ok(Json(RegistryDownload {
+        protocols: package.protocols,
+        os,
+        arch,
+        filename: package.filename,
+        shasums_url: format!("{base_url}{SHASUMS_SUFFIX}{query}"),
+        shasums_signature_url: format!("{base_url}{SHASUMS_SIGNATURE_SUFFIX}{query}"),
+        download_url: format!("{base_url}{query}"),
+        shasum: package.shasum,
+        signing_keys,
+    }))
+}
+
+pub(crate) async fn shasums_handler<C>(
+    State(AppState { db_client: db, .. }): State<AppState<C>>,
+    Path(version_id): Path<i64>,
+) -> Result<Response, StatusCode> {
+    let row = sqlx::query!(
+        r#"
+        select "shasums" from "terraform_provider_package" where "version_id" = $1;
+        "#,
+        version_id
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|e| {
+        tracing::error!(reason = ?e, "Error querying database for provider checksums");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    Ok((
+        [(CONTENT_TYPE, HeaderValue::from_static("text/plain"))],
+        row.shasums,
+    )
+        .into_response())
+}
+
+pub(crate) async fn shasums_signature_handler<C>(
+    State(AppState { db_client: db, .. }): State<AppState<C>>,
+    Path(version_id): Path<i64>,
+) -> Result<Response, StatusCode> {
+    let row = sqlx::query!(
+        r#"
+        select "shasums_signature" from "terraform_provider_package" where "version_id" = $1;
+        "#,
+        version_id
+    )
+    .fetch_optional(&db)
+    .await
+    .map_err(|e| {
+        tracing::error!(reason = ?e, "Error querying database for provider checksum signature");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    Ok((
+        [(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        )],
+        row.shasums_signature,
+    )
+        .into_response())
+}
+
+struct PlatformRow {
+    version: String,
+    os: String,
+    arch: String,
+    protocols: Option<Vec<String>>,
+}
+
+impl PlatformRow {
+    /// Versions are filtered by plugin protocol the same way as the mirror index.
+    fn is_protocol_allowed(&self, args: &ServerArgs) -> bool {
+        args.is_protocol_allowed(self.protocols.as_deref().unwrap_or_default())
+    }
+}
+
+impl From<Vec<PlatformRow>> for RegistryVersions {
+    fn from(rows: Vec<PlatformRow>) -> Self {
+        let mut versions = BTreeMap::<String, RegistryVersion>::new();
+        for row in rows {
+            let entry = versions
+                .entry(row.version.clone())
+                .or_insert_with(|| RegistryVersion {
+                    version: row.version,
+                    protocols: vec![],
+                    platforms: vec![],
+                });
+            if let Some(protocols) = row.protocols {
+                if entry.protocols.is_empty() {
+                    entry.protocols = protocols;
+                }
+            }
+            entry.platforms.push(RegistryPlatform {
+                os: row.os,
+                arch: row.arch,
+            });
+        }
+        RegistryVersions {
+            versions: versions.into_values().collect(),
+        }
+    }
+}
+
+async fn list_provider_platforms(
+    db: &PgPool,
+    provider: &TerraformProvider,
+) -> Result<Vec<PlatformRow>, TerrashineError> {
+    let rows = sqlx::query_as!(
+        PlatformRow,
+        r#"
+        select
+            "terraform_provider_version"."version",
+            "terraform_provider_version"."os",
+            "terraform_provider_version"."arch",
+            -- Versions list protocols from the upstream index, packages once downloaded
+            coalesce(
+                "terraform_provider_version"."protocols",
+                "terraform_provider_package"."protocols"
+            ) as "protocols?"
+        from "terraform_provider_version"
+        inner join "terraform_provider" on
+            "terraform_provider_version"."provider_id" = "terraform_provider"."id"
+        left join "terraform_provider_package" on
+            "terraform_provider_package"."version_id" = "terraform_provider_version"."id"
+        where "terraform_provider"."hostname" = $1
+            and "terraform_provider"."namespace" = $2
+            and "terraform_provider"."type" = $3
+        order by "terraform_provider_version"."id";
+        "#,
+        provider.hostname,
+        provider.namespace,
+        provider.provider_type,
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows)
+}
+
+async fn find_version_id(
+    db: &PgPool,
+    provider: &TerraformProvider,
+    version: &str,
+    os: &str,
+    arch: &str,
+) -> Result<Option<i64>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        select "terraform_provider_version"."id"
+        from "terraform_provider_version"
+        inner join "terraform_provider" on
+            "terraform_provider_version"."provider_id" = "terraform_provider"."id"
+        where "terraform_provider"."hostname" = $1
+            and "terraform_provider"."namespace" = $2
+            and "terraform_provider"."type" = $3
+            and "terraform_provider_version"."version" = $4
+            and "terraform_provider_version"."os" = $5
+            and "terraform_provider_version"."arch" = $6;
+        "#,
+        provider.hostname,
+        provider.namespace,
+        provider.provider_type,
+        version,
+        os,
+        arch,
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(|r| r.id))
+}
+
+struct ProviderPackage {
+    protocols: Vec<String>,
+    filename: String,
+    shasum: String,
+    signing_keys: String,
+}
+
+async fn get_provider_package(
+    db: &PgPool,
+    version_id: i64,
+) -> Result<Option<ProviderPackage>, anyhow::Error> {
+    let package = sqlx::query_as!(
+        ProviderPackage,
+        r#"
+        select "protocols", "filename", "shasum", "signing_keys"::text as "signing_keys!"
+        from "terraform_provider_package"
+        where "version_id" = $1;
+        "#,
+        version_id
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(package)
+}
+
+async fn fetch_provider_package<T: CredentialHelper>(
+    db: &PgPool,
+    registry: &RegistryClient<T>,
+    provider: &TerraformProvider,
+    version_id: i64,
+    version: &str,
+    os: &str,
+    arch: &str,
+) -> Result<ProviderPackage, anyhow::Error> {
+    let path = format!(
+        "{}/{}/{version}/download/{os}/{arch}",
+        provider.namespace, provider.provider_type
+    );
+    let response: ProviderResponse = registry.provider_get(&provider.hostname, &path).await?;
+    let shasums = registry
+        .package_file(Some(&provider.hostname), response.shasums_url.clone())
+        .await?;
+    let shasums = String::from_utf8(shasums).context("Provider checksums are not valid UTF-8")?;
+    let shasums_signature = registry
+        .package_file(
+            Some(&provider.hostname),
+            response.shasums_signature_url.clone(),
+        )
+        .await?;
+    let signing_keys = serde_json::to_string(&response.signing_keys)?;
+
+    sqlx::query!(
+        r#"
+        insert into "terraform_provider_package"
+            ("version_id", "protocols", "filename", "shasum", "shasums", "shasums_signature", "signing_keys")
+        values ($1, $2, $3, $4, $5, $6, $7::text::jsonb)
+        on conflict do nothing;
+        "#,
+        version_id,
+        &response.protocols[..],
+        response.filename,
+        response.shasum,
+        shasums,
+        shasums_signature,
+        signing_keys,
+    )
+    .execute(db)
+    .await
+    .context("Writing provider package to database")?;
+    tracing::info!(?provider, %version, %os, %arch, "Saved provider package metadata to the database");
+
+    Ok(ProviderPackage {
+        protocols: response.protocols,
+        filename: response.filename,
+        shasum: response.shasum,
+        signing_keys,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::config::Args;
+
+    use super::*;
+
+    #[test]
+    fn test_versions_grouped_by_version() {
+        let row = |version: &str, os: &str, protocols: Option<Vec<String>>| PlatformRow {
+            version: version.into(),
+            os: os.into(),
+            arch: "amd64".into(),
+            protocols,
+        };
+        let versions = RegistryVersions::from(vec![
+            row("1.0.0", "linux", None),
+            row("1.0.0", "darwin", Some(vec!["5.0".into()])),
+            row("2.0.0", "linux", None),
+        ]);
+        assert_eq!(
+            versions,
+            RegistryVersions {
+                versions: vec![
+                    RegistryVersion {
+                        version: "1.0.0".into(),
+                        protocols: vec!["5.0".into()],
+                        platforms: vec![
+                            RegistryPlatform {
+                                os: "linux".into(),
+                                arch: "amd64".into()
+                            },
+                            RegistryPlatform {
+                                os: "darwin".into(),
+                                arch: "amd64".into()
+                            },
+                        ],
+                    },
+                    RegistryVersion {
+                        version: "2.0.0".into(),
+                        protocols: vec![],
+                        platforms: vec![RegistryPlatform {
+                            os: "linux".into(),
+                            arch: "amd64".into()
+                        }],
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_versions_filtered_by_protocol() {
+        let Args::Server(args) = Args::try_parse_from([
+            "./terrashine",
+            "server",
+            "--http-redirect-url",
+            "https://example.com/",
+            "--s3-bucket-name",
+            "terrashine",
+            "--allowed-protocols",
+            "5",
+        ])
+        .expect("Could not parse") else {
+            panic!("Expected server subcommand");
+        };
+        let row = |version: &str, protocols: Option<Vec<String>>| PlatformRow {
+            version: version.into(),
+            os: "linux".into(),
+            arch: "amd64".into(),
+            protocols,
+        };
+        let mut platforms = vec![
+            row("1.0.0", Some(vec!["4.0".into()])),
+            row("2.0.0", Some(vec!["5.0".into()])),
+            row("3.0.0", None),
+        ];
+        platforms.retain(|row| row.is_protocol_allowed(&args));
+        let versions = RegistryVersions::from(platforms)
+            .versions
+            .into_iter()
+            .map(|version| version.version)
+            .collect::<Vec<_>>();
+        assert_eq!(versions, vec!["2.0.0", "3.0.0"]);
+    }
+}
@@ -0,0 +1,602 @@
+use crate::{
+    app::AppState,
+    credhelper::CredentialHelper,
+    error::TerrashineError,
+    http::{
+        artifacts::{
+            allocate_artifact_id, artifact_s3_key, presign_artifact, upload_artifact,
+            ArtifactResponse,
+        },
+        modules::upstream_status,
+        offline::{refuse_cache_miss, CacheMiss},
+    },
+    registry::RegistryClient,
+    signing::SignatureVerifier,
+};
+use anyhow::Context;
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+};
+use http::{header::CONTENT_TYPE, HeaderValue, StatusCode};
+use serde_json::Value;
+use sqlx::PgPool;
+use std::time::Duration;
+use url::Url;
+
+#[derive(Debug, Clone)]
+struct Release<'a> {
+    product: &'a str,
+    version: &'a str,
+}
+
+impl Release<'_> {
+    fn shasums_filename(&self) -> String {
+        format!("{}_{}_SHA256SUMS", self.product, self.version)
+    }
+
+    fn upstream_url(&self, upstream: &Url, filename: &str) -> Result<Url, TerrashineError> {
+        upstream
+            .join(&format!("{}/{}/{filename}", self.product, self.version))
+            .context("Building release URL")
+            .map_err(Into::into)
+    }
+}
+
+struct ReleaseVersion {
+    id: i64,
+    shasums: String,
+    shasums_signature: Vec<u8>,
+}
+
+pub(crate) async fn release_index_handler<C>(
+    State(AppState {
+        db_client: db,
+        registry_client: registry,
+        config: args,
+        ..
+    }): State<AppState<C>>,
+    Path(product): Path<String>,
+) -> Result<Response, StatusCode> {
+    if !is_valid_segment(&product) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let url = args
+        .releases_upstream_url
+        .join(&format!("{product}/index.json"))
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    serve_index(
+        &db,
+        &registry,
+        &args.http_redirect_url,
+        args.refresh_interval,
+        args.offline,
+        &product,
+        "",
+        url,
+    )
+    .await
+}
+
+pub(crate) async fn release_version_index_handler<C>(
+    State(AppState {
+        db_client: db,
+        registry_client: registry,
+        config: args,
+        ..
+    }): State<AppState<C>>,
+    Path((product, version)): Path<(String, String)>,
+) -> Result<Response, StatusCode> {
+    if !is_valid_segment(&product) || !is_valid_segment(&version) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let release = Release {
+        product: &product,
+        version: &version,
+    };
+    let url = release
+        .upstream_url(&args.releases_upstream_url, "index.json")
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    serve_index(
+        &db,
+        &registry,
+        &args.http_redirect_url,
+        args.refresh_interval,
+        args.offline,
+        &product,
+        &version,
+        url,
+    )
+    .await
+}
+
+pub(crate) async fn release_file_handler<C>(
+    State(AppState {
+        db_client: db,
+        registry_client: registry,
+        s3_client: s3,
+        config: args,
+        release_verifier: verifier,
+        ..
+    }): State<AppState<C>>,
+    Path((product, version, filename)): Path<(String, String, String)>,
+) -> Result<Response, StatusCode> {
+    if ![&product, &version, &filename]
+        .iter()
+        .all(|segment| is_valid_segment(segment))
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let Some(verifier) = verifier else {
+        tracing::debug!("No release signing keys configured, refusing to serve releases");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let release = Release {
+        product: &product,
+        version: &version,
+    };
+    let release_version = match get_release_version(&db, &release).await {
+        Ok(Some(release_version)) => release_version,
+        Ok(None) if args.offline => {
+            tracing::debug!(?release, "Release checksums not cached");
+            refuse_cache_miss(CacheMiss::ReleaseChecksums);
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(None) => fetch_release_version(
+            &db,
+            &registry,
+            &verifier,
+            &args.releases_upstream_url,
+            &release,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!(reason = %e, ?release, "Error fetching release checksums from upstream");
+            upstream_status(&e)
+        })?,
+        Err(e) => {
+            tracing::error!(reason = ?e, "Error querying database for release");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let shasums_filename = release.shasums_filename();
+    if filename == shasums_filename {
+        return Ok((
+            [(CONTENT_TYPE, HeaderValue::from_static("text/plain"))],
+            release_version.shasums,
+        )
+            .into_response());
+    }
+    if filename == format!("{shasums_filename}.sig") {
+        return Ok((
+            [(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            )],
+            release_version.shasums_signature,
+        )
+            .into_response());
+    }
+
+    // Only archives listed in the verified checksums are cached
+    let Some(expected_shasum) = find_shasum(&release_version.shasums, &filename) else {
+        tracing::debug!(?release, %filename, "File is not listed in release checksums");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let artifact_id = match get_release_artifact(&db, release_version.id, &filename).await {
+        Ok(Some(artifact_id)) => artifact_id,
+        Ok(None) if args.offline => {
+            tracing::debug!(?release, %filename, "Release archive not cached");
+            refuse_cache_miss(CacheMiss::ReleaseArtifact);
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(None) => stash_release_artifact(
+            &db,
+            &registry,
+            &s3,
+            &args.s3_bucket_name,
+            &args.s3_bucket_prefix,
+            release_version.id,
+            release
+                .upstream_url(&args.releases_upstream_url, &filename)
+                .map_err(|_| StatusCode::NOT_FOUND)?,
+            &filename,
+            expected_shasum,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!(reason = ?e, "Error occurred caching release archive");
+            StatusCode::BAD_GATEWAY
+        })?,
+        Err(e) => {
+            tracing::error!(reason = ?e, "Error querying database for release archive");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let key = artifact_s3_key(&args.s3_bucket_prefix, artifact_id);
+    let uri = presign_artifact(&s3, &args.s3_bucket_name, &key)
+        .await
+        .map_err(|e| {
+            tracing::error!(reason = ?e, "Error presigning url");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(ArtifactResponse::new(uri).into_response())
+}
+
+/// Serves an index document, refreshing it from upstream once the refresh interval has passed.
+///
+/// The last known document is served if the upstream is unavailable, and is never
+/// refreshed when offline.
+#[allow(clippy::too_many_arguments)]
+async fn serve_index<T: CredentialHelper>(
+    db: &PgPool,
+    registry: &RegistryClient<T>,
+    base_url: &Url,
+    refresh_interval: Duration,
+    offline: bool,
+    product: &str,
+    version: &str,
+    url: Url,
+) -> Result<Response, StatusCode> {
+    let cached = get_release_index(db, product, version, refresh_interval).await;
+    let body = match cached {
+        Ok(Some((true, body))) => body,
+        Ok(Some((false, body))) if offline => body,
+        Ok(None) if offline => {
+            refuse_cache_miss(CacheMiss::ReleaseIndex);
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Err(error) if offline => {
+            tracing::error!(reason = %error, "Error occurred fetching release index from database");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        cached => {
+            if let Err(error) = &cached {
+                tracing::warn!(reason = %error, "Error occurred fetching release index from database, fetching upstream");
+            }
+            match registry.release_index(url).await {
+                Ok(mut index) => {
+                    rewrite_build_urls(&mut index, base_url);
+                    let body = index.to_string();
+                    if let Err(error) = store_release_index(db, product, version, &body).await {
+                        tracing::error!(reason = %error, "Error saving release index to database");
+                    }
+                    body
+                }
+                Err(error) => match cached {
+                    Ok(Some((_, body))) => {
+                        tracing::warn!(reason = %error, "Could not refresh release index, serving stale index");
+                        body
+                    }
+                    _ => {
+                        tracing::error!(reason = %error, %product, %version, "Error fetching release index from upstream");
+                        return Err(upstream_status(&error));
+                    }
+                },
+            }
+        }
+    };
+    Ok((
+        [(CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+        body,
+    )
+        .into_response())
+}
+
+/// Points the download URL of every build in an index document at terrashine.
+///
+/// Handles both product indexes, which contain every release, and release indexes.
+/// Releases are served from the root of the redirect URL's origin, not under its
+/// `/mirror/v1/` path.
+fn rewrite_build_urls(index: &mut Value, base_url: &Url) {
+    match index.get_mut("versions").and_then(Value::as_object_mut) {
+        Some(releases) => releases
+            .values_mut()
+            .for_each(|release| rewrite_release_build_urls(release, base_url)),
+        None => rewrite_release_build_urls(index, base_url),
+    }
+}
+
+fn rewrite_release_build_urls(release: &mut Value, base_url: &Url) {
+    let (Some(product), Some(version)) = (
+        release
+            .get("name")
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+        release
+            .get("version")
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+    ) else {
+        return;
+    };
+    let Some(builds) = release.get_mut("builds").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for build in builds.iter_mut() {
+        let Some(filename) = build.get("filename").and_then(Value::as_str) else {
+            continue;
+        };
+        let mut url = base_url.clone();
+        url.set_path(&format!("/releases/{product}/{version}/{filename}"));
+        url.set_query(None);
+        url.set_fragment(None);
+        build["url"] = Value::String(url.into());
+    }
+}
+
+/// Finds the checksum of a file in a checksum file produced by `sha256sum`.
+fn find_shasum<'a>(shasums: &'a str, filename: &str) -> Option<&'a str> {
+    shasums.lines().find_map(|line| {
+        let (shasum, name) = line.split_once(char::is_whitespace)?;
+        // Binary mode entries prefix the file name with an asterisk
+        let name = name.trim_start();
+        let name = name.strip_prefix('*').unwrap_or(name);
+        (name == filename).then_some(shasum)
+    })
+}
+
+/// Restricts path segments to characters used in release names, preventing path traversal upstream.
+fn is_valid_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && !segment.starts_with('.')
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+'))
+}
+
+async fn get_release_index(
+    db: &PgPool,
+    product: &str,
+    version: &str,
+    refresh_interval: Duration,
+) -> Result<Option<(bool, String)>, TerrashineError> {
+    let row = sqlx::query!(
+        r#"
+        select
+            "last_refreshed" > now() - $3 * interval '1 second' as "fresh!",
+            "body"
+        from "release_index"
+        where "product" = $1 and "version" = $2;
+        "#,
+        product,
+        version,
+        refresh_interval.as_secs_f64(),
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(|row| (row.fresh, row.body)))
+}
+
+async fn store_release_index(
+    db: &PgPool,
+    product: &str,
+    version: &str,
+    body: &str,
+) -> Result<(), TerrashineError> {
+    sqlx::query!(
+        r#"
+        insert into "release_index" ("product", "version", "body", "last_refreshed")
+        values ($1, $2, $3, now())
+        on conflict ("product", "version")
+            do update set "body" = "excluded"."body", "last_refreshed" = "excluded"."last_refreshed";
+        "#,
+        product,
+        version,
+        body,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+async fn get_release_version(
+    db: &PgPool,
+    release: &Release<'_>,
+) -> Result<Option<ReleaseVersion>, TerrashineError> {
+    let row = sqlx::query_as!(
+        ReleaseVersion,
+        r#"
+        select "id", "shasums", "shasums_signature"
+        from "release_version"
+        where "product" = $1 and "version" = $2;
+        "#,
+        release.product,
+        release.version,
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(row)
+}
+
+/// Downloads and verifies the checksums of a release before recording them.
+async fn fetch_release_version<T: CredentialHelper>(
+    db: &PgPool,
+    registry: &RegistryClient<T>,
+    verifier: &SignatureVerifier,
+    upstream: &Url,
+    release: &Release<'_>,
+) -> Result<ReleaseVersion, TerrashineError> {
+    let shasums_filename = release.shasums_filename();
+    let shasums = registry
+        .package_file(None, release.upstream_url(upstream, &shasums_filename)?)
+        .await?;
+    let shasums_signature = registry
+        .package_file(
+            None,
+            release.upstream_url(upstream, &format!("{shasums_filename}.sig"))?,
+        )
+        .await?;
+    if !verifier.verify(&shasums, &shasums_signature) {
+        return Err(TerrashineError::SignatureVerificationFailure {
+            name: shasums_filename,
+        });
+    }
+    let shasums = String::from_utf8(shasums).context("Release checksums are not valid UTF-8")?;
+
+    let row = sqlx::query!(
+        r#"
+        insert into "release_version" ("product", "version", "shasums", "shasums_signature")
+        values ($1, $2, $3, $4)
+        on conflict ("product", "version")
+            do update set "shasums" = "excluded"."shasums",
+                "shasums_signature" = "excluded"."shasums_signature"
+        returning "id";
+        "#,
+        release.product,
+        release.version,
+        shasums,
+        shasums_signature,
+    )
+    .fetch_one(db)
+    .await?;
+    tracing::info!(?release, "Saved release checksums to the database");
+    Ok(ReleaseVersion {
+        id: row.id,
+        shasums,
+        shasums_signature,
+    })
+}
+
+async fn get_release_artifact(
+    db: &PgPool,
+    release_version_id: i64,
+    filename: &str,
+) -> Result<Option<i64>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        select "artifact_id" from "release_artifact"
+        where "release_version_id" = $1 and "filename" = $2;
+        "#,
+        release_version_id,
+        filename,
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(|row| row.artifact_id))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn stash_release_artifact<T: CredentialHelper>(
+    db: &PgPool,
+    registry: &RegistryClient<T>,
+    s3: &aws_sdk_s3::Client,
+    bucket_name: &str,
+    bucket_prefix: &str,
+    release_version_id: i64,
+    url: Url,
+    filename: &str,
+    expected_shasum: &str,
+) -> Result<i64, anyhow::Error> {
+    let artifact_id = allocate_artifact_id(db).await?;
+    let stream = registry.download(None, url).await?.bytes_stream();
+    let key = artifact_s3_key(bucket_prefix, artifact_id);
+    upload_artifact(
+        s3,
+        bucket_name,
+        &key,
+        Some(expected_shasum),
+        Box::pin(stream),
+    )
+    .await?;
+
+    // A concurrent request may have already cached the same archive, in which
+    // case the first stored artifact wins.
+    let row = sqlx::query!(
+        r#"
+        insert into "release_artifact"
+            ("release_version_id", "filename", "artifact_id", "artifact_timestamp")
+        values ($1, $2, $3, now())
+        on conflict ("release_version_id", "filename")
+            do update set "filename" = "excluded"."filename"
+        returning "artifact_id";
+        "#,
+        release_version_id,
+        filename,
+        artifact_id,
+    )
+    .fetch_one(db)
+    .await
+    .with_context(|| format!("Writing release artifact id({artifact_id}) to database"))?;
+    Ok(row.artifact_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_release_index() {
+        let mut index = serde_json::json!({
+            "name": "terraform",
+            "version": "1.5.7",
+            "shasums": "terraform_1.5.7_SHA256SUMS",
+            "builds": [{
+                "name": "terraform",
+                "version": "1.5.7",
+                "os": "linux",
+                "arch": "amd64",
+                "filename": "terraform_1.5.7_linux_amd64.zip",
+                "url": "https://releases.hashicorp.com/terraform/1.5.7/terraform_1.5.7_linux_amd64.zip"
+            }]
+        });
+        rewrite_build_urls(
+            &mut index,
+            &Url::parse("https://mirror.example.com/mirror/v1/").unwrap(),
+        );
+        assert_eq!(
+            index["builds"][0]["url"],
+            "https://mirror.example.com/releases/terraform/1.5.7/terraform_1.5.7_linux_amd64.zip"
+        );
+        assert_eq!(index["shasums"], "terraform_1.5.7_SHA256SUMS");
+    }
+
+    #[test]
+    fn test_rewrite_product_index() {
+        let mut index = serde_json::json!({
+            "name": "terraform",
+            "versions": {
+                "1.5.7": {
+                    "name": "terraform",
+                    "version": "1.5.7",
+                    "builds": [{
+                        "filename": "terraform_1.5.7_darwin_arm64.zip",
+                        "url": "https://releases.hashicorp.com/terraform/1.5.7/terraform_1.5.7_darwin_arm64.zip"
+                    }]
+                }
+            }
+        });
+        rewrite_build_urls(
+            &mut index,
+            &Url::parse("https://mirror.example.com/mirror/v1/").unwrap(),
+        );
+        assert_eq!(
+            index["versions"]["1.5.7"]["builds"][0]["url"],
+            "https://mirror.example.com/releases/terraform/1.5.7/terraform_1.5.7_darwin_arm64.zip"
+        );
+    }
+
+    #[test]
+    fn test_find_shasum() {
+        let shasums =
+            "abc  terraform_1.5.7_darwin_arm64.zip\ndef *terraform_1.5.7_linux_amd64.zip\n";
+        assert_eq!(
+            find_shasum(shasums, "terraform_1.5.7_darwin_arm64.zip"),
+            Some("abc")
+        );
+        assert_eq!(
+            find_shasum(shasums, "terraform_1.5.7_linux_amd64.zip"),
+            Some("def")
+        );
+        assert_eq!(find_shasum(shasums, "terraform_1.5.7_linux_arm.zip"), None);
+    }
+
+    #[test]
+    fn test_valid_segment() {
+        assert!(is_valid_segment("terraform_1.5.7+ent_linux_amd64.zip"));
+        assert!(!is_valid_segment(".."));
+        assert!(!is_valid_segment("terraform/1.5.7"));
+        assert!(!is_valid_segment(""));
+    }
+}
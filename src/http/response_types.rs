@@ -9,15 +9,83 @@ pub(crate) struct MirrorVersion {
 #[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct TargetPlatformIdentifier {
     pub(crate) url: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) hashes: Vec<String>,
 }
 
 /// Index response from terrashine mirror registry
 #[derive(Serialize, Debug)]
 pub(crate) struct MirrorIndex {
-    // TODO: the nested hash value is always empty, we should implement
-    // custom serialize to avoid unneeded work.
-    pub(crate) versions: HashMap<String, HashMap<String, String>>,
+    pub(crate) versions: HashMap<String, MirrorIndexVersion>,
+}
+
+/// Per version object of the index response.
+///
+/// The protocol reserves this object for future use and clients ignore its contents,
+/// terrashine uses it to pass known plugin protocols on to chained mirrors.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct MirrorIndexVersion {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) protocols: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct Empty;
+
+/// Versions response from terrashine module registry
+#[derive(Serialize, Debug)]
+pub(crate) struct ModuleIndex {
+    pub(crate) modules: Vec<ModuleIndexVersions>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ModuleIndexVersions {
+    pub(crate) versions: Vec<ModuleIndexVersion>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ModuleIndexVersion {
+    pub(crate) version: String,
+}
+
+/// Terraform remote service discovery document served by terrashine
+#[derive(Serialize, Debug)]
+pub(crate) struct ServiceDiscovery {
+    #[serde(rename = "modules.v1")]
+    pub(crate) modules_v1: &'static str,
+    #[serde(rename = "providers.v1")]
+    pub(crate) providers_v1: &'static str,
+}
+
+/// Provider registry protocol response for "List Available Versions"
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub(crate) struct RegistryVersions {
+    pub(crate) versions: Vec<RegistryVersion>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub(crate) struct RegistryVersion {
+    pub(crate) version: String,
+    pub(crate) protocols: Vec<String>,
+    pub(crate) platforms: Vec<RegistryPlatform>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub(crate) struct RegistryPlatform {
+    pub(crate) os: String,
+    pub(crate) arch: String,
+}
+
+/// Provider registry protocol response for "Find a Provider Package"
+#[derive(Serialize, Debug)]
+pub(crate) struct RegistryDownload {
+    pub(crate) protocols: Vec<String>,
+    pub(crate) os: String,
+    pub(crate) arch: String,
+    pub(crate) filename: String,
+    pub(crate) download_url: String,
+    pub(crate) shasums_url: String,
+    pub(crate) shasums_signature_url: String,
+    pub(crate) shasum: String,
+    pub(crate) signing_keys: serde_json::Value,
+}
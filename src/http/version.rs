@@ -1,7 +1,8 @@
-use crate::app::AppState;
+use crate::{apitoken::TokenIdentity, app::AppState, refresh::TerraformProvider};
 use axum::{
     extract::{Path, State},
     response::{IntoResponse, Response},
+    Extension,
 };
 use http::{header::CONTENT_TYPE, HeaderMap, HeaderValue};
 use hyper::StatusCode;
@@ -10,28 +11,47 @@ use sqlx::PgPool;
 use std::collections::HashMap;
 use tokio_stream::StreamExt;
 
-use super::response_types::{MirrorVersion, TargetPlatformIdentifier};
+use super::{
+    auth::download_query,
+    platforms::AllowedPlatforms,
+    response_types::{MirrorVersion, TargetPlatformIdentifier},
+};
 
 pub(crate) async fn version_handler<C>(
     State(AppState {
         db_client: db,
         config: args,
+        mirror_urls,
         ..
     }): State<AppState<C>>,
+    identity: Option<Extension<TokenIdentity>>,
     Path((hostname, namespace, provider_type, version)): Path<(String, String, String, Version)>,
 ) -> Result<MirrorVersion, StatusCode> {
     let downloads_result =
         list_downloads(&db, &hostname, &namespace, &provider_type, version.prefix()).await;
-    let downloads = match downloads_result {
+    let mut downloads = match downloads_result {
         Ok(d) => d,
         Err(e) => {
             tracing::error!(reason=?e,"Error occured querying database");
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
+    let provider = TerraformProvider {
+        hostname,
+        namespace,
+        provider_type,
+    };
+    let platforms = AllowedPlatforms::for_request(&db, &args, &provider)
+        .await
+        .map_err(|e| {
+            tracing::error!(reason=?e, "Error occured querying database for allowed platforms");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    downloads.retain(|download| platforms.contains(&download.os, &download.arch));
     Ok(MirrorVersion::build(
         downloads,
         args.http_redirect_url.as_str(),
+        |id| download_query(mirror_urls.as_ref(), identity.as_deref(), id),
     ))
 }
 
@@ -62,7 +82,7 @@ impl TryFrom<String> for Version {
     }
 }
 
-fn archive_name(os: &str, arch: &str) -> String {
+pub(crate) fn archive_name(os: &str, arch: &str) -> String {
     let mut s = String::with_capacity(os.len() + 1 + arch.len());
     s.push_str(os);
     s.push('_');
@@ -70,7 +90,7 @@ fn archive_name(os: &str, arch: &str) -> String {
     s
 }
 
-fn build_url(base_url: String, id: i64) -> String {
+pub(crate) fn build_url(base_url: String, id: i64) -> String {
     let mut s = base_url;
     s.push_str("artifacts/");
     s.push_str(&id.to_string());
@@ -81,15 +101,39 @@ struct DatabaseDownloadResult {
     os: String,
     arch: String,
     id: i64,
+    /// sha256 checksum of the package, once known
+    shasum: Option<String>,
 }
 
 impl MirrorVersion {
-    fn build(result: Vec<DatabaseDownloadResult>, base_url: &str) -> Self {
+    /// Lists the archives of a version, appending the query for each version to its URL.
+    fn build(
+        result: Vec<DatabaseDownloadResult>,
+        base_url: &str,
+        query: impl Fn(i64) -> String,
+    ) -> Self {
         let mut archives = HashMap::new();
-        for DatabaseDownloadResult { os, arch, id } in result.iter() {
-            let target = archive_name(os, arch);
-            let url = build_url(base_url.to_string(), *id);
-            archives.insert(target, TargetPlatformIdentifier { url });
+        for DatabaseDownloadResult {
+            os,
+            arch,
+            id,
+            shasum,
+        } in result
+        {
+            let target = archive_name(&os, &arch);
+            let mut url = build_url(base_url.to_string(), id);
+            url.push_str(&query(id));
+            archives.insert(
+                target,
+                TargetPlatformIdentifier {
+                    url,
+                    // Lets clients and child mirrors verify the package once it is cached
+                    hashes: shasum
+                        .map(|shasum| format!("zh:{shasum}"))
+                        .into_iter()
+                        .collect(),
+                },
+            );
         }
         Self { archives }
     }
@@ -124,10 +168,19 @@ async fn list_downloads(
     tracing::trace!(?hostname, ?namespace, ?provider_type, ?version);
     let query = sqlx::query!(
         r#"
-        select "terraform_provider_version"."id", "os", "arch"
+        select
+            "terraform_provider_version"."id",
+            "os",
+            "arch",
+            coalesce(
+                "terraform_provider_version"."artifact_shasum",
+                "terraform_provider_package"."shasum"
+            ) as "shasum?"
         from "terraform_provider_version"
         inner join "terraform_provider" on
             "terraform_provider_version"."provider_id" = "terraform_provider"."id"
+        left join "terraform_provider_package" on
+            "terraform_provider_package"."version_id" = "terraform_provider_version"."id"
         where
             "terraform_provider_version"."version" = $1
             and "terraform_provider"."hostname" = $2
@@ -146,7 +199,52 @@ async fn list_downloads(
             id: row.id,
             os: row.os,
             arch: row.arch,
+            shasum: row.shasum,
         });
     }
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_lists_known_checksums() {
+        let version = MirrorVersion::build(
+            vec![
+                DatabaseDownloadResult {
+                    os: "linux".into(),
+                    arch: "amd64".into(),
+                    id: 1,
+                    shasum: Some("abc".into()),
+                },
+                DatabaseDownloadResult {
+                    os: "darwin".into(),
+                    arch: "arm64".into(),
+                    id: 2,
+                    shasum: None,
+                },
+            ],
+            "https://mirror.example.com/mirror/v1/",
+            |id| {
+                if id == 2 {
+                    "?signature=x".into()
+                } else {
+                    String::new()
+                }
+            },
+        );
+        let linux = &version.archives["linux_amd64"];
+        assert_eq!(
+            linux.url,
+            "https://mirror.example.com/mirror/v1/artifacts/1"
+        );
+        assert_eq!(linux.hashes, vec!["zh:abc"]);
+        assert!(version.archives["darwin_arm64"].hashes.is_empty());
+        assert_eq!(
+            version.archives["darwin_arm64"].url,
+            "https://mirror.example.com/mirror/v1/artifacts/2?signature=x"
+        );
+    }
+}
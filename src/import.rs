@@ -0,0 +1,469 @@
+use anyhow::Context;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use std::{
+    fs::File,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::ImportArgs,
+    export::ProviderAddress,
+    http::{
+        artifacts::{allocate_artifact_id, artifact_s3_key, upload_artifact},
+        response_types::MirrorVersion,
+        version::archive_name,
+    },
+    publish::PackageName,
+    s3_client,
+};
+
+/// Recorded as the upstream source of imported packages.
+const ARTIFACT_SOURCE: &str = "import";
+
+/// Provider package found in a packed filesystem mirror.
+#[derive(Debug, PartialEq, Eq)]
+struct MirrorPackage {
+    provider: ProviderAddress,
+    version: String,
+    os: String,
+    arch: String,
+    path: PathBuf,
+    hashes: Vec<String>,
+}
+
+/// Finds every provider package in a packed layout mirror, in the form
+/// {hostname}/{namespace}/{type}/terraform-provider-{type}_{version}_{os}_{arch}.zip
+fn scan_mirror(root: &Path) -> Result<Vec<MirrorPackage>, anyhow::Error> {
+    let mut packages = vec![];
+    for hostname in subdirectories(root)? {
+        for namespace in subdirectories(&hostname)? {
+            for type_dir in subdirectories(&namespace)? {
+                let provider = ProviderAddress {
+                    hostname: file_name(&hostname).to_ascii_lowercase(),
+                    namespace: file_name(&namespace).to_ascii_lowercase(),
+                    provider_type: file_name(&type_dir).to_ascii_lowercase(),
+                };
+                packages.extend(scan_provider(&type_dir, provider)?);
+            }
+        }
+    }
+    packages.sort_by(|a, b| {
+        (&a.provider, &a.version, &a.os, &a.arch).cmp(&(&b.provider, &b.version, &b.os, &b.arch))
+    });
+    Ok(packages)
+}
+
+fn scan_provider(
+    type_dir: &Path,
+    provider: ProviderAddress,
+) -> Result<Vec<MirrorPackage>, anyhow::Error> {
+    let mut packages = vec![];
+    for entry in std::fs::read_dir(type_dir)
+        .with_context(|| format!("Reading directory {}", type_dir.display()))?
+    {
+        let path = entry?.path();
+        if path.is_dir() {
+            warn!(path = %path.display(), "Unpacked mirror layout is not supported, skipping");
+            continue;
+        }
+        let Some(package) = PackageName::parse(file_name(&path)) else {
+            if path.extension().is_some_and(|ext| ext == "zip") {
+                warn!(path = %path.display(), "Unrecognised provider package name, skipping");
+            }
+            continue;
+        };
+        if !package
+            .provider_type
+            .eq_ignore_ascii_case(&provider.provider_type)
+        {
+            warn!(path = %path.display(), "Provider package does not match its directory, skipping");
+            continue;
+        }
+        let hashes = read_version_hashes(type_dir, &package)?;
+        packages.push(MirrorPackage {
+            provider: provider.clone(),
+            version: package.version,
+            os: package.os,
+            arch: package.arch,
+            path,
+            hashes,
+        });
+    }
+    Ok(packages)
+}
+
+/// Hashes recorded for a package in the {version}.json file next to it.
+fn read_version_hashes(
+    type_dir: &Path,
+    package: &PackageName,
+) -> Result<Vec<String>, anyhow::Error> {
+    let path = type_dir.join(format!("{}.json", package.version));
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let contents = std::fs::read(&path).with_context(|| format!("Reading {}", path.display()))?;
+    let mut version: MirrorVersion =
+        serde_json::from_slice(&contents).with_context(|| format!("Parsing {}", path.display()))?;
+    Ok(version
+        .archives
+        .remove(&archive_name(&package.os, &package.arch))
+        .map(|archive| archive.hashes)
+        .unwrap_or_default())
+}
+
+fn subdirectories(path: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut directories = vec![];
+    for entry in
+        std::fs::read_dir(path).with_context(|| format!("Reading directory {}", path.display()))?
+    {
+        let path = entry?.path();
+        if path.is_dir() {
+            directories.push(path);
+        }
+    }
+    Ok(directories)
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+/// Hash of the files inside a provider package, as recorded by Terraform in
+/// dependency lock files and filesystem mirrors.
+///
+/// This is the dirhash "h1:" scheme: a sha256 over the sorted list of
+/// "{sha256}  {name}\n" lines for every file in the archive.
+fn package_hash_h1(data: &[u8]) -> Result<String, anyhow::Error> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).context("Reading zip archive")?;
+    let mut names = archive.file_names().map(String::from).collect::<Vec<_>>();
+    names.sort();
+    let mut summary = Sha256::new();
+    for name in names {
+        anyhow::ensure!(!name.contains('\n'), "File names must not contain newlines");
+        let mut file = archive.by_name(&name)?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        summary.update(format!("{}  {name}\n", hex::encode(hasher.finalize())));
+    }
+    Ok(format!("h1:{}", BASE64_STANDARD.encode(summary.finalize())))
+}
+
+/// Checks a package against its recorded hashes.
+///
+/// Returns whether any hash was verified, hash schemes other than "zh:" and
+/// "h1:" are ignored.
+fn verify_hashes(data: &[u8], shasum: &str, hashes: &[String]) -> Result<bool, anyhow::Error> {
+    let mut verified = false;
+    let mut h1 = None;
+    for hash in hashes {
+        let matches = if let Some(expected) = hash.strip_prefix("zh:") {
+            expected.eq_ignore_ascii_case(shasum)
+        } else if hash.starts_with("h1:") {
+            if h1.is_none() {
+                h1 = Some(package_hash_h1(data)?);
+            }
+            h1.as_deref() == Some(hash.as_str())
+        } else {
+            debug!(%hash, "Skipping unsupported hash scheme");
+            continue;
+        };
+        anyhow::ensure!(matches, "Package does not match hash {hash}");
+        verified = true;
+    }
+    Ok(verified)
+}
+
+pub async fn run_import(args: ImportArgs) -> Result<(), ()> {
+    match import(args).await {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            error!("{:#}", e);
+            Err(())
+        }
+    }
+}
+
+async fn import(args: ImportArgs) -> Result<(), anyhow::Error> {
+    // Tarballs are unpacked to a temporary directory removed once the import finishes
+    let unpacked;
+    let root = match (&args.input_dir, &args.input_tarball) {
+        (Some(dir), _) => dir.as_path(),
+        (None, Some(tarball)) => {
+            unpacked = tempfile::tempdir().context("Creating temporary directory")?;
+            let file =
+                File::open(tarball).with_context(|| format!("Opening {}", tarball.display()))?;
+            tar::Archive::new(GzDecoder::new(file))
+                .unpack(unpacked.path())
+                .with_context(|| format!("Unpacking {}", tarball.display()))?;
+            unpacked.path()
+        }
+        (None, None) => anyhow::bail!("An input directory or tarball is required"),
+    };
+
+    let packages = scan_mirror(root)?;
+    anyhow::ensure!(!packages.is_empty(), "No provider packages found");
+
+    let db = PgPoolOptions::new()
+        .max_connections(1)
+        .connect_with(args.store.database_url.clone())
+        .await
+        .context("Connecting to database")?;
+    let s3 = s3_client(args.store.s3_endpoint.as_ref()).await;
+
+    let mut imported = 0;
+    for package in packages.iter() {
+        let filename = file_name(&package.path);
+        if is_cached(&db, package).await? {
+            info!(provider = ?package.provider, version = %package.version, %filename, "Provider package already cached, skipping");
+            continue;
+        }
+        let mut data = vec![];
+        File::open(&package.path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .with_context(|| format!("Reading {}", package.path.display()))?;
+        let shasum = hex::encode(Sha256::digest(&data));
+        let verified = verify_hashes(&data, &shasum, &package.hashes)
+            .with_context(|| format!("Verifying {}", package.path.display()))?;
+        if !verified {
+            anyhow::ensure!(
+                args.allow_unverified,
+                "No hashes recorded for {}, use --allow-unverified to import it anyway",
+                package.path.display()
+            );
+            warn!(provider = ?package.provider, version = %package.version, %filename, "Importing provider package without verifying hashes");
+        }
+
+        let artifact_id = allocate_artifact_id(&db).await?;
+        let key = artifact_s3_key(&args.store.s3_bucket_prefix, artifact_id);
+        let stream = Box::pin(futures::stream::once(async move {
+            Ok::<_, reqwest::Error>(data.into())
+        }));
+        upload_artifact(&s3, &args.store.s3_bucket_name, &key, Some(&shasum), stream)
+            .await
+            .with_context(|| format!("Uploading {filename}"))?;
+        store_package(&db, package, artifact_id, &shasum).await?;
+        info!(provider = ?package.provider, version = %package.version, %filename, "Imported provider package");
+        imported += 1;
+    }
+    info!(
+        count = imported,
+        skipped = packages.len() - imported,
+        "Import complete"
+    );
+    Ok(())
+}
+
+async fn is_cached(db: &PgPool, package: &MirrorPackage) -> Result<bool, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"
+        select "terraform_provider_version"."artifact_id"
+        from "terraform_provider_version"
+        inner join "terraform_provider" on
+            "terraform_provider_version"."provider_id" = "terraform_provider"."id"
+        where
+            "terraform_provider"."hostname" = $1
+            and "terraform_provider"."namespace" = $2
+            and "terraform_provider"."type" = $3
+            and "terraform_provider_version"."version" = $4
+            and "terraform_provider_version"."os" = $5
+            and "terraform_provider_version"."arch" = $6;
+        "#,
+        package.provider.hostname,
+        package.provider.namespace,
+        package.provider.provider_type,
+        package.version,
+        package.os,
+        package.arch,
+    )
+    .fetch_optional(db)
+    .await
+    .context("Reading provider version from database")?;
+    Ok(row.is_some_and(|row| row.artifact_id.is_some()))
+}
+
+async fn store_package(
+    db: &PgPool,
+    package: &MirrorPackage,
+    artifact_id: i64,
+    shasum: &str,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = db.begin().await?;
+    // New providers are due a refresh, so an online mirror still lists the upstream versions
+    let provider = sqlx::query!(
+        r#"
+        insert into "terraform_provider"
+            ("hostname", "namespace", "type", "last_refreshed")
+        values ($1, $2, $3, to_timestamp(0))
+        on conflict ("hostname", "namespace", "type")
+            do update set "last_refreshed" = "terraform_provider"."last_refreshed"
+        returning "id";
+        "#,
+        package.provider.hostname,
+        package.provider.namespace,
+        package.provider.provider_type,
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .context("Writing provider to database")?;
+
+    // Versions already listed from the upstream index only gain the artifact
+    sqlx::query!(
+        r#"
+        insert into "terraform_provider_version"
+            ("provider_id", "version", "os", "arch", "artifact_id", "artifact_timestamp", "artifact_shasum", "artifact_source")
+        values ($1, $2, $3, $4, $5, now(), $6, $7)
+        on conflict ("provider_id", "version", "os", "arch")
+            do update set
+                "artifact_id" = "excluded"."artifact_id",
+                "artifact_timestamp" = "excluded"."artifact_timestamp",
+                "artifact_shasum" = "excluded"."artifact_shasum",
+                "artifact_source" = "excluded"."artifact_source"
+            where "terraform_provider_version"."artifact_id" is null;
+        "#,
+        provider.id,
+        package.version,
+        package.os,
+        package.arch,
+        artifact_id,
+        shasum,
+        ARTIFACT_SOURCE,
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Writing provider version to database")?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn zip_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, contents) in files {
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_package_hash_h1() {
+        // Independent of the order files are stored in the archive
+        let a = zip_archive(&[("b.txt", b"world\n"), ("a.txt", b"hello\n")]);
+        let b = zip_archive(&[("a.txt", b"hello\n"), ("b.txt", b"world\n")]);
+        let summary = format!(
+            "{}  a.txt\n{}  b.txt\n",
+            hex::encode(Sha256::digest(b"hello\n")),
+            hex::encode(Sha256::digest(b"world\n"))
+        );
+        let expected = format!("h1:{}", BASE64_STANDARD.encode(Sha256::digest(summary)));
+        assert_eq!(package_hash_h1(&a).unwrap(), expected);
+        assert_eq!(package_hash_h1(&b).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_verify_hashes() {
+        let data = zip_archive(&[("terraform-provider-widget_v1.0.0", b"binary")]);
+        let shasum = hex::encode(Sha256::digest(&data));
+        let h1 = package_hash_h1(&data).unwrap();
+
+        assert!(!verify_hashes(&data, &shasum, &[]).unwrap());
+        assert!(!verify_hashes(&data, &shasum, &["x1:unknown".into()]).unwrap());
+        assert!(verify_hashes(&data, &shasum, &[format!("zh:{shasum}")]).unwrap());
+        assert!(verify_hashes(&data, &shasum, &[h1.clone(), format!("zh:{shasum}")]).unwrap());
+        assert!(verify_hashes(&data, &shasum, &["zh:0000".into()]).is_err());
+        assert!(verify_hashes(&data, &shasum, &[h1, "h1:AAAA".into()]).is_err());
+    }
+
+    #[test]
+    fn test_scan_mirror() {
+        let dir = tempfile::tempdir().unwrap();
+        let provider_dir = dir.path().join("registry.terraform.io/hashicorp/random");
+        std::fs::create_dir_all(provider_dir.join("3.6.0/linux_amd64")).unwrap();
+        for filename in [
+            "terraform-provider-random_3.6.0_linux_amd64.zip",
+            "terraform-provider-random_3.6.0_darwin_arm64.zip",
+            "terraform-provider-null_3.2.0_linux_amd64.zip",
+            "README.md",
+        ] {
+            std::fs::write(provider_dir.join(filename), b"").unwrap();
+        }
+        std::fs::write(
+            provider_dir.join("3.6.0.json"),
+            r#"{"archives":{"linux_amd64":{"url":"terraform-provider-random_3.6.0_linux_amd64.zip","hashes":["zh:abc"]}}}"#,
+        )
+        .unwrap();
+
+        let packages = scan_mirror(dir.path()).unwrap();
+        let summary = packages
+            .iter()
+            .map(|p| (p.provider.mirror_path(), p.os.as_str(), p.hashes.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "registry.terraform.io/hashicorp/random".to_string(),
+                    "darwin",
+                    vec![]
+                ),
+                (
+                    "registry.terraform.io/hashicorp/random".to_string(),
+                    "linux",
+                    vec!["zh:abc".to_string()]
+                ),
+            ]
+        );
+        assert_eq!(packages[1].version, "3.6.0");
+        assert_eq!(packages[1].arch, "amd64");
+    }
+
+    #[sqlx::test]
+    async fn test_store_package_leaves_provider_due_refresh(db: PgPool) {
+        let package = |version: &str, os: &str| MirrorPackage {
+            provider: ProviderAddress {
+                hostname: "registry.terraform.io".into(),
+                namespace: "hashicorp".into(),
+                provider_type: "null".into(),
+            },
+            version: version.into(),
+            os: os.into(),
+            arch: "amd64".into(),
+            path: PathBuf::new(),
+            hashes: vec![],
+        };
+        store_package(&db, &package("3.2.1", "linux"), 1, "aa")
+            .await
+            .unwrap();
+        store_package(&db, &package("3.2.1", "darwin"), 2, "bb")
+            .await
+            .unwrap();
+
+        let due: bool = sqlx::query_scalar(
+            r#"select "last_refreshed" = to_timestamp(0) from "terraform_provider""#,
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert!(due);
+        let sources: Vec<Option<String>> = sqlx::query_scalar(
+            r#"select "artifact_source" from "terraform_provider_version" order by "artifact_id""#,
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(sources, vec![Some("import".to_string()); 2]);
+    }
+}
@@ -1,16 +1,25 @@
+pub mod apitoken;
 mod app;
 pub mod config;
 pub mod credhelper;
 mod error;
+pub mod export;
 pub mod healthy;
 mod http;
+pub mod import;
+pub mod login;
 mod migrate;
+pub mod publish;
 mod refresh;
 mod registry;
+mod rotate;
+mod signing;
+mod tls;
+mod webhook;
 
 use app::AppState;
 use aws_config::BehaviorVersion;
-use axum::extract::Request;
+use axum::extract::{ConnectInfo, Request};
 use axum_prometheus::metrics_exporter_prometheus::PrometheusHandle;
 use config::{Args, ServerArgs};
 use futures::join;
@@ -20,23 +29,49 @@ use hyper_util::{
     server,
 };
 use migrate::run_migrate;
-use reqwest::{Certificate, Client, Proxy};
+use reqwest::Proxy;
 use rustls_native_certs::CertificateResult;
 use sqlx::{postgres::PgPoolOptions, PgPool};
-use std::{net::SocketAddr, time::Duration};
+use std::sync::Arc;
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::TcpListener,
     select,
     sync::{mpsc, oneshot::Sender},
     task::JoinSet,
 };
+use tokio_rustls::TlsAcceptor;
 use tokio_util::sync::CancellationToken;
 use tower::Service;
 use tracing::{error, warn};
+use url::Url;
 
 use crate::{
-    credhelper::database::DatabaseCredentials, healthy::run_healthy, refresh::refresher,
-    registry::RegistryClient,
+    apitoken::run_api_token,
+    credhelper::encryption::TokenCipher,
+    credhelper::{
+        chained::ChainedCredentials, database::DatabaseCredentials, external::ExternalCredentials,
+        terraform::TerraformCredentials, UpstreamCredentials,
+    },
+    export::run_export,
+    healthy::run_healthy,
+    http::api::v1::{
+        login::{login_refresher, RegistryLogin},
+        provider::ProviderPublisher,
+    },
+    import::run_import,
+    login::run_login,
+    publish::run_publish,
+    refresh::refresher,
+    registry::{
+        load_upstream_config, ClientSettings, DownloadPolicy, HostPattern, HttpSettings,
+        NetworkMirror, RegistryClient, UpstreamRoutes, UpstreamSource,
+    },
+    rotate::run_rotate_tokens,
+    signing::{ProviderSigner, SignatureVerifier},
+    tls::{certificate_reloader, tls_acceptor, CertificateFiles},
+    webhook::{dispatcher, Webhooks},
 };
 
 #[derive(Debug)]
@@ -44,12 +79,46 @@ pub struct StartUpNotify<T> {
     pub msg: T,
 }
 
-async fn serve(listener: TcpListener, cancel: CancellationToken, app: axum::Router) {
+/// Time a client has to complete the TLS handshake before the connection is dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn serve_connection<I>(socket: I, remote_addr: SocketAddr, tower_service: axum::Router)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let socket = TokioIo::new(socket);
+
+    // Hyper also has its own `Service` trait and doesn't use tower. We can use
+    // `hyper::service::service_fn` to create a hyper `Service` that calls our app through
+    // `tower::Service::call`.
+    let hyper_service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        request.extensions_mut().insert(ConnectInfo(remote_addr));
+        // We have to clone `tower_service` because hyper's `Service` uses `&self` whereas
+        // tower's `Service` requires `&mut self`.
+        //
+        // We don't need to call `poll_ready` since `Router` is always ready.
+        tower_service.clone().call(request)
+    });
+
+    if let Err(err) = server::conn::auto::Builder::new(TokioExecutor::new())
+        .serve_connection(socket, hyper_service)
+        .await
+    {
+        tracing::warn!("failed to serve connection: {err:#}");
+    }
+}
+
+async fn serve(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    cancel: CancellationToken,
+    app: axum::Router,
+) {
     let mut join_set = JoinSet::new();
     loop {
         select! {
                 result = listener.accept() => {
-                let (socket, _remote_addr) = match result {
+                let (socket, remote_addr) = match result {
                     Ok(x) => x,
                     Err(err) => {
                         warn!(reason = %err, "failed to accept connection");
@@ -60,25 +129,18 @@ async fn serve(listener: TcpListener, cancel: CancellationToken, app: axum::Rout
 
                 // Spawn a task to handle the connection. That way we can multiple connections
                 // concurrently.
+                let tls = tls.clone();
                 join_set.spawn(async move {
-                    let socket = TokioIo::new(socket);
-
-                    // Hyper also has its own `Service` trait and doesn't use tower. We can use
-                    // `hyper::service::service_fn` to create a hyper `Service` that calls our app through
-                    // `tower::Service::call`.
-                    let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
-                        // We have to clone `tower_service` because hyper's `Service` uses `&self` whereas
-                        // tower's `Service` requires `&mut self`.
-                        //
-                        // We don't need to call `poll_ready` since `Router` is always ready.
-                        tower_service.clone().call(request)
-                    });
-
-                    if let Err(err) = server::conn::auto::Builder::new(TokioExecutor::new())
-                        .serve_connection(socket, hyper_service)
-                        .await
-                    {
-                        tracing::warn!("failed to serve connection: {err:#}");
+                    // The handshake happens in the connection's task so slow clients don't hold up others
+                    match tls {
+                        Some(acceptor) => {
+                            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                                Ok(Ok(socket)) => serve_connection(socket, remote_addr, tower_service).await,
+                                Ok(Err(err)) => tracing::debug!(%remote_addr, "TLS handshake failed: {err:#}"),
+                                Err(_) => tracing::debug!(%remote_addr, "TLS handshake timed out"),
+                            }
+                        }
+                        None => serve_connection(socket, remote_addr, tower_service).await,
                     }
                 });
                 while join_set.try_join_next().is_some() {} // clean up any completed requests
@@ -102,7 +164,27 @@ pub async fn run(
         Args::Server(args) => run_server(args, metric_handle, cancel, startup).await,
         Args::Migrate(args) => run_migrate(args).await,
         Args::IsHealthy(args) => run_healthy(args).await,
+        Args::Publish(args) => run_publish(args).await,
+        Args::Export(args) => run_export(args).await,
+        Args::Import(args) => run_import(args).await,
+        Args::RotateTokens(args) => run_rotate_tokens(args).await,
+        Args::Login(args) => run_login(args).await,
+        Args::ApiToken(args) => run_api_token(args).await,
+    }
+}
+
+/// Sets up the S3 client used for the artifact cache.
+pub(crate) async fn s3_client(endpoint: Option<&Url>) -> aws_sdk_s3::Client {
+    // path style required for minio to work
+    // Set up AWS SDK
+    let aws_config = aws_config::defaults(BehaviorVersion::v2025_01_17())
+        .load()
+        .await;
+    let mut s3_config = aws_sdk_s3::config::Builder::from(&aws_config).force_path_style(true);
+    if let Some(endpoint) = endpoint {
+        s3_config = s3_config.endpoint_url(endpoint.as_str());
     }
+    aws_sdk_s3::Client::from_conf(s3_config.build())
 }
 
 pub async fn setup_server(
@@ -112,7 +194,9 @@ pub async fn setup_server(
         reqwest::Client,
         PgPool,
         aws_sdk_s3::Client,
-        DatabaseCredentials,
+        UpstreamCredentials,
+        UpstreamRoutes,
+        Option<TokenCipher>,
     ),
     (),
 > {
@@ -125,16 +209,7 @@ pub async fn setup_server(
         warn!(reason = %error, "Could not load certificate");
     }
 
-    // path style required for minio to work
-    // Set up AWS SDK
-    let aws_config = aws_config::defaults(BehaviorVersion::v2025_01_17())
-        .load()
-        .await;
-    let mut s3_config = aws_sdk_s3::config::Builder::from(&aws_config).force_path_style(true);
-    if let Some(endpoint) = &config.s3_endpoint {
-        s3_config = s3_config.endpoint_url(endpoint.as_str());
-    }
-    let s3 = aws_sdk_s3::Client::from_conf(s3_config.build());
+    let s3 = s3_client(config.s3_endpoint.as_ref()).await;
 
     let db_result = PgPoolOptions::new()
         .max_connections(config.database_pool)
@@ -151,25 +226,27 @@ pub async fn setup_server(
     };
 
     // Set up HTTP pool
-    let mut http_builder = Client::builder()
-        .connect_timeout(Duration::from_secs(10))
-        .timeout(Duration::from_secs(60));
-    for cert in certificates.iter() {
-        http_builder = http_builder
-            .add_root_certificate(Certificate::from_der(cert.as_ref()).expect("Not a certificate"));
-    }
-    if let Some(proxy) = &config.http_proxy {
-        let proxy = match Proxy::all(proxy) {
-            Ok(proxy) => proxy,
+    let proxy = match &config.http_proxy {
+        Some(proxy) => match Proxy::all(proxy) {
+            Ok(client_proxy) => {
+                warn!(
+                    %proxy,
+                    "Downloads through the proxy are resolved by the proxy, so only IP address URLs are checked for private addresses"
+                );
+                Some((
+                    client_proxy.no_proxy(config.no_proxy.clone()),
+                    proxy.as_str(),
+                ))
+            }
             Err(error) => {
                 error!(reason = %error, "Could not initialize proxy, exiting.");
                 return Err(());
             }
-        };
-        let proxy = proxy.no_proxy(config.no_proxy.clone());
-        http_builder = http_builder.proxy(proxy);
+        },
+        None => None,
     };
-    let http = match http_builder.build() {
+    let settings = HttpSettings::new(certificates, proxy);
+    let http = match settings.client(&ClientSettings::default()) {
         Ok(client) => client,
         Err(error) => {
             error!(reason = %error, "Could not initialize http client, exiting.");
@@ -177,10 +254,77 @@ pub async fn setup_server(
         }
     };
 
+    // Set up upstream routing
+    let hosts = match &config.upstream_config {
+        Some(path) => match load_upstream_config(path) {
+            Ok(hosts) => hosts,
+            Err(error) => {
+                error!(reason = ?error, "Could not load upstream config, exiting.");
+                return Err(());
+            }
+        },
+        None => HashMap::new(),
+    };
+    let mut download_policy = DownloadPolicy::new(
+        config.download_allowed_hosts.clone(),
+        config.download_private_hosts.clone(),
+        config.download_max_redirects,
+    )
+    .with_credential_hosts(config.download_credential_hosts.clone());
+    // Hosts terrashine is configured to download from are trusted
+    for url in std::iter::once(&config.releases_upstream_url).chain(&config.upstream_mirror_url) {
+        if let Some(host) = HostPattern::for_url(url) {
+            download_policy.trust(host);
+        }
+    }
+    let cipher = match config.token_encryption.cipher() {
+        Ok(cipher) => cipher,
+        Err(error) => {
+            error!(reason = ?error, "Could not load token encryption keys, exiting.");
+            return Err(());
+        }
+    };
+    if cipher.is_none() {
+        warn!("No token encryption keys configured, registry auth tokens are stored in plaintext");
+    }
+    let routes = UpstreamRoutes::new(
+        config.upstream_registry_port,
+        settings,
+        hosts,
+        Some(db.clone()),
+        download_policy,
+    )
+    .with_cipher(cipher.clone());
+
     // Set up credentials
-    let credentials = DatabaseCredentials::new(db.clone());
+    let terraform_credentials = if config.terraform_credentials {
+        match TerraformCredentials::load(config.terraform_cli_config.as_deref()) {
+            Ok(credentials) => credentials,
+            Err(error) => {
+                error!(reason = ?error, "Could not load Terraform CLI credentials, exiting.");
+                return Err(());
+            }
+        }
+    } else {
+        TerraformCredentials::default()
+    };
+    let credentials_helper = config.credentials_helper.as_ref().map(|program| {
+        tracing::info!(program = %program.display(), "Using external credentials helper");
+        ExternalCredentials::new(
+            program.clone(),
+            config.credentials_helper_args.clone(),
+            config.credentials_helper_timeout,
+            config.credentials_helper_cache_ttl,
+        )
+    });
+    let credentials = ChainedCredentials::new(
+        DatabaseCredentials::new(db.clone()).with_cipher(cipher.clone()),
+        ChainedCredentials::new(terraform_credentials, credentials_helper)
+            .store_in_rest(config.credentials_helper_store),
+    )
+    .store_in_rest(config.credentials_helper_store);
 
-    Ok((http, db, s3, credentials))
+    Ok((http, db, s3, credentials, routes, cipher))
 }
 
 pub async fn run_server(
@@ -189,39 +333,182 @@ pub async fn run_server(
     cancel: CancellationToken,
     startup: Sender<StartUpNotify<SocketAddr>>,
 ) -> Result<(), ()> {
-    let (http, db, s3, credentials) = setup_server(&config).await.unwrap();
+    let (http, db, s3, credentials, routes, cipher) = setup_server(&config).await.unwrap();
+
+    if config.api_auth {
+        if config.api_publish_token.is_some() {
+            warn!("--api-publish-token is ignored with --api-auth, publish with an API token with the providers:admin scope");
+        }
+    } else {
+        warn!("API authentication is disabled, the /api endpoints must be protected by a reverse proxy");
+    }
+    if config.mirror_auth {
+        tracing::info!(
+            "Mirror authentication enabled, clients require API tokens with the mirror:read scope"
+        );
+    }
+    let publish_token = match (config.api_auth, &config.api_publish_token) {
+        (true, _) => None,
+        (false, token) => token.as_deref().map(Into::into),
+    };
+    let publisher = match (&config.private_registry_hostname, &config.signing_key) {
+        (Some(hostname), Some(signing_key)) => {
+            let signer = match ProviderSigner::from_file(
+                signing_key,
+                config.signing_key_passphrase.as_deref(),
+            ) {
+                Ok(signer) => signer,
+                Err(error) => {
+                    error!(reason = ?error, "Could not load signing key, exiting.");
+                    return Err(());
+                }
+            };
+            tracing::info!(%hostname, key_id = %signer.key_id(), "Provider publishing enabled");
+            Some(ProviderPublisher {
+                db: db.clone(),
+                s3: s3.clone(),
+                bucket_name: config.s3_bucket_name.clone(),
+                bucket_prefix: config.s3_bucket_prefix.clone(),
+                hostname: hostname.to_ascii_lowercase(),
+                token: publish_token,
+                signer,
+            })
+        }
+        _ => None,
+    };
+
+    let release_verifier = match &config.releases_signing_key {
+        Some(path) => match SignatureVerifier::from_file(path) {
+            Ok(verifier) => Some(verifier),
+            Err(error) => {
+                error!(reason = ?error, "Could not load release signing keys, exiting.");
+                return Err(());
+            }
+        },
+        None => {
+            warn!("No release signing keys configured, the releases mirror is disabled");
+            None
+        }
+    };
 
     let (tx, rx) = mpsc::channel(10000);
 
-    let refresher_db = db.clone();
-    let refresher_registry = RegistryClient::new(
-        config.upstream_registry_port,
-        http.clone(),
-        credentials.clone(),
-    );
-    let refresher = refresher(
-        &refresher_db,
-        &refresher_registry,
-        rx,
-        config.refresh_interval,
+    let webhooks = Webhooks::new(db.clone(), config.webhook_url.clone());
+    let webhook_secret = config.webhook_secret.clone().unwrap_or_default();
+    let dispatcher = dispatcher(
+        &webhooks,
+        &http,
+        webhook_secret.as_bytes(),
+        config.webhook_max_attempts,
         cancel.child_token(),
     );
 
+    let refresher_db = db.clone();
+    let mut sources = Vec::new();
+    if let Some(url) = &config.upstream_mirror_url {
+        sources.push(UpstreamSource::Mirror(NetworkMirror::new(url.clone())));
+    }
+    sources.push(UpstreamSource::Origin);
+    tracing::info!(
+        sources = ?sources.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "Default upstream sources"
+    );
+    let registry = RegistryClient::new(routes, sources, credentials.clone());
+    let refresher_registry = registry.clone();
+    let refresher_cancel = cancel.child_token();
+    let refresher = async {
+        if config.offline {
+            warn!("Offline mode, upstream requests are disabled and only cached content is served");
+            drop(rx);
+        } else {
+            refresher(
+                &refresher_db,
+                &refresher_registry,
+                &webhooks,
+                rx,
+                config.refresh_interval,
+                &config.allowed_platforms,
+                refresher_cancel,
+            )
+            .await
+        }
+    };
+
+    let login = RegistryLogin {
+        db: db.clone(),
+        registry: registry.clone(),
+        cipher,
+    };
+    let login_refresher = {
+        let (login, credentials) = (login.clone(), credentials.clone());
+        let cancel = cancel.child_token();
+        async move {
+            if !config.offline {
+                login_refresher(login, credentials, cancel).await
+            }
+        }
+    };
+
     let bind_addr = config.http_listen;
     let app = app::provider_mirror_app(
-        AppState::new(config.clone(), s3, db, http, tx, credentials.clone()),
+        AppState::new(
+            config.clone(),
+            s3,
+            db,
+            registry,
+            tx,
+            credentials.clone(),
+            webhooks.clone(),
+            publisher,
+            // Logging in discovers and calls the registry's login service upstream
+            (!config.offline).then_some(login),
+            release_verifier,
+        ),
         metric_handle,
     );
 
+    let certificate = match (&config.tls.tls_cert, &config.tls.tls_key) {
+        (Some(cert), Some(key)) => match CertificateFiles::load(cert.clone(), key.clone()) {
+            Ok(certificate) => Some(Arc::new(certificate)),
+            Err(error) => {
+                error!(reason = ?error, "Could not load TLS certificate, exiting.");
+                return Err(());
+            }
+        },
+        _ => None,
+    };
+    let tls = match certificate.clone().map(tls_acceptor).transpose() {
+        Ok(tls) => tls,
+        Err(error) => {
+            error!(reason = ?error, "Could not initialize TLS, exiting.");
+            return Err(());
+        }
+    };
+    let certificate_reloader = {
+        let cancel = cancel.child_token();
+        async move {
+            if let Some(certificate) = certificate {
+                certificate_reloader(certificate, cancel).await
+            }
+        }
+    };
+
     let listener = TcpListener::bind(&bind_addr).await.unwrap();
     let local_addr = listener.local_addr().unwrap();
-    let server = serve(listener, cancel.child_token(), app);
+    tracing::info!(%local_addr, tls = tls.is_some(), "Listening");
+    let server = serve(listener, tls, cancel.child_token(), app);
 
     startup
         .send(StartUpNotify { msg: local_addr })
         .expect("Sender channel has already been used");
 
-    join!(server, refresher);
+    join!(
+        server,
+        refresher,
+        login_refresher,
+        certificate_reloader,
+        dispatcher
+    );
     tracing::debug!("Shutting down server");
     Ok(())
 }
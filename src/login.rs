@@ -0,0 +1,198 @@
+use anyhow::Context;
+use axum::{extract::Query, response::Html, routing::get, Router};
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::{net::TcpListener, sync::mpsc};
+use tracing::{error, info};
+use url::Url;
+
+use crate::{config::LoginArgs, publish::api_client};
+
+/// Login service of a registry, as described by the terrashine API.
+#[derive(Debug, Deserialize)]
+struct LoginService {
+    ports: (u16, u16),
+}
+
+#[derive(Debug, Deserialize)]
+struct StartedLogin {
+    authorization_url: Url,
+    state: String,
+}
+
+fn login_url(base: &Url, hostname: &str, token: bool) -> Url {
+    let mut url = base.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments
+            .pop_if_empty()
+            .extend(["api", "v1", "logins", hostname]);
+        if token {
+            segments.push("token");
+        }
+    }
+    url
+}
+
+/// Extracts the authorization code from the query of the redirect back from the registry.
+fn authorization_code(query: &HashMap<String, String>, state: &str) -> anyhow::Result<String> {
+    if let Some(error) = query.get("error") {
+        let description = query
+            .get("error_description")
+            .map(String::as_str)
+            .unwrap_or_default();
+        anyhow::bail!("Registry denied the login: {error} {description}");
+    }
+    if query.get("state").map(String::as_str) != Some(state) {
+        anyhow::bail!("Login state does not match, the redirect was not for this login");
+    }
+    query
+        .get("code")
+        .cloned()
+        .context("Redirect from the registry has no authorization code")
+}
+
+/// Binds the first free localhost port the registry allows redirects to.
+async fn bind_redirect((first, last): (u16, u16)) -> anyhow::Result<TcpListener> {
+    for port in first..=last {
+        if let Ok(listener) = TcpListener::bind(("127.0.0.1", port)).await {
+            return Ok(listener);
+        }
+    }
+    anyhow::bail!("No free port between {first} and {last} to receive the login redirect")
+}
+
+/// Waits for the registry to redirect the browser back with the authorization code.
+async fn receive_code(listener: TcpListener, state: &str) -> anyhow::Result<String> {
+    let (tx, mut rx) = mpsc::channel(1);
+    let app = Router::new().route(
+        "/login",
+        get(|Query(query): Query<HashMap<String, String>>| async move {
+            let _ = tx.send(query).await;
+            Html("<p>Terrashine login received, this window can be closed.</p>")
+        }),
+    );
+    let server = axum::serve(listener, app);
+    let query = tokio::select! {
+        result = server => {
+            result?;
+            anyhow::bail!("Redirect server stopped before the login completed");
+        }
+        query = rx.recv() => query.context("Redirect server stopped before the login completed")?,
+    };
+    authorization_code(&query, state)
+}
+
+async fn api_request(
+    request: reqwest::RequestBuilder,
+    token: Option<&str>,
+) -> anyhow::Result<Value> {
+    let request = match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    };
+    let response = request.send().await?;
+    let status = response.status();
+    let mut body: Value = serde_json::from_slice(&response.bytes().await?).unwrap_or_default();
+    if !status.is_success() {
+        let msg = body["error"]["msg"].as_str().unwrap_or_default();
+        anyhow::bail!("Request failed with status {status}: {msg}");
+    }
+    Ok(body["data"].take())
+}
+
+async fn login(http: &Client, args: &LoginArgs) -> anyhow::Result<()> {
+    let service: LoginService = serde_json::from_value(
+        api_request(
+            http.get(login_url(&args.url, &args.hostname, false)),
+            args.token.as_deref(),
+        )
+        .await?,
+    )?;
+    let listener = bind_redirect(service.ports).await?;
+    let redirect_uri = Url::parse(&format!(
+        "http://localhost:{}/login",
+        listener.local_addr()?.port()
+    ))?;
+
+    let started: StartedLogin = serde_json::from_value(
+        api_request(
+            http.post(login_url(&args.url, &args.hostname, false))
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::json!({ "data": { "redirect_uri": redirect_uri } }).to_string()),
+            args.token.as_deref(),
+        )
+        .await?,
+    )?;
+    println!(
+        "Open the following URL in a browser to log in to {}:\n\n    {}\n",
+        args.hostname, started.authorization_url
+    );
+    let code = tokio::time::timeout(args.timeout, receive_code(listener, &started.state))
+        .await
+        .context("Timed out waiting for the login to be authorized")??;
+
+    api_request(
+        http.post(login_url(&args.url, &args.hostname, true))
+            .header(CONTENT_TYPE, "application/json")
+            .body(
+                serde_json::json!({ "data": { "state": started.state, "code": code } }).to_string(),
+            ),
+        args.token.as_deref(),
+    )
+    .await?;
+    info!(hostname = %args.hostname, "Logged in to registry");
+    Ok(())
+}
+
+pub async fn run_login(args: LoginArgs) -> Result<(), ()> {
+    let http = match api_client() {
+        Ok(client) => client,
+        Err(error) => {
+            error!(reason = %error, "Could not initialize http client");
+            return Err(());
+        }
+    };
+    login(&http, &args).await.map_err(|error| {
+        error!(hostname = %args.hostname, "{:#}", error);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_url() {
+        let base = Url::parse("https://terrashine.example.com/").unwrap();
+        assert_eq!(
+            login_url(&base, "app.terraform.io", false).as_str(),
+            "https://terrashine.example.com/api/v1/logins/app.terraform.io"
+        );
+        assert_eq!(
+            login_url(&base, "app.terraform.io", true).as_str(),
+            "https://terrashine.example.com/api/v1/logins/app.terraform.io/token"
+        );
+    }
+
+    #[test]
+    fn test_authorization_code() {
+        let query = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(
+            authorization_code(&query(&[("code", "abc"), ("state", "s1")]), "s1").unwrap(),
+            "abc"
+        );
+        assert!(authorization_code(&query(&[("code", "abc"), ("state", "s2")]), "s1").is_err());
+        assert!(authorization_code(&query(&[("state", "s1")]), "s1").is_err());
+        assert!(
+            authorization_code(&query(&[("error", "access_denied"), ("state", "s1")]), "s1")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use reqwest::{header::CONTENT_TYPE, Certificate, Client};
+use rustls_native_certs::CertificateResult;
+use std::path::Path;
+use tracing::{error, info, warn};
+use url::Url;
+
+use crate::config::PublishArgs;
+
+/// Platform details encoded in a provider package file name.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PackageName {
+    pub(crate) provider_type: String,
+    pub(crate) version: String,
+    pub(crate) os: String,
+    pub(crate) arch: String,
+}
+
+impl PackageName {
+    /// Parses file names in the form terraform-provider-{type}_{version}_{os}_{arch}.zip
+    pub(crate) fn parse(filename: &str) -> Option<Self> {
+        let stem = filename
+            .strip_prefix("terraform-provider-")?
+            .strip_suffix(".zip")?;
+        match *stem.split('_').collect::<Vec<_>>().as_slice() {
+            [provider_type, version, os, arch]
+                if [provider_type, version, os, arch]
+                    .iter()
+                    .all(|part| !part.is_empty()) =>
+            {
+                Some(Self {
+                    provider_type: provider_type.to_string(),
+                    version: version.to_string(),
+                    os: os.to_string(),
+                    arch: arch.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn publish_url(base: &Url, namespace: &str, package: &PackageName, protocols: &[String]) -> Url {
+    let mut url = base.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().extend([
+            "api",
+            "v1",
+            "providers",
+            namespace,
+            &package.provider_type,
+            &package.version,
+            &package.os,
+            &package.arch,
+        ]);
+    }
+    url.query_pairs_mut()
+        .clear()
+        .append_pair("protocols", &protocols.join(","));
+    url
+}
+
+/// HTTP client for subcommands calling the terrashine API, trusting the native certificates.
+pub(crate) fn api_client() -> Result<Client, reqwest::Error> {
+    let CertificateResult {
+        certs: certificates,
+        errors: cert_errors,
+        ..
+    } = rustls_native_certs::load_native_certs();
+    for error in cert_errors {
+        warn!(reason = %error, "Could not load certificate");
+    }
+    let mut http_builder = Client::builder();
+    for cert in certificates.iter() {
+        http_builder = http_builder
+            .add_root_certificate(Certificate::from_der(cert.as_ref()).expect("Not a certificate"));
+    }
+    http_builder.build()
+}
+
+pub async fn run_publish(args: PublishArgs) -> Result<(), ()> {
+    let http = match api_client() {
+        Ok(client) => client,
+        Err(error) => {
+            error!(reason = %error, "Could not initialize http client");
+            return Err(());
+        }
+    };
+
+    let mut result = Ok(());
+    for file in args.files.iter() {
+        if let Err(error) = publish_file(&http, &args, file).await {
+            error!(file = %file.display(), "{:#}", error);
+            result = Err(());
+        }
+    }
+    result
+}
+
+async fn publish_file(http: &Client, args: &PublishArgs, file: &Path) -> anyhow::Result<()> {
+    let filename = file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let mut package = PackageName::parse(filename).ok_or_else(|| {
+        anyhow::anyhow!(
+            "File name must be in the form terraform-provider-{{type}}_{{version}}_{{os}}_{{arch}}.zip"
+        )
+    })?;
+    if let Some(provider_type) = &args.provider_type {
+        package.provider_type = provider_type.clone();
+    }
+    if let Some(version) = &args.version {
+        package.version = version.clone();
+    }
+
+    let body = tokio::fs::read(file).await?;
+    let url = publish_url(&args.url, &args.namespace, &package, &args.protocols);
+    let response = http
+        .put(url)
+        .bearer_auth(&args.token)
+        .header(CONTENT_TYPE, "application/zip")
+        .body(body)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        anyhow::bail!("Publishing failed with status {status}: {text}");
+    }
+    info!(
+        namespace = %args.namespace,
+        provider_type = %package.provider_type,
+        version = %package.version,
+        os = %package.os,
+        arch = %package.arch,
+        "Published provider"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_package_name() {
+        assert_eq!(
+            PackageName::parse("terraform-provider-my-widget_1.2.0-beta.1_linux_amd64.zip"),
+            Some(PackageName {
+                provider_type: "my-widget".into(),
+                version: "1.2.0-beta.1".into(),
+                os: "linux".into(),
+                arch: "amd64".into(),
+            })
+        );
+        assert_eq!(
+            PackageName::parse("terraform-provider-widget_1.2.0_linux.zip"),
+            None
+        );
+        assert_eq!(PackageName::parse("widget_1.2.0_linux_amd64.zip"), None);
+    }
+
+    #[test]
+    fn test_publish_url() {
+        let package =
+            PackageName::parse("terraform-provider-widget_1.2.0_linux_amd64.zip").unwrap();
+        let protocols = vec!["5.0".to_string(), "6.0".to_string()];
+        for base in [
+            "https://terrashine.example.com",
+            "https://terrashine.example.com/",
+        ] {
+            assert_eq!(
+                publish_url(&Url::parse(base).unwrap(), "example", &package, &protocols).as_str(),
+                "https://terrashine.example.com/api/v1/providers/example/widget/1.2.0/linux/amd64?protocols=5.0%2C6.0"
+            );
+        }
+    }
+}
@@ -3,6 +3,7 @@ use crate::{
     error::TerrashineError,
     http::index::refresh_versions,
     registry::{ProviderVersions, RegistryClient},
+    webhook::Webhooks,
 };
 use sqlx::PgPool;
 use std::{
@@ -46,8 +47,10 @@ pub(crate) enum RefreshResponse {
 pub(crate) async fn refresher<T: CredentialHelper>(
     db: &PgPool,
     registry: &RegistryClient<T>,
+    webhooks: &Webhooks,
     mut rx: sync::mpsc::Receiver<RefreshRequest>,
     refresh_interval: Duration,
+    allowed_platforms: &[String],
     cancel: CancellationToken,
 ) {
     let mut last_refresh = HashMap::new();
@@ -79,9 +82,9 @@ pub(crate) async fn refresher<T: CredentialHelper>(
                             let result = refresh_versions(
                                 db,
                                 registry,
-                                key.hostname.as_str(),
-                                key.namespace.as_str(),
-                                key.provider_type.as_str(),
+                                webhooks,
+                                allowed_platforms,
+                                key,
                             )
                             .await;
                             if result.is_ok() {
@@ -100,9 +103,9 @@ pub(crate) async fn refresher<T: CredentialHelper>(
                             let result = refresh_versions(
                                 db,
                                 registry,
-                                key.hostname.as_str(),
-                                key.namespace.as_str(),
-                                key.provider_type.as_str(),
+                                webhooks,
+                                allowed_platforms,
+                                key,
                             )
                             .await;
                             // If an error occurs here, it isn't critical, just leave it for a bit
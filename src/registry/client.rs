@@ -1,42 +1,58 @@
-use reqwest::{Client, Response};
+use anyhow::Context;
+use http::header::LOCATION;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use serde::Deserialize;
-use std::str;
+use std::{str, sync::Arc};
 use url::Url;
 
-use crate::{credhelper::CredentialHelper, error::TerrashineError};
+use super::{PrivateAddress, SourceHealth, UpstreamRoutes, UpstreamSource};
+use crate::{
+    credhelper::{Credential, CredentialHelper},
+    error::TerrashineError,
+};
 
 const DISCOVERY_RESPONSE_SIZE_MAX_BYTES: usize = 16384; // 16KB
-const REGISTRY_METADATA_SIZE_MAX_BYTES: usize = 8388608; // 8MB
+pub(super) const REGISTRY_METADATA_SIZE_MAX_BYTES: usize = 8388608; // 8MB
+const PACKAGE_FILE_SIZE_MAX_BYTES: usize = 1048576; // 1MB
+const RELEASE_INDEX_SIZE_MAX_BYTES: usize = 67108864; // 64MB
+pub const TERRAFORM_GET_HEADER: &str = "X-Terraform-Get";
 
 #[derive(Clone)]
 pub struct RegistryClient<T> {
-    port: u16,
-    http: Client,
-    credentials: T,
+    pub(super) routes: UpstreamRoutes,
+    /// Sources used for hostnames without sources of their own
+    pub(super) default_sources: Arc<[UpstreamSource]>,
+    pub(super) health: SourceHealth,
+    pub(super) credentials: T,
 }
 
 impl<T> RegistryClient<T> {
-    pub fn new(port: u16, http: Client, credentials: T) -> Self {
+    pub fn new(
+        routes: UpstreamRoutes,
+        default_sources: Vec<UpstreamSource>,
+        credentials: T,
+    ) -> Self {
         RegistryClient {
-            port,
-            http,
+            routes,
+            default_sources: default_sources.into(),
+            health: SourceHealth::default(),
             credentials,
         }
     }
 }
 
 #[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct DiscoveredServices {
+pub(super) struct DiscoveredServices {
     #[serde(rename = "providers.v1")]
     providers_v1: Option<String>,
     #[serde(rename = "modules.v1")]
     modules_v1: Option<String>,
+    /// Login service object, only parsed when logging in so it cannot break other services
     #[serde(rename = "login.v1")]
-    login_v1: Option<String>,
+    pub(super) login_v1: Option<serde_json::Value>,
 }
 
-async fn read_body_limit(
+pub(super) async fn read_body_limit(
     buffer: &mut Vec<u8>,
     mut response: Response,
     limit: usize,
@@ -52,17 +68,16 @@ async fn read_body_limit(
 
 impl<T: CredentialHelper> RegistryClient<T> {
     /// Performs request upstream to handle terraform service discovery protocol
-    async fn discover_services(
+    pub(super) async fn discover_services(
         &self,
-        hostname: &str,
+        root: &Url,
+        http: &Client,
     ) -> Result<DiscoveredServices, TerrashineError> {
-        let url = Url::parse(&format!(
-            "https://{}:{}/.well-known/terraform.json",
-            hostname, self.port
-        ))
-        .expect("Could not parse URL");
+        let url = root
+            .join(".well-known/terraform.json")
+            .expect("Could not parse URL");
         let mut response_buffer = Vec::with_capacity(DISCOVERY_RESPONSE_SIZE_MAX_BYTES);
-        let response = self.http.get(url).send().await?.error_for_status()?;
+        let response = http.get(url).send().await?.error_for_status()?;
         read_body_limit(
             &mut response_buffer,
             response,
@@ -73,41 +88,459 @@ impl<T: CredentialHelper> RegistryClient<T> {
         Ok(services)
     }
 
+    /// Builds the URL for a path relative to a discovered service base URL, along
+    /// with the client used to reach the upstream host.
+    ///
+    /// Service paths are resolved beneath the root URL of the upstream host, so
+    /// a host routed through a forwarder keeps the forwarder's path prefix.
+    /// Services on another host are chosen by the registry, so are restricted by the
+    /// download policy like the download URLs it provides.
+    async fn service_url(
+        &self,
+        hostname: &str,
+        service_type: &'static str,
+        service: fn(DiscoveredServices) -> Option<String>,
+        path: &str,
+    ) -> Result<(Url, Client), TerrashineError> {
+        let (root, http) = self.routes.route(hostname).await?;
+        let services = self.discover_services(&root, &http).await?;
+        let Some(base_url) = service(services) else {
+            return Err(TerrashineError::TerraformServiceNotSupported {
+                service_type,
+                hostname: hostname.to_string(),
+            });
+        };
+        let url = root
+            .join(base_url.trim_start_matches('/'))
+            .and_then(|u| u.join(path))
+            .map_err(|_| TerrashineError::ProviderGetBuildUrlFailure {
+                hostname: hostname.to_string(),
+                port: root.port_or_known_default().unwrap_or_default(),
+                base_url,
+                path: path.to_string(),
+            })?;
+        if url.origin() != root.origin() {
+            self.routes.download_policy().check(&url)?;
+            let http = self.routes.download_client_for_url(&url).await?;
+            return Ok((url, http));
+        }
+        Ok((url, http))
+    }
+
+    /// Sends a request with the credentials for the hostname, recording upstream
+    /// rejections of the credentials.
+    pub(super) async fn send_authenticated(
+        &self,
+        hostname: &str,
+        request: RequestBuilder,
+    ) -> Result<Response, TerrashineError> {
+        let request = self.credentials.transform(request, hostname).await?;
+        let response = request.send().await?;
+        self.record_rejection(hostname, &response).await;
+        Ok(response)
+    }
+
+    /// Records the upstream rejecting the credentials for a hostname.
+    async fn record_rejection(&self, hostname: &str, response: &Response) {
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            if let Err(error) = self
+                .credentials
+                .record_auth_failure(hostname, status.as_u16())
+                .await
+            {
+                tracing::warn!(reason = ?error, %hostname, "Could not record credential failure");
+            }
+        }
+    }
+
+    /// Hostname of the credentials sent with a download, if any.
+    ///
+    /// Downloads are sent the credentials stored for their own host, or those of the
+    /// registry they are for when the host is trusted with them, and nothing otherwise
+    /// so tokens are not leaked to third party hosts.
+    async fn download_credentials(
+        &self,
+        registry: Option<&str>,
+        url: &Url,
+    ) -> Result<Option<String>, TerrashineError> {
+        let Some(host) = url.host_str() else {
+            return Ok(None);
+        };
+        if let Credential::Entry(Some(_)) = self.credentials.get(host).await? {
+            return Ok(Some(host.to_string()));
+        }
+        Ok(registry
+            .filter(|_| {
+                self.routes
+                    .download_policy()
+                    .sends_registry_credentials(host)
+            })
+            .map(str::to_string))
+    }
+
+    async fn get_json<A: for<'a> Deserialize<'a>>(
+        &self,
+        hostname: &str,
+        http: &Client,
+        url: Url,
+    ) -> Result<A, TerrashineError> {
+        let mut response_buffer = Vec::with_capacity(REGISTRY_METADATA_SIZE_MAX_BYTES);
+        let response = self
+            .send_authenticated(hostname, http.get(url))
+            .await?
+            .error_for_status()?;
+        read_body_limit(
+            &mut response_buffer,
+            response,
+            REGISTRY_METADATA_SIZE_MAX_BYTES,
+        )
+        .await?;
+        let result = serde_json::from_slice(&response_buffer[..])?;
+        Ok(result)
+    }
+
     pub async fn provider_get<A: for<'a> Deserialize<'a>>(
         &self,
         hostname: &str,
         path: &str,
     ) -> Result<A, TerrashineError> {
-        let services = self.discover_services(hostname).await?;
-        if let Some(base_url) = services.providers_v1 {
-            let url = Url::parse(&format!("https://{hostname}:{port}", port = self.port))
-                .and_then(|u| u.join(&base_url))
-                .and_then(|u| u.join(path))
-                .map_err(|_| TerrashineError::ProviderGetBuildUrlFailure {
-                    hostname: hostname.to_string(),
-                    port: self.port,
-                    base_url,
-                    path: path.to_string(),
-                })?;
-            let mut response_buffer = Vec::with_capacity(REGISTRY_METADATA_SIZE_MAX_BYTES);
-            tracing::debug!(%url, "GET registry provider");
-            let request = self.http.get(url);
-            let request = self.credentials.transform(request, hostname).await?;
-
-            let response = request.send().await?.error_for_status()?;
-            read_body_limit(
-                &mut response_buffer,
-                response,
-                REGISTRY_METADATA_SIZE_MAX_BYTES,
-            )
+        let (url, http) = self
+            .service_url(hostname, "provider", |s| s.providers_v1, path)
+            .await?;
+        tracing::debug!(%url, "GET registry provider");
+        self.get_json(hostname, &http, url).await
+    }
+
+    pub async fn module_get<A: for<'a> Deserialize<'a>>(
+        &self,
+        hostname: &str,
+        path: &str,
+    ) -> Result<A, TerrashineError> {
+        let (url, http) = self
+            .service_url(hostname, "module", |s| s.modules_v1, path)
             .await?;
-            let result = serde_json::from_slice(&response_buffer[..])?;
-            Ok(result)
-        } else {
-            Err(TerrashineError::TerraformServiceNotSupported {
-                service_type: "provider",
+        tracing::debug!(%url, "GET registry module");
+        self.get_json(hostname, &http, url).await
+    }
+
+    /// Requests the download location of a module package.
+    ///
+    /// Returns the URL of the download endpoint along with the `X-Terraform-Get`
+    /// header, which may be relative to the download endpoint.
+    pub async fn module_download_source(
+        &self,
+        hostname: &str,
+        path: &str,
+    ) -> Result<(Url, String), TerrashineError> {
+        let (url, http) = self
+            .service_url(hostname, "module", |s| s.modules_v1, path)
+            .await?;
+        tracing::debug!(%url, "GET registry module download");
+        let response = self
+            .send_authenticated(hostname, http.get(url.clone()))
+            .await?
+            .error_for_status()?;
+        let source = response
+            .headers()
+            .get(TERRAFORM_GET_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| TerrashineError::ModuleSourceMissing {
                 hostname: hostname.to_string(),
-            })
+                path: path.to_string(),
+            })?
+            .to_string();
+        Ok((url, source))
+    }
+
+    /// Downloads the checksum file or checksum signature of a provider package or release.
+    ///
+    /// These are commonly hosted by a third party, so are only sent credentials as
+    /// described in [`RegistryClient::download`].
+    pub async fn package_file(
+        &self,
+        registry: Option<&str>,
+        url: Url,
+    ) -> Result<Vec<u8>, TerrashineError> {
+        tracing::debug!(%url, "GET package file");
+        let mut response_buffer = Vec::new();
+        let response = self.guarded_get(registry, url).await?;
+        read_body_limit(&mut response_buffer, response, PACKAGE_FILE_SIZE_MAX_BYTES).await?;
+        Ok(response_buffer)
+    }
+
+    /// Fetches an index document from a releases server.
+    ///
+    /// Product indexes list every release so are allowed to be much larger than registry metadata.
+    pub async fn release_index(&self, url: Url) -> Result<serde_json::Value, TerrashineError> {
+        tracing::debug!(%url, "GET release index");
+        let mut response_buffer = Vec::new();
+        self.routes.download_policy().check(&url)?;
+        let http = self.routes.download_client_for_url(&url).await?;
+        let response = http
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|error| download_error(&url, error))?
+            .error_for_status()?;
+        read_body_limit(&mut response_buffer, response, RELEASE_INDEX_SIZE_MAX_BYTES).await?;
+        Ok(serde_json::from_slice(&response_buffer[..])?)
+    }
+
+    /// Starts downloading a package or archive, leaving the body to be streamed by the caller.
+    ///
+    /// Credentials are sent to hosts with credentials of their own, and the credentials of
+    /// `registry`, the hostname of the registry the download is for, to hosts trusted with them.
+    pub async fn download(
+        &self,
+        registry: Option<&str>,
+        url: Url,
+    ) -> Result<Response, TerrashineError> {
+        tracing::debug!(%url, "GET download");
+        self.guarded_get(registry, url).await
+    }
+
+    /// Requests an upstream provided URL, checking it and every redirect against the download policy.
+    ///
+    /// Credentials are chosen again for every redirect, so are not forwarded to other hosts.
+    async fn guarded_get(
+        &self,
+        registry: Option<&str>,
+        mut url: Url,
+    ) -> Result<Response, TerrashineError> {
+        let policy = self.routes.download_policy();
+        for _ in 0..=policy.max_redirects() {
+            policy.check(&url)?;
+            let http = self.routes.download_client_for_url(&url).await?;
+            let mut request = http.get(url.clone());
+            let credentials = self.download_credentials(registry, &url).await?;
+            if let Some(hostname) = &credentials {
+                tracing::debug!(%url, %hostname, "Sending credentials with download");
+                request = self.credentials.transform(request, hostname).await?;
+            }
+            let response = request
+                .send()
+                .await
+                .map_err(|error| download_error(&url, error))?;
+            if let Some(hostname) = &credentials {
+                self.record_rejection(hostname, &response).await;
+            }
+            if !response.status().is_redirection() {
+                return Ok(response.error_for_status()?);
+            }
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .with_context(|| format!("Redirect from {url} has no location"))?;
+            let next = url
+                .join(location)
+                .with_context(|| format!("Invalid redirect location from {url}"))?;
+            tracing::debug!(from = %url, to = %next, "Following download redirect");
+            url = next;
         }
+        Err(TerrashineError::DownloadNotAllowed {
+            url: url.to_string(),
+            reason: format!("more than {} redirects", policy.max_redirects()),
+        })
+    }
+}
+
+/// Reports a download blocked by the resolver as not allowed, rather than a connection failure.
+fn download_error(url: &Url, error: reqwest::Error) -> TerrashineError {
+    let mut source = std::error::Error::source(&error);
+    while let Some(inner) = source {
+        if let Some(PrivateAddress(host)) = inner.downcast_ref::<PrivateAddress>() {
+            return TerrashineError::DownloadNotAllowed {
+                url: url.to_string(),
+                reason: format!("{host} resolves to a private address"),
+            };
+        }
+        source = inner.source();
+    }
+    error.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        credhelper::memory::MemoryCredentials,
+        registry::{DownloadPolicy, HostPattern, HttpSettings, UpstreamHost},
+    };
+    use std::collections::HashMap;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn registry(policy: DownloadPolicy) -> RegistryClient<MemoryCredentials> {
+        let routes =
+            UpstreamRoutes::new(443, HttpSettings::default(), HashMap::new(), None, policy);
+        RegistryClient::new(routes, vec![], MemoryCredentials::default())
+    }
+
+    /// Serves a redirect back to itself for every connection.
+    async fn redirect_loop() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "http://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let location = url.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let response = format!(
+                    "HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    type Requests = Arc<std::sync::Mutex<Vec<(String, Option<String>)>>>;
+
+    /// Records the path and authorization header of requests, redirecting `/redirect` to
+    /// `/file` on 127.0.0.1. Returns the URL of `/redirect` through localhost.
+    async fn recording_server() -> (Url, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Requests::default();
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut buffer = [0; 1024];
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => head.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&head);
+                let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+                let authorization = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("authorization: "))
+                    .map(str::to_string);
+                let response = if path == "/redirect" {
+                    format!("HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:{port}/file\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                } else {
+                    "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+                        .to_string()
+                };
+                recorded.lock().unwrap().push((path, authorization));
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let url = Url::parse(&format!("http://localhost:{port}/redirect")).unwrap();
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn test_download_credentials() {
+        let private_hosts = vec!["localhost".parse().unwrap(), "127.0.0.1".parse().unwrap()];
+        for (credential_hosts, expected) in [
+            (vec![], None),
+            (
+                vec!["127.0.0.1".parse().unwrap()],
+                Some("Bearer password2".to_string()),
+            ),
+        ] {
+            let (url, requests) = recording_server().await;
+            let policy = DownloadPolicy::new(vec![], private_hosts.clone(), 5)
+                .with_credential_hosts(credential_hosts);
+            let mut registry = registry(policy);
+            registry
+                .credentials
+                .store("localhost".into(), "password1".into())
+                .await
+                .unwrap();
+            registry
+                .credentials
+                .store("registry.example.com".into(), "password2".into())
+                .await
+                .unwrap();
+            registry
+                .download(Some("registry.example.com"), url)
+                .await
+                .unwrap();
+            assert_eq!(
+                *requests.lock().unwrap(),
+                vec![
+                    (
+                        "/redirect".to_string(),
+                        Some("Bearer password1".to_string())
+                    ),
+                    ("/file".to_string(), expected),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_blocks_private_addresses() {
+        let url = redirect_loop().await;
+        let error = registry(DownloadPolicy::default())
+            .download(None, url)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, TerrashineError::DownloadNotAllowed { reason, .. } if reason.contains("private")),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_service_url_on_other_host_is_guarded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let root = Url::parse(&format!(
+            "http://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let body = r#"{"providers.v1":"http://169.254.169.254/v1/providers/"}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let host = UpstreamHost {
+            base_url: Some(root),
+            ..Default::default()
+        };
+        let routes = UpstreamRoutes::new(
+            443,
+            HttpSettings::default(),
+            HashMap::from([("registry.example.com".to_string(), host)]),
+            None,
+            DownloadPolicy::default(),
+        );
+        let registry = RegistryClient::new(routes, vec![], MemoryCredentials::default());
+        let error = registry
+            .provider_get::<serde_json::Value>("registry.example.com", "hashicorp/aws/versions")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, TerrashineError::DownloadNotAllowed { reason, .. } if reason.contains("private")),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_caps_redirects() {
+        let url = redirect_loop().await;
+        let policy = DownloadPolicy::new(vec![], vec![HostPattern::for_url(&url).unwrap()], 2);
+        let error = registry(policy).download(None, url).await.unwrap_err();
+        assert!(
+            matches!(&error, TerrashineError::DownloadNotAllowed { reason, .. } if reason.contains("redirects")),
+            "{error:?}"
+        );
     }
 }
@@ -0,0 +1,349 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use url::{Host, Url};
+
+use crate::error::TerrashineError;
+
+/// Hostname pattern, either an exact hostname or `*.` followed by a domain suffix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPattern(String);
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim().trim_end_matches('.').to_ascii_lowercase();
+        let host = pattern.strip_prefix("*.").unwrap_or(&pattern);
+        if host.is_empty() || host.contains(['*', '/', ':']) {
+            return Err(format!("Invalid host pattern {s}"));
+        }
+        Ok(Self(pattern))
+    }
+}
+
+impl Display for HostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl HostPattern {
+    /// Pattern matching exactly the host of a URL, if it has one.
+    pub fn for_url(url: &Url) -> Option<Self> {
+        url.host_str().and_then(|host| host.parse().ok())
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match self.0.strip_prefix("*.") {
+            Some(suffix) => host
+                .strip_suffix(suffix)
+                .is_some_and(|prefix| prefix.ends_with('.')),
+            None => host == self.0,
+        }
+    }
+}
+
+fn matches_any(patterns: &[HostPattern], host: &str) -> bool {
+    patterns.iter().any(|pattern| pattern.matches(host))
+}
+
+/// Whether an address is loopback, private, link-local or otherwise not publicly routable.
+fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4(ip),
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_private_ipv4(ip),
+            None => is_private_ipv6(ip),
+        },
+    }
+}
+
+/// IPv4 address an IPv6 address translates to, for IPv4-mapped, NAT64 and 6to4 addresses.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return Some(ip);
+    }
+    let segments = ip.segments();
+    let octets = ip.octets();
+    match segments {
+        // 64:ff9b::/96 NAT64 well-known prefix
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(Ipv4Addr::new(
+            octets[12], octets[13], octets[14], octets[15],
+        )),
+        // 2002::/16 6to4
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+fn is_private_ipv6(ip: Ipv6Addr) -> bool {
+    let [first, ..] = ip.segments();
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        || ip.is_multicast()
+        // fec0::/10 deprecated site-local
+        || (first & 0xffc0) == 0xfec0
+}
+
+fn is_private_ipv4(ip: Ipv4Addr) -> bool {
+    let [first, second, third, _] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // 0.0.0.0/8 "this network" and 100.64.0.0/10 shared address space
+        || first == 0
+        || (first == 100 && (64..128).contains(&second))
+        // 192.0.0.0/24 IETF protocol assignments and 198.18.0.0/15 benchmarking
+        || (first == 192 && second == 0 && third == 0)
+        || (first == 198 && (second & 0xfe) == 18)
+        // 240.0.0.0/4 reserved
+        || first >= 240
+}
+
+/// Restrictions on where upstream provided download URLs can point.
+///
+/// Registries choose the URLs that packages, checksums and module archives are
+/// downloaded from, so these are checked before every request and redirect.
+#[derive(Debug, Clone)]
+pub struct DownloadPolicy {
+    /// Hosts downloads are allowed from, any host is allowed if empty
+    allowed_hosts: Vec<HostPattern>,
+    /// Hosts allowed to resolve to private addresses
+    private_hosts: Vec<HostPattern>,
+    max_redirects: usize,
+    /// Hosts sent the credentials of the registry a download is for
+    credential_hosts: Vec<HostPattern>,
+}
+
+impl Default for DownloadPolicy {
+    fn default() -> Self {
+        Self::new(vec![], vec![], 5)
+    }
+}
+
+impl DownloadPolicy {
+    pub fn new(
+        allowed_hosts: Vec<HostPattern>,
+        private_hosts: Vec<HostPattern>,
+        max_redirects: usize,
+    ) -> Self {
+        Self {
+            allowed_hosts,
+            private_hosts,
+            max_redirects,
+            credential_hosts: vec![],
+        }
+    }
+
+    /// Sends downloads from these hosts the credentials of the registry they are for.
+    pub fn with_credential_hosts(mut self, credential_hosts: Vec<HostPattern>) -> Self {
+        self.credential_hosts = credential_hosts;
+        self
+    }
+
+    /// Whether a download host is trusted with the credentials of the registry it is for.
+    pub(crate) fn sends_registry_credentials(&self, host: &str) -> bool {
+        matches_any(&self.credential_hosts, host)
+    }
+
+    /// Allows downloads from a host terrashine is configured to use, including private addresses.
+    pub fn trust(&mut self, host: HostPattern) {
+        if !self.allowed_hosts.is_empty() {
+            self.allowed_hosts.push(host.clone());
+        }
+        self.private_hosts.push(host);
+    }
+
+    pub(crate) fn max_redirects(&self) -> usize {
+        self.max_redirects
+    }
+
+    /// Checks a download URL before it is requested.
+    ///
+    /// Hostnames are checked for private addresses when they are resolved, by [`GuardedResolver`].
+    pub(crate) fn check(&self, url: &Url) -> Result<(), TerrashineError> {
+        let not_allowed = |reason: &str| TerrashineError::DownloadNotAllowed {
+            url: url.to_string(),
+            reason: reason.to_string(),
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(not_allowed("unsupported scheme"));
+        }
+        let Some(host) = url.host() else {
+            return Err(not_allowed("no host"));
+        };
+        let host_str = url.host_str().unwrap_or_default();
+        if !self.allowed_hosts.is_empty() && !matches_any(&self.allowed_hosts, host_str) {
+            return Err(not_allowed("host is not in the allowed download hosts"));
+        }
+        let ip = match host {
+            Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+            Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+            Host::Domain(_) => None,
+        };
+        if let Some(ip) = ip {
+            if is_private_address(ip) && !matches_any(&self.private_hosts, host_str) {
+                return Err(not_allowed("address is private"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolving a hostname returned only private addresses.
+#[derive(Debug, thiserror::Error)]
+#[error("{0} resolves to a private address")]
+pub(crate) struct PrivateAddress(pub(crate) String);
+
+/// DNS resolver that drops private addresses, unless the host is allowed to be private.
+///
+/// Checking the resolved addresses rather than resolving ahead of the request
+/// means a hostname cannot resolve differently by the time it is connected to.
+pub(crate) struct GuardedResolver {
+    policy: Arc<DownloadPolicy>,
+    /// Proxies are resolved by this resolver too, and are trusted as configured
+    proxy_hosts: Vec<HostPattern>,
+}
+
+impl GuardedResolver {
+    pub(crate) fn new(policy: Arc<DownloadPolicy>, proxy_hosts: Vec<HostPattern>) -> Self {
+        Self {
+            policy,
+            proxy_hosts,
+        }
+    }
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let allow_private =
+            matches_any(&self.policy.private_hosts, &host) || matches_any(&self.proxy_hosts, &host);
+        Box::pin(async move {
+            let resolved = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .collect::<Vec<SocketAddr>>();
+            let addrs = resolved
+                .iter()
+                .copied()
+                .filter(|addr| allow_private || !is_private_address(addr.ip()))
+                .collect::<Vec<_>>();
+            if addrs.is_empty() && !resolved.is_empty() {
+                return Err(PrivateAddress(host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<HostPattern> {
+        patterns.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_host_pattern() {
+        let pattern: HostPattern = "*.GitHub.com".parse().unwrap();
+        assert!(pattern.matches("objects.github.com"));
+        assert!(pattern.matches("a.b.github.com."));
+        assert!(!pattern.matches("github.com"));
+        assert!(!pattern.matches("evilgithub.com"));
+
+        let pattern: HostPattern = "releases.hashicorp.com".parse().unwrap();
+        assert!(pattern.matches("Releases.Hashicorp.com"));
+        assert!(!pattern.matches("releases.hashicorp.com.evil.com"));
+
+        assert!("*".parse::<HostPattern>().is_err());
+        assert!("releases.*.com".parse::<HostPattern>().is_err());
+        assert!("https://releases.hashicorp.com/"
+            .parse::<HostPattern>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_private_addresses() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "192.0.0.8",
+            "224.0.0.1",
+            "240.0.0.1",
+            "fec0::1",
+            "ff02::1",
+            // NAT64 and 6to4 forms of private IPv4 addresses
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::127.0.0.1",
+            "2002:a00:1::1",
+            "2002:7f00:1::",
+        ] {
+            assert!(is_private_address(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "1.1.1.1",
+            "100.128.0.1",
+            "198.20.0.1",
+            "2606:4700::1111",
+            "64:ff9b::101:101",
+            "2002:101:101::1",
+        ] {
+            assert!(!is_private_address(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_check_download_url() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        let policy = DownloadPolicy::default();
+        assert!(policy
+            .check(&url("https://releases.hashicorp.com/a.zip"))
+            .is_ok());
+        assert!(policy
+            .check(&url("http://169.254.169.254/latest/"))
+            .is_err());
+        assert!(policy.check(&url("http://[::1]:8080/")).is_err());
+        assert!(policy.check(&url("file:///etc/passwd")).is_err());
+
+        let mut policy = DownloadPolicy::new(
+            patterns(&["releases.hashicorp.com", "*.githubusercontent.com"]),
+            patterns(&["10.0.0.5"]),
+            5,
+        );
+        assert!(policy
+            .check(&url("https://releases.hashicorp.com/a.zip"))
+            .is_ok());
+        assert!(policy
+            .check(&url("https://objects.githubusercontent.com/a.zip"))
+            .is_ok());
+        assert!(policy.check(&url("https://example.com/a.zip")).is_err());
+        assert!(policy.check(&url("http://10.0.0.5/a.zip")).is_err());
+
+        policy.trust("10.0.0.5".parse().unwrap());
+        assert!(policy.check(&url("http://10.0.0.5/a.zip")).is_ok());
+        assert!(policy.check(&url("http://10.0.0.6/a.zip")).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use super::{client::read_body_limit, RegistryClient};
+use crate::{credhelper::CredentialHelper, error::TerrashineError};
+
+const TOKEN_RESPONSE_SIZE_MAX_BYTES: usize = 65536; // 64KB
+
+/// Grant type of the OAuth authorization code flow, the only one terrashine supports.
+const AUTHZ_CODE_GRANT: &str = "authz_code";
+
+fn default_grant_types() -> Vec<String> {
+    vec![AUTHZ_CODE_GRANT.to_string()]
+}
+
+fn default_ports() -> (u16, u16) {
+    (1024, 65535)
+}
+
+/// The `login.v1` service of a registry, as advertised by service discovery.
+#[derive(Debug, Clone, Deserialize)]
+struct LoginServiceDocument {
+    client: String,
+    #[serde(default = "default_grant_types")]
+    grant_types: Vec<String>,
+    authz: Option<String>,
+    token: String,
+    #[serde(default = "default_ports")]
+    ports: (u16, u16),
+    #[serde(default)]
+    scopes: Vec<String>,
+}
+
+/// OAuth client and endpoints used to log in to a registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginService {
+    /// OAuth client ID registered with the registry
+    pub client_id: String,
+    pub authz_url: Url,
+    pub token_url: Url,
+    /// Inclusive range of localhost ports the registry allows redirects to
+    pub ports: (u16, u16),
+    pub scopes: Vec<String>,
+}
+
+impl LoginService {
+    /// Whether the registry allows redirecting to a URI, which must be a localhost `/login` URL.
+    pub fn allows_redirect(&self, redirect_uri: &Url) -> bool {
+        let (first, last) = self.ports;
+        redirect_uri.scheme() == "http"
+            && matches!(
+                redirect_uri.host_str(),
+                Some("localhost" | "127.0.0.1" | "[::1]")
+            )
+            && redirect_uri
+                .port()
+                .is_some_and(|port| (first..=last).contains(&port))
+            && redirect_uri.path() == "/login"
+            && redirect_uri.query().is_none()
+    }
+
+    /// URL the user is sent to, to authorize the client.
+    pub fn authorization_url(&self, redirect_uri: &Url, state: &str, code_verifier: &str) -> Url {
+        let mut url = self.authz_url.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", redirect_uri.as_str())
+            .append_pair("state", state)
+            .append_pair("code_challenge", &code_challenge(code_verifier))
+            .append_pair("code_challenge_method", "S256");
+        if !self.scopes.is_empty() {
+            url.query_pairs_mut()
+                .append_pair("scope", &self.scopes.join(" "));
+        }
+        url
+    }
+}
+
+/// Tokens issued by a registry's token endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LoginToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Lifetime of the access token in seconds
+    pub expires_in: Option<u64>,
+}
+
+/// Random URL safe value, used for OAuth states and PKCE code verifiers.
+pub fn random_secret() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// PKCE S256 code challenge for a code verifier.
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Resolves a login endpoint beneath the root URL of the registry, unless it is absolute.
+fn endpoint_url(root: &Url, endpoint: &str) -> Result<Url, anyhow::Error> {
+    match Url::parse(endpoint) {
+        Ok(url) => Ok(url),
+        Err(url::ParseError::RelativeUrlWithoutBase) => root
+            .join(endpoint.trim_start_matches('/'))
+            .with_context(|| format!("Invalid login endpoint {endpoint}")),
+        Err(error) => Err(error).with_context(|| format!("Invalid login endpoint {endpoint}")),
+    }
+}
+
+impl<T: CredentialHelper> RegistryClient<T> {
+    /// Discovers the `login.v1` service of a registry.
+    pub async fn login_service(&self, hostname: &str) -> Result<LoginService, TerrashineError> {
+        let (root, http) = self.routes.route(hostname).await?;
+        let not_supported = || TerrashineError::TerraformServiceNotSupported {
+            service_type: "login",
+            hostname: hostname.to_string(),
+        };
+        let document = self
+            .discover_services(&root, &http)
+            .await?
+            .login_v1
+            .ok_or_else(not_supported)?;
+        let document: LoginServiceDocument = serde_json::from_value(document)?;
+        if !document.grant_types.iter().any(|g| g == AUTHZ_CODE_GRANT) {
+            return Err(not_supported());
+        }
+        let authz = document.authz.ok_or_else(not_supported)?;
+        Ok(LoginService {
+            client_id: document.client,
+            authz_url: endpoint_url(&root, &authz)?,
+            token_url: endpoint_url(&root, &document.token)?,
+            ports: document.ports,
+            scopes: document.scopes,
+        })
+    }
+
+    /// Requests tokens from a registry's token endpoint with the given grant.
+    pub async fn login_token(
+        &self,
+        token_url: &Url,
+        form: &[(&str, &str)],
+    ) -> Result<LoginToken, TerrashineError> {
+        tracing::debug!(url = %token_url, "POST login token");
+        let http = self.routes.client_for_url(token_url).await?;
+        let response = http
+            .post(token_url.clone())
+            .form(form)
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut response_buffer = Vec::new();
+        read_body_limit(
+            &mut response_buffer,
+            response,
+            TOKEN_RESPONSE_SIZE_MAX_BYTES,
+        )
+        .await?;
+        Ok(serde_json::from_slice(&response_buffer[..])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> LoginService {
+        LoginService {
+            client_id: "terraform-cli".into(),
+            authz_url: Url::parse("https://registry.example.com/oauth/authorization").unwrap(),
+            token_url: Url::parse("https://registry.example.com/oauth/token").unwrap(),
+            ports: (10000, 10010),
+            scopes: vec!["read".into(), "write".into()],
+        }
+    }
+
+    #[test]
+    fn test_code_challenge() {
+        // RFC 7636 appendix B
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+        assert_ne!(random_secret(), random_secret());
+        assert_eq!(random_secret().len(), 43);
+    }
+
+    #[test]
+    fn test_login_service_document_defaults() {
+        let document: LoginServiceDocument = serde_json::from_str(
+            r#"{ "client": "terraform-cli", "authz": "/oauth/authorization", "token": "/oauth/token" }"#,
+        )
+        .unwrap();
+        assert_eq!(document.grant_types, vec!["authz_code"]);
+        assert_eq!(document.ports, (1024, 65535));
+
+        let root = Url::parse("https://forwarder.example.com/registry/").unwrap();
+        assert_eq!(
+            endpoint_url(&root, "/oauth/token").unwrap().as_str(),
+            "https://forwarder.example.com/registry/oauth/token"
+        );
+        assert_eq!(
+            endpoint_url(&root, "https://auth.example.com/token")
+                .unwrap()
+                .as_str(),
+            "https://auth.example.com/token"
+        );
+    }
+
+    #[test]
+    fn test_allowed_redirects() {
+        let service = service();
+        for uri in [
+            "http://localhost:10000/login",
+            "http://127.0.0.1:10010/login",
+        ] {
+            assert!(service.allows_redirect(&Url::parse(uri).unwrap()), "{uri}");
+        }
+        for uri in [
+            "https://localhost:10000/login",
+            "http://localhost:9999/login",
+            "http://localhost:10000/other",
+            "http://localhost:10000/login?next=1",
+            "http://evil.example.com:10000/login",
+        ] {
+            assert!(!service.allows_redirect(&Url::parse(uri).unwrap()), "{uri}");
+        }
+    }
+
+    #[test]
+    fn test_authorization_url() {
+        let redirect_uri = Url::parse("http://localhost:10000/login").unwrap();
+        let url = service().authorization_url(&redirect_uri, "state1", "verifier1");
+        let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        let get = |key: &str| {
+            query
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(get("response_type"), Some("code"));
+        assert_eq!(get("client_id"), Some("terraform-cli"));
+        assert_eq!(get("redirect_uri"), Some("http://localhost:10000/login"));
+        assert_eq!(get("state"), Some("state1"));
+        assert_eq!(
+            get("code_challenge"),
+            Some(code_challenge("verifier1").as_str())
+        );
+        assert_eq!(get("code_challenge_method"), Some("S256"));
+        assert_eq!(get("scope"), Some("read write"));
+    }
+}
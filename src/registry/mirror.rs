@@ -0,0 +1,311 @@
+use futures::{StreamExt, TryStreamExt};
+use http::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use url::Url;
+
+use super::{
+    client::{read_body_limit, REGISTRY_METADATA_SIZE_MAX_BYTES},
+    ProviderPlatform, ProviderVersionItem, ProviderVersions, RegistryClient,
+};
+use crate::{credhelper::CredentialHelper, error::TerrashineError};
+
+/// Version documents fetched at once when listing a provider from a parent mirror.
+const MIRROR_CONCURRENT_REQUESTS: usize = 16;
+
+/// A parent provider network mirror, such as a central terrashine.
+///
+/// https://developer.hashicorp.com/terraform/internals/provider-network-mirror-protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkMirror {
+    base_url: Url,
+}
+
+impl NetworkMirror {
+    pub fn new(mut base_url: Url) -> Self {
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        Self { base_url }
+    }
+
+    fn provider_url(
+        &self,
+        hostname: &str,
+        namespace: &str,
+        provider_type: &str,
+        file: &str,
+    ) -> Url {
+        let mut url = self.base_url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments
+                .pop_if_empty()
+                .extend([hostname, namespace, provider_type, file]);
+        }
+        url
+    }
+}
+
+impl std::fmt::Display for NetworkMirror {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.base_url.fmt(f)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MirrorIndexResponse {
+    versions: HashMap<String, MirrorIndexVersion>,
+}
+
+/// Per version object of a mirror index, only filled in by a parent terrashine.
+#[derive(Debug, Deserialize)]
+struct MirrorIndexVersion {
+    #[serde(default)]
+    protocols: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MirrorVersionResponse {
+    archives: HashMap<String, MirrorArchive>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MirrorArchive {
+    url: String,
+    #[serde(default)]
+    hashes: Vec<String>,
+}
+
+/// Location of a provider package and its expected sha256 checksum, if known.
+#[derive(Debug)]
+pub struct ProviderPackageSource {
+    pub download_url: Url,
+    pub shasum: Option<String>,
+}
+
+impl MirrorVersionResponse {
+    fn platforms(&self) -> Vec<ProviderPlatform> {
+        self.archives
+            .keys()
+            .filter_map(|platform| platform.split_once('_'))
+            .map(|(os, arch)| ProviderPlatform {
+                os: os.to_string(),
+                arch: arch.to_string(),
+            })
+            .collect()
+    }
+
+    fn package(self, version_url: &Url, os: &str, arch: &str) -> Option<ProviderPackageSource> {
+        let archive = self.archives.into_iter().find_map(|(platform, archive)| {
+            (platform.split_once('_') == Some((os, arch))).then_some(archive)
+        })?;
+        let download_url = version_url.join(&archive.url).ok()?;
+        let shasum = archive
+            .hashes
+            .iter()
+            .find_map(|hash| hash.strip_prefix("zh:"))
+            .map(str::to_string);
+        Some(ProviderPackageSource {
+            download_url,
+            shasum,
+        })
+    }
+}
+
+impl<T: CredentialHelper> RegistryClient<T> {
+    /// Fetches a document from the parent mirror, returning None if it does not exist.
+    async fn mirror_get<A: for<'a> Deserialize<'a>>(
+        &self,
+        url: Url,
+    ) -> Result<Option<A>, TerrashineError> {
+        tracing::debug!(%url, "GET network mirror");
+        let hostname = url.host_str().unwrap_or_default().to_string();
+        let request = self.routes.client_for_url(&url).await?.get(url);
+        let response = self.send_authenticated(&hostname, request).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let mut response_buffer = Vec::new();
+        read_body_limit(
+            &mut response_buffer,
+            response.error_for_status()?,
+            REGISTRY_METADATA_SIZE_MAX_BYTES,
+        )
+        .await?;
+        Ok(Some(serde_json::from_slice(&response_buffer[..])?))
+    }
+
+    pub(super) async fn mirror_provider_versions(
+        &self,
+        mirror: &NetworkMirror,
+        hostname: &str,
+        namespace: &str,
+        provider_type: &str,
+    ) -> Result<Option<ProviderVersions>, TerrashineError> {
+        let index_url = mirror.provider_url(hostname, namespace, provider_type, "index.json");
+        let Some(index) = self.mirror_get::<MirrorIndexResponse>(index_url).await? else {
+            return Ok(None);
+        };
+        let origin_protocols = if index.versions.values().any(|v| v.protocols.is_empty()) {
+            self.origin_protocols(hostname, namespace, provider_type)
+                .await
+        } else {
+            HashMap::new()
+        };
+        let origin_protocols = &origin_protocols;
+        let versions = futures::stream::iter(index.versions)
+            .map(|(version, item)| async move {
+                let url = mirror.provider_url(
+                    hostname,
+                    namespace,
+                    provider_type,
+                    &format!("{version}.json"),
+                );
+                let platforms = self
+                    .mirror_get::<MirrorVersionResponse>(url)
+                    .await?
+                    .map(|response| response.platforms())
+                    .unwrap_or_default();
+                let protocols = if item.protocols.is_empty() {
+                    origin_protocols.get(&version).cloned().unwrap_or_default()
+                } else {
+                    item.protocols
+                };
+                Ok::<_, TerrashineError>(ProviderVersionItem {
+                    version,
+                    protocols,
+                    platforms,
+                })
+            })
+            .buffer_unordered(MIRROR_CONCURRENT_REQUESTS)
+            .try_collect()
+            .await?;
+        Ok(Some(ProviderVersions { versions }))
+    }
+
+    /// Looks up the plugin protocols of each provider version from the origin registry.
+    ///
+    /// The network mirror protocol does not carry protocols, so these are fetched
+    /// on a best effort basis when the parent mirror does not pass them on.
+    async fn origin_protocols(
+        &self,
+        hostname: &str,
+        namespace: &str,
+        provider_type: &str,
+    ) -> HashMap<String, Vec<String>> {
+        match self
+            .provider_get::<ProviderVersions>(
+                hostname,
+                &format!("{namespace}/{provider_type}/versions"),
+            )
+            .await
+        {
+            Ok(versions) => versions
+                .versions
+                .into_iter()
+                .map(|item| (item.version, item.protocols))
+                .collect(),
+            Err(error) => {
+                tracing::debug!(reason = %error, "Could not fetch provider protocols from origin registry");
+                HashMap::new()
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) async fn mirror_provider_package(
+        &self,
+        mirror: &NetworkMirror,
+        hostname: &str,
+        namespace: &str,
+        provider_type: &str,
+        version: &str,
+        os: &str,
+        arch: &str,
+    ) -> Result<Option<ProviderPackageSource>, TerrashineError> {
+        let url = mirror.provider_url(
+            hostname,
+            namespace,
+            provider_type,
+            &format!("{version}.json"),
+        );
+        Ok(self
+            .mirror_get::<MirrorVersionResponse>(url.clone())
+            .await?
+            .and_then(|response| response.package(&url, os, arch)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provider_url() {
+        for base in [
+            "https://central.example.com/mirror/v1",
+            "https://central.example.com/mirror/v1/",
+        ] {
+            let mirror = NetworkMirror::new(Url::parse(base).unwrap());
+            assert_eq!(
+                mirror
+                    .provider_url("registry.terraform.io", "hashicorp", "aws", "5.31.0.json")
+                    .as_str(),
+                "https://central.example.com/mirror/v1/registry.terraform.io/hashicorp/aws/5.31.0.json"
+            );
+        }
+    }
+
+    #[test]
+    fn test_mirror_index_protocols() {
+        let index: MirrorIndexResponse = serde_json::from_str(
+            r#"{"versions": {"5.31.0": {"protocols": ["5.0"]}, "5.30.0": {}}}"#,
+        )
+        .unwrap();
+        assert_eq!(index.versions["5.31.0"].protocols, vec!["5.0"]);
+        assert!(index.versions["5.30.0"].protocols.is_empty());
+    }
+
+    #[test]
+    fn test_mirror_version_package() {
+        let response: MirrorVersionResponse = serde_json::from_str(
+            r#"{
+                "archives": {
+                    "linux_amd64": {
+                        "url": "terraform-provider-aws_5.31.0_linux_amd64.zip",
+                        "hashes": ["h1:abc", "zh:def"]
+                    },
+                    "darwin_arm64": {
+                        "url": "https://central.example.com/mirror/v1/artifacts/12"
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let mut platforms = response
+            .platforms()
+            .into_iter()
+            .map(|p| format!("{}/{}", p.os, p.arch))
+            .collect::<Vec<_>>();
+        platforms.sort();
+        assert_eq!(platforms, vec!["darwin/arm64", "linux/amd64"]);
+
+        let version_url = Url::parse(
+            "https://central.example.com/mirror/v1/registry.terraform.io/hashicorp/aws/5.31.0.json",
+        )
+        .unwrap();
+        let response: MirrorVersionResponse = serde_json::from_str(
+            r#"{"archives": {"linux_amd64": {"url": "terraform-provider-aws_5.31.0_linux_amd64.zip", "hashes": ["h1:abc", "zh:def"]}}}"#,
+        )
+        .unwrap();
+        let package = response.package(&version_url, "linux", "amd64").unwrap();
+        assert_eq!(
+            package.download_url.as_str(),
+            "https://central.example.com/mirror/v1/registry.terraform.io/hashicorp/aws/terraform-provider-aws_5.31.0_linux_amd64.zip"
+        );
+        assert_eq!(package.shasum.as_deref(), Some("def"));
+
+        let response: MirrorVersionResponse = serde_json::from_str(r#"{"archives": {}}"#).unwrap();
+        assert!(response.package(&version_url, "linux", "amd64").is_none());
+    }
+}
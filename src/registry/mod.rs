@@ -1,4 +1,16 @@
 mod client;
 pub use client::*;
+mod download;
+pub use download::*;
+mod login;
+pub use login::*;
+mod mirror;
+pub use mirror::*;
+mod sources;
+pub use sources::*;
+mod tls;
+pub use tls::*;
 mod types;
 pub use types::*;
+mod upstream;
+pub use upstream::*;
@@ -0,0 +1,365 @@
+use reqwest::Response;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+use super::{
+    NetworkMirror, ProviderPackageSource, ProviderResponse, ProviderVersions, RegistryClient,
+};
+use crate::{credhelper::CredentialHelper, error::TerrashineError};
+
+/// Consecutive failures after which a source is tried after the healthy sources.
+const UNHEALTHY_FAILURES: u32 = 3;
+/// How long a source is considered unhealthy once it reaches the failure threshold.
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Where provider versions and packages for an upstream hostname can be fetched from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamSource {
+    /// The registry for the hostname itself
+    Origin,
+    /// A provider network mirror, such as another terrashine or a static bucket
+    Mirror(NetworkMirror),
+}
+
+impl FromStr for UpstreamSource {
+    type Err = url::ParseError;
+
+    /// Parses "origin" or the base URL of a network mirror.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("origin") {
+            return Ok(UpstreamSource::Origin);
+        }
+        Ok(UpstreamSource::Mirror(NetworkMirror::new(Url::parse(s)?)))
+    }
+}
+
+impl Display for UpstreamSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamSource::Origin => f.write_str("origin"),
+            UpstreamSource::Mirror(mirror) => write!(f, "{mirror}"),
+        }
+    }
+}
+
+impl UpstreamSource {
+    /// Key that failures are tracked under, origins are tracked per hostname.
+    fn health_key(&self, hostname: &str) -> String {
+        match self {
+            UpstreamSource::Origin => format!("origin:{hostname}"),
+            UpstreamSource::Mirror(mirror) => mirror.to_string(),
+        }
+    }
+}
+
+/// A provider package download that has started from an upstream source.
+#[derive(Debug)]
+pub struct ProviderDownload {
+    /// Source the package is downloaded from
+    pub source: String,
+    /// Expected sha256 checksum of the package
+    pub shasum: String,
+    pub response: Response,
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    consecutive_failures: u32,
+    unhealthy_until: Option<Instant>,
+}
+
+/// Tracks failing upstream sources so they are tried after healthy ones.
+#[derive(Clone, Default)]
+pub struct SourceHealth {
+    state: Arc<Mutex<HashMap<String, HealthState>>>,
+}
+
+impl SourceHealth {
+    fn is_healthy(&self, key: &str) -> bool {
+        let state = self.state.lock().expect("Source health poisoned");
+        state
+            .get(key)
+            .and_then(|health| health.unhealthy_until)
+            .is_none_or(|until| until <= Instant::now())
+    }
+
+    /// Orders sources with healthy sources first, otherwise keeping the configured order.
+    fn order<'a>(&self, hostname: &str, sources: &'a [UpstreamSource]) -> Vec<&'a UpstreamSource> {
+        let (mut healthy, unhealthy): (Vec<_>, Vec<_>) = sources
+            .iter()
+            .partition(|source| self.is_healthy(&source.health_key(hostname)));
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    fn record_success(&self, key: &str) {
+        let mut state = self.state.lock().expect("Source health poisoned");
+        if let Some(health) = state.remove(key) {
+            if health.unhealthy_until.is_some() {
+                tracing::info!(source = %key, "Upstream source has recovered");
+            }
+        }
+    }
+
+    fn record_failure(&self, key: &str) {
+        let mut state = self.state.lock().expect("Source health poisoned");
+        let health = state.entry(key.to_string()).or_default();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= UNHEALTHY_FAILURES {
+            if health.unhealthy_until.is_none() {
+                tracing::warn!(source = %key, "Upstream source is unhealthy");
+            }
+            health.unhealthy_until = Some(Instant::now() + UNHEALTHY_COOLDOWN);
+        }
+    }
+}
+
+/// Whether an error indicates the source is unavailable, rather than the content missing.
+fn is_source_failure(error: &TerrashineError) -> bool {
+    match error {
+        TerrashineError::ProviderResponseFailure { source } => !source
+            .status()
+            .is_some_and(|status| status.is_client_error()),
+        TerrashineError::TerraformServiceNotSupported { .. } => false,
+        TerrashineError::ArtifactChecksumUnavailable { .. } => false,
+        TerrashineError::DownloadNotAllowed { .. } => false,
+        _ => true,
+    }
+}
+
+impl<T: CredentialHelper> RegistryClient<T> {
+    /// Sources configured for a hostname, in priority order.
+    async fn sources(&self, hostname: &str) -> Result<Vec<UpstreamSource>, TerrashineError> {
+        let sources = self.routes.sources(hostname).await?;
+        if sources.is_empty() {
+            return Ok(self.default_sources.to_vec());
+        }
+        Ok(sources)
+    }
+
+    /// Tries each source in turn until one has the content.
+    ///
+    /// Returns the last error if no source has the content, or an error naming
+    /// the content if every source reported it missing.
+    async fn try_sources<A, F, Fut>(
+        &self,
+        hostname: &str,
+        name: &str,
+        fetch: F,
+    ) -> Result<(String, A), TerrashineError>
+    where
+        F: Fn(UpstreamSource) -> Fut,
+        Fut: std::future::Future<Output = Result<Option<A>, TerrashineError>>,
+    {
+        let sources = self.sources(hostname).await?;
+        let mut last_error = None;
+        for source in self.health.order(hostname, &sources) {
+            let key = source.health_key(hostname);
+            match fetch(source.clone()).await {
+                Ok(Some(result)) => {
+                    self.health.record_success(&key);
+                    return Ok((source.to_string(), result));
+                }
+                Ok(None) => {
+                    self.health.record_success(&key);
+                    tracing::debug!(%source, %name, "Not found in upstream source, trying next source");
+                }
+                Err(error) => {
+                    if is_source_failure(&error) {
+                        self.health.record_failure(&key);
+                        tracing::warn!(%source, %name, reason = %error, "Upstream source failed, trying next source");
+                    } else {
+                        self.health.record_success(&key);
+                        tracing::debug!(%source, %name, reason = %error, "Not found in upstream source, trying next source");
+                    }
+                    last_error = Some(error);
+                }
+            }
+        }
+        Err(
+            last_error.unwrap_or_else(|| TerrashineError::UpstreamSourcesExhausted {
+                name: name.to_string(),
+            }),
+        )
+    }
+
+    /// Lists the available versions of a provider from the first source that has it.
+    pub async fn provider_versions(
+        &self,
+        hostname: &str,
+        namespace: &str,
+        provider_type: &str,
+    ) -> Result<ProviderVersions, TerrashineError> {
+        let name = format!("{hostname}/{namespace}/{provider_type}");
+        let (source, versions) = self
+            .try_sources(hostname, &name, |source| async move {
+                match source {
+                    UpstreamSource::Origin => self
+                        .provider_get(hostname, &format!("{namespace}/{provider_type}/versions"))
+                        .await
+                        .map(Some),
+                    UpstreamSource::Mirror(mirror) => {
+                        self.mirror_provider_versions(&mirror, hostname, namespace, provider_type)
+                            .await
+                    }
+                }
+            })
+            .await?;
+        tracing::debug!(%source, %name, "Listed provider versions");
+        Ok(versions)
+    }
+
+    /// Starts downloading a provider package from the first source that can serve it.
+    pub async fn provider_download(
+        &self,
+        hostname: &str,
+        namespace: &str,
+        provider_type: &str,
+        version: &str,
+        os: &str,
+        arch: &str,
+    ) -> Result<ProviderDownload, TerrashineError> {
+        let name = format!("{hostname}/{namespace}/{provider_type} {version} {os}_{arch}");
+        let name = name.as_str();
+        let (source, (shasum, response)) = self
+            .try_sources(hostname, name, |source| async move {
+                let package = match source {
+                    UpstreamSource::Origin => {
+                        let response: ProviderResponse = self
+                            .provider_get(
+                                hostname,
+                                &format!(
+                                    "{namespace}/{provider_type}/{version}/download/{os}/{arch}"
+                                ),
+                            )
+                            .await?;
+                        Some(ProviderPackageSource {
+                            download_url: response.download_url,
+                            shasum: Some(response.shasum),
+                        })
+                    }
+                    UpstreamSource::Mirror(ref mirror) => {
+                        self.mirror_provider_package(
+                            mirror,
+                            hostname,
+                            namespace,
+                            provider_type,
+                            version,
+                            os,
+                            arch,
+                        )
+                        .await?
+                    }
+                };
+                let Some(mut package) = package else {
+                    return Ok(None);
+                };
+                // Mirrors need not list hashes, packages are only cached with a checksum
+                let shasum = match package.shasum.take() {
+                    Some(shasum) => shasum,
+                    None => {
+                        tracing::info!(url = %package.download_url, %source, "No checksum from mirror, fetching it from the origin registry");
+                        let response: ProviderResponse = self
+                            .provider_get(
+                                hostname,
+                                &format!(
+                                    "{namespace}/{provider_type}/{version}/download/{os}/{arch}"
+                                ),
+                            )
+                            .await
+                            .map_err(|error| TerrashineError::ArtifactChecksumUnavailable {
+                                name: name.to_string(),
+                                source_name: source.to_string(),
+                                reason: format!("the origin registry failed with {error}"),
+                            })?;
+                        response.shasum
+                    }
+                };
+                let response = self.download(Some(hostname), package.download_url).await?;
+                Ok(Some((shasum, response)))
+            })
+            .await?;
+        Ok(ProviderDownload {
+            source,
+            shasum,
+            response,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(url: &str) -> UpstreamSource {
+        url.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            "origin".parse::<UpstreamSource>().unwrap(),
+            UpstreamSource::Origin
+        );
+        assert_eq!(
+            mirror("https://central.example.com/mirror/v1").to_string(),
+            "https://central.example.com/mirror/v1/"
+        );
+        assert!("central.example.com".parse::<UpstreamSource>().is_err());
+    }
+
+    #[test]
+    fn test_unhealthy_sources_are_tried_last() {
+        let health = SourceHealth::default();
+        let sources = vec![
+            UpstreamSource::Origin,
+            mirror("https://central.example.com/mirror/v1/"),
+            mirror("https://bucket.example.com/"),
+        ];
+        let key = UpstreamSource::Origin.health_key("registry.terraform.io");
+
+        for _ in 1..UNHEALTHY_FAILURES {
+            health.record_failure(&key);
+        }
+        assert_eq!(
+            health.order("registry.terraform.io", &sources),
+            sources.iter().collect::<Vec<_>>()
+        );
+
+        health.record_failure(&key);
+        assert_eq!(
+            health.order("registry.terraform.io", &sources),
+            vec![&sources[1], &sources[2], &sources[0]]
+        );
+        // Origins of other hostnames are tracked separately
+        assert_eq!(
+            health.order("registry.example.com", &sources),
+            sources.iter().collect::<Vec<_>>()
+        );
+
+        health.record_success(&key);
+        assert_eq!(
+            health.order("registry.terraform.io", &sources),
+            sources.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_missing_content_is_not_a_failure() {
+        assert!(!is_source_failure(
+            &TerrashineError::TerraformServiceNotSupported {
+                service_type: "provider",
+                hostname: "registry.example.com".into(),
+            }
+        ));
+        assert!(is_source_failure(
+            &TerrashineError::ProviderResponseTooLarge { limit: 1 }
+        ));
+    }
+}
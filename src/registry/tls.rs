@@ -0,0 +1,358 @@
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::{fmt::Debug, sync::Arc};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::ClientSettings;
+
+/// Prefix of SPKI pins, which are followed by the base64 SHA-256 digest of the public key.
+const SPKI_PIN_PREFIX: &str = "sha256/";
+
+/// Parses a `sha256/{base64}` pin of a certificate's subject public key info.
+pub(crate) fn parse_spki_pin(pin: &str) -> Result<[u8; 32], anyhow::Error> {
+    let digest = pin
+        .strip_prefix(SPKI_PIN_PREFIX)
+        .with_context(|| format!("SPKI pin {pin} must start with {SPKI_PIN_PREFIX}"))?;
+    let digest = STANDARD
+        .decode(digest)
+        .with_context(|| format!("SPKI pin {pin} is not valid base64"))?;
+    digest
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("SPKI pin {pin} is not a SHA-256 digest"))
+}
+
+/// SHA-256 digest of a certificate's subject public key info.
+fn spki_digest(cert: &CertificateDer<'_>) -> Result<[u8; 32], rustls::Error> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref())
+        .map_err(|_| rustls::Error::General("Invalid server certificate".into()))?;
+    Ok(Sha256::digest(cert.public_key().raw).into())
+}
+
+/// Verifies the certificate chain as usual, then requires the server's public key to be pinned.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let digest = spki_digest(end_entity)?;
+        if !self.pins.contains(&digest) {
+            tracing::warn!(
+                server_name = %server_name.to_str(),
+                spki = format!("{SPKI_PIN_PREFIX}{}", STANDARD.encode(digest)),
+                "Upstream certificate does not match a pinned public key"
+            );
+            return Err(rustls::Error::General(
+                "Server public key is not pinned".into(),
+            ));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Client certificate and key presented to an upstream host for mutual TLS.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ClientIdentity {
+    /// PEM encoded certificate chain, starting with the client certificate
+    pub cert: String,
+    /// PEM encoded private key
+    pub key: String,
+}
+
+impl Debug for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientIdentity")
+            .field("cert", &self.cert)
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+impl ClientIdentity {
+    /// Validates an optional certificate and key, which must be configured together.
+    pub(crate) fn from_parts(
+        cert: Option<String>,
+        key: Option<String>,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        match (cert, key) {
+            (Some(cert), Some(key)) => {
+                parse_client_identity(&cert, &key)?;
+                Ok(Some(Self { cert, key }))
+            }
+            (None, None) => Ok(None),
+            _ => anyhow::bail!("A client certificate and key must be configured together"),
+        }
+    }
+
+    pub(crate) fn reqwest_identity(&self) -> Result<reqwest::Identity, anyhow::Error> {
+        reqwest::Identity::from_pem(format!("{}\n{}", self.cert, self.key).as_bytes())
+            .context("Invalid client certificate")
+    }
+}
+
+/// Parses a PEM client certificate chain and private key.
+pub(crate) fn parse_client_identity(
+    cert: &str,
+    key: &str,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), anyhow::Error> {
+    let certs = CertificateDer::pem_slice_iter(cert.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .context("Parsing client certificate")?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in client certificate");
+    }
+    let key = PrivateKeyDer::from_pem_slice(key.as_bytes()).context("Parsing client key")?;
+    Ok((certs, key))
+}
+
+/// TLS configuration for hosts with pinned public keys.
+///
+/// The HTTP client's own TLS configuration cannot pin keys, so the roots, extra
+/// CA certificates and client certificate are all configured here instead.
+pub(crate) fn pinned_tls_config(
+    roots: &[CertificateDer<'static>],
+    settings: &ClientSettings,
+) -> Result<ClientConfig, anyhow::Error> {
+    let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+    let mut store = RootCertStore::empty();
+    store.add_parsable_certificates(roots.iter().cloned());
+    if let Some(bundle) = &settings.ca_bundle {
+        for cert in CertificateDer::pem_slice_iter(bundle.as_bytes()) {
+            store
+                .add(cert.context("Parsing CA bundle")?)
+                .context("Invalid CA certificate")?;
+        }
+    }
+    let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(store), provider.clone())
+        .build()
+        .context("Building certificate verifier")?;
+    let pins = settings
+        .spki_pins
+        .iter()
+        .map(|pin| parse_spki_pin(pin))
+        .collect::<Result<_, _>>()?;
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier { inner, pins }));
+    let config = match &settings.client_identity {
+        Some(identity) => {
+            let (certs, key) = parse_client_identity(&identity.cert, &identity.key)?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertifiedKey, KeyPair, PublicKeyData};
+    use rustls::{server::WebPkiClientVerifier, ServerConfig};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    fn generate() -> CertifiedKey<KeyPair> {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap()
+    }
+
+    fn pin(generated: &CertifiedKey<KeyPair>) -> String {
+        let digest = Sha256::digest(generated.signing_key.subject_public_key_info());
+        format!("{SPKI_PIN_PREFIX}{}", STANDARD.encode(digest))
+    }
+
+    fn client_config(server: &CertifiedKey<KeyPair>, settings: ClientSettings) -> ClientConfig {
+        let settings = ClientSettings {
+            ca_bundle: Some(server.cert.pem()),
+            ..settings
+        };
+        pinned_tls_config(&[], &settings).unwrap()
+    }
+
+    fn server_config(
+        server: &CertifiedKey<KeyPair>,
+        client_ca: Option<&CertifiedKey<KeyPair>>,
+    ) -> ServerConfig {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                roots.add(client_ca.cert.der().clone()).unwrap();
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .unwrap();
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let key =
+            PrivateKeyDer::from_pem_slice(server.signing_key.serialize_pem().as_bytes()).unwrap();
+        builder
+            .with_single_cert(vec![server.cert.der().clone()], key)
+            .unwrap()
+    }
+
+    /// Connects the client to the server, returning the certificates the server received.
+    async fn handshake(
+        client: ClientConfig,
+        server: ServerConfig,
+    ) -> Result<Vec<CertificateDer<'static>>, std::io::Error> {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let (client, server) = tokio::join!(
+            TlsConnector::from(Arc::new(client))
+                .connect(ServerName::try_from("localhost").unwrap(), client_io),
+            TlsAcceptor::from(Arc::new(server)).accept(server_io),
+        );
+        client?;
+        let server = server?;
+        Ok(server
+            .get_ref()
+            .1
+            .peer_certificates()
+            .unwrap_or_default()
+            .to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_pinned_key_handshake() {
+        let server = generate();
+        let settings = ClientSettings {
+            spki_pins: vec![pin(&generate()), pin(&server)],
+            ..Default::default()
+        };
+        let received = handshake(
+            client_config(&server, settings),
+            server_config(&server, None),
+        )
+        .await
+        .unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unpinned_key_handshake_fails() {
+        let server = generate();
+        let settings = ClientSettings {
+            spki_pins: vec![pin(&generate())],
+            ..Default::default()
+        };
+        let error = handshake(
+            client_config(&server, settings),
+            server_config(&server, None),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("not pinned"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_client_identity_handshake() {
+        let server = generate();
+        let client = generate();
+        let settings = ClientSettings {
+            spki_pins: vec![pin(&server)],
+            client_identity: Some(ClientIdentity {
+                cert: client.cert.pem(),
+                key: client.signing_key.serialize_pem(),
+            }),
+            ..Default::default()
+        };
+        let received = handshake(
+            client_config(&server, settings),
+            server_config(&server, Some(&client)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(received, vec![client.cert.der().clone()]);
+
+        // The server requires a client certificate, so connecting without one fails
+        let settings = ClientSettings {
+            spki_pins: vec![pin(&server)],
+            ..Default::default()
+        };
+        assert!(handshake(
+            client_config(&server, settings),
+            server_config(&server, Some(&client)),
+        )
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_spki_pin() {
+        let pin = format!("sha256/{}", STANDARD.encode([7u8; 32]));
+        assert_eq!(parse_spki_pin(&pin).unwrap(), [7u8; 32]);
+        assert!(parse_spki_pin(&STANDARD.encode([7u8; 32])).is_err());
+        assert!(parse_spki_pin("sha256/not base64").is_err());
+        assert!(parse_spki_pin(&format!("sha256/{}", STANDARD.encode([7u8; 20]))).is_err());
+    }
+
+    #[test]
+    fn test_client_identity_requires_cert_and_key() {
+        assert!(ClientIdentity::from_parts(None, None).unwrap().is_none());
+        assert!(ClientIdentity::from_parts(Some("cert".into()), None).is_err());
+        assert!(ClientIdentity::from_parts(None, Some("key".into())).is_err());
+        assert!(ClientIdentity::from_parts(Some("".into()), Some("".into())).is_err());
+    }
+
+    #[test]
+    fn test_client_identity_redacts_key() {
+        let identity = ClientIdentity {
+            cert: "cert".into(),
+            key: "secret".into(),
+        };
+        assert!(!format!("{identity:?}").contains("secret"));
+    }
+}
@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
 /// Types in this module correspond to the API responses from hashicorp,
@@ -42,15 +42,37 @@ pub struct ProviderResponse {
     pub signing_keys: ProviderSigningKeys,
 }
 
-#[allow(dead_code)]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct ProviderSigningKeys {
     pub gpg_public_keys: Vec<ProviderGPGPublicKey>,
 }
 
-#[allow(dead_code)]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct ProviderGPGPublicKey {
     pub key_id: String,
     pub ascii_armor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trust_signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+}
+
+// Terraform registry module API response for "List Available Versions for a Specific Module"
+// https://developer.hashicorp.com/terraform/internals/module-registry-protocol
+
+#[derive(Deserialize, Debug)]
+pub struct ModuleVersionsResponse {
+    pub modules: Vec<ModuleVersions>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModuleVersions {
+    pub versions: Vec<ModuleVersionItem>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModuleVersionItem {
+    pub version: String,
 }
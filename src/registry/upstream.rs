@@ -0,0 +1,699 @@
+use anyhow::Context;
+use reqwest::{redirect, Certificate, Client, ClientBuilder, Proxy};
+use rustls::pki_types::CertificateDer;
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+use super::{
+    tls::{parse_spki_pin, pinned_tls_config},
+    ClientIdentity, DownloadPolicy, GuardedResolver, HostPattern, UpstreamSource,
+};
+use crate::{
+    credhelper::encryption::{is_encrypted, TokenCipher},
+    error::TerrashineError,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time routes read from the database are reused for, before changes to them take effect
+const HOST_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// HTTP client settings that can be overridden per upstream host.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ClientSettings {
+    /// Proxy used instead of the global proxy
+    pub proxy: Option<String>,
+    /// PEM encoded certificates trusted in addition to the native roots
+    pub ca_bundle: Option<String>,
+    /// Total request timeout
+    pub timeout: Option<Duration>,
+    /// Client certificate for mutual TLS
+    pub client_identity: Option<ClientIdentity>,
+    /// `sha256/{base64}` digests of the public keys the upstream certificate must have
+    pub spki_pins: Vec<String>,
+}
+
+/// Where and how to connect to a logical upstream hostname.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpstreamHost {
+    /// Base URL used in place of https://{hostname}/
+    pub base_url: Option<Url>,
+    /// Port used in place of the default upstream port
+    pub port: Option<u16>,
+    pub client: ClientSettings,
+    /// Sources tried in order for providers, the default sources are used when empty
+    pub sources: Vec<UpstreamSource>,
+}
+
+impl UpstreamHost {
+    /// URL that service discovery and relative service paths are resolved against.
+    fn root_url(&self, hostname: &str, default_port: u16) -> Result<Url, TerrashineError> {
+        let mut url = match &self.base_url {
+            Some(base_url) => base_url.clone(),
+            None => Url::parse(&format!("https://{hostname}/"))
+                .with_context(|| format!("Invalid upstream hostname {hostname}"))?,
+        };
+        if let Some(port) = self
+            .port
+            .or(self.base_url.is_none().then_some(default_port))
+        {
+            url.set_port(Some(port))
+                .map_err(|_| anyhow::anyhow!("Cannot set port on upstream URL {url}"))?;
+        }
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Ok(url)
+    }
+}
+
+/// Upstream host entry in the upstream config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamHostEntry {
+    base_url: Option<Url>,
+    port: Option<u16>,
+    proxy: Option<String>,
+    ca_bundle: Option<PathBuf>,
+    timeout: Option<String>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    #[serde(default)]
+    spki_pins: Vec<String>,
+    #[serde(default)]
+    sources: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamConfigFile {
+    #[serde(default)]
+    hosts: HashMap<String, UpstreamHostEntry>,
+}
+
+/// Reads upstream host overrides from a TOML config file, keyed by lower case hostname.
+pub fn load_upstream_config(path: &Path) -> Result<HashMap<String, UpstreamHost>, anyhow::Error> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading upstream config {}", path.display()))?;
+    parse_upstream_config(&contents, path.parent().unwrap_or(Path::new(".")))
+        .with_context(|| format!("Parsing upstream config {}", path.display()))
+}
+
+/// Reads an optional PEM file from the upstream config, relative to `base_dir`.
+fn read_pem(
+    base_dir: &Path,
+    path: Option<PathBuf>,
+    kind: &str,
+) -> Result<Option<String>, anyhow::Error> {
+    path.map(|path| {
+        let path = base_dir.join(path);
+        std::fs::read_to_string(&path).with_context(|| format!("Reading {kind} {}", path.display()))
+    })
+    .transpose()
+}
+
+/// Parses an upstream config file, resolving PEM file paths relative to `base_dir`.
+fn parse_upstream_config(
+    contents: &str,
+    base_dir: &Path,
+) -> Result<HashMap<String, UpstreamHost>, anyhow::Error> {
+    let file: UpstreamConfigFile = toml::from_str(contents)?;
+    let mut hosts = HashMap::new();
+    for (hostname, entry) in file.hosts {
+        let ca_bundle = read_pem(base_dir, entry.ca_bundle, "CA bundle")?;
+        let client_identity = ClientIdentity::from_parts(
+            read_pem(base_dir, entry.client_cert, "client certificate")?,
+            read_pem(base_dir, entry.client_key, "client key")?,
+        )
+        .with_context(|| format!("Invalid client certificate for {hostname}"))?;
+        validate_spki_pins(&entry.spki_pins)
+            .with_context(|| format!("Invalid SPKI pins for {hostname}"))?;
+        let timeout = entry
+            .timeout
+            .map(|timeout| timeout.parse::<humantime::Duration>().map(Into::into))
+            .transpose()
+            .with_context(|| format!("Invalid timeout for {hostname}"))?;
+        let sources = parse_sources(&entry.sources)
+            .with_context(|| format!("Invalid sources for {hostname}"))?;
+        let host = UpstreamHost {
+            base_url: entry.base_url,
+            port: entry.port,
+            client: ClientSettings {
+                proxy: entry.proxy,
+                ca_bundle,
+                timeout,
+                client_identity,
+                spki_pins: entry.spki_pins,
+            },
+            sources,
+        };
+        host.root_url(&hostname, 443)
+            .with_context(|| format!("Invalid upstream for {hostname}"))?;
+        hosts.insert(hostname.to_ascii_lowercase(), host);
+    }
+    Ok(hosts)
+}
+
+fn parse_sources(sources: &[String]) -> Result<Vec<UpstreamSource>, anyhow::Error> {
+    sources
+        .iter()
+        .map(|source| {
+            source
+                .parse()
+                .with_context(|| format!("Invalid upstream source {source}"))
+        })
+        .collect()
+}
+
+fn validate_spki_pins(pins: &[String]) -> Result<(), anyhow::Error> {
+    for pin in pins {
+        parse_spki_pin(pin)?;
+    }
+    Ok(())
+}
+
+/// Settings shared by every HTTP client terrashine builds.
+#[derive(Clone, Default)]
+pub struct HttpSettings {
+    roots: Arc<[CertificateDer<'static>]>,
+    proxy: Option<Proxy>,
+    /// Host of the global proxy, trusted by download clients
+    proxy_host: Option<HostPattern>,
+}
+
+impl HttpSettings {
+    /// Creates settings from the trusted roots and the global proxy and its address.
+    pub fn new(roots: Vec<CertificateDer<'static>>, proxy: Option<(Proxy, &str)>) -> Self {
+        let proxy_host = proxy
+            .as_ref()
+            .and_then(|(_, address)| Url::parse(address).ok())
+            .as_ref()
+            .and_then(HostPattern::for_url);
+        Self {
+            roots: roots.into(),
+            proxy: proxy.map(|(proxy, _)| proxy),
+            proxy_host,
+        }
+    }
+
+    fn builder(&self, settings: &ClientSettings) -> Result<ClientBuilder, anyhow::Error> {
+        let mut builder = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(settings.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        if settings.spki_pins.is_empty() {
+            for cert in self.roots.iter() {
+                builder = builder.add_root_certificate(Certificate::from_der(cert.as_ref())?);
+            }
+            if let Some(bundle) = &settings.ca_bundle {
+                for cert in
+                    Certificate::from_pem_bundle(bundle.as_bytes()).context("Parsing CA bundle")?
+                {
+                    builder = builder.add_root_certificate(cert);
+                }
+            }
+            if let Some(identity) = &settings.client_identity {
+                builder = builder.identity(identity.reqwest_identity()?);
+            }
+        } else {
+            builder = builder.use_preconfigured_tls(pinned_tls_config(&self.roots, settings)?);
+        }
+        match (&settings.proxy, &self.proxy) {
+            (Some(proxy), _) => {
+                builder = builder.proxy(Proxy::all(proxy).context("Invalid proxy")?)
+            }
+            (None, Some(proxy)) => builder = builder.proxy(proxy.clone()),
+            (None, None) => {}
+        }
+        Ok(builder)
+    }
+
+    /// Builds an HTTP client with the given overrides applied.
+    pub fn client(&self, settings: &ClientSettings) -> Result<Client, anyhow::Error> {
+        Ok(self.builder(settings)?.build()?)
+    }
+
+    /// Builds an HTTP client for upstream provided download URLs.
+    ///
+    /// Redirects are not followed, so each location can be checked against the policy.
+    fn download_client(
+        &self,
+        settings: &ClientSettings,
+        policy: Arc<DownloadPolicy>,
+    ) -> Result<Client, anyhow::Error> {
+        let mut proxy_hosts = Vec::from_iter(self.proxy_host.clone());
+        if let Some(proxy) = &settings.proxy {
+            proxy_hosts.extend(
+                Url::parse(proxy)
+                    .ok()
+                    .as_ref()
+                    .and_then(HostPattern::for_url),
+            );
+        }
+        Ok(self
+            .builder(settings)?
+            .redirect(redirect::Policy::none())
+            .dns_resolver(Arc::new(GuardedResolver::new(policy, proxy_hosts)))
+            .build()?)
+    }
+}
+
+/// Routes read from the database, including hostnames without one, kept for a short time so
+/// each request does not query the database for every upstream call.
+#[derive(Default)]
+struct HostCache {
+    entries: Mutex<HashMap<String, (Instant, Option<UpstreamHost>)>>,
+}
+
+impl HostCache {
+    fn get(&self, hostname: &str, ttl: Duration) -> Option<Option<UpstreamHost>> {
+        let mut entries = self.entries.lock().expect("Upstream host cache poisoned");
+        match entries.get(hostname) {
+            Some((read, host)) if read.elapsed() < ttl => Some(host.clone()),
+            Some(_) => {
+                entries.remove(hostname);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, hostname: String, host: Option<UpstreamHost>) {
+        self.entries
+            .lock()
+            .expect("Upstream host cache poisoned")
+            .insert(hostname, (Instant::now(), host));
+    }
+}
+
+/// Resolves logical upstream hostnames to concrete URLs and HTTP clients.
+///
+/// Hosts in the database take precedence over those in the upstream config file,
+/// so routes can be changed without restarting terrashine.
+#[derive(Clone)]
+pub struct UpstreamRoutes {
+    default_port: u16,
+    settings: HttpSettings,
+    hosts: Arc<HashMap<String, UpstreamHost>>,
+    db: Option<PgPool>,
+    /// Decrypts client keys stored in the database, which are in plaintext when None
+    cipher: Option<TokenCipher>,
+    db_hosts: Arc<HostCache>,
+    download_policy: Arc<DownloadPolicy>,
+    /// Clients keyed by their settings and whether they are for downloads
+    clients: Arc<Mutex<HashMap<(ClientSettings, bool), Client>>>,
+}
+
+impl UpstreamRoutes {
+    pub fn new(
+        default_port: u16,
+        settings: HttpSettings,
+        hosts: HashMap<String, UpstreamHost>,
+        db: Option<PgPool>,
+        download_policy: DownloadPolicy,
+    ) -> Self {
+        Self {
+            default_port,
+            settings,
+            hosts: Arc::new(hosts),
+            db,
+            cipher: None,
+            db_hosts: Default::default(),
+            download_policy: Arc::new(download_policy),
+            clients: Default::default(),
+        }
+    }
+
+    /// Decrypts client keys of routes in the database with the token encryption keys.
+    pub fn with_cipher(mut self, cipher: Option<TokenCipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    async fn host(&self, hostname: &str) -> Result<UpstreamHost, TerrashineError> {
+        let hostname = hostname.to_ascii_lowercase();
+        if let Some(db) = &self.db {
+            let host = match self.db_hosts.get(&hostname, HOST_CACHE_TTL) {
+                Some(host) => host,
+                None => {
+                    let host = get_upstream_host(db, self.cipher.as_ref(), &hostname).await?;
+                    self.db_hosts.insert(hostname.clone(), host.clone());
+                    host
+                }
+            };
+            if let Some(host) = host {
+                return Ok(host);
+            }
+        }
+        Ok(self.hosts.get(&hostname).cloned().unwrap_or_default())
+    }
+
+    fn client(&self, settings: &ClientSettings, download: bool) -> Result<Client, TerrashineError> {
+        let mut clients = self.clients.lock().expect("Upstream client cache poisoned");
+        let key = (settings.clone(), download);
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+        let client = if download {
+            self.settings
+                .download_client(settings, self.download_policy.clone())?
+        } else {
+            self.settings.client(settings)?
+        };
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    pub(crate) fn download_policy(&self) -> &DownloadPolicy {
+        &self.download_policy
+    }
+
+    /// Root URL and client for a registry hostname.
+    pub(crate) async fn route(&self, hostname: &str) -> Result<(Url, Client), TerrashineError> {
+        let host = self.host(hostname).await?;
+        let root = host.root_url(hostname, self.default_port)?;
+        Ok((root, self.client(&host.client, false)?))
+    }
+
+    /// Sources configured for a registry hostname, empty if the defaults should be used.
+    pub(crate) async fn sources(
+        &self,
+        hostname: &str,
+    ) -> Result<Vec<UpstreamSource>, TerrashineError> {
+        Ok(self.host(hostname).await?.sources)
+    }
+
+    /// Client for an absolute URL, using the overrides of the URL's host.
+    pub(crate) async fn client_for_url(&self, url: &Url) -> Result<Client, TerrashineError> {
+        let host = match url.host_str() {
+            Some(hostname) => self.host(hostname).await?,
+            None => UpstreamHost::default(),
+        };
+        self.client(&host.client, false)
+    }
+
+    /// Client for an upstream provided download URL, enforcing the download policy.
+    pub(crate) async fn download_client_for_url(
+        &self,
+        url: &Url,
+    ) -> Result<Client, TerrashineError> {
+        let host = match url.host_str() {
+            Some(hostname) => self.host(hostname).await?,
+            None => UpstreamHost::default(),
+        };
+        self.client(&host.client, true)
+    }
+}
+
+/// Client keys are bound to a different name than registry tokens when encrypted,
+/// so one cannot be swapped for the other.
+fn client_key_aad(hostname: &str) -> String {
+    format!("{hostname}#client_key")
+}
+
+fn decrypt_client_key(
+    cipher: Option<&TokenCipher>,
+    hostname: &str,
+    stored: String,
+) -> Result<String, anyhow::Error> {
+    match cipher {
+        Some(cipher) => cipher.decrypt(&client_key_aad(hostname), &stored),
+        None if is_encrypted(&stored) => Err(anyhow::anyhow!(
+            "Client key for {hostname} is encrypted but no token encryption keys are configured"
+        )),
+        None => Ok(stored),
+    }
+}
+
+/// Encrypts client keys of upstream routes that are in plaintext or encrypted with an old key.
+///
+/// Returns the number of client keys re-encrypted.
+pub async fn rotate_client_keys(pool: &PgPool, cipher: &TokenCipher) -> Result<u64, anyhow::Error> {
+    let mut tx = pool.begin().await?;
+    let rows = sqlx::query!(
+        r#"
+        select "hostname", "client_key" as "client_key!"
+        from "upstream_host"
+        where "client_key" is not null
+        for update;
+        "#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut rotated = 0;
+    for row in rows {
+        let Some(client_key) = cipher.rotate(&client_key_aad(&row.hostname), &row.client_key)?
+        else {
+            continue;
+        };
+        sqlx::query!(
+            r#"
+            update "upstream_host" set "client_key" = $2 where "hostname" = $1;
+            "#,
+            row.hostname,
+            client_key,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tracing::info!(hostname = %row.hostname, "Re-encrypted upstream client_key");
+        rotated += 1;
+    }
+    tx.commit().await?;
+    Ok(rotated)
+}
+
+async fn get_upstream_host(
+    db: &PgPool,
+    cipher: Option<&TokenCipher>,
+    hostname: &str,
+) -> Result<Option<UpstreamHost>, TerrashineError> {
+    let row = sqlx::query!(
+        r#"
+        select "base_url", "port", "proxy", "ca_bundle", "timeout_seconds", "sources",
+            "client_cert", "client_key", "spki_pins"
+        from "upstream_host"
+        where "hostname" = $1;
+        "#,
+        hostname
+    )
+    .fetch_optional(db)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let base_url = row
+        .base_url
+        .map(|url| Url::parse(&url))
+        .transpose()
+        .with_context(|| format!("Invalid base URL stored for upstream {hostname}"))?;
+    let sources = parse_sources(&row.sources.unwrap_or_default())
+        .with_context(|| format!("Invalid sources stored for upstream {hostname}"))?;
+    let client_key = row
+        .client_key
+        .map(|stored| decrypt_client_key(cipher, hostname, stored))
+        .transpose()?;
+    let client_identity = ClientIdentity::from_parts(row.client_cert, client_key)
+        .with_context(|| format!("Invalid client certificate stored for upstream {hostname}"))?;
+    let spki_pins = row.spki_pins.unwrap_or_default();
+    validate_spki_pins(&spki_pins)
+        .with_context(|| format!("Invalid SPKI pins stored for upstream {hostname}"))?;
+    Ok(Some(UpstreamHost {
+        base_url,
+        port: row.port.and_then(|port| u16::try_from(port).ok()),
+        client: ClientSettings {
+            proxy: row.proxy,
+            ca_bundle: row.ca_bundle,
+            timeout: row
+                .timeout_seconds
+                .map(|seconds| Duration::from_secs(seconds.unsigned_abs().into())),
+            client_identity,
+            spki_pins,
+        },
+        sources,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_url() {
+        let host = UpstreamHost::default();
+        assert_eq!(
+            host.root_url("registry.terraform.io", 443)
+                .unwrap()
+                .as_str(),
+            "https://registry.terraform.io/"
+        );
+        assert_eq!(
+            host.root_url("registry.example.com", 8443)
+                .unwrap()
+                .as_str(),
+            "https://registry.example.com:8443/"
+        );
+
+        let host = UpstreamHost {
+            base_url: Some(Url::parse("http://forwarder.internal/registry.terraform.io").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            host.root_url("registry.terraform.io", 8443)
+                .unwrap()
+                .as_str(),
+            "http://forwarder.internal/registry.terraform.io/"
+        );
+
+        let host = UpstreamHost {
+            base_url: Some(Url::parse("https://forwarder.internal/").unwrap()),
+            port: Some(9443),
+            ..Default::default()
+        };
+        assert_eq!(
+            host.root_url("registry.terraform.io", 443)
+                .unwrap()
+                .as_str(),
+            "https://forwarder.internal:9443/"
+        );
+    }
+
+    #[test]
+    fn test_parse_upstream_config() {
+        let hosts = parse_upstream_config(
+            r#"
+            [hosts."Registry.Terraform.io"]
+            base_url = "https://forwarder.internal/registry.terraform.io/"
+            proxy = "http://proxy.internal:3128"
+            timeout = "30s"
+
+            [hosts."registry.example.com"]
+            port = 8443
+            sources = ["https://central.example.com/mirror/v1/", "origin"]
+            "#,
+            Path::new("."),
+        )
+        .unwrap();
+        assert_eq!(
+            hosts["registry.terraform.io"],
+            UpstreamHost {
+                base_url: Some(
+                    Url::parse("https://forwarder.internal/registry.terraform.io/").unwrap()
+                ),
+                port: None,
+                client: ClientSettings {
+                    proxy: Some("http://proxy.internal:3128".into()),
+                    ca_bundle: None,
+                    timeout: Some(Duration::from_secs(30)),
+                    client_identity: None,
+                    spki_pins: vec![],
+                },
+                sources: vec![],
+            }
+        );
+        assert_eq!(hosts["registry.example.com"].port, Some(8443));
+        assert_eq!(
+            hosts["registry.example.com"].sources,
+            vec![
+                "https://central.example.com/mirror/v1/".parse().unwrap(),
+                UpstreamSource::Origin
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_upstream_config_rejects_unknown_fields() {
+        assert!(parse_upstream_config(
+            r#"
+            [hosts."registry.example.com"]
+            prot = 8443
+            "#,
+            Path::new("."),
+        )
+        .is_err());
+        assert!(parse_upstream_config(
+            r#"
+            [hosts."registry.example.com"]
+            timeout = "soon"
+            "#,
+            Path::new("."),
+        )
+        .is_err());
+        assert!(parse_upstream_config(
+            r#"
+            [hosts."registry.example.com"]
+            sources = ["central.example.com"]
+            "#,
+            Path::new("."),
+        )
+        .is_err());
+        assert!(parse_upstream_config(
+            r#"
+            [hosts."registry.example.com"]
+            spki_pins = ["sha1/AAAA"]
+            "#,
+            Path::new("."),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_host_cache_expires() {
+        let cache = HostCache::default();
+        assert_eq!(cache.get("registry.example.com", HOST_CACHE_TTL), None);
+        cache.insert("registry.example.com".into(), None);
+        assert_eq!(
+            cache.get("registry.example.com", HOST_CACHE_TTL),
+            Some(None)
+        );
+        assert_eq!(cache.get("registry.example.com", Duration::ZERO), None);
+        assert_eq!(cache.get("registry.example.com", HOST_CACHE_TTL), None);
+    }
+
+    #[test]
+    fn test_decrypt_client_key() {
+        let cipher = TokenCipher::new(vec![("k1".into(), [7; 32])]).unwrap();
+        let stored = cipher
+            .encrypt(&client_key_aad("registry.example.com"), "PEM")
+            .unwrap();
+        assert_eq!(
+            decrypt_client_key(Some(&cipher), "registry.example.com", stored.clone()).unwrap(),
+            "PEM"
+        );
+        // Bound to the hostname, and unreadable without keys
+        assert!(decrypt_client_key(Some(&cipher), "other.example.com", stored.clone()).is_err());
+        assert!(decrypt_client_key(None, "registry.example.com", stored).is_err());
+        assert_eq!(
+            decrypt_client_key(None, "registry.example.com", "PEM".into()).unwrap(),
+            "PEM"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_routes_fall_back_to_defaults() {
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            "registry.example.com".to_string(),
+            UpstreamHost {
+                port: Some(8443),
+                ..Default::default()
+            },
+        );
+        let routes = UpstreamRoutes::new(
+            443,
+            HttpSettings::default(),
+            hosts,
+            None,
+            DownloadPolicy::default(),
+        );
+        let (root, _) = routes.route("Registry.Example.com").await.unwrap();
+        assert_eq!(root.as_str(), "https://registry.example.com:8443/");
+        let (root, _) = routes.route("registry.terraform.io").await.unwrap();
+        assert_eq!(root.as_str(), "https://registry.terraform.io/");
+    }
+}
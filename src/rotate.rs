@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use sqlx::postgres::PgPoolOptions;
+use tracing::{error, info};
+
+use crate::{
+    config::RotateTokensArgs, credhelper::database::rotate_tokens, registry::rotate_client_keys,
+};
+
+/// Encrypts stored registry auth tokens and upstream client keys with the current token
+/// encryption key.
+pub async fn run_rotate_tokens(config: RotateTokensArgs) -> Result<(), ()> {
+    let cipher = match config.token_encryption.cipher() {
+        Ok(Some(cipher)) => cipher,
+        Ok(None) => {
+            error!("No token encryption keys configured, exiting.");
+            return Err(());
+        }
+        Err(error) => {
+            error!(reason = ?error, "Could not load token encryption keys, exiting.");
+            return Err(());
+        }
+    };
+
+    let db = match PgPoolOptions::new()
+        .acquire_timeout(Duration::from_secs(10))
+        .connect_with(config.database_url.clone())
+        .await
+    {
+        Ok(pool) => pool,
+        Err(error) => {
+            error!(reason = %error, "Could not initialize pool, exiting.");
+            return Err(());
+        }
+    };
+
+    match rotate_tokens(&db, &cipher).await {
+        Ok(rotated) => info!(rotated, "Re-encrypted registry auth tokens"),
+        Err(error) => {
+            error!(reason = ?error, "Could not re-encrypt registry auth tokens, exiting.");
+            return Err(());
+        }
+    }
+    match rotate_client_keys(&db, &cipher).await {
+        Ok(rotated) => {
+            info!(rotated, "Re-encrypted upstream client keys");
+            Ok(())
+        }
+        Err(error) => {
+            error!(reason = ?error, "Could not re-encrypt upstream client keys, exiting.");
+            Err(())
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::Context;
+use chrono::{SubsecRound, Utc};
+use pgp::{
+    composed::{
+        ArmorOptions, Deserializable, SignedPublicKey, SignedSecretKey, StandaloneSignature,
+    },
+    packet::{SignatureConfig, SignatureType, Subpacket, SubpacketData},
+    ser::Serialize,
+    types::{KeyDetails, Password, SecretKeyTrait},
+};
+use std::{path::Path, sync::Arc};
+
+use crate::registry::{ProviderGPGPublicKey, ProviderSigningKeys};
+
+/// Signs provider checksum files with an OpenPGP key owned by terrashine.
+///
+/// Terraform verifies the detached signature of the SHA256SUMS file against the
+/// public keys advertised by the registry before installing a provider.
+#[derive(Clone)]
+pub(crate) struct ProviderSigner {
+    key: Arc<SignedSecretKey>,
+    passphrase: Arc<Password>,
+}
+
+impl std::fmt::Debug for ProviderSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProviderSigner")
+            .field("key_id", &self.key_id())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProviderSigner {
+    /// Loads an ASCII armored OpenPGP secret key from disk.
+    pub(crate) fn from_file(path: &Path, passphrase: Option<&str>) -> Result<Self, anyhow::Error> {
+        let armored = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading signing key {}", path.display()))?;
+        Self::from_armored(&armored, passphrase)
+    }
+
+    pub(crate) fn from_armored(
+        armored: &str,
+        passphrase: Option<&str>,
+    ) -> Result<Self, anyhow::Error> {
+        let (key, _) =
+            SignedSecretKey::from_string(armored).context("Parsing OpenPGP secret key")?;
+        let passphrase = passphrase.map(Password::from).unwrap_or_default();
+        key.verify().context("Verifying OpenPGP secret key")?;
+        key.primary_key
+            .unlock(&passphrase, |_, _| Ok(()))
+            .context("Unlocking OpenPGP secret key")??;
+        Ok(Self {
+            key: Arc::new(key),
+            passphrase: Arc::new(passphrase),
+        })
+    }
+
+    /// Key ID in the upper case hexadecimal form used by the registry protocol.
+    pub(crate) fn key_id(&self) -> String {
+        hex::encode_upper(self.key.key_id())
+    }
+
+    /// Public keys advertised to clients for verifying signatures.
+    pub(crate) fn signing_keys(&self) -> Result<ProviderSigningKeys, anyhow::Error> {
+        let ascii_armor = self
+            .key
+            .signed_public_key()
+            .to_armored_string(ArmorOptions::default())
+            .context("Armoring OpenPGP public key")?;
+        Ok(ProviderSigningKeys {
+            gpg_public_keys: vec![ProviderGPGPublicKey {
+                key_id: self.key_id(),
+                ascii_armor,
+                trust_signature: None,
+                source: None,
+                source_url: None,
+            }],
+        })
+    }
+
+    /// Produces a binary detached signature of the data.
+    pub(crate) fn sign(&self, data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut config = SignatureConfig::v4(
+            SignatureType::Binary,
+            self.key.algorithm(),
+            self.key.hash_alg(),
+        );
+        config.hashed_subpackets = vec![
+            Subpacket::regular(SubpacketData::IssuerFingerprint(self.key.fingerprint()))?,
+            Subpacket::regular(SubpacketData::SignatureCreationTime(
+                Utc::now().trunc_subsecs(0),
+            ))?,
+        ];
+        config.unhashed_subpackets = vec![Subpacket::regular(SubpacketData::Issuer(
+            self.key.key_id(),
+        ))?];
+        let signature = config
+            .sign(&self.key.primary_key, &self.passphrase, data)
+            .context("Signing data")?;
+        Ok(StandaloneSignature::new(signature).to_bytes()?)
+    }
+}
+
+/// Verifies detached signatures against a set of trusted OpenPGP public keys.
+#[derive(Clone)]
+pub(crate) struct SignatureVerifier {
+    keys: Arc<[SignedPublicKey]>,
+}
+
+impl std::fmt::Debug for SignatureVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignatureVerifier")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+impl SignatureVerifier {
+    /// Loads ASCII armored OpenPGP public keys from disk.
+    pub(crate) fn from_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let armored = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading public keys {}", path.display()))?;
+        Self::from_armored(&armored)
+    }
+
+    pub(crate) fn from_armored(armored: &str) -> Result<Self, anyhow::Error> {
+        let (keys, _) =
+            SignedPublicKey::from_string_many(armored).context("Parsing OpenPGP public keys")?;
+        let keys = keys
+            .collect::<Result<Vec<_>, _>>()
+            .context("Parsing OpenPGP public keys")?;
+        anyhow::ensure!(!keys.is_empty(), "No OpenPGP public keys found");
+        Ok(Self { keys: keys.into() })
+    }
+
+    /// Checks a binary detached signature was made by any trusted key or subkey.
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let Ok(signature) = StandaloneSignature::from_bytes(signature) else {
+            return false;
+        };
+        self.keys.iter().any(|key| {
+            signature.verify(&key.primary_key, data).is_ok()
+                || key
+                    .public_subkeys
+                    .iter()
+                    .any(|subkey| signature.verify(&subkey.key, data).is_ok())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &str = include_str!("../resources/test/gpg/signing-key.asc");
+
+    #[test]
+    fn test_detached_signature_verifies() {
+        let signer = ProviderSigner::from_armored(TEST_KEY, None).unwrap();
+        let data = b"abc123  terraform-provider-example_1.0.0_linux_amd64.zip\n";
+        let signature = signer.sign(data).unwrap();
+
+        let keys = signer.signing_keys().unwrap();
+        assert_eq!(keys.gpg_public_keys[0].key_id, signer.key_id());
+        let (public, _) =
+            SignedPublicKey::from_string(&keys.gpg_public_keys[0].ascii_armor).unwrap();
+        let signature = StandaloneSignature::from_bytes(&signature[..]).unwrap();
+        signature.verify(&public, data).unwrap();
+        assert!(signature.verify(&public, b"tampered").is_err());
+    }
+
+    #[test]
+    fn test_invalid_key_rejected() {
+        assert!(ProviderSigner::from_armored("not a key", None).is_err());
+    }
+
+    #[test]
+    fn test_verifier_accepts_trusted_signature() {
+        let signer = ProviderSigner::from_armored(TEST_KEY, None).unwrap();
+        let keys = signer.signing_keys().unwrap();
+        let verifier =
+            SignatureVerifier::from_armored(&keys.gpg_public_keys[0].ascii_armor).unwrap();
+        let data = b"abc123  terraform_1.0.0_linux_amd64.zip\n";
+        let signature = signer.sign(data).unwrap();
+        assert!(verifier.verify(data, &signature));
+        assert!(!verifier.verify(b"tampered", &signature));
+        assert!(!verifier.verify(data, b"not a signature"));
+    }
+}
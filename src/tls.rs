@@ -0,0 +1,229 @@
+use anyhow::Context;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Certificate and key loaded from files, along with the modification times they were loaded at.
+#[derive(Debug)]
+struct Loaded {
+    key: Arc<CertifiedKey>,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+/// Server certificate read from PEM files, which is replaced when the files change.
+///
+/// Connections already established keep the certificate they were accepted with.
+#[derive(Debug)]
+pub(crate) struct CertificateFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    loaded: RwLock<Loaded>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Parses a PEM certificate chain and the private key of its first certificate.
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, anyhow::Error> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .with_context(|| format!("Reading TLS certificate {}", cert.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", cert.display());
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Reading TLS key {}", key.display()))?;
+    let signing_key = ring::default_provider()
+        .key_provider
+        .load_private_key(private_key)
+        .context("Unsupported TLS key")?;
+    let certified = CertifiedKey::new(certs, signing_key);
+    certified
+        .keys_match()
+        .context("TLS key does not match the certificate")?;
+    Ok(certified)
+}
+
+impl CertificateFiles {
+    /// Loads the certificate chain and key, which must be valid at startup.
+    pub(crate) fn load(cert: PathBuf, key: PathBuf) -> Result<Self, anyhow::Error> {
+        let modified = (modified(&cert), modified(&key));
+        let loaded = Loaded {
+            key: Arc::new(load_certified_key(&cert, &key)?),
+            modified,
+        };
+        Ok(Self {
+            cert,
+            key,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    /// Reloads the files if they changed since they were last loaded, or always when forced.
+    ///
+    /// The current certificate is kept if the files cannot be loaded, such as while
+    /// they are being replaced. Returns whether the certificate was replaced.
+    pub(crate) fn reload(&self, force: bool) -> Result<bool, anyhow::Error> {
+        let modified = (modified(&self.cert), modified(&self.key));
+        if !force && self.loaded.read().expect("Poisoned lock").modified == modified {
+            return Ok(false);
+        }
+        let key = Arc::new(load_certified_key(&self.cert, &self.key)?);
+        *self.loaded.write().expect("Poisoned lock") = Loaded { key, modified };
+        Ok(true)
+    }
+
+    fn reload_logged(&self, force: bool) {
+        match self.reload(force) {
+            Ok(true) => info!(cert = %self.cert.display(), "Reloaded TLS certificate"),
+            Ok(false) => {}
+            Err(error) => {
+                error!(reason = ?error, "Could not reload TLS certificate, serving the previous certificate")
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateFiles {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.loaded.read().expect("Poisoned lock").key.clone())
+    }
+}
+
+/// TLS acceptor serving the certificate files, offering HTTP/2 and HTTP/1.1 with ALPN.
+pub(crate) fn tls_acceptor(
+    certificate: Arc<CertificateFiles>,
+) -> Result<TlsAcceptor, anyhow::Error> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(certificate);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reloads the certificate files when they change, or unconditionally on SIGHUP.
+pub(crate) async fn certificate_reloader(
+    certificate: Arc<CertificateFiles>,
+    cancel: CancellationToken,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(error) => {
+            warn!(reason = %error, "Could not listen for SIGHUP, TLS certificate is only reloaded when changed");
+            None
+        }
+    };
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => certificate.reload_logged(false),
+            Some(_) = async {
+                match &mut hangup {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => certificate.reload_logged(true),
+            _ = cancel.cancelled() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+
+    fn generate(dir: &Path, name: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = dir.join(format!("{name}.crt"));
+        let key = dir.join(format!("{name}.key"));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+        (cert, key, generated.cert.der().clone())
+    }
+
+    fn serving(certificate: &CertificateFiles) -> CertificateDer<'static> {
+        certificate.loaded.read().unwrap().key.cert[0].clone()
+    }
+
+    #[test]
+    fn test_load_certificate_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key, der) = generate(dir.path(), "first");
+        let (_, other_key, _) = generate(dir.path(), "second");
+
+        let certificate = CertificateFiles::load(cert.clone(), key.clone()).unwrap();
+        assert_eq!(serving(&certificate), der);
+        assert!(CertificateFiles::load(cert.clone(), other_key).is_err());
+        assert!(CertificateFiles::load(key.clone(), key).is_err());
+        assert!(CertificateFiles::load(dir.path().join("missing.crt"), cert).is_err());
+    }
+
+    #[test]
+    fn test_reload_certificate_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key, first) = generate(dir.path(), "server");
+        let certificate = CertificateFiles::load(cert.clone(), key.clone()).unwrap();
+        assert!(!certificate.reload(false).unwrap());
+
+        // A broken file keeps the previous certificate in place
+        std::fs::write(&key, "not a key").unwrap();
+        assert!(certificate.reload(true).is_err());
+        assert_eq!(serving(&certificate), first);
+
+        let (_, _, second) = generate(dir.path(), "server");
+        assert!(certificate.reload(true).unwrap());
+        assert_eq!(serving(&certificate), second);
+    }
+
+    #[tokio::test]
+    async fn test_tls_acceptor_negotiates_http2() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key, der) = generate(dir.path(), "server");
+        let acceptor = tls_acceptor(Arc::new(CertificateFiles::load(cert, key).unwrap())).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(socket).await;
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(der).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                TcpStream::connect(addr).await.unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    }
+}
@@ -0,0 +1,357 @@
+use super::Webhooks;
+use hmac::{Hmac, Mac};
+use http::header::CONTENT_TYPE;
+use reqwest::Client;
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const BATCH_SIZE: i64 = 20;
+// Deliveries are leased for longer than the HTTP client timeout so that
+// another instance will not pick up a delivery that is still in flight.
+const LEASE_SECONDS: f64 = 120.0;
+const MAX_BACKOFF_SECONDS: f64 = 3600.0;
+
+#[derive(Debug)]
+struct PendingDelivery {
+    id: i64,
+    endpoint: String,
+    event_type: String,
+    payload: String,
+}
+
+/// Delivers queued webhook events until cancelled.
+pub(crate) async fn dispatcher(
+    webhooks: &Webhooks,
+    http: &Client,
+    secret: &[u8],
+    max_attempts: i32,
+    cancel: CancellationToken,
+) {
+    if !webhooks.is_enabled() {
+        tracing::debug!("No webhook endpoints configured, dispatcher not started");
+        return;
+    }
+    loop {
+        let batch_full = match claim_deliveries(webhooks).await {
+            Ok(deliveries) => {
+                let batch_full = deliveries.len() as i64 == BATCH_SIZE;
+                for delivery in deliveries {
+                    let result = deliver(http, secret, &delivery).await;
+                    complete_delivery(webhooks, &delivery, result, max_attempts).await;
+                }
+                batch_full
+            }
+            Err(error) => {
+                tracing::error!(reason = %error, "Could not claim webhook deliveries");
+                false
+            }
+        };
+
+        if batch_full && !cancel.is_cancelled() {
+            continue;
+        }
+        select! {
+            _ = webhooks.notify.notified() => {},
+            _ = tokio::time::sleep(POLL_INTERVAL) => {},
+            _ = cancel.cancelled() => {
+                tracing::debug!("Webhook dispatcher cancelled");
+                break;
+            }
+        }
+    }
+}
+
+async fn claim_deliveries(webhooks: &Webhooks) -> Result<Vec<PendingDelivery>, sqlx::Error> {
+    sqlx::query_as!(
+        PendingDelivery,
+        r#"
+        update "webhook_delivery"
+            set "next_attempt_at" = now() + $2 * interval '1 second'
+        where "id" in (
+            select "id" from "webhook_delivery"
+            where "abandoned_at" is null and "next_attempt_at" <= now()
+            order by "id"
+            limit $1
+            for update skip locked
+        )
+        returning "id", "endpoint", "event_type", "payload"::text as "payload!";
+        "#,
+        BATCH_SIZE,
+        LEASE_SECONDS,
+    )
+    .fetch_all(&webhooks.db)
+    .await
+}
+
+async fn deliver(
+    http: &Client,
+    secret: &[u8],
+    delivery: &PendingDelivery,
+) -> Result<(), anyhow::Error> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .to_string();
+    let signature = sign(secret, &timestamp, delivery.payload.as_bytes());
+    http.post(&delivery.endpoint)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Terrashine-Event", &delivery.event_type)
+        .header("X-Terrashine-Delivery", delivery.id)
+        .header("X-Terrashine-Timestamp", &timestamp)
+        .header("X-Terrashine-Signature", format!("sha256={signature}"))
+        .body(delivery.payload.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn complete_delivery(
+    webhooks: &Webhooks,
+    delivery: &PendingDelivery,
+    result: Result<(), anyhow::Error>,
+    max_attempts: i32,
+) {
+    let PendingDelivery { id, endpoint, .. } = delivery;
+    let result = match result {
+        Ok(()) => {
+            tracing::debug!(%id, %endpoint, "Webhook delivered");
+            sqlx::query!(r#"delete from "webhook_delivery" where "id" = $1;"#, id)
+                .execute(&webhooks.db)
+                .await
+                .map(|_| ())
+        }
+        Err(error) => {
+            let reason = format!("{error:#}");
+            sqlx::query!(
+                r#"
+                update "webhook_delivery" set
+                    "attempts" = "attempts" + 1,
+                    "last_error" = $2,
+                    "next_attempt_at" = now() + least(power(2, "attempts"), $3) * interval '1 second',
+                    "abandoned_at" = case when "attempts" + 1 >= $4 then now() end
+                where "id" = $1
+                returning "abandoned_at" is not null as "abandoned!";
+                "#,
+                id,
+                reason,
+                MAX_BACKOFF_SECONDS,
+                max_attempts,
+            )
+            .fetch_one(&webhooks.db)
+            .await
+            .map(|row| {
+                if row.abandoned {
+                    tracing::error!(%id, %endpoint, %reason, "Webhook delivery abandoned after too many attempts");
+                } else {
+                    tracing::warn!(%id, %endpoint, %reason, "Webhook delivery failed, will retry");
+                }
+            })
+        }
+    };
+    if let Err(error) = result {
+        tracing::error!(reason = %error, %id, "Could not update webhook delivery");
+    }
+}
+
+/// Computes the value of the `X-Terrashine-Signature` header.
+///
+/// The signature is the hex encoded HMAC-SHA256 of the timestamp and body
+/// joined with a period, receivers should reject stale timestamps to prevent replays.
+fn sign(secret: &[u8], timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::Event;
+    use axum::{extract::State, routing::post, Router};
+    use http::{HeaderMap, StatusCode};
+    use sqlx::PgPool;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    const SECRET: &[u8] = b"secret";
+
+    #[derive(Clone, Default)]
+    struct Received {
+        failures: Arc<Mutex<usize>>,
+        requests: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+    }
+
+    /// Receives webhook requests, answering the first `failures` of them with an error.
+    async fn endpoint(failures: usize) -> (Url, Received) {
+        let received = Received::default();
+        *received.failures.lock().unwrap() = failures;
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State(received): State<Received>, headers: HeaderMap, body: String| async move {
+                        received.requests.lock().unwrap().push((headers, body));
+                        let mut failures = received.failures.lock().unwrap();
+                        if *failures > 0 {
+                            *failures -= 1;
+                            return StatusCode::INTERNAL_SERVER_ERROR;
+                        }
+                        StatusCode::NO_CONTENT
+                    },
+                ),
+            )
+            .with_state(received.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "http://127.0.0.1:{}/hook",
+            listener.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, received)
+    }
+
+    async fn queue(db: &PgPool, endpoint: Url) -> Webhooks {
+        let webhooks = Webhooks::new(db.clone(), vec![endpoint]);
+        webhooks
+            .enqueue(&Event::UpstreamFailure {
+                hostname: "registry.terraform.io".into(),
+                namespace: "hashicorp".into(),
+                provider_type: "aws".into(),
+                version: None,
+                reason: "timed out".into(),
+            })
+            .await
+            .unwrap();
+        webhooks
+    }
+
+    /// Attempts, seconds until the next attempt and whether the delivery was abandoned.
+    async fn delivery_state(db: &PgPool) -> (i32, f64, bool) {
+        sqlx::query_as(
+            r#"
+            select "attempts",
+                extract(epoch from "next_attempt_at" - now())::float8,
+                "abandoned_at" is not null
+            from "webhook_delivery";
+            "#,
+        )
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn make_due(db: &PgPool) {
+        sqlx::query(r#"update "webhook_delivery" set "next_attempt_at" = now();"#)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn test_failed_delivery_is_retried(db: PgPool) {
+        let (url, received) = endpoint(1).await;
+        let webhooks = queue(&db, url).await;
+        let http = Client::new();
+
+        let deliveries = claim_deliveries(&webhooks).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        let result = deliver(&http, SECRET, &deliveries[0]).await;
+        assert!(result.is_err());
+        complete_delivery(&webhooks, &deliveries[0], result, 5).await;
+        let (attempts, delay, abandoned) = delivery_state(&db).await;
+        assert_eq!(attempts, 1);
+        assert!(delay > 0.0 && delay <= 1.0, "{delay}");
+        assert!(!abandoned);
+        assert!(claim_deliveries(&webhooks).await.unwrap().is_empty());
+
+        make_due(&db).await;
+        let deliveries = claim_deliveries(&webhooks).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        let result = deliver(&http, SECRET, &deliveries[0]).await;
+        assert!(result.is_ok());
+        complete_delivery(&webhooks, &deliveries[0], result, 5).await;
+        let remaining: i64 = sqlx::query_scalar(r#"select count(*) from "webhook_delivery";"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 0);
+
+        let requests = received.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (headers, body) = &requests[1];
+        assert_eq!(headers["x-terrashine-event"], "upstream_failure");
+        let timestamp = headers["x-terrashine-timestamp"].to_str().unwrap();
+        assert_eq!(
+            headers["x-terrashine-signature"],
+            format!("sha256={}", sign(SECRET, timestamp, body.as_bytes()))
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_failed_delivery_backoff(db: PgPool) {
+        let webhooks = queue(&db, Url::parse("http://127.0.0.1:1/hook").unwrap()).await;
+        for (attempts, expected) in [(3, 8.0), (11, 2048.0), (20, MAX_BACKOFF_SECONDS)] {
+            sqlx::query(
+                r#"update "webhook_delivery" set "attempts" = $1, "next_attempt_at" = now();"#,
+            )
+            .bind(attempts)
+            .execute(&db)
+            .await
+            .unwrap();
+            let deliveries = claim_deliveries(&webhooks).await.unwrap();
+            assert_eq!(deliveries.len(), 1);
+            complete_delivery(
+                &webhooks,
+                &deliveries[0],
+                Err(anyhow::anyhow!("refused")),
+                100,
+            )
+            .await;
+            let (_, delay, abandoned) = delivery_state(&db).await;
+            assert!(
+                delay > expected - 1.0 && delay <= expected,
+                "{attempts}: {delay}"
+            );
+            assert!(!abandoned);
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_delivery_abandoned_after_max_attempts(db: PgPool) {
+        let webhooks = queue(&db, Url::parse("http://127.0.0.1:1/hook").unwrap()).await;
+        for attempt in 1..=2 {
+            make_due(&db).await;
+            let deliveries = claim_deliveries(&webhooks).await.unwrap();
+            assert_eq!(deliveries.len(), 1);
+            complete_delivery(
+                &webhooks,
+                &deliveries[0],
+                Err(anyhow::anyhow!("refused")),
+                2,
+            )
+            .await;
+            let (attempts, _, abandoned) = delivery_state(&db).await;
+            assert_eq!(attempts, attempt);
+            assert_eq!(abandoned, attempt == 2);
+        }
+        make_due(&db).await;
+        assert!(claim_deliveries(&webhooks).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_signature() {
+        // echo -n '1700000000.{"type":"test"}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign(b"secret", "1700000000", br#"{"type":"test"}"#),
+            "5164242d2d7c1061af198b4bfea622c8f5aeec1b9276e38d50a14d7f9dd39bee"
+        );
+    }
+}
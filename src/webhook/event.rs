@@ -0,0 +1,124 @@
+use serde::Serialize;
+use std::{error::Error, time::SystemTime};
+
+/// Events emitted by terrashine to the configured webhook endpoints.
+///
+/// The serialized form is the body of the webhook request, the variant name
+/// is used for the `type` field and the fields are nested under `data`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub(crate) enum Event {
+    /// Provider versions seen for the first time when refreshing against the upstream registry.
+    ProviderVersionsDiscovered {
+        hostname: String,
+        namespace: String,
+        provider_type: String,
+        versions: Vec<String>,
+    },
+    /// A provider package has been downloaded and stored in the artifact cache.
+    ArtifactCached {
+        hostname: String,
+        namespace: String,
+        provider_type: String,
+        version: String,
+        os: String,
+        arch: String,
+    },
+    /// A request to the upstream registry failed.
+    UpstreamFailure {
+        hostname: String,
+        namespace: String,
+        provider_type: String,
+        version: Option<String>,
+        reason: String,
+    },
+    /// A downloaded provider package did not match the checksum published by the registry.
+    IntegrityFailure {
+        hostname: String,
+        namespace: String,
+        provider_type: String,
+        version: String,
+        os: String,
+        arch: String,
+        expected_shasum: String,
+        actual_shasum: String,
+    },
+}
+
+impl Event {
+    pub(crate) fn event_type(&self) -> &'static str {
+        match self {
+            Event::ProviderVersionsDiscovered { .. } => "provider_versions_discovered",
+            Event::ArtifactCached { .. } => "artifact_cached",
+            Event::UpstreamFailure { .. } => "upstream_failure",
+            Event::IntegrityFailure { .. } => "integrity_failure",
+        }
+    }
+}
+
+/// Body of the webhook request
+#[derive(Serialize, Debug)]
+pub(super) struct EventEnvelope<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    timestamp: String,
+}
+
+impl<'a> EventEnvelope<'a> {
+    pub(super) fn new(event: &'a Event) -> Self {
+        Self {
+            event,
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+}
+
+/// Flattens an error and its sources into a single line for use in event payloads.
+pub(crate) fn describe_error(error: &dyn Error) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(inner) = source {
+        description.push_str(": ");
+        description.push_str(&inner.to_string());
+        source = inner.source();
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_serialization() {
+        let event = Event::ArtifactCached {
+            hostname: "registry.terraform.io".into(),
+            namespace: "hashicorp".into(),
+            provider_type: "random".into(),
+            version: "3.5.1".into(),
+            os: "linux".into(),
+            arch: "amd64".into(),
+        };
+        let envelope = EventEnvelope {
+            event: &event,
+            timestamp: "2024-01-01T00:00:00Z".into(),
+        };
+        let value = serde_json::to_value(&envelope).expect("Could not serialize event");
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "type": "artifact_cached",
+                "timestamp": "2024-01-01T00:00:00Z",
+                "data": {
+                    "hostname": "registry.terraform.io",
+                    "namespace": "hashicorp",
+                    "provider_type": "random",
+                    "version": "3.5.1",
+                    "os": "linux",
+                    "arch": "amd64",
+                }
+            })
+        );
+        assert_eq!(value["type"], event.event_type());
+    }
+}
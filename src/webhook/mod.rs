@@ -0,0 +1,82 @@
+//! Webhook notifications for events observed by the mirror.
+//!
+//! Events are written to the `webhook_delivery` table, one row per configured
+//! endpoint, and delivered by the [`dispatcher`] task. Keeping the queue in the
+//! database means deliveries survive restarts and are retried when an endpoint
+//! is unavailable.
+mod dispatcher;
+mod event;
+
+pub(crate) use dispatcher::dispatcher;
+pub(crate) use event::{describe_error, Event};
+
+use crate::error::TerrashineError;
+use event::EventEnvelope;
+use sqlx::PgPool;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use url::Url;
+
+#[derive(Clone)]
+pub(crate) struct Webhooks {
+    db: PgPool,
+    endpoints: Arc<[Url]>,
+    notify: Arc<Notify>,
+}
+
+impl Webhooks {
+    pub(crate) fn new(db: PgPool, endpoints: Vec<Url>) -> Self {
+        Self {
+            db,
+            endpoints: endpoints.into(),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.endpoints.is_empty()
+    }
+
+    /// Queue an event for delivery to every configured endpoint.
+    ///
+    /// Errors are logged rather than returned as a notification failure
+    /// should never fail the mirror request that produced it.
+    pub(crate) async fn emit(&self, event: Event) {
+        if !self.is_enabled() {
+            return;
+        }
+        match self.enqueue(&event).await {
+            Ok(()) => self.notify.notify_one(),
+            Err(error) => {
+                tracing::error!(
+                    reason = %error,
+                    event_type = event.event_type(),
+                    "Could not queue webhook event"
+                );
+            }
+        }
+    }
+
+    async fn enqueue(&self, event: &Event) -> Result<(), TerrashineError> {
+        let payload = serde_json::to_string(&EventEnvelope::new(event))?;
+        let endpoints = self
+            .endpoints
+            .iter()
+            .map(Url::to_string)
+            .collect::<Vec<_>>();
+        sqlx::query!(
+            r#"
+            insert into "webhook_delivery" ("endpoint", "event_type", "payload")
+                select "endpoint", $2, $3::text::jsonb
+                from unnest($1::text[]) as "t" ("endpoint");
+            "#,
+            &endpoints[..],
+            event.event_type(),
+            payload,
+        )
+        .execute(&self.db)
+        .await?;
+        tracing::debug!(event_type = event.event_type(), "Queued webhook event");
+        Ok(())
+    }
+}